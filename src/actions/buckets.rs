@@ -0,0 +1,611 @@
+use aws_config::SdkConfig;
+use aws_sdk_s3::error::{ProvideErrorMetadata, SdkError};
+use aws_sdk_s3::types::{
+    BucketLifecycleConfiguration, BucketLocationConstraint, BucketVersioningStatus,
+    CreateBucketConfiguration, ExpirationStatus, LifecycleExpiration, LifecycleRuleFilter,
+    NoncurrentVersionExpiration, PublicAccessBlockConfiguration, ServerSideEncryption,
+    ServerSideEncryptionByDefault, ServerSideEncryptionConfiguration, ServerSideEncryptionRule,
+    Tag, Tagging, VersioningConfiguration,
+};
+use aws_sdk_s3::{Client, Error};
+use std::collections::BTreeMap;
+
+use crate::actions::plan::{AttrChange, ChangeKind, NamedPlan, PlanAction, STACK_TAG, tags_change};
+use crate::aws::{AwsSym, LifecycleRule, S3Sym};
+use crate::state::StackState;
+
+/// Kind of the bucket plans
+pub(crate) const KIND: &'static str = "s3";
+
+/// The region S3 creates buckets in when given no location constraint
+const DEFAULT_REGION: &'static str = "us-east-1";
+
+/// The settings awsdsl manages on a bucket, in the order they are applied
+pub(crate) const SETTINGS: [&str; 5] = [
+    "versioning",
+    "encryption",
+    "public_access_block",
+    "lifecycle",
+    "tags",
+];
+
+/// A bucket that already exists and is reachable with the credentials of
+/// the stack, as returned by `describe_bucket`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ExistingBucket {
+    pub(crate) name: String,
+    pub(crate) versioning: bool,
+    /// the default server side encryption, if any
+    pub(crate) encryption: Option<String>,
+    /// every kind of public access is blocked
+    pub(crate) public_access_block: bool,
+    pub(crate) lifecycle: Vec<LifecycleRule>,
+    pub(crate) tags: BTreeMap<String, String>,
+}
+
+/// S3 client for a stack. Local stand-ins behind an endpoint don't serve
+/// virtual hosted buckets, they are addressed by path.
+pub(crate) fn client(config: &SdkConfig) -> Client {
+    let s3_config = aws_sdk_s3::config::Builder::from(config)
+        .force_path_style(config.endpoint_url().is_some())
+        .build();
+    Client::from_conf(s3_config)
+}
+
+/// Result of reading a bucket setting: the error `code` S3 answers with
+/// when the setting was never made is `None`
+fn absent<T, E, R>(
+    result: Result<T, SdkError<E, R>>,
+    code: &str,
+) -> Result<Option<T>, SdkError<E, R>>
+where
+    E: ProvideErrorMetadata,
+{
+    match result {
+        Ok(output) => Ok(Some(output)),
+        Err(e) if e.code() == Some(code) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// The tags of the bucket `s3`: the user tags and the stack tag
+pub(crate) fn desired_tags(aws_sym: &AwsSym, s3: &S3Sym) -> BTreeMap<String, String> {
+    let mut tags = s3.tags.clone();
+    tags.insert(STACK_TAG.to_string(), aws_sym.name.to_string());
+    tags
+}
+
+/// Read the bucket `name` and the settings awsdsl manages on it, `None`
+/// if there is no such bucket
+pub(crate) async fn describe_bucket(
+    client: &Client,
+    name: &str,
+) -> Result<Option<ExistingBucket>, Error> {
+    match client.head_bucket().bucket(name).send().await {
+        Ok(_) => {}
+        Err(e) if e.as_service_error().map(|se| se.is_not_found()) == Some(true) => {
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    let versioning = client.get_bucket_versioning().bucket(name).send().await?;
+    let encryption = absent(
+        client.get_bucket_encryption().bucket(name).send().await,
+        "ServerSideEncryptionConfigurationNotFoundError",
+    )?;
+    let public_access = absent(
+        client.get_public_access_block().bucket(name).send().await,
+        "NoSuchPublicAccessBlockConfiguration",
+    )?;
+    let lifecycle = absent(
+        client
+            .get_bucket_lifecycle_configuration()
+            .bucket(name)
+            .send()
+            .await,
+        "NoSuchLifecycleConfiguration",
+    )?;
+    let tagging = absent(
+        client.get_bucket_tagging().bucket(name).send().await,
+        "NoSuchTagSet",
+    )?;
+
+    let encryption = encryption
+        .as_ref()
+        .and_then(|e| e.server_side_encryption_configuration())
+        .and_then(|c| c.rules().first())
+        .and_then(|r| r.apply_server_side_encryption_by_default())
+        .map(|d| d.sse_algorithm().as_str().to_string());
+    let public_access_block = match public_access
+        .as_ref()
+        .and_then(|p| p.public_access_block_configuration())
+    {
+        Some(c) => {
+            c.block_public_acls() == Some(true)
+                && c.ignore_public_acls() == Some(true)
+                && c.block_public_policy() == Some(true)
+                && c.restrict_public_buckets() == Some(true)
+        }
+        None => false,
+    };
+    let lifecycle = match &lifecycle {
+        Some(l) => l
+            .rules()
+            .iter()
+            .map(|r| LifecycleRule {
+                prefix: r
+                    .filter()
+                    .and_then(|f| f.prefix())
+                    .unwrap_or_default()
+                    .to_string(),
+                expiration_days: r.expiration().and_then(|e| e.days()),
+                noncurrent_expiration_days: r
+                    .noncurrent_version_expiration()
+                    .and_then(|n| n.noncurrent_days()),
+            })
+            .collect(),
+        None => vec![],
+    };
+    let tags = match &tagging {
+        Some(t) => t
+            .tag_set()
+            .iter()
+            .map(|t| (t.key().to_string(), t.value().to_string()))
+            .collect(),
+        None => BTreeMap::new(),
+    };
+
+    Ok(Some(ExistingBucket {
+        name: name.to_string(),
+        versioning: versioning.status() == Some(&BucketVersioningStatus::Enabled),
+        encryption: encryption,
+        public_access_block: public_access_block,
+        lifecycle: lifecycle,
+        tags: tags,
+    }))
+}
+
+/// Describe the buckets named in `names`, the missing ones are left out
+pub(crate) async fn describe_buckets(
+    client: &Client,
+    names: &[String],
+) -> Result<BTreeMap<String, ExistingBucket>, Error> {
+    let mut existing: BTreeMap<String, ExistingBucket> = BTreeMap::new();
+    for name in names {
+        if let Some(bucket) = describe_bucket(client, name).await? {
+            existing.insert(name.to_string(), bucket);
+        }
+    }
+    Ok(existing)
+}
+
+/// Create the bucket `s3` in `region` and make every setting. A bucket
+/// of that name already owned by the account is taken over as is.
+pub(crate) async fn create_bucket(
+    client: &Client,
+    aws_sym: &AwsSym,
+    s3: &S3Sym,
+) -> Result<(), Error> {
+    let mut req = client.create_bucket().bucket(&s3.name);
+    if aws_sym.region != DEFAULT_REGION {
+        req = req.create_bucket_configuration(
+            CreateBucketConfiguration::builder()
+                .location_constraint(BucketLocationConstraint::from(aws_sym.region.as_str()))
+                .build(),
+        );
+    }
+    match req.send().await {
+        Ok(_) => {}
+        Err(e)
+            if e.as_service_error()
+                .map(|se| se.is_bucket_already_owned_by_you())
+                == Some(true) => {}
+        Err(e) => return Err(e.into()),
+    }
+    update_bucket(client, aws_sym, s3, &SETTINGS).await
+}
+
+/// Make the `settings` of the bucket `s3` what the file says. The tags
+/// not set by the file are kept.
+pub(crate) async fn update_bucket(
+    client: &Client,
+    aws_sym: &AwsSym,
+    s3: &S3Sym,
+    settings: &[&str],
+) -> Result<(), Error> {
+    for setting in settings {
+        match *setting {
+            "versioning" => {
+                let status = match s3.versioning {
+                    true => BucketVersioningStatus::Enabled,
+                    false => BucketVersioningStatus::Suspended,
+                };
+                client
+                    .put_bucket_versioning()
+                    .bucket(&s3.name)
+                    .versioning_configuration(
+                        VersioningConfiguration::builder().status(status).build(),
+                    )
+                    .send()
+                    .await?;
+            }
+            "encryption" => {
+                let by_default = ServerSideEncryptionByDefault::builder()
+                    .sse_algorithm(ServerSideEncryption::from(s3.encryption.as_str()))
+                    .build()?;
+                let config = ServerSideEncryptionConfiguration::builder()
+                    .rules(
+                        ServerSideEncryptionRule::builder()
+                            .apply_server_side_encryption_by_default(by_default)
+                            .build(),
+                    )
+                    .build()?;
+                client
+                    .put_bucket_encryption()
+                    .bucket(&s3.name)
+                    .server_side_encryption_configuration(config)
+                    .send()
+                    .await?;
+            }
+            "public_access_block" => {
+                if s3.public_access_block {
+                    let config = PublicAccessBlockConfiguration::builder()
+                        .block_public_acls(true)
+                        .ignore_public_acls(true)
+                        .block_public_policy(true)
+                        .restrict_public_buckets(true)
+                        .build();
+                    client
+                        .put_public_access_block()
+                        .bucket(&s3.name)
+                        .public_access_block_configuration(config)
+                        .send()
+                        .await?;
+                } else {
+                    client
+                        .delete_public_access_block()
+                        .bucket(&s3.name)
+                        .send()
+                        .await?;
+                }
+            }
+            "lifecycle" => {
+                if s3.lifecycle.is_empty() {
+                    client
+                        .delete_bucket_lifecycle()
+                        .bucket(&s3.name)
+                        .send()
+                        .await?;
+                    continue;
+                }
+                let mut rules = vec![];
+                for (n, rule) in s3.lifecycle.iter().enumerate() {
+                    let mut builder = aws_sdk_s3::types::LifecycleRule::builder()
+                        .id(format!("awsdsl-{}", n))
+                        .status(ExpirationStatus::Enabled)
+                        .filter(LifecycleRuleFilter::builder().prefix(&rule.prefix).build());
+                    if let Some(days) = rule.expiration_days {
+                        builder =
+                            builder.expiration(LifecycleExpiration::builder().days(days).build());
+                    }
+                    if let Some(days) = rule.noncurrent_expiration_days {
+                        builder = builder.noncurrent_version_expiration(
+                            NoncurrentVersionExpiration::builder()
+                                .noncurrent_days(days)
+                                .build(),
+                        );
+                    }
+                    rules.push(builder.build()?);
+                }
+                client
+                    .put_bucket_lifecycle_configuration()
+                    .bucket(&s3.name)
+                    .lifecycle_configuration(
+                        BucketLifecycleConfiguration::builder()
+                            .set_rules(Some(rules))
+                            .build()?,
+                    )
+                    .send()
+                    .await?;
+            }
+            "tags" => {
+                // tagging replaces the whole set, keep what others added
+                let current = absent(
+                    client.get_bucket_tagging().bucket(&s3.name).send().await,
+                    "NoSuchTagSet",
+                )?;
+                let mut tags: BTreeMap<String, String> = match &current {
+                    Some(t) => t
+                        .tag_set()
+                        .iter()
+                        .map(|t| (t.key().to_string(), t.value().to_string()))
+                        .collect(),
+                    None => BTreeMap::new(),
+                };
+                tags.extend(desired_tags(aws_sym, s3));
+                let mut tag_set = vec![];
+                for (k, v) in tags {
+                    tag_set.push(Tag::builder().key(k).value(v).build()?);
+                }
+                client
+                    .put_bucket_tagging()
+                    .bucket(&s3.name)
+                    .tagging(Tagging::builder().set_tag_set(Some(tag_set)).build()?)
+                    .send()
+                    .await?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Delete the bucket `name`, a bucket that is already gone is fine. S3
+/// refuses to delete a bucket that still holds objects, they are never
+/// deleted for the user.
+pub(crate) async fn delete_bucket(client: &Client, name: &str) -> Result<(), Error> {
+    match client.delete_bucket().bucket(name).send().await {
+        Ok(_) => Ok(()),
+        Err(e) if e.code() == Some("NoSuchBucket") => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Compare the desired settings of `s3` against the existing bucket
+fn diff_bucket(aws_sym: &AwsSym, s3: &S3Sym, bucket: &ExistingBucket) -> Vec<AttrChange> {
+    let mut changes: Vec<AttrChange> = vec![];
+    let mut check = |attr: &str, actual: String, desired: String| {
+        if actual != desired {
+            let mut change = AttrChange::new(attr, actual, desired);
+            change.kind = ChangeKind::InPlace;
+            changes.push(change);
+        }
+    };
+    check(
+        "versioning",
+        bucket.versioning.to_string(),
+        s3.versioning.to_string(),
+    );
+    check(
+        "encryption",
+        bucket.encryption.clone().unwrap_or(String::from("<none>")),
+        s3.encryption.to_string(),
+    );
+    check(
+        "public_access_block",
+        bucket.public_access_block.to_string(),
+        s3.public_access_block.to_string(),
+    );
+    let rules = |rules: &[LifecycleRule]| {
+        let rules: Vec<String> = rules.iter().map(|r| r.to_string()).collect();
+        format!("[{}]", rules.join(", "))
+    };
+    check("lifecycle", rules(&bucket.lifecycle), rules(&s3.lifecycle));
+
+    changes.extend(tags_change(&bucket.tags, &desired_tags(aws_sym, s3)));
+
+    changes
+}
+
+/// Compute the plans of the buckets of `aws_sym` given the buckets that
+/// exist, by name, and the recorded `state`. A bucket whose name changed
+/// is replaced; buckets of the state no longer in the file are deleted.
+pub(crate) fn diff(
+    aws_sym: &AwsSym,
+    existing: &BTreeMap<String, ExistingBucket>,
+    state: &StackState,
+) -> Vec<NamedPlan> {
+    let mut plans: Vec<NamedPlan> = vec![];
+    for s3 in &aws_sym.s3s {
+        let mut plan = NamedPlan::new(KIND, s3.id.as_str(), s3.name.as_str(), PlanAction::NoOp);
+        let renamed = state
+            .buckets
+            .get(&s3.id)
+            .filter(|b| b.name != s3.name && existing.contains_key(&b.name));
+        match (renamed, existing.get(&s3.name)) {
+            (Some(old), _) => {
+                plan.action = PlanAction::Replace;
+                let mut change = AttrChange::new("name", old.name.as_str(), s3.name.as_str());
+                change.kind = ChangeKind::Replace;
+                plan.changes.push(change);
+            }
+            (None, None) => plan.action = PlanAction::Create,
+            (None, Some(bucket)) => {
+                plan.changes = diff_bucket(aws_sym, s3, bucket);
+                if !plan.changes.is_empty() {
+                    plan.action = PlanAction::Update;
+                }
+            }
+        }
+        plans.push(plan);
+    }
+    for bucket in state.buckets.values() {
+        if !aws_sym.s3s.iter().any(|s| s.id == bucket.id) {
+            plans.push(NamedPlan::new(
+                KIND,
+                bucket.id.as_str(),
+                bucket.name.as_str(),
+                PlanAction::Delete,
+            ));
+        }
+    }
+    plans
+}
+
+/// Compute the plans deleting every bucket `aws_sym` deployed: the ones
+/// recorded in `state` and the existing buckets of the file tagged with
+/// the stack
+pub(crate) fn destroy(
+    aws_sym: &AwsSym,
+    existing: &BTreeMap<String, ExistingBucket>,
+    state: &StackState,
+) -> Vec<NamedPlan> {
+    let mut plans: Vec<NamedPlan> = state
+        .buckets
+        .values()
+        .map(|b| NamedPlan::new(KIND, b.id.as_str(), b.name.as_str(), PlanAction::Delete))
+        .collect();
+    for s3 in &aws_sym.s3s {
+        let tagged = existing
+            .get(&s3.name)
+            .is_some_and(|b| b.tags.get(STACK_TAG) == Some(&aws_sym.name));
+        if tagged && !plans.iter().any(|p| p.name == s3.name) {
+            plans.push(NamedPlan::new(
+                KIND,
+                s3.id.as_str(),
+                s3.name.as_str(),
+                PlanAction::Delete,
+            ));
+        }
+    }
+    plans.sort_by(|a, b| a.id.cmp(&b.id));
+    plans
+}
+
+/// The bucket names to describe for a plan: the ones of the file and
+/// the ones recorded in the state
+pub(crate) fn known_names(aws_sym: &AwsSym, state: &StackState) -> Vec<String> {
+    let mut names: Vec<String> = aws_sym.s3s.iter().map(|s| s.name.clone()).collect();
+    for bucket in state.buckets.values() {
+        if !names.contains(&bucket.name) {
+            names.push(bucket.name.clone());
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::fixtures::{empty_state, stack};
+    use crate::state::BucketState;
+    use std::env;
+
+    fn s3_sym(id: &str, name: &str) -> S3Sym {
+        S3Sym {
+            id: id.to_string(),
+            name: name.to_string(),
+            versioning: true,
+            encryption: "AES256".to_string(),
+            public_access_block: true,
+            lifecycle: vec![LifecycleRule {
+                prefix: "logs/".to_string(),
+                expiration_days: Some(30),
+                noncurrent_expiration_days: None,
+            }],
+            tags: BTreeMap::from([("team".to_string(), "web".to_string())]),
+        }
+    }
+
+    fn s3_stack(s3s: Vec<S3Sym>) -> AwsSym {
+        let mut aws_sym = stack();
+        for s3 in s3s {
+            aws_sym.add_s3(s3);
+        }
+        aws_sym
+    }
+
+    fn existing(aws_sym: &AwsSym, s3: &S3Sym) -> ExistingBucket {
+        ExistingBucket {
+            name: s3.name.to_string(),
+            versioning: s3.versioning,
+            encryption: Some(s3.encryption.to_string()),
+            public_access_block: s3.public_access_block,
+            lifecycle: s3.lifecycle.clone(),
+            tags: desired_tags(aws_sym, s3),
+        }
+    }
+
+    #[test]
+    fn test_bucket_plan() {
+        let aws_sym = s3_stack(vec![
+            s3_sym("logs", "acme-logs"),
+            s3_sym("data", "acme-data"),
+        ]);
+        let mut bucket = existing(&aws_sym, &aws_sym.s3s[0]);
+        let mut state = empty_state();
+        state.buckets.insert(
+            "old".to_string(),
+            BucketState {
+                id: "old".to_string(),
+                name: "acme-old".to_string(),
+            },
+        );
+        let mut buckets = BTreeMap::from([(bucket.name.clone(), bucket.clone())]);
+        let plans = diff(&aws_sym, &buckets, &state);
+        assert_eq!(plans[0].action, PlanAction::NoOp);
+        assert_eq!(plans[1].action, PlanAction::Create);
+        assert_eq!(plans[2].action, PlanAction::Delete);
+        assert_eq!(plans[2].name, "acme-old");
+
+        bucket.versioning = false;
+        bucket.encryption = None;
+        bucket.tags.insert("other".to_string(), "kept".to_string());
+        buckets.insert(bucket.name.clone(), bucket);
+        let plans = diff(&aws_sym, &buckets, &state);
+        assert_eq!(plans[0].action, PlanAction::Update);
+        let attrs: Vec<&str> = plans[0].changes.iter().map(|c| c.attr.as_str()).collect();
+        assert_eq!(attrs, vec!["versioning", "encryption"]);
+        assert_eq!(plans[0].changes[1].actual, "<none>");
+    }
+
+    #[test]
+    fn test_bucket_plan_rename() {
+        let aws_sym = s3_stack(vec![s3_sym("logs", "acme-logs-2")]);
+        let mut state = empty_state();
+        state.buckets.insert(
+            "logs".to_string(),
+            BucketState::new(&s3_sym("logs", "acme-logs")),
+        );
+        let old = existing(&aws_sym, &s3_sym("logs", "acme-logs"));
+        let buckets = BTreeMap::from([(old.name.clone(), old)]);
+        let plans = diff(&aws_sym, &buckets, &state);
+        assert_eq!(plans.len(), 1);
+        assert_eq!(plans[0].action, PlanAction::Replace);
+        assert_eq!(
+            plans[0].changes[0].to_string(),
+            "name: acme-logs -> acme-logs-2 (forces replacement)"
+        );
+
+        let plans = destroy(&aws_sym, &buckets, &state);
+        assert_eq!(plans.len(), 1);
+        assert_eq!(plans[0].name, "acme-logs");
+    }
+
+    /// Runs against a local S3 stand-in, e.g. localstack:
+    /// AWSDSL_TEST_ENDPOINT=http://localhost:4566 cargo test -- --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn test_bucket_round_trip() {
+        let endpoint = env::var("AWSDSL_TEST_ENDPOINT").unwrap();
+        let config = aws_config::defaults(aws_config::BehaviorVersion::latest())
+            .region(aws_config::Region::new("eu-west-1"))
+            .endpoint_url(endpoint)
+            .load()
+            .await;
+        let client = client(&config);
+        let aws_sym = s3_stack(vec![s3_sym("test", "awsdsl-test-bucket")]);
+        let mut s3 = aws_sym.s3s[0].clone();
+
+        // creating twice is fine, the second run finds nothing to do
+        create_bucket(&client, &aws_sym, &s3).await.unwrap();
+        create_bucket(&client, &aws_sym, &s3).await.unwrap();
+        let bucket = describe_bucket(&client, &s3.name).await.unwrap().unwrap();
+        assert!(diff_bucket(&aws_sym, &s3, &bucket).is_empty());
+
+        s3.versioning = false;
+        s3.lifecycle = vec![];
+        let changes = diff_bucket(&aws_sym, &s3, &bucket);
+        let settings: Vec<&str> = changes.iter().map(|c| c.attr.as_str()).collect();
+        update_bucket(&client, &aws_sym, &s3, &settings)
+            .await
+            .unwrap();
+        let bucket = describe_bucket(&client, &s3.name).await.unwrap().unwrap();
+        assert!(diff_bucket(&aws_sym, &s3, &bucket).is_empty());
+
+        delete_bucket(&client, &s3.name).await.unwrap();
+        delete_bucket(&client, &s3.name).await.unwrap();
+        assert_eq!(describe_bucket(&client, &s3.name).await.unwrap(), None);
+    }
+}
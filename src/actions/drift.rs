@@ -0,0 +1,234 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::actions::plan::{
+    ExistingInstance, INDEX_TAG, KEY_TAG, STACK_TAG, desired_tags, name_tag, sorted_ids, tag_diff,
+};
+use crate::aws::AwsSym;
+use crate::state::{Ec2State, StackState};
+
+/// Instance state awsdsl leaves its instances in
+const EXPECTED_STATE: &'static str = "running";
+
+/// One attribute of a deployed instance changed outside of awsdsl.
+/// `expected` is what the state (or the file) says, `actual` what EC2
+/// reports.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Drift {
+    pub(crate) ec2_id: String,
+    pub(crate) instance_id: String,
+    pub(crate) attr: String,
+    pub(crate) expected: String,
+    pub(crate) actual: String,
+}
+
+impl Drift {
+    fn new(
+        ec2_id: &str,
+        instance_id: &str,
+        attr: &str,
+        expected: impl std::convert::Into<String>,
+        actual: impl std::convert::Into<String>,
+    ) -> Self {
+        Drift {
+            ec2_id: ec2_id.to_string(),
+            instance_id: instance_id.to_string(),
+            attr: attr.to_string(),
+            expected: expected.into(),
+            actual: actual.into(),
+        }
+    }
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ec2 [{}] ({}) {}: expected {}, actual {}",
+            self.ec2_id, self.instance_id, self.attr, self.expected, self.actual
+        )
+    }
+}
+
+/// The values an instance is expected to have
+struct Expected<'a> {
+    instance_type: &'a str,
+    ami_id: &'a str,
+    subnet_id: &'a str,
+    sg_ids: Vec<String>,
+    key_name: &'a str,
+    tags: BTreeMap<String, String>,
+}
+
+/// Compare one instance against what is expected of it
+fn check_instance(ec2_id: &str, expected: &Expected, inst: &ExistingInstance) -> Vec<Drift> {
+    let mut drifts: Vec<Drift> = vec![];
+    let mut check = |attr: &str, expected: &str, actual: &str| {
+        if expected != actual {
+            drifts.push(Drift::new(
+                ec2_id,
+                &inst.instance_id,
+                attr,
+                expected,
+                actual,
+            ));
+        }
+    };
+    check("state", EXPECTED_STATE, &inst.state);
+    check("instance_type", expected.instance_type, &inst.instance_type);
+    check("ami", expected.ami_id, &inst.ami_id);
+    check("subnet_id", expected.subnet_id, &inst.subnet_id);
+    check(
+        "sg_ids",
+        &sorted_ids(&expected.sg_ids),
+        &sorted_ids(&inst.sg_ids),
+    );
+    check("key_name", expected.key_name, &inst.key_name);
+    for (k, actual, wanted) in tag_diff(&inst.tags, &expected.tags) {
+        check(&format!("tag {}", k), &wanted, &actual);
+    }
+    drifts
+}
+
+/// The tags recorded instances carry, rebuilt from the state
+fn state_tags(state: &StackState, ec2: &Ec2State, instance_id: &str) -> BTreeMap<String, String> {
+    let mut tags: BTreeMap<String, String> = ec2.tags.clone();
+    tags.insert("Name".to_string(), name_tag(&ec2.name));
+    tags.insert(STACK_TAG.to_string(), state.stack.to_string());
+    if let Some(index) = ec2.indexes.get(instance_id) {
+        tags.insert(INDEX_TAG.to_string(), index.to_string());
+    }
+    if let Some(key) = &ec2.key {
+        tags.insert(KEY_TAG.to_string(), key.to_string());
+    }
+    tags
+}
+
+/// Compare what was deployed against `existing`, the instances EC2
+/// reports. Instances recorded in `state` are checked against the values
+/// recorded there; an `Ec2Sym` the state doesn't know about is checked
+/// against the file, through the instances named after it.
+pub(crate) fn detect(
+    aws_sym: &AwsSym,
+    state: &StackState,
+    existing: &[ExistingInstance],
+) -> Vec<Drift> {
+    let mut drifts: Vec<Drift> = vec![];
+    for ec2 in state.ec2s.values() {
+        for inst_id in &ec2.instance_ids {
+            let expected = Expected {
+                instance_type: &ec2.instance_type,
+                ami_id: &ec2.ami_id,
+                subnet_id: &ec2.subnet_id,
+                sg_ids: ec2.sg_ids.clone(),
+                key_name: &ec2.key_name,
+                tags: state_tags(state, ec2, inst_id),
+            };
+            match existing.iter().find(|i| &i.instance_id == inst_id) {
+                Some(inst) => drifts.extend(check_instance(&ec2.id, &expected, inst)),
+                None => drifts.push(Drift::new(
+                    &ec2.id,
+                    inst_id,
+                    "state",
+                    EXPECTED_STATE,
+                    "missing",
+                )),
+            }
+        }
+    }
+
+    for ec2 in &aws_sym.ec2s {
+        if state.ec2s.contains_key(&ec2.id) {
+            continue;
+        }
+        let matched = existing.iter().filter(|i| {
+            i.name == ec2.name
+                && state.owner_of(&i.instance_id).is_none()
+                && i.stack.as_deref().unwrap_or(aws_sym.name.as_str()) == aws_sym.name
+        });
+        for inst in matched {
+            let expected = Expected {
+                instance_type: &ec2.instance_type,
+                ami_id: &ec2.ami_id,
+                subnet_id: &ec2.subnet_id,
+                sg_ids: ec2.sg_ids.clone(),
+                key_name: &ec2.key_name,
+                tags: desired_tags(aws_sym, ec2, inst.index().unwrap_or_default()),
+            };
+            drifts.extend(check_instance(&ec2.id, &expected, inst));
+        }
+    }
+    drifts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::fixtures::{ec2_stack, ec2_sym, empty_state, instance};
+    use crate::aws::Ec2Sym;
+
+    fn existing(id: &str, name: &str) -> ExistingInstance {
+        let mut inst = instance(id, name, Some("stack"));
+        inst.tags.insert("Owner".to_string(), "someone".to_string());
+        inst
+    }
+
+    fn state_with(ec2: &Ec2Sym, inst_ids: &[&str]) -> StackState {
+        let mut state = empty_state();
+        let indexes = inst_ids
+            .iter()
+            .enumerate()
+            .map(|(n, i)| (i.to_string(), n as u32))
+            .collect();
+        state
+            .ec2s
+            .insert(ec2.id.clone(), Ec2State::new(ec2, indexes));
+        state
+    }
+
+    #[test]
+    fn test_no_drift() {
+        let ec2 = ec2_sym("web01", 1);
+        let state = state_with(&ec2, &["i-1"]);
+        let aws_sym = ec2_stack(vec![ec2]);
+        assert!(detect(&aws_sym, &state, &[existing("i-1", "web01")]).is_empty());
+    }
+
+    #[test]
+    fn test_drift_against_state() {
+        let ec2 = ec2_sym("web01", 1);
+        let state = state_with(&ec2, &["i-1", "i-2"]);
+        // the file changed since the apply, the state is what is compared
+        let aws_sym = ec2_stack(vec![ec2_sym("web02", 1)]);
+        let mut inst = existing("i-1", "web01");
+        inst.state = "stopped".to_string();
+        inst.instance_type = "t3.large".to_string();
+        inst.sg_ids.push("sg-2".to_string());
+        inst.tags.insert("Name".to_string(), "renamed".to_string());
+        let drifts = detect(&aws_sym, &state, &[inst]);
+        let attrs: Vec<&str> = drifts.iter().map(|d| d.attr.as_str()).collect();
+        assert_eq!(
+            attrs,
+            vec!["state", "instance_type", "sg_ids", "tag Name", "state"]
+        );
+        assert_eq!(drifts[2].actual, "sg-1,sg-2");
+        // i-2 was terminated by hand
+        assert_eq!(drifts[4].instance_id, "i-2");
+        assert_eq!(drifts[4].actual, "missing");
+    }
+
+    #[test]
+    fn test_drift_against_file() {
+        let aws_sym = ec2_stack(vec![ec2_sym("web01", 1)]);
+        let mut inst = existing("i-1", "web01");
+        inst.key_name = "other".to_string();
+        let mut other = existing("i-2", "web01");
+        other.stack = Some("other-stack".to_string());
+        other.key_name = "other".to_string();
+        let drifts = detect(&aws_sym, &empty_state(), &[inst, other]);
+        assert_eq!(drifts.len(), 1);
+        assert_eq!(drifts[0].instance_id, "i-1");
+        assert_eq!(drifts[0].attr, "key_name");
+        assert_eq!(drifts[0].expected, "key");
+    }
+}
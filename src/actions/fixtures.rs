@@ -0,0 +1,63 @@
+use std::collections::BTreeMap;
+
+use crate::actions::plan::{ExistingInstance, INDEX_TAG, STACK_TAG, name_tag};
+use crate::aws::{AwsSym, Ec2Sym};
+use crate::state::StackState;
+
+/// The stack `stack` in eu-west-1, without resources
+pub(crate) fn stack() -> AwsSym {
+    AwsSym::new(
+        "aws".to_string(),
+        "stack".to_string(),
+        "eu-west-1".to_string(),
+    )
+}
+
+pub(crate) fn ec2_stack(ec2s: Vec<Ec2Sym>) -> AwsSym {
+    let mut aws_sym = stack();
+    for ec2 in ec2s {
+        aws_sym.add_ec2(ec2);
+    }
+    aws_sym
+}
+
+pub(crate) fn empty_state() -> StackState {
+    StackState::new("stack", "eu-west-1")
+}
+
+pub(crate) fn ec2_sym(name: &str, count: u8) -> Ec2Sym {
+    Ec2Sym::new(
+        name.to_string(),
+        "desc".to_string(),
+        "t2.micro".to_string(),
+        "ami-1".to_string(),
+        "subnet-1".to_string(),
+        vec!["sg-1".to_string()],
+        0.0,
+        count,
+        "key".to_string(),
+    )
+}
+
+/// A running instance matching `ec2_sym(name, _)`, at index 0 of `stack` when it
+/// carries the stack tag
+pub(crate) fn instance(id: &str, name: &str, stack: Option<&str>) -> ExistingInstance {
+    let mut tags = BTreeMap::from([("Name".to_string(), name_tag(name))]);
+    if let Some(s) = stack {
+        tags.insert(STACK_TAG.to_string(), s.to_string());
+        tags.insert(INDEX_TAG.to_string(), "0".to_string());
+    }
+    ExistingInstance {
+        instance_id: id.to_string(),
+        name: name.to_string(),
+        stack: stack.map(|s| s.to_string()),
+        state: "running".to_string(),
+        instance_type: "t2.micro".to_string(),
+        ami_id: "ami-1".to_string(),
+        subnet_id: "subnet-1".to_string(),
+        sg_ids: vec!["sg-1".to_string()],
+        key_name: "key".to_string(),
+        tags: tags,
+        launch_time: 0,
+    }
+}
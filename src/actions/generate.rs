@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+
+use crate::actions::import::{ec2_sym_from, user_tags};
+use crate::actions::plan::ExistingInstance;
+use crate::aws::{AwsSym, Ec2Sym, source};
+
+/// Name of the ec2 resource of an instance: its `Name` tag, without the
+/// awsdsl prefix, or its id when it has none
+fn resource_name(inst: &ExistingInstance) -> String {
+    if inst.name.is_empty() {
+        return inst.instance_id.to_string();
+    }
+    inst.name.to_string()
+}
+
+/// true if both instances can be declared by the same ec2 block
+fn same_resource(a: &ExistingInstance, b: &ExistingInstance) -> bool {
+    a.instance_type == b.instance_type
+        && a.ami_id == b.ami_id
+        && a.subnet_id == b.subnet_id
+        && a.sg_ids == b.sg_ids
+        && a.key_name == b.key_name
+        && user_tags(a) == user_tags(b)
+}
+
+/// Turn the instances into ec2 resources, sorted by name. Instances
+/// sharing a name and all their attributes become one resource with a
+/// `count`; when the attributes differ each instance gets its own
+/// resource, named after the instance id. A resource holds at most 255
+/// instances.
+pub(crate) fn ec2_syms(existing: &[ExistingInstance]) -> Result<Vec<Ec2Sym>, String> {
+    let mut by_name: BTreeMap<String, Vec<&ExistingInstance>> = BTreeMap::new();
+    for inst in existing {
+        by_name.entry(resource_name(inst)).or_default().push(inst);
+    }
+
+    let mut ec2s: Vec<Ec2Sym> = vec![];
+    for (name, mut insts) in by_name {
+        insts.sort_by(|a, b| a.instance_id.cmp(&b.instance_id));
+        if insts.iter().all(|i| same_resource(insts[0], i)) {
+            let mut ec2 = ec2_sym_from(&name, insts[0]);
+            let ids: Vec<&str> = insts.iter().map(|i| i.instance_id.as_str()).collect();
+            ec2.desc = format!("imported from {}", ids.join(", "));
+            ec2.count = match u8::try_from(insts.len()) {
+                Ok(count) => count,
+                Err(_) => {
+                    let s = format!(
+                        "{} has {} instances, a resource holds at most {}",
+                        name,
+                        insts.len(),
+                        u8::MAX
+                    );
+                    return Err(s);
+                }
+            };
+            ec2s.push(ec2);
+            continue;
+        }
+        for inst in insts {
+            ec2s.push(ec2_sym_from(
+                &format!("{}-{}", name, inst.instance_id),
+                inst,
+            ));
+        }
+    }
+    Ok(ec2s)
+}
+
+/// The `aws { ... }` document declaring `existing` as the stack `stack`
+/// in `region`
+pub(crate) fn document(
+    stack: &str,
+    region: &str,
+    existing: &[ExistingInstance],
+) -> Result<String, String> {
+    let mut aws_sym = AwsSym::new("aws".to_string(), stack.to_string(), region.to_string());
+    for ec2 in ec2_syms(existing)? {
+        aws_sym.add_ec2(ec2);
+    }
+    Ok(source::aws_block(
+        &aws_sym,
+        &format!("generated from {}", region),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::fixtures::instance;
+    use crate::aws::parser::Parser;
+    use crate::lex::Scanner;
+    use crate::symbols::walk_ast;
+
+    fn existing(id: &str, name: &str, instance_type: &str) -> ExistingInstance {
+        let mut inst = instance(id, name, None);
+        inst.instance_type = instance_type.to_string();
+        inst.key_name = "".to_string();
+        inst.tags.clear();
+        inst
+    }
+
+    #[test]
+    fn test_generated_document_parses_back() {
+        let doc = document(
+            "eu west",
+            "eu-west-1",
+            &[
+                existing("i-2", "web", "t2.micro"),
+                existing("i-1", "web", "t2.micro"),
+                existing("i-3", "db", "t3.large"),
+                existing("i-4", "db", "t3.small"),
+                existing("i-5", "", "t2.nano"),
+            ],
+        )
+        .unwrap();
+        let mut parser = Parser::new(Scanner::new("".to_string(), doc));
+        let aws_sym = walk_ast(&parser.parse().unwrap()).unwrap().remove(0);
+        assert_eq!(aws_sym.name, "eu west");
+        assert_eq!(aws_sym.region, "eu-west-1");
+        let names: Vec<&str> = aws_sym.ec2s.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["db-i-3", "db-i-4", "i-5", "web"]);
+        assert_eq!(aws_sym.ec2s[0].instance_type, "t3.large");
+        assert_eq!(aws_sym.ec2s[3].count, 2);
+        assert_eq!(aws_sym.ec2s[3].desc, "imported from i-1, i-2");
+        assert_eq!(aws_sym.ec2s[3].key_name, "");
+    }
+
+    #[test]
+    fn test_ec2_syms() {
+        // instances differing by a user tag aren't copies of one resource
+        let mut tagged = existing("i-2", "web", "t2.micro");
+        tagged.tags.insert("team".to_string(), "web".to_string());
+        let ec2s = ec2_syms(&[existing("i-1", "web", "t2.micro"), tagged]).unwrap();
+        let names: Vec<&str> = ec2s.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["web-i-1", "web-i-2"]);
+
+        let insts: Vec<ExistingInstance> = (0..256)
+            .map(|n| existing(&format!("i-{}", n), "web", "t2.micro"))
+            .collect();
+        assert_eq!(
+            ec2_syms(&insts).unwrap_err(),
+            "web has 256 instances, a resource holds at most 255"
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::BTreeMap;
+
+use crate::actions::plan::ExistingInstance;
+use crate::aws::Ec2Sym;
+use crate::state::{Ec2State, StackState};
+
+/// The tags of an existing instance that are user tags: all of them
+/// except the ones awsdsl sets and the `aws:` ones AWS reserves
+pub(crate) fn user_tags(inst: &ExistingInstance) -> BTreeMap<String, String> {
+    inst.tags
+        .iter()
+        .filter(|(k, _)| {
+            k.as_str() != "Name" && !k.starts_with("awsdsl:") && !k.starts_with("aws:")
+        })
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+/// The `Ec2Sym` equivalent to an existing instance, named `name`, with
+/// its user tags
+pub(crate) fn ec2_sym_from(name: &str, inst: &ExistingInstance) -> Ec2Sym {
+    let mut ec2 = Ec2Sym::new(
+        name.to_string(),
+        format!("imported from {}", inst.instance_id),
+        inst.instance_type.to_string(),
+        inst.ami_id.to_string(),
+        inst.subnet_id.to_string(),
+        inst.sg_ids.clone(),
+        0.0,
+        1,
+        inst.key_name.to_string(),
+    );
+    ec2.set_tags(user_tags(inst));
+    ec2
+}
+
+/// Record `inst` in `state` as one of the instances of `ec2`. The index
+/// tag of the instance is kept when it is free, else it gets the lowest
+/// free index. An instance already recorded is refused.
+pub(crate) fn bind(
+    state: &mut StackState,
+    ec2: &Ec2Sym,
+    inst: &ExistingInstance,
+) -> Result<u32, String> {
+    if let Some(owner) = state.owner_of(&inst.instance_id) {
+        let s = format!("{} is already managed as ec2 [{}]", inst.instance_id, owner);
+        return Err(s);
+    }
+    let ec2_state = state
+        .ec2s
+        .entry(ec2.id.to_string())
+        .or_insert(Ec2State::new(ec2, Default::default()));
+    let count = match u8::try_from(ec2_state.instance_ids.len() + 1) {
+        Ok(count) => count,
+        Err(_) => {
+            let s = format!(
+                "ec2 [{}] has {} instances already, a resource holds at most {}",
+                ec2.id,
+                ec2_state.instance_ids.len(),
+                u8::MAX
+            );
+            return Err(s);
+        }
+    };
+    if ec2_state.instance_ids.is_empty() {
+        ec2_state.sg_ids = inst.sg_ids.clone();
+    }
+    let taken = |i: &u32| ec2_state.indexes.values().any(|x| x == i);
+    let index = match inst.index() {
+        Some(i) if !taken(&i) => i,
+        _ => (0..).find(|i| !taken(i)).unwrap_or_default(),
+    };
+    ec2_state
+        .indexes
+        .insert(inst.instance_id.to_string(), index);
+    let indexes = &ec2_state.indexes;
+    ec2_state.instance_ids = indexes.keys().cloned().collect();
+    ec2_state
+        .instance_ids
+        .sort_by_key(|i| indexes.get(i).copied());
+    ec2_state.count = count;
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::fixtures::{empty_state, instance};
+    use crate::actions::plan::INDEX_TAG;
+
+    fn existing(id: &str, index: Option<&str>) -> ExistingInstance {
+        let mut inst = instance(id, "", None);
+        inst.instance_type = "t3.small".to_string();
+        inst.sg_ids.push("sg-2".to_string());
+        inst.tags = BTreeMap::from([("team".to_string(), "web".to_string())]);
+        if let Some(i) = index {
+            inst.tags.insert(INDEX_TAG.to_string(), i.to_string());
+        }
+        inst
+    }
+
+    #[test]
+    fn test_import_binds_instances() {
+        let mut state = empty_state();
+        let first = existing("i-1", Some("1"));
+        let ec2 = ec2_sym_from("web01", &first);
+        assert_eq!(ec2.sg_ids, vec!["sg-1", "sg-2"]);
+        assert_eq!(ec2.tags.len(), 1);
+        assert_eq!(bind(&mut state, &ec2, &first), Ok(1));
+        // index 1 is taken, the second instance gets the lowest free one
+        assert_eq!(bind(&mut state, &ec2, &existing("i-2", Some("1"))), Ok(0));
+        let web01 = &state.ec2s["web01"];
+        assert_eq!(web01.instance_ids, vec!["i-2", "i-1"]);
+        assert_eq!(web01.count, 2);
+        assert_eq!(web01.instance_type, "t3.small");
+        assert_eq!(web01.sg_ids, vec!["sg-1", "sg-2"]);
+        // an instance can't be imported twice
+        assert!(bind(&mut state, &ec2, &first).is_err());
+
+        // nor past the instances a count holds
+        for n in 3..=255 {
+            bind(&mut state, &ec2, &existing(&format!("i-{}", n), None)).unwrap();
+        }
+        assert_eq!(state.ec2s["web01"].count, 255);
+        assert_eq!(
+            bind(&mut state, &ec2, &existing("i-256", None)),
+            Err("ec2 [web01] has 255 instances already, a resource holds at most 255".to_string())
+        );
+    }
+}
@@ -1,88 +1,468 @@
 use aws_config::SdkConfig;
 use aws_sdk_ec2::client::Waiters;
-use aws_sdk_ec2::types::{InstanceType, Tag};
+use aws_sdk_ec2::error::{ProvideErrorMetadata, SdkError};
+use aws_sdk_ec2::types::{
+    AttributeValue, Filter, Instance, InstanceType, ResourceType, Tag, TagSpecification,
+};
 use aws_sdk_ec2::{Client, Error};
+use std::collections::BTreeMap;
 use std::time::Duration;
 
-pub(crate) async fn show_instances(config: &SdkConfig) -> Result<(), Error> {
-    let client = Client::new(&config);
-    let instances = client.describe_instances().send().await?;
+use crate::actions::plan::{ExistingInstance, NAME_TAG_PREFIX, STACK_TAG};
 
-    for rsrv in instances.reservations() {
-        for inst in rsrv.instances() {
-            println!(
-                "  {}[{}]",
-                inst.instance_id().unwrap_or_default(),
-                inst.image_id().unwrap_or_default()
+/// Narrows down the instances described by `describe_instances`: a tag
+/// key, optionally with its value, and a VPC id
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct InstanceFilter {
+    pub(crate) tag_key: Option<String>,
+    pub(crate) tag_value: Option<String>,
+    pub(crate) vpc_id: Option<String>,
+}
+
+/// EC2 client of a stack
+pub(crate) fn client(config: &SdkConfig) -> Client {
+    Client::new(config)
+}
+
+/// Describe every live instance of the region matching `filter`, across
+/// all result pages
+pub(crate) async fn describe_instances(
+    config: &SdkConfig,
+    filter: &InstanceFilter,
+) -> Result<Vec<ExistingInstance>, Error> {
+    let client = client(config);
+    let mut req = client.describe_instances().filters(
+        Filter::builder()
+            .name("instance-state-name")
+            .values("pending")
+            .values("running")
+            .values("stopping")
+            .values("stopped")
+            .build(),
+    );
+    match (&filter.tag_key, &filter.tag_value) {
+        (Some(k), Some(v)) => {
+            req = req.filters(
+                Filter::builder()
+                    .name(format!("tag:{}", k))
+                    .values(v)
+                    .build(),
             );
         }
+        (Some(k), None) => {
+            req = req.filters(Filter::builder().name("tag-key").values(k).build());
+        }
+        _ => {}
+    }
+    if let Some(vpc_id) = &filter.vpc_id {
+        req = req.filters(Filter::builder().name("vpc-id").values(vpc_id).build());
+    }
+
+    let mut existing: Vec<ExistingInstance> = vec![];
+    let mut pages = req.into_paginator().send();
+    while let Some(page) = pages.next().await {
+        for rsrv in page?.reservations() {
+            for inst in rsrv.instances() {
+                existing.push(to_existing(inst));
+            }
+        }
+    }
+
+    Ok(existing)
+}
+
+/// Error code EC2 answers a `dry_run(true)` call with when the call would
+/// have succeeded
+const DRY_RUN_OK: &'static str = "DryRunOperation";
+
+/// Result of a call sent with `dry_run(true)`: `DryRunOperation` is the
+/// call going through, any other answer is an error
+pub(crate) fn dry_run_ok<T, E, R>(result: Result<T, SdkError<E, R>>) -> Result<(), Error>
+where
+    E: ProvideErrorMetadata,
+    Error: From<SdkError<E, R>>,
+{
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) if e.code() == Some(DRY_RUN_OK) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Read the value of tag `key` from an instance
+fn tag_value<'a>(inst: &'a Instance, key: &str) -> Option<&'a str> {
+    inst.tags()
+        .iter()
+        .find(|t| t.key() == Some(key))
+        .and_then(|t| t.value())
+}
+
+/// Convert an instance returned by `describe_instances`
+fn to_existing(inst: &Instance) -> ExistingInstance {
+    let name = tag_value(inst, "Name")
+        .unwrap_or_default()
+        .trim_start_matches(NAME_TAG_PREFIX);
+    ExistingInstance {
+        instance_id: inst.instance_id().unwrap_or_default().to_string(),
+        name: name.to_string(),
+        stack: tag_value(inst, STACK_TAG).map(|s| s.to_string()),
+        state: inst
+            .state()
+            .and_then(|s| s.name())
+            .map(|n| n.as_str().to_string())
+            .unwrap_or_default(),
+        instance_type: inst
+            .instance_type()
+            .map(|t| t.as_str().to_string())
+            .unwrap_or_default(),
+        ami_id: inst.image_id().unwrap_or_default().to_string(),
+        subnet_id: inst.subnet_id().unwrap_or_default().to_string(),
+        sg_ids: inst
+            .security_groups()
+            .iter()
+            .filter_map(|g| g.group_id())
+            .map(|g| g.to_string())
+            .collect(),
+        key_name: inst.key_name().unwrap_or_default().to_string(),
+        tags: inst
+            .tags()
+            .iter()
+            .map(|t| {
+                (
+                    t.key().unwrap_or_default().to_string(),
+                    t.value().unwrap_or_default().to_string(),
+                )
+            })
+            .collect(),
+        launch_time: inst.launch_time().map(|t| t.secs()).unwrap_or_default(),
+    }
+}
+
+/// Describe the given instances whatever their state or tags, across all
+/// result pages. Unknown ids are left out of the result rather than
+/// failing the call.
+pub(crate) async fn describe_instances_by_id(
+    config: &SdkConfig,
+    inst_ids: Vec<String>,
+) -> Result<Vec<ExistingInstance>, Error> {
+    if inst_ids.is_empty() {
+        return Ok(vec![]);
+    }
+    let client = client(config);
+    let req = client.describe_instances().filters(
+        Filter::builder()
+            .name("instance-id")
+            .set_values(Some(inst_ids))
+            .build(),
+    );
+
+    let mut existing: Vec<ExistingInstance> = vec![];
+    let mut pages = req.into_paginator().send();
+    while let Some(page) = pages.next().await {
+        for rsrv in page?.reservations() {
+            for inst in rsrv.instances() {
+                existing.push(to_existing(inst));
+            }
+        }
+    }
+
+    Ok(existing)
+}
+
+/// List the live (not terminated) instances launched by awsdsl, i.e. the
+/// ones carrying an `awsdsl - <name>` Name tag, across all result pages
+pub(crate) async fn describe_managed_instances(
+    config: &SdkConfig,
+) -> Result<Vec<ExistingInstance>, Error> {
+    let client = client(config);
+    let req = client
+        .describe_instances()
+        .filters(
+            Filter::builder()
+                .name("tag:Name")
+                .values(format!("{}*", NAME_TAG_PREFIX))
+                .build(),
+        )
+        .filters(
+            Filter::builder()
+                .name("instance-state-name")
+                .values("pending")
+                .values("running")
+                .values("stopping")
+                .values("stopped")
+                .build(),
+        );
+
+    let mut existing: Vec<ExistingInstance> = vec![];
+    let mut pages = req.into_paginator().send();
+    while let Some(page) = pages.next().await {
+        for rsrv in page?.reservations() {
+            for inst in rsrv.instances() {
+                existing.push(to_existing(inst));
+            }
+        }
+    }
+
+    Ok(existing)
+}
+
+/// Terminate the given instances and wait until they are gone
+pub(crate) async fn terminate_instances(
+    config: &SdkConfig,
+    inst_ids: Vec<String>,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let client = client(config);
+    let terminated = client
+        .terminate_instances()
+        .set_instance_ids(Some(inst_ids.clone()))
+        .dry_run(dry_run)
+        .send()
+        .await;
+    if dry_run {
+        return dry_run_ok(terminated);
+    }
+    terminated?;
+
+    let insts_gone = client
+        .wait_until_instance_terminated()
+        .set_instance_ids(Some(inst_ids))
+        .wait(Duration::from_secs(300))
+        .await;
+
+    match insts_gone {
+        Ok(_) => {
+            println!("=> instance terminated!");
+        }
+        Err(e) => {
+            println!("Error: Timed out terminating instance!");
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+/// Change the instance type of an instance: it is stopped, modified and
+/// started again
+pub(crate) async fn modify_instance_type(
+    config: &SdkConfig,
+    inst_id: &str,
+    ec2_size: InstanceType,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let client = client(config);
+    if dry_run {
+        // every step is checked, nothing is waited for
+        dry_run_ok(
+            client
+                .stop_instances()
+                .instance_ids(inst_id)
+                .dry_run(true)
+                .send()
+                .await,
+        )?;
+        dry_run_ok(
+            client
+                .modify_instance_attribute()
+                .instance_id(inst_id)
+                .instance_type(AttributeValue::builder().value(ec2_size.as_str()).build())
+                .dry_run(true)
+                .send()
+                .await,
+        )?;
+        return dry_run_ok(
+            client
+                .start_instances()
+                .instance_ids(inst_id)
+                .dry_run(true)
+                .send()
+                .await,
+        );
+    }
+    println!("  {}: stopping", inst_id);
+    client.stop_instances().instance_ids(inst_id).send().await?;
+    let insts_stopped = client
+        .wait_until_instance_stopped()
+        .instance_ids(inst_id)
+        .wait(Duration::from_secs(300))
+        .await;
+    match insts_stopped {
+        Ok(_) => {}
+        Err(e) => {
+            println!("Error: Timed out stopping instance!");
+            return Err(e.into());
+        }
+    }
+
+    println!("  {}: instance type -> {}", inst_id, ec2_size);
+    client
+        .modify_instance_attribute()
+        .instance_id(inst_id)
+        .instance_type(AttributeValue::builder().value(ec2_size.as_str()).build())
+        .send()
+        .await?;
+
+    println!("  {}: starting", inst_id);
+    client
+        .start_instances()
+        .instance_ids(inst_id)
+        .send()
+        .await?;
+    let insts_running = client
+        .wait_until_instance_running()
+        .instance_ids(inst_id)
+        .wait(Duration::from_secs(300))
+        .await;
+    match insts_running {
+        Ok(_) => {}
+        Err(e) => {
+            println!("Error: Timed out starting instance!");
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+/// Replace the set of security groups of a running instance
+pub(crate) async fn modify_security_groups(
+    config: &SdkConfig,
+    inst_id: &str,
+    sg_ids: Vec<&str>,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let client = client(config);
+    println!("  {}: security groups -> {}", inst_id, sg_ids.join(", "));
+    let modified = client
+        .modify_instance_attribute()
+        .instance_id(inst_id)
+        .set_groups(Some(sg_ids.iter().map(|x| x.to_string()).collect()))
+        .dry_run(dry_run)
+        .send()
+        .await;
+    if dry_run {
+        return dry_run_ok(modified);
+    }
+    modified?;
+    Ok(())
+}
+
+/// Create or overwrite the given tags on instances
+pub(crate) async fn set_tags(
+    config: &SdkConfig,
+    inst_ids: Vec<String>,
+    tags: &BTreeMap<String, String>,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let client = client(config);
+    println!("  {}: tags -> {} tag(s)", inst_ids.join(", "), tags.len());
+    let tagged = client
+        .create_tags()
+        .set_resources(Some(inst_ids))
+        .set_tags(Some(
+            tags.iter()
+                .map(|(k, v)| Tag::builder().key(k).value(v).build())
+                .collect(),
+        ))
+        .dry_run(dry_run)
+        .send()
+        .await;
+    if dry_run {
+        return dry_run_ok(tagged);
     }
+    tagged?;
+    Ok(())
+}
 
+/// Remove the tags with the given keys from instances, whatever their value
+pub(crate) async fn delete_tags(
+    config: &SdkConfig,
+    inst_ids: Vec<String>,
+    keys: &[String],
+    dry_run: bool,
+) -> Result<(), Error> {
+    let client = client(config);
+    println!("  {}: untag -> {}", inst_ids.join(", "), keys.join(", "));
+    let untagged = client
+        .delete_tags()
+        .set_resources(Some(inst_ids))
+        .set_tags(Some(
+            keys.iter().map(|k| Tag::builder().key(k).build()).collect(),
+        ))
+        .dry_run(dry_run)
+        .send()
+        .await;
+    if dry_run {
+        return dry_run_ok(untagged);
+    }
+    untagged?;
     Ok(())
 }
 
+/// Launch one instance per index of `instance_tags`, tagged at launch
+/// with its entry through `tag_specifications`. The instances differ by
+/// their index tag, so each one is its own `run_instances` call. Every
+/// instance launched is added to `launched` with its index as soon as it
+/// exists, so the ones launched before a failing call aren't lost. None
+/// is added on a dry run.
 pub(crate) async fn create_instance(
     config: &SdkConfig,
-    name: impl std::convert::Into<String> + std::fmt::Display,
     ami_id: &str,
-    count: i32,
+    instance_tags: &BTreeMap<u32, BTreeMap<String, String>>,
     ec2_size: InstanceType,
     subnet_id: &str,
     key_name: &str,
     sg_ids: Vec<&str>,
+    launched: &mut BTreeMap<String, u32>,
+    dry_run: bool,
 ) -> Result<(), Error> {
-    let client = Client::new(&config);
-    let created = client
-        .run_instances()
-        .image_id(ami_id)
-        .instance_type(ec2_size)
-        .subnet_id(subnet_id)
-        .set_security_group_ids(Some(sg_ids.iter().map(|x| x.to_string()).collect()))
-        .set_key_name(Some(key_name.to_string()))
-        .min_count(count)
-        .max_count(count)
-        .send()
-        .await?;
-    //
-    if created.instances().is_empty() {
-        println!("No instances created, please check!");
-    }
-
+    let client = client(config);
     let mut inst_ids: Vec<String> = vec![];
-    match created.instances {
-        Some(insts) => {
-            for inst in insts {
-                let inst_id = inst.instance_id.unwrap_or_default();
+    for (index, tags) in instance_tags {
+        let tag_spec = TagSpecification::builder()
+            .resource_type(ResourceType::Instance)
+            .set_tags(Some(
+                tags.iter()
+                    .map(|(k, v)| Tag::builder().key(k).value(v).build())
+                    .collect(),
+            ))
+            .build();
+        let created = client
+            .run_instances()
+            .image_id(ami_id)
+            .instance_type(ec2_size.clone())
+            .subnet_id(subnet_id)
+            .set_security_group_ids(Some(sg_ids.iter().map(|x| x.to_string()).collect()))
+            .set_key_name(Some(key_name.to_string()))
+            .tag_specifications(tag_spec)
+            .min_count(1)
+            .max_count(1)
+            .dry_run(dry_run)
+            .send()
+            .await;
+        if dry_run {
+            dry_run_ok(created)?;
+            continue;
+        }
+        match created?.instances().first() {
+            Some(inst) => {
+                let inst_id = inst.instance_id().unwrap_or_default();
                 println!("  {}", inst_id);
                 inst_ids.push(inst_id.to_string());
-
-                // create tag
-                let tag_name = format!("awsdsl - {}", name.to_string());
-                let tag_response = client
-                    .create_tags()
-                    .resources(inst_id)
-                    .tags(Tag::builder().key("Name").value(tag_name).build())
-                    .send()
-                    .await;
-                match tag_response {
-                    Ok(_) => {
-                        println!("Tag set successfully!");
-                    }
-                    Err(e) => {
-                        println!("Error whilst applying tags: {}", e);
-                    }
-                }
+                launched.insert(inst_id.to_string(), *index);
+            }
+            None => {
+                println!("** No instances created **");
             }
         }
-        _ => {
-            println!("** No instances created **");
-        }
+    }
+    if dry_run || inst_ids.is_empty() {
+        return Ok(());
     }
 
     // check if instance is ready
     let insts_ready = client
         .wait_until_instance_exists()
-        .set_instance_ids(Some(inst_ids))
+        .set_instance_ids(Some(inst_ids.clone()))
         .wait(Duration::from_secs(5))
         .await;
 
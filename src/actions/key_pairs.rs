@@ -0,0 +1,460 @@
+use aws_sdk_ec2::error::ProvideErrorMetadata;
+use aws_sdk_ec2::types::{Filter, KeyPairInfo, ResourceType, Tag, TagSpecification};
+use aws_sdk_ec2::{Client, Error};
+use ssh_key::rand_core::OsRng;
+use ssh_key::{Algorithm, HashAlg, LineEnding, PrivateKey, PublicKey};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::PathBuf;
+
+use crate::actions::instances::dry_run_ok;
+use crate::actions::plan::{AttrChange, ChangeKind, NamedPlan, PlanAction, STACK_TAG, tags_change};
+use crate::aws::{AwsSym, KEY_KIND, KeyPairSym, pending_id};
+use crate::state::StackState;
+
+/// Kind of the key pair plans
+pub(crate) const KIND: &'static str = KEY_KIND;
+
+/// A key pair that exists in the region, as returned by `describe_keys`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ExistingKey {
+    pub(crate) name: String,
+    pub(crate) key_id: String,
+    /// the OpenSSH public key, EC2 doesn't return it for some old keys
+    pub(crate) public_key: Option<String>,
+    pub(crate) tags: BTreeMap<String, String>,
+}
+
+/// The tags of the key pair `key`: the user tags and the stack tag
+pub(crate) fn desired_tags(aws_sym: &AwsSym, key: &KeyPairSym) -> BTreeMap<String, String> {
+    let mut tags = key.tags.clone();
+    tags.insert(STACK_TAG.to_string(), aws_sym.name.to_string());
+    tags
+}
+
+/// `path` with a leading `~/` replaced by the home directory
+pub(crate) fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var("HOME")) {
+        (Some(rest), Ok(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// The SHA256 fingerprint of an OpenSSH public key, `None` if it doesn't
+/// parse
+pub(crate) fn fingerprint(public_key: &str) -> Option<String> {
+    PublicKey::from_openssh(public_key.trim())
+        .ok()
+        .map(|k| k.fingerprint(HashAlg::Sha256).to_string())
+}
+
+/// Read the OpenSSH public key of the file `path`
+pub(crate) fn read_public_key(path: &str) -> Result<String, String> {
+    let contents = fs::read_to_string(expand_home(path))
+        .map_err(|e| format!("cannot read public_key_file {}: {}", path, e))?;
+    match fingerprint(&contents) {
+        Some(_) => Ok(contents.trim().to_string()),
+        None => Err(format!("{} is not an OpenSSH public key", path)),
+    }
+}
+
+/// The public keys the imported key pairs of `aws_sym` are read from,
+/// by key pair id. Generated key pairs are left out.
+pub(crate) fn public_keys(aws_sym: &AwsSym) -> Result<BTreeMap<String, String>, String> {
+    let mut keys: BTreeMap<String, String> = BTreeMap::new();
+    for key in &aws_sym.keys {
+        if let Some(path) = &key.public_key_file {
+            keys.insert(key.id.to_string(), read_public_key(path)?);
+        }
+    }
+    Ok(keys)
+}
+
+/// A new ed25519 key pair commented with `name`: the OpenSSH public key
+/// and the OpenSSH private key
+pub(crate) fn generate(name: &str) -> Result<(String, String), ssh_key::Error> {
+    let mut private = PrivateKey::random(&mut OsRng, Algorithm::Ed25519)?;
+    private.set_comment(name);
+    let public = private.public_key().to_openssh()?;
+    let private = private.to_openssh(LineEnding::LF)?;
+    Ok((public, private.to_string()))
+}
+
+/// Write the private key `contents` to `path`, readable by its owner
+/// only. An existing file is only replaced when `overwrite` is set.
+pub(crate) fn write_private_key(path: &str, contents: &str, overwrite: bool) -> io::Result<()> {
+    let path = expand_home(path);
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .create_new(!overwrite)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)?;
+    // `mode` only applies to a file that is created
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(contents.as_bytes())
+}
+
+/// Remove the private key `path` of a key pair that is deleted, a file
+/// that is already gone is fine
+pub(crate) fn remove_private_key(path: &str) -> io::Result<()> {
+    match fs::remove_file(expand_home(path)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn to_existing(key: &KeyPairInfo) -> ExistingKey {
+    ExistingKey {
+        name: key.key_name().unwrap_or_default().to_string(),
+        key_id: key.key_pair_id().unwrap_or_default().to_string(),
+        public_key: key.public_key().map(|k| k.to_string()),
+        tags: key
+            .tags()
+            .iter()
+            .map(|t| {
+                (
+                    t.key().unwrap_or_default().to_string(),
+                    t.value().unwrap_or_default().to_string(),
+                )
+            })
+            .collect(),
+    }
+}
+
+/// Describe the key pairs named in `names`, the missing ones are left out
+pub(crate) async fn describe_keys(
+    client: &Client,
+    names: &[String],
+) -> Result<BTreeMap<String, ExistingKey>, Error> {
+    // unlike key_names, a filter doesn't fail on the missing ones
+    let described = client
+        .describe_key_pairs()
+        .filters(
+            Filter::builder()
+                .name("key-name")
+                .set_values(Some(names.to_vec()))
+                .build(),
+        )
+        .include_public_key(true)
+        .send()
+        .await?;
+    Ok(described
+        .key_pairs()
+        .iter()
+        .map(|k| (k.key_name().unwrap_or_default().to_string(), to_existing(k)))
+        .collect())
+}
+
+/// Import the OpenSSH `public_key` as the key pair `key`
+pub(crate) async fn import_key(
+    client: &Client,
+    aws_sym: &AwsSym,
+    key: &KeyPairSym,
+    public_key: &str,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let tag_spec = TagSpecification::builder()
+        .resource_type(ResourceType::KeyPair)
+        .set_tags(Some(
+            desired_tags(aws_sym, key)
+                .iter()
+                .map(|(k, v)| Tag::builder().key(k).value(v).build())
+                .collect(),
+        ))
+        .build();
+    let imported = client
+        .import_key_pair()
+        .key_name(&key.name)
+        .public_key_material(public_key.as_bytes().to_vec().into())
+        .tag_specifications(tag_spec)
+        .dry_run(dry_run)
+        .send()
+        .await;
+    if dry_run {
+        return dry_run_ok(imported);
+    }
+    imported?;
+    Ok(())
+}
+
+/// Set the tags of the file on the key pair `key`, the other tags are
+/// left alone
+pub(crate) async fn set_tags(
+    client: &Client,
+    aws_sym: &AwsSym,
+    key: &KeyPairSym,
+) -> Result<(), Error> {
+    let existing = describe_keys(client, &[key.name.to_string()]).await?;
+    let key_id = match existing.get(&key.name) {
+        Some(existing) => existing.key_id.to_string(),
+        None => return Ok(()),
+    };
+    client
+        .create_tags()
+        .resources(key_id)
+        .set_tags(Some(
+            desired_tags(aws_sym, key)
+                .iter()
+                .map(|(k, v)| Tag::builder().key(k).value(v).build())
+                .collect(),
+        ))
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Delete the key pair `name`, a key pair that is already gone is fine.
+/// The instances launched with it keep the key.
+pub(crate) async fn delete_key(client: &Client, name: &str) -> Result<(), Error> {
+    match client.delete_key_pair().key_name(name).send().await {
+        Ok(_) => Ok(()),
+        Err(e) if e.code() == Some("InvalidKeyPair.NotFound") => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Compare the key pair `key`, whose public key is `public_key` when it
+/// is imported, against the existing one. The key material of a key pair
+/// can't change, a new public key replaces it.
+fn diff_key(
+    aws_sym: &AwsSym,
+    key: &KeyPairSym,
+    existing: &ExistingKey,
+    public_key: Option<&String>,
+) -> Vec<AttrChange> {
+    let mut changes: Vec<AttrChange> = vec![];
+    let actual = existing.public_key.as_deref().and_then(fingerprint);
+    if let (Some(actual), Some(desired)) = (actual, public_key.and_then(|k| fingerprint(k)))
+        && actual != desired
+    {
+        let mut change = AttrChange::new("public_key", actual, desired);
+        change.kind = ChangeKind::Replace;
+        changes.push(change);
+    }
+
+    changes.extend(tags_change(&existing.tags, &desired_tags(aws_sym, key)));
+    changes
+}
+
+/// Compute the plans of the key pairs of `aws_sym` given the ones that
+/// exist, by name, the public keys of the imported ones and the recorded
+/// `state`. A renamed key pair is replaced. Also returns the pending ids
+/// of the key pairs that exist mapped to their names. A generated key
+/// pair is kept as long as it exists. A key pair of the same name that
+/// awsdsl didn't import for the stack is an error: it is never taken
+/// over, or it would be deleted with the stack.
+pub(crate) fn diff(
+    aws_sym: &AwsSym,
+    existing: &BTreeMap<String, ExistingKey>,
+    public_keys: &BTreeMap<String, String>,
+    state: &StackState,
+) -> Result<(Vec<NamedPlan>, BTreeMap<String, String>), String> {
+    let mut plans: Vec<NamedPlan> = vec![];
+    let mut ids: BTreeMap<String, String> = BTreeMap::new();
+    for key in &aws_sym.keys {
+        if let Some(actual) = existing.get(&key.name) {
+            let recorded = state.keys.values().any(|k| k.name == key.name);
+            let tagged = actual.tags.get(STACK_TAG) == Some(&aws_sym.name);
+            if !recorded && !tagged {
+                return Err(format!(
+                    "key pair `{}` already exists and doesn't belong to stack `{}`, rename the key_pair or delete it",
+                    key.name, aws_sym.name
+                ));
+            }
+        }
+        let mut plan = NamedPlan::new(KIND, key.id.as_str(), key.name.as_str(), PlanAction::NoOp);
+        let renamed = state
+            .keys
+            .get(&key.id)
+            .filter(|k| k.name != key.name && existing.contains_key(&k.name));
+        match (renamed, existing.get(&key.name)) {
+            (Some(old), _) => {
+                plan.action = PlanAction::Replace;
+                let mut change = AttrChange::new("name", old.name.as_str(), key.name.as_str());
+                change.kind = ChangeKind::Replace;
+                plan.changes.push(change);
+            }
+            (None, None) => plan.action = PlanAction::Create,
+            (None, Some(actual)) => {
+                plan.changes = diff_key(aws_sym, key, actual, public_keys.get(&key.id));
+                let replace = plan.changes.iter().any(|c| c.kind == ChangeKind::Replace);
+                plan.action = match (replace, plan.changes.is_empty()) {
+                    (true, _) => PlanAction::Replace,
+                    (false, false) => PlanAction::Update,
+                    (false, true) => PlanAction::NoOp,
+                };
+                ids.insert(pending_id(KIND, &key.id), key.name.to_string());
+            }
+        }
+        plans.push(plan);
+    }
+    for key in state.keys.values() {
+        if !aws_sym.keys.iter().any(|k| k.id == key.id) {
+            plans.push(NamedPlan::new(
+                KIND,
+                key.id.as_str(),
+                key.name.as_str(),
+                PlanAction::Delete,
+            ));
+        }
+    }
+    Ok((plans, ids))
+}
+
+/// Compute the plans deleting every key pair `aws_sym` imported: the
+/// ones recorded in `state` and the existing key pairs of the file tagged
+/// with the stack
+pub(crate) fn destroy(
+    aws_sym: &AwsSym,
+    existing: &BTreeMap<String, ExistingKey>,
+    state: &StackState,
+) -> Vec<NamedPlan> {
+    let mut plans: Vec<NamedPlan> = state
+        .keys
+        .values()
+        .map(|k| NamedPlan::new(KIND, k.id.as_str(), k.name.as_str(), PlanAction::Delete))
+        .collect();
+    for key in &aws_sym.keys {
+        let tagged = existing
+            .get(&key.name)
+            .is_some_and(|k| k.tags.get(STACK_TAG) == Some(&aws_sym.name));
+        if tagged && !plans.iter().any(|p| p.name == key.name) {
+            plans.push(NamedPlan::new(
+                KIND,
+                key.id.as_str(),
+                key.name.as_str(),
+                PlanAction::Delete,
+            ));
+        }
+    }
+    plans.sort_by(|a, b| a.id.cmp(&b.id));
+    plans
+}
+
+/// The key pair names to describe for a plan: the ones of the file and
+/// the ones recorded in the state
+pub(crate) fn known_names(aws_sym: &AwsSym, state: &StackState) -> Vec<String> {
+    let mut names: Vec<String> = aws_sym.keys.iter().map(|k| k.name.clone()).collect();
+    for key in state.keys.values() {
+        if !names.contains(&key.name) {
+            names.push(key.name.clone());
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::fixtures::{empty_state, stack};
+    use crate::state::KeyState;
+    use std::{env, process};
+
+    fn key_sym(id: &str, public_key_file: Option<&str>) -> KeyPairSym {
+        KeyPairSym {
+            id: id.to_string(),
+            name: id.to_string(),
+            public_key_file: public_key_file.map(|f| f.to_string()),
+            private_key_file: match public_key_file {
+                Some(_) => None,
+                None => Some(format!("{}.pem", id)),
+            },
+            tags: BTreeMap::new(),
+        }
+    }
+
+    fn existing(aws_sym: &AwsSym, key: &KeyPairSym, public_key: &str) -> ExistingKey {
+        ExistingKey {
+            name: key.name.to_string(),
+            key_id: format!("key-{}", key.id),
+            public_key: Some(public_key.to_string()),
+            tags: desired_tags(aws_sym, key),
+        }
+    }
+
+    #[test]
+    fn test_key_plan() {
+        let (public, _) = generate("ops").unwrap();
+        let (other, _) = generate("ops").unwrap();
+        let mut aws_sym = stack();
+        aws_sym.add_key(key_sym("ops", Some("ops.pub")));
+        aws_sym.add_key(key_sym("deploy", None));
+        let public_keys = BTreeMap::from([("ops".to_string(), public.to_string())]);
+        let mut state = empty_state();
+        state
+            .keys
+            .insert("old".to_string(), KeyState::new(&key_sym("old", None)));
+
+        let (plans, ids) = diff(&aws_sym, &BTreeMap::new(), &public_keys, &state).unwrap();
+        assert_eq!(plans[0].action, PlanAction::Create);
+        assert_eq!(plans[1].action, PlanAction::Create);
+        assert_eq!(plans[2].action, PlanAction::Delete);
+        assert!(ids.is_empty());
+
+        // the comment of a public key doesn't matter, a generated key
+        // pair is kept whatever its key
+        let mut keys = BTreeMap::from([
+            (
+                "ops".to_string(),
+                existing(&aws_sym, &aws_sym.keys[0], &format!("{} other", public)),
+            ),
+            (
+                "deploy".to_string(),
+                existing(&aws_sym, &aws_sym.keys[1], &other),
+            ),
+        ]);
+        let (plans, ids) = diff(&aws_sym, &keys, &public_keys, &state).unwrap();
+        assert_eq!(plans[0].action, PlanAction::NoOp);
+        assert_eq!(plans[1].action, PlanAction::NoOp);
+        assert_eq!(ids.get("<key_pair.deploy.id>"), Some(&"deploy".to_string()));
+
+        // a new public key replaces the key pair
+        keys.get_mut("ops").unwrap().public_key = Some(other.to_string());
+        aws_sym.keys[1]
+            .tags
+            .insert("team".to_string(), "ops".to_string());
+        let (plans, _) = diff(&aws_sym, &keys, &public_keys, &state).unwrap();
+        assert_eq!(plans[0].action, PlanAction::Replace);
+        assert_eq!(plans[0].changes[0].attr, "public_key");
+        assert_eq!(plans[1].action, PlanAction::Update);
+
+        // a key pair of the name made outside of the stack is left alone
+        keys.get_mut("deploy").unwrap().tags.clear();
+        let err = diff(&aws_sym, &keys, &public_keys, &state).err().unwrap();
+        assert!(err.starts_with("key pair `deploy` already exists"));
+        state
+            .keys
+            .insert("deploy".to_string(), KeyState::new(&aws_sym.keys[1]));
+        assert!(diff(&aws_sym, &keys, &public_keys, &state).is_ok());
+
+        let plans = destroy(&aws_sym, &keys, &state);
+        let names: Vec<&str> = plans.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["deploy", "old", "ops"]);
+    }
+
+    #[test]
+    fn test_private_key_file() {
+        let path = env::temp_dir().join(format!("awsdsl-key-{}.pem", process::id()));
+        let path = path.to_str().unwrap();
+        let (public, private) = generate("deploy").unwrap();
+        assert!(public.starts_with("ssh-ed25519 "));
+        assert!(public.ends_with(" deploy"));
+
+        write_private_key(path, &private, false).unwrap();
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let written = PrivateKey::from_openssh(fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(written.public_key().to_openssh().unwrap(), public);
+
+        // a file is only replaced when asked to
+        let err = write_private_key(path, &private, false).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        write_private_key(path, &private, true).unwrap();
+        remove_private_key(path).unwrap();
+        remove_private_key(path).unwrap();
+    }
+}
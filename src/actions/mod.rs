@@ -1,24 +1,94 @@
+pub(crate) mod buckets;
+pub(crate) mod drift;
+#[cfg(test)]
+pub(crate) mod fixtures;
+pub(crate) mod generate;
+pub(crate) mod import;
 pub(crate) mod instances;
+pub(crate) mod key_pairs;
+pub(crate) mod network;
+pub(crate) mod plan;
+pub(crate) mod security_groups;
+pub(crate) mod tables;
 
 use crate::AwsSym;
 use crate::actions;
-use aws_config::BehaviorVersion;
-use aws_config::meta::region::RegionProviderChain;
+use crate::actions::drift::Drift;
+use crate::actions::instances::InstanceFilter;
+use crate::actions::plan::{ChangeKind, NamedPlan, Plan, PlanAction, ResourcePlan};
+use crate::aws::{Ec2Sym, KeyPairSym, is_pending, kind_rank, pending_id};
+use crate::state::{
+    Backend, BucketState, Ec2State, GroupState, KeyState, NetworkState, StackState, StateError,
+    TableState, network_key,
+};
+use aws_config::sts::AssumeRoleProvider;
+use aws_config::{BehaviorVersion, Region, SdkConfig};
+use aws_sdk_ec2::error::ProvideErrorMetadata;
 use aws_sdk_ec2::types::InstanceType;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt;
 
-const AWS_REGION: &'static str = "eu-west-1";
+/// Error code of the calls the credentials lack the IAM permissions for
+const UNAUTHORIZED: &'static str = "UnauthorizedOperation";
+
+/// Error code of the S3 calls the credentials lack the permissions for
+const ACCESS_DENIED: &'static str = "AccessDenied";
+
+/// Error code of the DynamoDB calls the credentials lack the permissions for
+const ACCESS_DENIED_EXCEPTION: &'static str = "AccessDeniedException";
 
 #[derive(Debug)]
 pub(crate) enum AwsErrorType {
     EC2Deploy,
+    EC2Describe,
+    EC2Terminate,
+    EC2Update,
+    S3Deploy,
+    S3Describe,
+    S3Delete,
+    DynamoDBDeploy,
+    DynamoDBDescribe,
+    DynamoDBDelete,
+    NetworkDeploy,
+    NetworkDescribe,
+    NetworkDelete,
+    SecurityGroupDeploy,
+    SecurityGroupDescribe,
+    SecurityGroupDelete,
+    KeyPairDeploy,
+    KeyPairDescribe,
+    KeyPairDelete,
+    State,
+    Unauthorized,
+    DryRun,
 }
 
 impl fmt::Display for AwsErrorType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s = match self {
             Self::EC2Deploy => "ec2 deploy",
+            Self::EC2Describe => "ec2 describe",
+            Self::EC2Terminate => "ec2 terminate",
+            Self::EC2Update => "ec2 update",
+            Self::S3Deploy => "s3 deploy",
+            Self::S3Describe => "s3 describe",
+            Self::S3Delete => "s3 delete",
+            Self::DynamoDBDeploy => "dynamodb deploy",
+            Self::DynamoDBDescribe => "dynamodb describe",
+            Self::DynamoDBDelete => "dynamodb delete",
+            Self::NetworkDeploy => "network deploy",
+            Self::NetworkDescribe => "network describe",
+            Self::NetworkDelete => "network delete",
+            Self::SecurityGroupDeploy => "security group deploy",
+            Self::SecurityGroupDescribe => "security group describe",
+            Self::SecurityGroupDelete => "security group delete",
+            Self::KeyPairDeploy => "key pair deploy",
+            Self::KeyPairDescribe => "key pair describe",
+            Self::KeyPairDelete => "key pair delete",
+            Self::State => "state",
+            Self::Unauthorized => "unauthorized",
+            Self::DryRun => "dry run",
         };
         write!(f, "{}", s)
     }
@@ -50,58 +120,1401 @@ impl fmt::Display for AwsDeployError {
     }
 }
 
-/// Supported Instance Types:
-/// t2.micro, t3.small. Defaults to t2.nano
+impl From<StateError> for AwsDeployError {
+    fn from(e: StateError) -> Self {
+        AwsDeployError::new(AwsErrorType::State, e.to_string())
+    }
+}
+
+/// Turn an EC2 error on the resource `ec2_id` into an error of `err_type`,
+/// or `Unauthorized` when the credentials lack the permissions for the call
+fn ec2_error(err_type: AwsErrorType, ec2_id: &str, e: aws_sdk_ec2::Error) -> AwsDeployError {
+    let err_type = match e.code() {
+        Some(UNAUTHORIZED) => AwsErrorType::Unauthorized,
+        _ => err_type,
+    };
+    let msg = match (e.code(), e.message()) {
+        (Some(code), Some(msg)) => format!("{}: {}: {}", ec2_id, code, msg),
+        _ => format!("{}: {}", ec2_id, e),
+    };
+    AwsDeployError::new(err_type, msg)
+}
+
+/// Turn an S3 error on the bucket `name` into an error of `err_type`, or
+/// `Unauthorized` when the credentials lack the permissions for the call
+fn s3_error(err_type: AwsErrorType, name: &str, e: aws_sdk_s3::Error) -> AwsDeployError {
+    let err_type = match aws_sdk_s3::error::ProvideErrorMetadata::code(&e) {
+        Some(ACCESS_DENIED) => AwsErrorType::Unauthorized,
+        _ => err_type,
+    };
+    AwsDeployError::new(err_type, format!("{}: {}", name, e))
+}
+
+/// Turn a DynamoDB error on the table `name` into an error of `err_type`,
+/// or `Unauthorized` when the credentials lack the permissions for the call
+fn dynamodb_error(
+    err_type: AwsErrorType,
+    name: &str,
+    e: aws_sdk_dynamodb::Error,
+) -> AwsDeployError {
+    let err_type = match aws_sdk_dynamodb::error::ProvideErrorMetadata::code(&e) {
+        Some(ACCESS_DENIED_EXCEPTION) => AwsErrorType::Unauthorized,
+        _ => err_type,
+    };
+    AwsDeployError::new(err_type, format!("{}: {}", name, e))
+}
+
+/// Any instance type known to EC2, e.g. t2.micro, t3.small. The plan
+/// compares the type by name, so it is passed through unchanged rather
+/// than defaulted.
 fn get_instance_type(inst_type: &str) -> InstanceType {
-    match inst_type {
-        "t2.micro" => InstanceType::T2Micro,
-        "t3.small" => InstanceType::T3Small,
-        _ => InstanceType::T2Nano,
+    InstanceType::from(inst_type)
+}
+
+/// Load the sdk config of a stack. The region of the file is used as is,
+/// whatever the environment says. The credentials are the ones of its
+/// profile (or the default chain) or, when it has a role, the ones of
+/// the role assumed with them. The `endpoint` of the stack, if any,
+/// replaces the AWS endpoints of every service.
+async fn load_config(aws_sym: &AwsSym) -> SdkConfig {
+    let region = Region::new(aws_sym.region.to_string());
+    let mut loader = aws_config::defaults(BehaviorVersion::latest()).region(region.clone());
+    if let Some(profile) = &aws_sym.profile {
+        loader = loader.profile_name(profile);
+    }
+    if let Some(endpoint) = &aws_sym.endpoint {
+        loader = loader.endpoint_url(endpoint);
     }
+    let config = loader.load().await;
+    let assume_role = match &aws_sym.assume_role {
+        Some(assume_role) => assume_role,
+        None => return config,
+    };
+    let mut builder = AssumeRoleProvider::builder(&assume_role.role_arn)
+        .session_name(&assume_role.session_name)
+        .region(region.clone())
+        .configure(&config);
+    if let Some(external_id) = &assume_role.external_id {
+        builder = builder.external_id(external_id);
+    }
+    let provider = builder.build().await;
+    let mut loader = aws_config::defaults(BehaviorVersion::latest())
+        .region(region)
+        .credentials_provider(provider);
+    if let Some(endpoint) = &aws_sym.endpoint {
+        loader = loader.endpoint_url(endpoint);
+    }
+    loader.load().await
 }
 
-/// Perform actions in aws based on the symbol table
-pub(crate) async fn apply_aws(aws_sym: &AwsSym) -> Result<(), AwsDeployError> {
-    let region_provider = RegionProviderChain::default_provider().or_else(AWS_REGION);
-    let config = aws_config::defaults(BehaviorVersion::latest())
-        .region(region_provider)
+/// Load the sdk config for an explicit region
+async fn load_region_config(region: &str) -> SdkConfig {
+    aws_config::defaults(BehaviorVersion::latest())
+        .region(Region::new(region.to_string()))
         .load()
-        .await;
-    let mut errors: Vec<AwsDeployError> = vec![];
-    for ec2 in &aws_sym.ec2s {
-        let inst_type = get_instance_type(&ec2.instance_type);
-        println!(
-            "ec2: {}, ami: {}, type: {}",
-            ec2.name, ec2.ami_id, inst_type
-        );
-        let ec2_inst = actions::instances::create_instance(
-            &config,
-            ec2.name.as_str(),
-            ec2.ami_id.as_str(),
-            ec2.count.into(),
-            inst_type,
-            ec2.subnet_id.as_str(),
-            ec2.key_name.as_str(),
-            vec![ec2.sg_id.as_str()],
-        )
-        .await;
+        .await
+}
 
-        match ec2_inst {
-            Ok(_) => {
-                //
+/// Query the networking resources and security groups of the stack, the
+/// instances awsdsl manages and the buckets, tables and key pairs of the
+/// file and the state, and diff them against the symbol table. The other
+/// resources are compared with the ids of the networking resources,
+/// groups and key pairs that are kept.
+async fn compute_plan(
+    config: &SdkConfig,
+    aws_sym: &AwsSym,
+    state: &StackState,
+) -> Result<Plan, AwsDeployError> {
+    let networks = describe_network(config, aws_sym, state).await?;
+    let (network, mut ids) = network::diff(aws_sym, &networks, state);
+    let groups = describe_groups(config, aws_sym, state).await?;
+    let (group_plans, group_ids) = security_groups::diff(&aws_sym.resolved(&ids), &groups, state);
+    ids.extend(group_ids);
+    let public_keys = key_pairs::public_keys(aws_sym)
+        .map_err(|e| AwsDeployError::new(AwsErrorType::KeyPairDeploy, e))?;
+    let keys = describe_keys(config, aws_sym, state).await?;
+    let (key_plans, key_ids) = key_pairs::diff(aws_sym, &keys, &public_keys, state)
+        .map_err(|e| AwsDeployError::new(AwsErrorType::KeyPairDeploy, e))?;
+    ids.extend(key_ids);
+    let aws_sym = &aws_sym.resolved(&ids);
+    let existing = actions::instances::describe_managed_instances(config)
+        .await
+        .map_err(|e| ec2_error(AwsErrorType::EC2Describe, &aws_sym.name, e))?;
+    let mut plan = plan::diff(aws_sym, &existing, state);
+    plan.network = network;
+    plan.groups = group_plans;
+    plan.keys = key_plans;
+    plan.ids = ids;
+    let buckets = describe_buckets(config, aws_sym, state).await?;
+    plan.named = buckets::diff(aws_sym, &buckets, state);
+    let tables = describe_tables(config, aws_sym, state).await?;
+    plan.named.extend(tables::diff(aws_sym, &tables, state));
+    Ok(plan)
+}
+
+/// Describe the networking resources of the stack, when the file
+/// declares some or the state records some
+async fn describe_network(
+    config: &SdkConfig,
+    aws_sym: &AwsSym,
+    state: &StackState,
+) -> Result<Vec<network::ExistingNetwork>, AwsDeployError> {
+    if aws_sym.networks.is_empty() && state.network.is_empty() {
+        return Ok(vec![]);
+    }
+    network::describe_network(&actions::instances::client(config), &aws_sym.name)
+        .await
+        .map_err(|e| ec2_error(AwsErrorType::NetworkDescribe, &aws_sym.name, e))
+}
+
+/// Describe the buckets the file declares or the state records
+async fn describe_buckets(
+    config: &SdkConfig,
+    aws_sym: &AwsSym,
+    state: &StackState,
+) -> Result<BTreeMap<String, buckets::ExistingBucket>, AwsDeployError> {
+    let names = buckets::known_names(aws_sym, state);
+    if names.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    buckets::describe_buckets(&buckets::client(config), &names)
+        .await
+        .map_err(|e| s3_error(AwsErrorType::S3Describe, &names.join(", "), e))
+}
+
+/// Describe the tables the file declares or the state records
+async fn describe_tables(
+    config: &SdkConfig,
+    aws_sym: &AwsSym,
+    state: &StackState,
+) -> Result<BTreeMap<String, tables::ExistingTable>, AwsDeployError> {
+    let names = tables::known_names(aws_sym, state);
+    if names.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    tables::describe_tables(&tables::client(config), &names)
+        .await
+        .map_err(|e| dynamodb_error(AwsErrorType::DynamoDBDescribe, &names.join(", "), e))
+}
+
+/// Describe the security groups of the stack and the ones the state
+/// records, when the file declares some or the state records some
+async fn describe_groups(
+    config: &SdkConfig,
+    aws_sym: &AwsSym,
+    state: &StackState,
+) -> Result<Vec<security_groups::ExistingGroup>, AwsDeployError> {
+    if aws_sym.groups.is_empty() && state.groups.is_empty() {
+        return Ok(vec![]);
+    }
+    let aws_ids: Vec<String> = state.groups.values().map(|g| g.aws_id.clone()).collect();
+    security_groups::describe_groups(&actions::instances::client(config), &aws_sym.name, &aws_ids)
+        .await
+        .map_err(|e| ec2_error(AwsErrorType::SecurityGroupDescribe, &aws_sym.name, e))
+}
+
+/// Describe the key pairs the file declares or the state records
+async fn describe_keys(
+    config: &SdkConfig,
+    aws_sym: &AwsSym,
+    state: &StackState,
+) -> Result<BTreeMap<String, key_pairs::ExistingKey>, AwsDeployError> {
+    let names = key_pairs::known_names(aws_sym, state);
+    if names.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    key_pairs::describe_keys(&actions::instances::client(config), &names)
+        .await
+        .map_err(|e| ec2_error(AwsErrorType::KeyPairDescribe, &names.join(", "), e))
+}
+
+/// A failed launch: the instances launched before the failure, mapped to
+/// their index, and the error
+type LaunchError = (BTreeMap<String, u32>, AwsDeployError);
+
+/// Launch the instances at `indexes` for one ec2 symbol, returns the new
+/// instance ids mapped to their index
+async fn launch_ec2(
+    config: &SdkConfig,
+    aws_sym: &AwsSym,
+    ec2: &Ec2Sym,
+    indexes: &[u32],
+    dry_run: bool,
+) -> Result<BTreeMap<String, u32>, LaunchError> {
+    let inst_type = get_instance_type(&ec2.instance_type);
+    println!(
+        "ec2: {}, ami: {}, type: {}",
+        ec2.name, ec2.ami_id, inst_type
+    );
+    let instance_tags: BTreeMap<u32, BTreeMap<String, String>> = indexes
+        .iter()
+        .map(|index| (*index, plan::desired_tags(aws_sym, ec2, *index)))
+        .collect();
+    let mut launched: BTreeMap<String, u32> = BTreeMap::new();
+    let created = actions::instances::create_instance(
+        config,
+        ec2.ami_id.as_str(),
+        &instance_tags,
+        inst_type,
+        ec2.subnet_id.as_str(),
+        ec2.key_name.as_str(),
+        ec2.sg_ids.iter().map(|s| s.as_str()).collect(),
+        &mut launched,
+        dry_run,
+    )
+    .await;
+    match created {
+        Ok(_) => Ok(launched),
+        Err(e) => Err((launched, ec2_error(AwsErrorType::EC2Deploy, &ec2.id, e))),
+    }
+}
+
+/// Terminate the instances of one resource plan
+async fn terminate_ec2(
+    config: &SdkConfig,
+    rsrc: &ResourcePlan,
+    dry_run: bool,
+) -> Result<(), AwsDeployError> {
+    if rsrc.instance_ids.is_empty() {
+        println!("ec2: {}, no instances left to terminate", rsrc.ec2_id);
+        return Ok(());
+    }
+    println!(
+        "ec2: {}, terminating: {}",
+        rsrc.ec2_id,
+        rsrc.instance_ids.join(", ")
+    );
+    actions::instances::terminate_instances(config, rsrc.instance_ids.clone(), dry_run)
+        .await
+        .map_err(|e| ec2_error(AwsErrorType::EC2Terminate, &rsrc.ec2_id, e))
+}
+
+/// Modify the existing instances of one resource plan in place, returns
+/// the instances of the resource mapped to their index. When scaling up
+/// fails, the error carries the instances of the resource including the
+/// ones launched so far; on any other failure it carries none. The
+/// `recorded_tags` applied on the last run and no longer in the file are
+/// removed.
+async fn update_ec2(
+    config: &SdkConfig,
+    aws_sym: &AwsSym,
+    ec2: &Ec2Sym,
+    rsrc: &ResourcePlan,
+    recorded_tags: &BTreeMap<String, String>,
+    dry_run: bool,
+) -> Result<BTreeMap<String, u32>, LaunchError> {
+    println!(
+        "ec2: {}, updating: {}",
+        ec2.name,
+        rsrc.instance_ids.join(", ")
+    );
+    let to_err = |e: aws_sdk_ec2::Error| {
+        (
+            BTreeMap::new(),
+            ec2_error(AwsErrorType::EC2Update, &ec2.id, e),
+        )
+    };
+    let mut indexes = rsrc.indexes.clone();
+    for change in &rsrc.changes {
+        if change.kind != ChangeKind::InPlace {
+            continue;
+        }
+        match change.attr.as_str() {
+            "instance_type" => {
+                for inst_id in &change.instance_ids {
+                    actions::instances::modify_instance_type(
+                        config,
+                        inst_id,
+                        get_instance_type(&ec2.instance_type),
+                        dry_run,
+                    )
+                    .await
+                    .map_err(to_err)?;
+                }
             }
-            Err(e) => {
-                errors.push(AwsDeployError::new(AwsErrorType::EC2Deploy, e.to_string()));
+            "sg_ids" => {
+                for inst_id in &change.instance_ids {
+                    actions::instances::modify_security_groups(
+                        config,
+                        inst_id,
+                        ec2.sg_ids.iter().map(|s| s.as_str()).collect(),
+                        dry_run,
+                    )
+                    .await
+                    .map_err(to_err)?;
+                }
+            }
+            "tags" => {
+                for inst_id in &change.instance_ids {
+                    let index = rsrc.indexes.get(inst_id).copied().unwrap_or_default();
+                    let desired = plan::desired_tags(aws_sym, ec2, index);
+                    actions::instances::set_tags(
+                        config,
+                        vec![inst_id.to_string()],
+                        &desired,
+                        dry_run,
+                    )
+                    .await
+                    .map_err(to_err)?;
+                    let dropped = plan::dropped_tags(recorded_tags, &desired);
+                    if !dropped.is_empty() {
+                        actions::instances::delete_tags(
+                            config,
+                            vec![inst_id.to_string()],
+                            &dropped,
+                            dry_run,
+                        )
+                        .await
+                        .map_err(to_err)?;
+                    }
+                }
             }
+            "count" => {
+                if !change.instance_ids.is_empty() {
+                    println!(
+                        "ec2: {}, scaling down: {}",
+                        ec2.name,
+                        change.instance_ids.join(", ")
+                    );
+                    actions::instances::terminate_instances(
+                        config,
+                        change.instance_ids.clone(),
+                        dry_run,
+                    )
+                    .await
+                    .map_err(to_err)?;
+                }
+                if !rsrc.launch_indexes.is_empty() {
+                    println!("ec2: {}, scaling up: {:?}", ec2.name, rsrc.launch_indexes);
+                    match launch_ec2(config, aws_sym, ec2, &rsrc.launch_indexes, dry_run).await {
+                        Ok(launched) => indexes.extend(launched),
+                        Err((launched, e)) => {
+                            indexes.extend(launched);
+                            return Err((indexes, e));
+                        }
+                    }
+                }
+            }
+            _ => {}
         }
     }
+    Ok(indexes)
+}
+
+/// The outcome of an operation run under the stack lock, once `unlock`
+/// released it. A failed operation is reported first, with a failed
+/// unlock attached to its message.
+fn unlocked<T>(
+    result: Result<T, AwsDeployError>,
+    unlock: Result<(), StateError>,
+) -> Result<T, AwsDeployError> {
+    match (result, unlock) {
+        (Err(mut e), Err(u)) => {
+            e.message = format!("{}\n-> the stack is still locked: {}", e.message, u);
+            Err(e)
+        }
+        (Ok(_), Err(u)) => Err(u.into()),
+        (result, Ok(_)) => result,
+    }
+}
+
+/// Fold the errors of the individual resources into one error
+fn combine_errors(
+    err_type: AwsErrorType,
+    errors: Vec<AwsDeployError>,
+) -> Result<(), AwsDeployError> {
     if !errors.is_empty() {
         let mut s = String::from("");
         for err in &errors {
             s = format!("{}\n-> {}", s, err);
         }
-        return Err(AwsDeployError::new(AwsErrorType::EC2Deploy, s));
+        return Err(AwsDeployError::new(err_type, s));
     }
 
     Ok(())
 }
+
+/// Execute the actions of `plan`. An update modifies the existing
+/// instances, a replacement terminates them before launching new ones.
+/// `state` is kept in step with every resource that was changed
+/// successfully. On a `dry_run` every call is sent with `dry_run(true)`
+/// and the outcome reported per resource, `state` must not be written.
+async fn execute_plan(
+    config: &SdkConfig,
+    aws_sym: &AwsSym,
+    plan: &Plan,
+    state: &mut StackState,
+    dry_run: bool,
+) -> Vec<AwsDeployError> {
+    let mut errors: Vec<AwsDeployError> = vec![];
+    for rsrc in &plan.resources {
+        let ec2 = aws_sym.ec2s.iter().find(|e| e.id == rsrc.ec2_id);
+        let pending = ec2.map(|e| e.pending()).unwrap_or_default();
+        if !pending.is_empty() && rsrc.action != PlanAction::Delete {
+            if dry_run {
+                println!(
+                    "{} ec2 [{}] not checked, it waits for {}",
+                    rsrc.action,
+                    rsrc.ec2_id,
+                    pending.join(", ")
+                );
+            } else {
+                println!("{} ec2 [{}] failed!", rsrc.action, rsrc.ec2_id);
+                errors.push(AwsDeployError::new(
+                    AwsErrorType::EC2Deploy,
+                    format!("{}: {} was not created", rsrc.ec2_id, pending.join(", ")),
+                ));
+            }
+            continue;
+        }
+        // the instances launched before a failure are still recorded, an
+        // error carrying none leaves the state of the resource alone
+        let result = match (&rsrc.action, ec2) {
+            (PlanAction::NoOp, Some(_)) => Ok(Some(rsrc.indexes.clone())),
+            (PlanAction::NoOp, None) => Ok(None),
+            (PlanAction::Create, Some(ec2)) => {
+                launch_ec2(config, aws_sym, ec2, &rsrc.launch_indexes, dry_run)
+                    .await
+                    .map(Some)
+            }
+            (PlanAction::Update, Some(ec2)) => {
+                let recorded_tags = state
+                    .ec2s
+                    .get(&ec2.id)
+                    .map(|s| s.tags.clone())
+                    .unwrap_or_default();
+                update_ec2(config, aws_sym, ec2, rsrc, &recorded_tags, dry_run)
+                    .await
+                    .map(Some)
+            }
+            (PlanAction::Replace, Some(ec2)) => match terminate_ec2(config, rsrc, dry_run).await {
+                Ok(_) => launch_ec2(config, aws_sym, ec2, &rsrc.launch_indexes, dry_run)
+                    .await
+                    .map(Some),
+                Err(e) => Err((BTreeMap::new(), e)),
+            },
+            (PlanAction::Delete, _) => terminate_ec2(config, rsrc, dry_run)
+                .await
+                .map(|_| None)
+                .map_err(|e| (BTreeMap::new(), e)),
+            (_, None) => Err((
+                BTreeMap::new(),
+                AwsDeployError::new(
+                    AwsErrorType::EC2Deploy,
+                    format!("No ec2 symbol found for {}", rsrc.ec2_id),
+                ),
+            )),
+        };
+
+        if dry_run && rsrc.action != PlanAction::NoOp {
+            match &result {
+                Ok(_) => println!("{} ec2 [{}] would succeed", rsrc.action, rsrc.ec2_id),
+                Err((_, e)) => println!(
+                    "{} ec2 [{}] would fail: {}",
+                    rsrc.action,
+                    rsrc.ec2_id,
+                    e.show()
+                ),
+            }
+        } else if result.is_ok() && rsrc.action != PlanAction::NoOp {
+            println!("{} ec2 [{}] done!", rsrc.action, rsrc.ec2_id);
+        }
+        match (result, ec2) {
+            (Ok(Some(indexes)), Some(ec2)) => {
+                state
+                    .ec2s
+                    .insert(ec2.id.to_string(), Ec2State::new(ec2, indexes));
+            }
+            (Ok(_), _) => {
+                state.ec2s.remove(&rsrc.ec2_id);
+            }
+            (Err((launched, e)), ec2) => {
+                if !dry_run {
+                    println!("{} ec2 [{}] failed!", rsrc.action, rsrc.ec2_id);
+                }
+                if let Some(ec2) = ec2
+                    && !launched.is_empty()
+                {
+                    state
+                        .ec2s
+                        .insert(ec2.id.to_string(), Ec2State::new(ec2, launched));
+                }
+                errors.push(e);
+            }
+        }
+    }
+
+    errors
+}
+
+/// Execute the plans of the named resources of `plan`, keeping `state`
+/// in step. S3 and DynamoDB have no dry run: on a `dry_run` the
+/// resources are only reported.
+async fn execute_named(
+    config: &SdkConfig,
+    aws_sym: &AwsSym,
+    plan: &Plan,
+    state: &mut StackState,
+    dry_run: bool,
+) -> Vec<AwsDeployError> {
+    let mut errors: Vec<AwsDeployError> = vec![];
+    for named in &plan.named {
+        if dry_run {
+            if named.action != PlanAction::NoOp {
+                println!(
+                    "{} {} [{}] not checked, {} has no dry run",
+                    named.action, named.kind, named.id, named.kind
+                );
+            }
+            continue;
+        }
+        let result = match named.kind {
+            buckets::KIND => execute_bucket(config, aws_sym, named, state).await,
+            tables::KIND => execute_table(config, aws_sym, named, state).await,
+            _ => Ok(()),
+        };
+        match result {
+            Ok(_) if named.action == PlanAction::NoOp => {}
+            Ok(_) => println!("{} {} [{}] done!", named.action, named.kind, named.id),
+            Err(e) => {
+                println!("{} {} [{}] failed!", named.action, named.kind, named.id);
+                errors.push(e);
+            }
+        }
+    }
+
+    errors
+}
+
+/// Create, update and replace the networking resources of `plan` in
+/// order. The AWS id of every resource created is added to `ids` and
+/// resolved in the resources after it. A replaced resource is created
+/// again first, the old one is left to `delete_network`.
+async fn create_network(
+    config: &SdkConfig,
+    aws_sym: &AwsSym,
+    plan: &Plan,
+    ids: &mut BTreeMap<String, String>,
+    state: &mut StackState,
+    dry_run: bool,
+) -> Vec<AwsDeployError> {
+    let client = actions::instances::client(config);
+    let mut errors: Vec<AwsDeployError> = vec![];
+    for named in plan
+        .network
+        .iter()
+        .filter(|n| n.action != PlanAction::Delete)
+    {
+        let key = network_key(named.kind, &named.id);
+        if dry_run {
+            if named.action != PlanAction::NoOp {
+                println!(
+                    "{} {} [{}] not checked, the resources after it need its id",
+                    named.action, named.kind, named.id
+                );
+            }
+            continue;
+        }
+        let network = match aws_sym
+            .networks
+            .iter()
+            .find(|n| n.kind == named.kind && n.id == named.id)
+        {
+            Some(network) => network.resolved(ids),
+            None => continue,
+        };
+        let pending = network.pending();
+        let result = match &named.action {
+            _ if !pending.is_empty() => Err(AwsDeployError::new(
+                AwsErrorType::NetworkDeploy,
+                format!("{}: {} was not created", key, pending.join(", ")),
+            )),
+            PlanAction::Create | PlanAction::Replace => {
+                network::create_network(&client, aws_sym, &network)
+                    .await
+                    .map_err(|e| ec2_error(AwsErrorType::NetworkDeploy, &key, e))
+            }
+            PlanAction::Update => {
+                network::update_network(&client, aws_sym, &network, &named.name, &named.changes)
+                    .await
+                    .map(|_| named.name.to_string())
+                    .map_err(|e| ec2_error(AwsErrorType::NetworkDeploy, &key, e))
+            }
+            _ => Ok(named.name.to_string()),
+        };
+        match result {
+            Ok(aws_id) => {
+                if named.action != PlanAction::NoOp {
+                    println!("{} {} [{}] done!", named.action, named.kind, named.id);
+                }
+                ids.insert(pending_id(network.kind, &network.id), aws_id.to_string());
+                state
+                    .network
+                    .insert(key, NetworkState::new(&network, aws_id));
+            }
+            Err(e) => {
+                println!("{} {} [{}] failed!", named.action, named.kind, named.id);
+                errors.push(e);
+            }
+        }
+    }
+
+    errors
+}
+
+/// Delete the networking resources of `plan` that are no longer declared
+/// and the ones that were replaced, in the reverse order of their
+/// creation. A replaced resource whose successor failed is kept.
+async fn delete_network(
+    config: &SdkConfig,
+    plan: &Plan,
+    state: &mut StackState,
+    dry_run: bool,
+) -> Vec<AwsDeployError> {
+    let client = actions::instances::client(config);
+    let mut errors: Vec<AwsDeployError> = vec![];
+    let mut deletes: Vec<&NamedPlan> = plan
+        .network
+        .iter()
+        .filter(|n| matches!(n.action, PlanAction::Delete | PlanAction::Replace))
+        .collect();
+    deletes.sort_by_key(|n| std::cmp::Reverse(kind_rank(n.kind)));
+    for named in deletes {
+        let key = network_key(named.kind, &named.id);
+        if dry_run {
+            println!(
+                "- delete {} [{}] ({}) not checked, it may still be in use",
+                named.kind, named.id, named.name
+            );
+            continue;
+        }
+        let replaced = state
+            .network
+            .get(&key)
+            .is_some_and(|n| n.aws_id != named.name);
+        if named.action == PlanAction::Replace && !replaced {
+            continue;
+        }
+        match network::delete_network(&client, named.kind, &named.name).await {
+            Ok(_) => {
+                println!(
+                    "- delete {} [{}] ({}) done!",
+                    named.kind, named.id, named.name
+                );
+                if named.action == PlanAction::Delete {
+                    state.network.remove(&key);
+                }
+            }
+            Err(e) => {
+                println!(
+                    "- delete {} [{}] ({}) failed!",
+                    named.kind, named.id, named.name
+                );
+                errors.push(ec2_error(AwsErrorType::NetworkDelete, &key, e));
+            }
+        }
+    }
+
+    errors
+}
+
+/// Create, update and replace the security groups of `plan`. The groups
+/// to create are created first and their ids added to `ids`, then the
+/// rules of every group are reconciled, so rules may refer to any group
+/// of the file. A replaced group is created again, the old one is left to
+/// `delete_groups`.
+async fn create_groups(
+    config: &SdkConfig,
+    aws_sym: &AwsSym,
+    plan: &Plan,
+    ids: &mut BTreeMap<String, String>,
+    state: &mut StackState,
+    dry_run: bool,
+) -> Vec<AwsDeployError> {
+    let client = actions::instances::client(config);
+    let mut errors: Vec<AwsDeployError> = vec![];
+    let group_of = |named: &NamedPlan| aws_sym.groups.iter().find(|g| g.id == named.id);
+    for named in plan.groups.iter().filter(|g| g.action == PlanAction::NoOp) {
+        if let Some(group) = group_of(named) {
+            state
+                .groups
+                .insert(group.id.to_string(), GroupState::new(group, &named.name));
+        }
+    }
+    let changed: Vec<&NamedPlan> = plan
+        .groups
+        .iter()
+        .filter(|g| !matches!(g.action, PlanAction::NoOp | PlanAction::Delete))
+        .collect();
+    if dry_run {
+        for named in changed {
+            println!(
+                "{} {} [{}] not checked, the resources after it need its id",
+                named.action, named.kind, named.id
+            );
+        }
+        return errors;
+    }
+
+    let mut failed: Vec<&str> = vec![];
+    for named in changed.iter().filter(|g| g.action != PlanAction::Update) {
+        let group = match group_of(named) {
+            Some(group) => group.resolved(ids),
+            None => continue,
+        };
+        let key = network_key(named.kind, &named.id);
+        let created = match &group.vpc_id {
+            Some(vpc_id) if is_pending(vpc_id) => Err(AwsDeployError::new(
+                AwsErrorType::SecurityGroupDeploy,
+                format!("{}: {} was not created", key, vpc_id),
+            )),
+            _ => security_groups::create_group(&client, aws_sym, &group)
+                .await
+                .map_err(|e| ec2_error(AwsErrorType::SecurityGroupDeploy, &key, e)),
+        };
+        match created {
+            Ok(aws_id) => {
+                ids.insert(pending_id(named.kind, &group.id), aws_id.to_string());
+                state
+                    .groups
+                    .insert(group.id.to_string(), GroupState::new(&group, aws_id));
+            }
+            Err(e) => {
+                println!("{} {} [{}] failed!", named.action, named.kind, named.id);
+                failed.push(named.id.as_str());
+                errors.push(e);
+            }
+        }
+    }
+
+    for named in changed {
+        let group = match group_of(named) {
+            Some(group) if !failed.contains(&named.id.as_str()) => group.resolved(ids),
+            _ => continue,
+        };
+        let key = network_key(named.kind, &named.id);
+        let aws_id = match (&named.action, state.groups.get(&group.id)) {
+            (PlanAction::Update, _) | (_, None) => named.name.to_string(),
+            (_, Some(recorded)) => recorded.aws_id.to_string(),
+        };
+        let pending: Vec<&str> = group
+            .ingress
+            .iter()
+            .chain(group.egress.iter())
+            .filter(|r| is_pending(&r.source))
+            .map(|r| r.source.as_str())
+            .collect();
+        let result = if !pending.is_empty() {
+            Err(AwsDeployError::new(
+                AwsErrorType::SecurityGroupDeploy,
+                format!("{}: {} was not created", key, pending.join(", ")),
+            ))
+        } else {
+            let retagged = match named.changes.iter().any(|c| c.attr == "tags") {
+                true => security_groups::set_tags(&client, aws_sym, &group, &aws_id).await,
+                false => Ok(()),
+            };
+            match retagged {
+                Ok(_) => security_groups::sync_rules(&client, &aws_id, Some(&group)).await,
+                Err(e) => Err(e),
+            }
+            .map_err(|e| ec2_error(AwsErrorType::SecurityGroupDeploy, &key, e))
+        };
+        match result {
+            Ok(_) => {
+                println!("{} {} [{}] done!", named.action, named.kind, named.id);
+                state
+                    .groups
+                    .insert(group.id.to_string(), GroupState::new(&group, aws_id));
+            }
+            Err(e) => {
+                println!("{} {} [{}] failed!", named.action, named.kind, named.id);
+                errors.push(e);
+            }
+        }
+    }
+
+    errors
+}
+
+/// Delete the security groups of `plan` that are no longer declared and
+/// the ones that were replaced. Their rules are revoked first, as a group
+/// can't be deleted while another one refers to it. A replaced group whose
+/// successor failed is kept.
+async fn delete_groups(
+    config: &SdkConfig,
+    plan: &Plan,
+    state: &mut StackState,
+    dry_run: bool,
+) -> Vec<AwsDeployError> {
+    let client = actions::instances::client(config);
+    let mut errors: Vec<AwsDeployError> = vec![];
+    if dry_run {
+        for named in plan
+            .groups
+            .iter()
+            .filter(|g| g.action == PlanAction::Delete)
+        {
+            println!(
+                "- delete {} [{}] ({}) not checked, it may still be in use",
+                named.kind, named.id, named.name
+            );
+        }
+        return errors;
+    }
+    let deletes: Vec<&NamedPlan> = plan
+        .groups
+        .iter()
+        .filter(|g| match g.action {
+            PlanAction::Delete => true,
+            PlanAction::Replace => state.groups.get(&g.id).is_some_and(|r| r.aws_id != g.name),
+            _ => false,
+        })
+        .collect();
+    for named in &deletes {
+        if let Err(e) = security_groups::sync_rules(&client, &named.name, None).await {
+            let key = network_key(named.kind, &named.id);
+            errors.push(ec2_error(AwsErrorType::SecurityGroupDelete, &key, e));
+        }
+    }
+    for named in deletes {
+        match security_groups::delete_group(&client, &named.name).await {
+            Ok(_) => {
+                println!(
+                    "- delete {} [{}] ({}) done!",
+                    named.kind, named.id, named.name
+                );
+                if named.action == PlanAction::Delete {
+                    state.groups.remove(&named.id);
+                }
+            }
+            Err(e) => {
+                println!(
+                    "- delete {} [{}] ({}) failed!",
+                    named.kind, named.id, named.name
+                );
+                let key = network_key(named.kind, &named.id);
+                errors.push(ec2_error(AwsErrorType::SecurityGroupDelete, &key, e));
+            }
+        }
+    }
+
+    errors
+}
+
+/// Execute the plans of the key pairs of `plan`, keeping `state` in
+/// step. The name of every key pair that exists afterwards is added to
+/// `ids`, for the instances that wait for it. A dry run only checks the
+/// imports, with a throwaway key for generated key pairs.
+async fn execute_keys(
+    config: &SdkConfig,
+    aws_sym: &AwsSym,
+    plan: &Plan,
+    ids: &mut BTreeMap<String, String>,
+    state: &mut StackState,
+    dry_run: bool,
+) -> Vec<AwsDeployError> {
+    let client = actions::instances::client(config);
+    let mut errors: Vec<AwsDeployError> = vec![];
+    for named in &plan.keys {
+        if dry_run && !matches!(named.action, PlanAction::NoOp | PlanAction::Create) {
+            println!(
+                "{} {} [{}] not checked, only imports have a dry run",
+                named.action, named.kind, named.id
+            );
+            continue;
+        }
+        let key = aws_sym.keys.iter().find(|k| k.id == named.id);
+        let result = match (&named.action, key) {
+            (PlanAction::NoOp, _) => Ok(()),
+            (PlanAction::Delete, _) => delete_key(&client, &named.name, &named.id, state).await,
+            (PlanAction::Update, Some(key)) => key_pairs::set_tags(&client, aws_sym, key)
+                .await
+                .map_err(|e| ec2_error(AwsErrorType::KeyPairDeploy, &named.name, e)),
+            (PlanAction::Replace, Some(key)) => {
+                // the name is free again once the old key pair is gone
+                let old = match named.changes.iter().find(|c| c.attr == "name") {
+                    Some(change) => change.actual.as_str(),
+                    None => key.name.as_str(),
+                };
+                match delete_key(&client, old, &named.id, state).await {
+                    Ok(_) => create_key(&client, aws_sym, key, state, false).await,
+                    Err(e) => Err(e),
+                }
+            }
+            (PlanAction::Create, Some(key)) => {
+                create_key(&client, aws_sym, key, state, dry_run).await
+            }
+            (_, None) => Err(AwsDeployError::new(
+                AwsErrorType::KeyPairDeploy,
+                format!("No key_pair symbol found for {}", named.id),
+            )),
+        };
+        match result {
+            Err(e) => {
+                match dry_run {
+                    true => println!(
+                        "{} {} [{}] would fail: {}",
+                        named.action,
+                        named.kind,
+                        named.id,
+                        e.show()
+                    ),
+                    false => println!("{} {} [{}] failed!", named.action, named.kind, named.id),
+                }
+                errors.push(e);
+            }
+            Ok(_) if named.action == PlanAction::NoOp && dry_run => {}
+            Ok(_) if dry_run => println!(
+                "{} {} [{}] would succeed",
+                named.action, named.kind, named.id
+            ),
+            Ok(_) => {
+                if let Some(key) = key.filter(|_| named.action != PlanAction::Delete) {
+                    let mut recorded = KeyState::new(key);
+                    // a key pair that was kept keeps the private key it had
+                    if matches!(named.action, PlanAction::NoOp | PlanAction::Update) {
+                        recorded.private_key_file = state
+                            .keys
+                            .get(&key.id)
+                            .and_then(|k| k.private_key_file.clone());
+                    }
+                    state.keys.insert(key.id.to_string(), recorded);
+                    ids.insert(pending_id(named.kind, &key.id), key.name.to_string());
+                }
+                if named.action != PlanAction::NoOp {
+                    println!("{} {} [{}] done!", named.action, named.kind, named.id);
+                }
+            }
+        }
+    }
+
+    errors
+}
+
+/// Import the key pair `key`, generating it first when it has no public
+/// key file. The private key of a generated key is written once the key
+/// pair exists; it never replaces a file awsdsl didn't write, and the key
+/// pair is deleted again when it can't be written.
+async fn create_key(
+    client: &aws_sdk_ec2::Client,
+    aws_sym: &AwsSym,
+    key: &KeyPairSym,
+    state: &StackState,
+    dry_run: bool,
+) -> Result<(), AwsDeployError> {
+    let to_err = |msg: String| AwsDeployError::new(AwsErrorType::KeyPairDeploy, msg);
+    let (public_key, private_key) = match &key.public_key_file {
+        Some(path) => (key_pairs::read_public_key(path).map_err(to_err)?, None),
+        None => {
+            let (public, private) = key_pairs::generate(&key.name)
+                .map_err(|e| to_err(format!("{}: {}", key.name, e)))?;
+            (public, Some(private))
+        }
+    };
+    let owned = |path: &String| {
+        state
+            .keys
+            .get(&key.id)
+            .is_some_and(|k| k.private_key_file.as_ref() == Some(path))
+    };
+    if let Some(path) = &key.private_key_file
+        && key_pairs::expand_home(path).exists()
+        && !owned(path)
+    {
+        return Err(to_err(format!(
+            "{}: {} already exists, move it away or set private_key_file",
+            key.name, path
+        )));
+    }
+    key_pairs::import_key(client, aws_sym, key, &public_key, dry_run)
+        .await
+        .map_err(|e| ec2_error(AwsErrorType::KeyPairDeploy, &key.name, e))?;
+    match (&key.private_key_file, private_key) {
+        (Some(path), Some(private_key)) if !dry_run => {
+            if let Err(e) = key_pairs::write_private_key(path, &private_key, owned(path)) {
+                let _ = key_pairs::delete_key(client, &key.name).await;
+                return Err(to_err(format!(
+                    "{}: cannot write {}: {}",
+                    key.name, path, e
+                )));
+            }
+            println!("  private key written to {}", path);
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Delete the key pair `name` of the key pair `id` and the private key
+/// awsdsl wrote for it, then forget it
+async fn delete_key(
+    client: &aws_sdk_ec2::Client,
+    name: &str,
+    id: &str,
+    state: &mut StackState,
+) -> Result<(), AwsDeployError> {
+    key_pairs::delete_key(client, name)
+        .await
+        .map_err(|e| ec2_error(AwsErrorType::KeyPairDelete, name, e))?;
+    if let Some(recorded) = state.keys.remove(id)
+        && let Some(path) = &recorded.private_key_file
+    {
+        key_pairs::remove_private_key(path).map_err(|e| {
+            let msg = format!("{}: cannot remove {}: {}", name, path, e);
+            AwsDeployError::new(AwsErrorType::KeyPairDelete, msg)
+        })?;
+    }
+    Ok(())
+}
+
+/// Carry out the plan of one bucket and record the outcome in `state`
+async fn execute_bucket(
+    config: &SdkConfig,
+    aws_sym: &AwsSym,
+    named: &NamedPlan,
+    state: &mut StackState,
+) -> Result<(), AwsDeployError> {
+    let client = buckets::client(config);
+    let s3 = aws_sym.s3s.iter().find(|s| s.id == named.id);
+    let to_err = |e: aws_sdk_s3::Error| s3_error(AwsErrorType::S3Deploy, &named.name, e);
+    match (&named.action, s3) {
+        (PlanAction::Delete, _) => {
+            buckets::delete_bucket(&client, &named.name)
+                .await
+                .map_err(|e| s3_error(AwsErrorType::S3Delete, &named.name, e))?;
+            state.buckets.remove(&named.id);
+            return Ok(());
+        }
+        (PlanAction::Create, Some(s3)) => buckets::create_bucket(&client, aws_sym, s3)
+            .await
+            .map_err(to_err)?,
+        (PlanAction::Update, Some(s3)) => {
+            let settings: Vec<&str> = named.changes.iter().map(|c| c.attr.as_str()).collect();
+            buckets::update_bucket(&client, aws_sym, s3, &settings)
+                .await
+                .map_err(to_err)?
+        }
+        (PlanAction::Replace, Some(s3)) => {
+            if let Some(old) = named.changes.iter().find(|c| c.attr == "name") {
+                buckets::delete_bucket(&client, &old.actual)
+                    .await
+                    .map_err(|e| s3_error(AwsErrorType::S3Delete, &old.actual, e))?;
+            }
+            buckets::create_bucket(&client, aws_sym, s3)
+                .await
+                .map_err(to_err)?
+        }
+        (_, s3) => {
+            if s3.is_none() {
+                return Err(AwsDeployError::new(
+                    AwsErrorType::S3Deploy,
+                    format!("No s3 symbol found for {}", named.id),
+                ));
+            }
+        }
+    }
+    if let Some(s3) = s3 {
+        state
+            .buckets
+            .insert(s3.id.to_string(), BucketState::new(s3));
+    }
+    Ok(())
+}
+
+/// Carry out the plan of one table and record the outcome in `state`. A
+/// replaced table is deleted, under its old name if it was renamed,
+/// before the new one is created.
+async fn execute_table(
+    config: &SdkConfig,
+    aws_sym: &AwsSym,
+    named: &NamedPlan,
+    state: &mut StackState,
+) -> Result<(), AwsDeployError> {
+    let client = tables::client(config);
+    let table = aws_sym.tables.iter().find(|t| t.id == named.id);
+    let to_err =
+        |e: aws_sdk_dynamodb::Error| dynamodb_error(AwsErrorType::DynamoDBDeploy, &named.name, e);
+    match (&named.action, table) {
+        (PlanAction::Delete, _) => {
+            tables::delete_table(&client, &named.name)
+                .await
+                .map_err(|e| dynamodb_error(AwsErrorType::DynamoDBDelete, &named.name, e))?;
+            state.tables.remove(&named.id);
+            return Ok(());
+        }
+        (PlanAction::Create, Some(table)) => tables::create_table(&client, aws_sym, table)
+            .await
+            .map_err(to_err)?,
+        (PlanAction::Update, Some(table)) => {
+            let settings: Vec<&str> = named.changes.iter().map(|c| c.attr.as_str()).collect();
+            tables::update_table(&client, aws_sym, table, &settings)
+                .await
+                .map_err(to_err)?
+        }
+        (PlanAction::Replace, Some(table)) => {
+            let old = match named.changes.iter().find(|c| c.attr == "name") {
+                Some(change) => change.actual.as_str(),
+                None => table.name.as_str(),
+            };
+            tables::delete_table(&client, old)
+                .await
+                .map_err(|e| dynamodb_error(AwsErrorType::DynamoDBDelete, old, e))?;
+            tables::create_table(&client, aws_sym, table)
+                .await
+                .map_err(to_err)?
+        }
+        (_, table) => {
+            if table.is_none() {
+                return Err(AwsDeployError::new(
+                    AwsErrorType::DynamoDBDeploy,
+                    format!("No dynamodb symbol found for {}", named.id),
+                ));
+            }
+        }
+    }
+    if let Some(table) = table {
+        state
+            .tables
+            .insert(table.id.to_string(), TableState::new(table));
+    }
+    Ok(())
+}
+
+/// Compute the plan for the symbol table without changing anything
+pub(crate) async fn plan_aws(aws_sym: &AwsSym, backend: &Backend) -> Result<Plan, AwsDeployError> {
+    let config = load_config(aws_sym).await;
+    let state = backend.read(aws_sym).await?;
+    compute_plan(&config, aws_sym, &state).await
+}
+
+/// Compare what was deployed against what EC2 reports, without changing
+/// anything. The recorded instances are described by id so stopped,
+/// terminated or renamed ones are still found.
+pub(crate) async fn drift_aws(
+    aws_sym: &AwsSym,
+    backend: &Backend,
+) -> Result<Vec<Drift>, AwsDeployError> {
+    let config = load_config(aws_sym).await;
+    let state = backend.read(aws_sym).await?;
+    let inst_ids: Vec<String> = state
+        .ec2s
+        .values()
+        .flat_map(|e| e.instance_ids.iter().cloned())
+        .collect();
+    let mut existing = actions::instances::describe_instances_by_id(&config, inst_ids)
+        .await
+        .map_err(|e| ec2_error(AwsErrorType::EC2Describe, &aws_sym.name, e))?;
+    let managed = actions::instances::describe_managed_instances(&config)
+        .await
+        .map_err(|e| ec2_error(AwsErrorType::EC2Describe, &aws_sym.name, e))?;
+    for inst in managed {
+        if !existing.iter().any(|i| i.instance_id == inst.instance_id) {
+            existing.push(inst);
+        }
+    }
+    Ok(drift::detect(aws_sym, &state, &existing))
+}
+
+/// Bring the hand made instance `instance_id` under management as one of
+/// the instances of the ec2 resource `ec2_name`: it is read through the
+/// EC2 API and recorded in the state. Returns the equivalent `Ec2Sym`,
+/// the instance itself is left untouched.
+pub(crate) async fn import_aws(
+    aws_sym: &AwsSym,
+    backend: &Backend,
+    ec2_name: &str,
+    instance_id: &str,
+) -> Result<Ec2Sym, AwsDeployError> {
+    backend.lock().await?;
+    let imported = import_locked(aws_sym, backend, ec2_name, instance_id).await;
+    unlocked(imported, backend.unlock().await)
+}
+
+async fn import_locked(
+    aws_sym: &AwsSym,
+    backend: &Backend,
+    ec2_name: &str,
+    instance_id: &str,
+) -> Result<Ec2Sym, AwsDeployError> {
+    let config = load_config(aws_sym).await;
+    let mut state = backend.read(aws_sym).await?;
+    let existing =
+        actions::instances::describe_instances_by_id(&config, vec![instance_id.to_string()])
+            .await
+            .map_err(|e| ec2_error(AwsErrorType::EC2Describe, instance_id, e))?;
+    let inst = existing
+        .iter()
+        .find(|i| i.instance_id == instance_id && i.state != "terminated")
+        .ok_or(AwsDeployError::new(
+            AwsErrorType::EC2Describe,
+            format!("instance {} not found", instance_id),
+        ))?;
+    let ec2 = import::ec2_sym_from(ec2_name, inst);
+    let index = import::bind(&mut state, &ec2, inst)
+        .map_err(|e| AwsDeployError::new(AwsErrorType::State, e))?;
+    backend.write(&mut state).await?;
+    println!(
+        "=> imported {} as ec2 [{}], index {}",
+        instance_id, ec2.id, index
+    );
+    if !aws_sym.ec2s.iter().any(|e| e.name == ec2_name) {
+        println!(
+            "=> ec2 [{}] is not declared in the file, the next apply deletes it unless it is added",
+            ec2_name
+        );
+    }
+    Ok(ec2)
+}
+
+/// Describe the instances of `region` matching `filter` and return the
+/// dsl document declaring them as the stack `stack`
+pub(crate) async fn generate_aws(
+    stack: &str,
+    region: &str,
+    filter: &InstanceFilter,
+) -> Result<String, AwsDeployError> {
+    let config = load_region_config(region).await;
+    let existing = actions::instances::describe_instances(&config, filter)
+        .await
+        .map_err(|e| ec2_error(AwsErrorType::EC2Describe, stack, e))?;
+    generate::document(stack, region, &existing)
+        .map_err(|e| AwsDeployError::new(AwsErrorType::EC2Describe, e))
+}
+
+/// Perform actions in aws based on the symbol table: compute the plan,
+/// print it, then execute only what the plan lists. The stack is locked
+/// for the whole run; the state is read before and rewritten after, even
+/// when some resources failed, so it records everything that was deployed.
+/// A `dry_run` only checks the calls of the plan: nothing is locked,
+/// launched or written.
+pub(crate) async fn apply_aws(
+    aws_sym: &AwsSym,
+    backend: &Backend,
+    dry_run: bool,
+) -> Result<(), AwsDeployError> {
+    if dry_run {
+        return apply_plan(aws_sym, backend, true).await;
+    }
+    backend.lock().await?;
+    let applied = apply_plan(aws_sym, backend, false).await;
+    unlocked(applied, backend.unlock().await)
+}
+
+async fn apply_plan(
+    aws_sym: &AwsSym,
+    backend: &Backend,
+    dry_run: bool,
+) -> Result<(), AwsDeployError> {
+    let config = load_config(aws_sym).await;
+    let mut state = backend.read(aws_sym).await?;
+    let plan = compute_plan(&config, aws_sym, &state).await?;
+    println!("{}", plan);
+    if plan.is_empty() {
+        println!("=> nothing to do!");
+    }
+    // nothing is changed on an empty plan, the resources that were only
+    // matched by their tags are still recorded
+    let recorded = state.clone();
+    let mut ids = plan.ids.clone();
+    let mut errors = create_network(&config, aws_sym, &plan, &mut ids, &mut state, dry_run).await;
+    let resolved = aws_sym.resolved(&ids);
+    errors.extend(create_groups(&config, &resolved, &plan, &mut ids, &mut state, dry_run).await);
+    errors.extend(execute_keys(&config, aws_sym, &plan, &mut ids, &mut state, dry_run).await);
+    let aws_sym = &aws_sym.resolved(&ids);
+    errors.extend(execute_plan(&config, aws_sym, &plan, &mut state, dry_run).await);
+    errors.extend(execute_named(&config, aws_sym, &plan, &mut state, dry_run).await);
+    errors.extend(delete_groups(&config, &plan, &mut state, dry_run).await);
+    errors.extend(delete_network(&config, &plan, &mut state, dry_run).await);
+    if dry_run {
+        return combine_errors(AwsErrorType::DryRun, errors);
+    }
+    if !plan.is_empty() || state != recorded {
+        backend.write(&mut state).await?;
+    }
+    combine_errors(AwsErrorType::EC2Deploy, errors)
+}
+
+/// Tear down everything the file deployed, found through the state and
+/// the tags. Each resource is reported as it is destroyed; the ones that
+/// failed are returned as one `EC2Terminate` error. A `dry_run` only
+/// checks the terminations.
+pub(crate) async fn destroy_aws(
+    aws_sym: &AwsSym,
+    backend: &Backend,
+    dry_run: bool,
+) -> Result<(), AwsDeployError> {
+    if dry_run {
+        return destroy_plan(aws_sym, backend, true).await;
+    }
+    backend.lock().await?;
+    let destroyed = destroy_plan(aws_sym, backend, false).await;
+    unlocked(destroyed, backend.unlock().await)
+}
+
+async fn destroy_plan(
+    aws_sym: &AwsSym,
+    backend: &Backend,
+    dry_run: bool,
+) -> Result<(), AwsDeployError> {
+    let config = load_config(aws_sym).await;
+    let mut state = backend.read(aws_sym).await?;
+    let existing = actions::instances::describe_managed_instances(&config)
+        .await
+        .map_err(|e| ec2_error(AwsErrorType::EC2Describe, &aws_sym.name, e))?;
+    let mut plan = plan::destroy(aws_sym, &existing, &state);
+    let buckets = describe_buckets(&config, aws_sym, &state).await?;
+    plan.named = buckets::destroy(aws_sym, &buckets, &state);
+    let tables = describe_tables(&config, aws_sym, &state).await?;
+    plan.named.extend(tables::destroy(aws_sym, &tables, &state));
+    let keys = describe_keys(&config, aws_sym, &state).await?;
+    plan.keys = key_pairs::destroy(aws_sym, &keys, &state);
+    let groups = describe_groups(&config, aws_sym, &state).await?;
+    plan.groups = security_groups::destroy(&groups, &state);
+    let networks = describe_network(&config, aws_sym, &state).await?;
+    plan.network = network::destroy(&networks, &state);
+    println!("{}", plan);
+    if plan.resources.is_empty()
+        && plan.named.is_empty()
+        && plan.groups.is_empty()
+        && plan.keys.is_empty()
+        && plan.network.is_empty()
+    {
+        println!("=> nothing to destroy!");
+        return Ok(());
+    }
+    let mut errors = execute_plan(&config, aws_sym, &plan, &mut state, dry_run).await;
+    errors.extend(execute_named(&config, aws_sym, &plan, &mut state, dry_run).await);
+    let mut ids = BTreeMap::new();
+    errors.extend(execute_keys(&config, aws_sym, &plan, &mut ids, &mut state, dry_run).await);
+    errors.extend(delete_groups(&config, &plan, &mut state, dry_run).await);
+    errors.extend(delete_network(&config, &plan, &mut state, dry_run).await);
+    if dry_run {
+        return combine_errors(AwsErrorType::DryRun, errors);
+    }
+    backend.write(&mut state).await?;
+    combine_errors(AwsErrorType::EC2Terminate, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unlocked() {
+        let failed = || Err::<(), _>(AwsDeployError::new(AwsErrorType::EC2Deploy, "i-1: boom"));
+        let stuck = || Err(StateError::new("lock held"));
+
+        // the failed operation comes first, the failed unlock with it
+        let err = unlocked(failed(), stuck()).unwrap_err();
+        assert!(matches!(err.err_type, AwsErrorType::EC2Deploy));
+        assert_eq!(
+            err.message,
+            "i-1: boom\n-> the stack is still locked: State Error: lock held"
+        );
+        let err = unlocked(Ok(()), stuck()).unwrap_err();
+        assert!(matches!(err.err_type, AwsErrorType::State));
+        assert_eq!(unlocked(failed(), Ok(())).unwrap_err().message, "i-1: boom");
+        assert!(unlocked(Ok(()), Ok(())).is_ok());
+    }
+}
@@ -0,0 +1,931 @@
+use aws_sdk_ec2::client::Waiters;
+use aws_sdk_ec2::error::ProvideErrorMetadata;
+use aws_sdk_ec2::types::{
+    AttributeBooleanValue, DomainType, Filter, NatGatewayState, ResourceType, RouteTable, Tag,
+    TagSpecification,
+};
+use aws_sdk_ec2::{Client, Error};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::actions::plan::{
+    AttrChange, ChangeKind, ID_TAG, NamedPlan, PlanAction, STACK_TAG, tags_change,
+};
+use crate::aws::{AwsSym, NETWORK_KINDS, NetworkSym, kind_rank, pending_id};
+use crate::state::{StackState, network_key};
+
+/// A networking resource of the stack, as returned by `describe_network`.
+/// `settings` holds the values compared against `NetworkSym::settings`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ExistingNetwork {
+    pub(crate) kind: &'static str,
+    pub(crate) aws_id: String,
+    /// `<kind>.<id>` from the id tag
+    pub(crate) key: Option<String>,
+    pub(crate) settings: BTreeMap<String, String>,
+    pub(crate) tags: BTreeMap<String, String>,
+}
+
+impl ExistingNetwork {
+    /// The id of the resource in the file it was created for, its AWS id
+    /// when it has no id tag
+    pub(crate) fn id(&self) -> String {
+        self.key
+            .as_ref()
+            .and_then(|k| k.split_once('.'))
+            .map(|(_, id)| id.to_string())
+            .unwrap_or(self.aws_id.to_string())
+    }
+}
+
+/// The tags of the networking resource `network`: the user tags, its
+/// name and the stack and id tags it is found by
+pub(crate) fn desired_tags(aws_sym: &AwsSym, network: &NetworkSym) -> BTreeMap<String, String> {
+    let mut tags: BTreeMap<String, String> = network.tags.clone();
+    tags.insert("Name".to_string(), network.name.to_string());
+    tags.insert(STACK_TAG.to_string(), aws_sym.name.to_string());
+    tags.insert(ID_TAG.to_string(), network_key(network.kind, &network.id));
+    tags
+}
+
+fn tag_specification(
+    resource_type: ResourceType,
+    tags: &BTreeMap<String, String>,
+) -> TagSpecification {
+    TagSpecification::builder()
+        .resource_type(resource_type)
+        .set_tags(Some(
+            tags.iter()
+                .map(|(k, v)| Tag::builder().key(k).value(v).build())
+                .collect(),
+        ))
+        .build()
+}
+
+fn to_tags(tags: &[Tag]) -> BTreeMap<String, String> {
+    tags.iter()
+        .map(|t| {
+            (
+                t.key().unwrap_or_default().to_string(),
+                t.value().unwrap_or_default().to_string(),
+            )
+        })
+        .collect()
+}
+
+fn network_from(
+    kind: &'static str,
+    aws_id: Option<&str>,
+    settings: Vec<(&str, String)>,
+    tags: &[Tag],
+) -> ExistingNetwork {
+    let tags = to_tags(tags);
+    ExistingNetwork {
+        kind: kind,
+        aws_id: aws_id.unwrap_or_default().to_string(),
+        key: tags.get(ID_TAG).cloned(),
+        settings: settings
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+        tags: tags,
+    }
+}
+
+/// The routes of a route table awsdsl manages, the `local` route of the
+/// vpc is left out
+fn route_strings(table: &RouteTable) -> Vec<String> {
+    let mut routes: Vec<String> = table
+        .routes()
+        .iter()
+        .filter(|r| r.gateway_id() != Some("local"))
+        .filter_map(|r| {
+            let target = r.gateway_id().or(r.nat_gateway_id())?;
+            Some(format!("{} -> {}", r.destination_cidr_block()?, target))
+        })
+        .collect();
+    routes.sort();
+    routes
+}
+
+/// The subnets a route table is explicitly associated with
+fn associated_subnets(table: &RouteTable) -> Vec<(String, String)> {
+    table
+        .associations()
+        .iter()
+        .filter(|a| a.main() != Some(true))
+        .filter_map(|a| {
+            Some((
+                a.subnet_id()?.to_string(),
+                a.route_table_association_id()?.to_string(),
+            ))
+        })
+        .collect()
+}
+
+/// Describe the networking resources tagged with the stack `stack`,
+/// kind by kind in the order of `NETWORK_KINDS`
+pub(crate) async fn describe_network(
+    client: &Client,
+    stack: &str,
+) -> Result<Vec<ExistingNetwork>, Error> {
+    let stack_filter = || {
+        Filter::builder()
+            .name(format!("tag:{}", STACK_TAG))
+            .values(stack)
+            .build()
+    };
+    let mut existing: Vec<ExistingNetwork> = vec![];
+
+    let mut pages = client
+        .describe_vpcs()
+        .filters(stack_filter())
+        .into_paginator()
+        .send();
+    while let Some(page) = pages.next().await {
+        for vpc in page?.vpcs() {
+            existing.push(network_from(
+                "vpc",
+                vpc.vpc_id(),
+                vec![("cidr", vpc.cidr_block().unwrap_or_default().to_string())],
+                vpc.tags(),
+            ));
+        }
+    }
+
+    let mut pages = client
+        .describe_internet_gateways()
+        .filters(stack_filter())
+        .into_paginator()
+        .send();
+    while let Some(page) = pages.next().await {
+        for igw in page?.internet_gateways() {
+            let vpc_id = igw.attachments().iter().find_map(|a| a.vpc_id());
+            existing.push(network_from(
+                "internet_gateway",
+                igw.internet_gateway_id(),
+                vec![("vpc", vpc_id.unwrap_or("<none>").to_string())],
+                igw.tags(),
+            ));
+        }
+    }
+
+    let mut pages = client
+        .describe_subnets()
+        .filters(stack_filter())
+        .into_paginator()
+        .send();
+    while let Some(page) = pages.next().await {
+        for subnet in page?.subnets() {
+            existing.push(network_from(
+                "subnet",
+                subnet.subnet_id(),
+                vec![
+                    ("cidr", subnet.cidr_block().unwrap_or_default().to_string()),
+                    ("vpc", subnet.vpc_id().unwrap_or_default().to_string()),
+                    (
+                        "az",
+                        subnet.availability_zone().unwrap_or_default().to_string(),
+                    ),
+                    (
+                        "public",
+                        subnet
+                            .map_public_ip_on_launch()
+                            .unwrap_or_default()
+                            .to_string(),
+                    ),
+                ],
+                subnet.tags(),
+            ));
+        }
+    }
+
+    let mut pages = client
+        .describe_nat_gateways()
+        .filter(stack_filter())
+        .into_paginator()
+        .send();
+    while let Some(page) = pages.next().await {
+        for nat in page?.nat_gateways() {
+            if matches!(
+                nat.state(),
+                Some(NatGatewayState::Deleted) | Some(NatGatewayState::Deleting)
+            ) {
+                continue;
+            }
+            existing.push(network_from(
+                "nat_gateway",
+                nat.nat_gateway_id(),
+                vec![("subnet", nat.subnet_id().unwrap_or_default().to_string())],
+                nat.tags(),
+            ));
+        }
+    }
+
+    let mut pages = client
+        .describe_route_tables()
+        .filters(stack_filter())
+        .into_paginator()
+        .send();
+    while let Some(page) = pages.next().await {
+        for table in page?.route_tables() {
+            let mut subnet_ids: Vec<String> = associated_subnets(table)
+                .into_iter()
+                .map(|(s, _)| s)
+                .collect();
+            subnet_ids.sort();
+            existing.push(network_from(
+                "route_table",
+                table.route_table_id(),
+                vec![
+                    ("vpc", table.vpc_id().unwrap_or_default().to_string()),
+                    ("subnets", subnet_ids.join(",")),
+                    ("routes", route_strings(table).join(", ")),
+                ],
+                table.tags(),
+            ));
+        }
+    }
+
+    Ok(existing)
+}
+
+/// Create the resource `network`, whose ids must all be resolved, with
+/// its settings and tags. Returns its AWS id.
+pub(crate) async fn create_network(
+    client: &Client,
+    aws_sym: &AwsSym,
+    network: &NetworkSym,
+) -> Result<String, Error> {
+    let tags = desired_tags(aws_sym, network);
+    let aws_id = match network.kind {
+        "vpc" => {
+            let created = client
+                .create_vpc()
+                .set_cidr_block(network.cidr.clone())
+                .tag_specifications(tag_specification(ResourceType::Vpc, &tags))
+                .send()
+                .await?;
+            let vpc_id = created
+                .vpc()
+                .and_then(|v| v.vpc_id())
+                .unwrap_or_default()
+                .to_string();
+            client
+                .wait_until_vpc_available()
+                .vpc_ids(&vpc_id)
+                .wait(Duration::from_secs(300))
+                .await?;
+            vpc_id
+        }
+        "internet_gateway" => {
+            let created = client
+                .create_internet_gateway()
+                .tag_specifications(tag_specification(ResourceType::InternetGateway, &tags))
+                .send()
+                .await?;
+            let igw_id = created
+                .internet_gateway()
+                .and_then(|g| g.internet_gateway_id())
+                .unwrap_or_default()
+                .to_string();
+            client
+                .attach_internet_gateway()
+                .internet_gateway_id(&igw_id)
+                .set_vpc_id(network.vpc_id.clone())
+                .send()
+                .await?;
+            igw_id
+        }
+        "subnet" => {
+            let created = client
+                .create_subnet()
+                .set_vpc_id(network.vpc_id.clone())
+                .set_cidr_block(network.cidr.clone())
+                .set_availability_zone(network.az.clone())
+                .tag_specifications(tag_specification(ResourceType::Subnet, &tags))
+                .send()
+                .await?;
+            let subnet_id = created
+                .subnet()
+                .and_then(|s| s.subnet_id())
+                .unwrap_or_default()
+                .to_string();
+            if network.public {
+                set_public(client, &subnet_id, true).await?;
+            }
+            subnet_id
+        }
+        "nat_gateway" => {
+            let allocated = client
+                .allocate_address()
+                .domain(DomainType::Vpc)
+                .tag_specifications(tag_specification(ResourceType::ElasticIp, &tags))
+                .send()
+                .await?;
+            let created = client
+                .create_nat_gateway()
+                .set_subnet_id(network.subnet_id.clone())
+                .set_allocation_id(allocated.allocation_id().map(|a| a.to_string()))
+                .tag_specifications(tag_specification(ResourceType::Natgateway, &tags))
+                .send()
+                .await?;
+            let nat_id = created
+                .nat_gateway()
+                .and_then(|n| n.nat_gateway_id())
+                .unwrap_or_default()
+                .to_string();
+            println!("  {}: waiting for the nat gateway", nat_id);
+            client
+                .wait_until_nat_gateway_available()
+                .nat_gateway_ids(&nat_id)
+                .wait(Duration::from_secs(600))
+                .await?;
+            nat_id
+        }
+        _ => {
+            let created = client
+                .create_route_table()
+                .set_vpc_id(network.vpc_id.clone())
+                .tag_specifications(tag_specification(ResourceType::RouteTable, &tags))
+                .send()
+                .await?;
+            let table_id = created
+                .route_table()
+                .and_then(|t| t.route_table_id())
+                .unwrap_or_default()
+                .to_string();
+            sync_route_table(client, &table_id, network).await?;
+            table_id
+        }
+    };
+    println!("  {}", aws_id);
+    Ok(aws_id)
+}
+
+async fn set_public(client: &Client, subnet_id: &str, public: bool) -> Result<(), Error> {
+    client
+        .modify_subnet_attribute()
+        .subnet_id(subnet_id)
+        .map_public_ip_on_launch(AttributeBooleanValue::builder().value(public).build())
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Make the routes and subnet associations of the route table
+/// `table_id` the ones of `network`. A subnet associated with another
+/// table is moved over.
+async fn sync_route_table(
+    client: &Client,
+    table_id: &str,
+    network: &NetworkSym,
+) -> Result<(), Error> {
+    let described = client
+        .describe_route_tables()
+        .route_table_ids(table_id)
+        .send()
+        .await?;
+    let table = match described.route_tables().first() {
+        Some(table) => table.clone(),
+        None => return Ok(()),
+    };
+
+    let actual = route_strings(&table);
+    for route in table.routes() {
+        let cidr = route.destination_cidr_block().unwrap_or_default();
+        let managed = actual
+            .iter()
+            .any(|r| r.starts_with(&format!("{} ->", cidr)));
+        if managed && !network.routes.iter().any(|r| r.cidr == cidr) {
+            client
+                .delete_route()
+                .route_table_id(table_id)
+                .destination_cidr_block(cidr)
+                .send()
+                .await?;
+        }
+    }
+    for route in &network.routes {
+        if actual.contains(&route.to_string()) {
+            continue;
+        }
+        let (gateway_id, nat_gateway_id) = match route.target.starts_with("nat-") {
+            true => (None, Some(route.target.to_string())),
+            false => (Some(route.target.to_string()), None),
+        };
+        if actual
+            .iter()
+            .any(|r| r.starts_with(&format!("{} ->", route.cidr)))
+        {
+            client
+                .replace_route()
+                .route_table_id(table_id)
+                .destination_cidr_block(&route.cidr)
+                .set_gateway_id(gateway_id)
+                .set_nat_gateway_id(nat_gateway_id)
+                .send()
+                .await?;
+        } else {
+            client
+                .create_route()
+                .route_table_id(table_id)
+                .destination_cidr_block(&route.cidr)
+                .set_gateway_id(gateway_id)
+                .set_nat_gateway_id(nat_gateway_id)
+                .send()
+                .await?;
+        }
+    }
+
+    let associated = associated_subnets(&table);
+    for (subnet_id, association_id) in &associated {
+        if !network.subnet_ids.contains(subnet_id) {
+            client
+                .disassociate_route_table()
+                .association_id(association_id)
+                .send()
+                .await?;
+        }
+    }
+    for subnet_id in &network.subnet_ids {
+        if associated.iter().any(|(s, _)| s == subnet_id) {
+            continue;
+        }
+        let elsewhere = client
+            .describe_route_tables()
+            .filters(
+                Filter::builder()
+                    .name("association.subnet-id")
+                    .values(subnet_id)
+                    .build(),
+            )
+            .send()
+            .await?;
+        let association_id = elsewhere.route_tables().iter().find_map(|t| {
+            associated_subnets(t)
+                .into_iter()
+                .find(|(s, _)| s == subnet_id)
+                .map(|(_, a)| a)
+        });
+        match association_id {
+            Some(association_id) => {
+                client
+                    .replace_route_table_association()
+                    .association_id(association_id)
+                    .route_table_id(table_id)
+                    .send()
+                    .await?;
+            }
+            None => {
+                client
+                    .associate_route_table()
+                    .route_table_id(table_id)
+                    .subnet_id(subnet_id)
+                    .send()
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Make the in place `settings` of the resource `aws_id` what `network`
+/// says
+pub(crate) async fn update_network(
+    client: &Client,
+    aws_sym: &AwsSym,
+    network: &NetworkSym,
+    aws_id: &str,
+    changes: &[AttrChange],
+) -> Result<(), Error> {
+    let mut table_synced = false;
+    for change in changes {
+        match (network.kind, change.attr.as_str()) {
+            ("subnet", "public") => set_public(client, aws_id, network.public).await?,
+            ("internet_gateway", "vpc") => {
+                if change.actual != "<none>" {
+                    client
+                        .detach_internet_gateway()
+                        .internet_gateway_id(aws_id)
+                        .vpc_id(&change.actual)
+                        .send()
+                        .await?;
+                }
+                client
+                    .attach_internet_gateway()
+                    .internet_gateway_id(aws_id)
+                    .set_vpc_id(network.vpc_id.clone())
+                    .send()
+                    .await?;
+            }
+            ("route_table", "subnets") | ("route_table", "routes") if !table_synced => {
+                sync_route_table(client, aws_id, network).await?;
+                table_synced = true;
+            }
+            (_, "tags") => {
+                client
+                    .create_tags()
+                    .resources(aws_id)
+                    .set_tags(Some(
+                        desired_tags(aws_sym, network)
+                            .iter()
+                            .map(|(k, v)| Tag::builder().key(k).value(v).build())
+                            .collect(),
+                    ))
+                    .send()
+                    .await?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Delete the resource `aws_id` of `kind`, one that is already gone is
+/// fine. A gateway is detached, a route table disassociated and the
+/// address of a nat gateway released first.
+pub(crate) async fn delete_network(client: &Client, kind: &str, aws_id: &str) -> Result<(), Error> {
+    let deleted = match kind {
+        "vpc" => client
+            .delete_vpc()
+            .vpc_id(aws_id)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(Error::from),
+        "subnet" => client
+            .delete_subnet()
+            .subnet_id(aws_id)
+            .send()
+            .await
+            .map(|_| ())
+            .map_err(Error::from),
+        "internet_gateway" => {
+            let described = client
+                .describe_internet_gateways()
+                .filters(
+                    Filter::builder()
+                        .name("internet-gateway-id")
+                        .values(aws_id)
+                        .build(),
+                )
+                .send()
+                .await?;
+            for igw in described.internet_gateways() {
+                for vpc_id in igw.attachments().iter().filter_map(|a| a.vpc_id()) {
+                    client
+                        .detach_internet_gateway()
+                        .internet_gateway_id(aws_id)
+                        .vpc_id(vpc_id)
+                        .send()
+                        .await?;
+                }
+            }
+            client
+                .delete_internet_gateway()
+                .internet_gateway_id(aws_id)
+                .send()
+                .await
+                .map(|_| ())
+                .map_err(Error::from)
+        }
+        "nat_gateway" => {
+            let described = client
+                .describe_nat_gateways()
+                .filter(
+                    Filter::builder()
+                        .name("nat-gateway-id")
+                        .values(aws_id)
+                        .build(),
+                )
+                .send()
+                .await?;
+            let allocation_ids: Vec<String> = described
+                .nat_gateways()
+                .iter()
+                .flat_map(|n| n.nat_gateway_addresses())
+                .filter_map(|a| a.allocation_id())
+                .map(|a| a.to_string())
+                .collect();
+            if described.nat_gateways().is_empty() {
+                return Ok(());
+            }
+            client
+                .delete_nat_gateway()
+                .nat_gateway_id(aws_id)
+                .send()
+                .await?;
+            println!("  {}: waiting for the nat gateway", aws_id);
+            client
+                .wait_until_nat_gateway_deleted()
+                .nat_gateway_ids(aws_id)
+                .wait(Duration::from_secs(600))
+                .await?;
+            for allocation_id in allocation_ids {
+                client
+                    .release_address()
+                    .allocation_id(allocation_id)
+                    .send()
+                    .await?;
+            }
+            Ok(())
+        }
+        _ => {
+            let described = client
+                .describe_route_tables()
+                .filters(
+                    Filter::builder()
+                        .name("route-table-id")
+                        .values(aws_id)
+                        .build(),
+                )
+                .send()
+                .await?;
+            for table in described.route_tables() {
+                for (_, association_id) in associated_subnets(table) {
+                    client
+                        .disassociate_route_table()
+                        .association_id(association_id)
+                        .send()
+                        .await?;
+                }
+            }
+            client
+                .delete_route_table()
+                .route_table_id(aws_id)
+                .send()
+                .await
+                .map(|_| ())
+                .map_err(Error::from)
+        }
+    };
+    match deleted {
+        Err(e) if e.code().is_some_and(|c| c.ends_with("NotFound")) => Ok(()),
+        deleted => deleted,
+    }
+}
+
+/// The existing resource deployed for `network`: the one recorded in the
+/// state, or the one carrying its id tag
+fn find_existing<'a>(
+    network: &NetworkSym,
+    existing: &'a [ExistingNetwork],
+    state: &StackState,
+) -> Option<&'a ExistingNetwork> {
+    let key = network_key(network.kind, &network.id);
+    match state.network.get(&key) {
+        Some(recorded) => existing.iter().find(|e| e.aws_id == recorded.aws_id),
+        None => existing
+            .iter()
+            .find(|e| e.kind == network.kind && e.key.as_ref() == Some(&key)),
+    }
+}
+
+/// Compare the desired settings of `network` against the existing
+/// resource
+fn diff_network(
+    aws_sym: &AwsSym,
+    network: &NetworkSym,
+    actual: &ExistingNetwork,
+) -> Vec<AttrChange> {
+    let mut changes: Vec<AttrChange> = vec![];
+    for (attr, desired, replace) in network.settings() {
+        let actual = actual
+            .settings
+            .get(attr)
+            .map(|a| a.as_str())
+            .unwrap_or("<none>");
+        if actual != desired {
+            let mut change = AttrChange::new(attr, actual, desired);
+            change.kind = match replace {
+                true => ChangeKind::Replace,
+                false => ChangeKind::InPlace,
+            };
+            changes.push(change);
+        }
+    }
+
+    changes.extend(tags_change(&actual.tags, &desired_tags(aws_sym, network)));
+    changes
+}
+
+/// Compute the plans of the networking resources of `aws_sym`, in the
+/// order they are created, given the resources of the stack that exist
+/// and the recorded `state`. Each resource is compared with the ids of
+/// the ones before it resolved, so replacing a vpc replaces what sits in
+/// it. The plans name the resources by their AWS id when they exist.
+/// Returns the plans and the AWS ids of the resources that are kept, by
+/// pending id.
+pub(crate) fn diff(
+    aws_sym: &AwsSym,
+    existing: &[ExistingNetwork],
+    state: &StackState,
+) -> (Vec<NamedPlan>, BTreeMap<String, String>) {
+    let mut plans: Vec<NamedPlan> = vec![];
+    let mut ids: BTreeMap<String, String> = BTreeMap::new();
+    for network in &aws_sym.networks {
+        let network = &network.resolved(&ids);
+        let mut plan = NamedPlan::new(
+            network.kind,
+            network.id.as_str(),
+            network.name.as_str(),
+            PlanAction::Create,
+        );
+        if let Some(actual) = find_existing(network, existing, state) {
+            plan.name = actual.aws_id.to_string();
+            plan.changes = diff_network(aws_sym, network, actual);
+            plan.action = if plan.changes.is_empty() {
+                PlanAction::NoOp
+            } else if plan.changes.iter().all(|c| c.kind == ChangeKind::InPlace) {
+                PlanAction::Update
+            } else {
+                PlanAction::Replace
+            };
+            if plan.action != PlanAction::Replace {
+                ids.insert(
+                    pending_id(network.kind, &network.id),
+                    actual.aws_id.to_string(),
+                );
+            }
+        }
+        plans.push(plan);
+    }
+
+    let mut orphans: Vec<NamedPlan> = vec![];
+    for (key, recorded) in &state.network {
+        let declared = aws_sym
+            .networks
+            .iter()
+            .any(|n| network_key(n.kind, &n.id) == *key);
+        if !declared {
+            orphans.push(orphan(&recorded.kind, &recorded.id, &recorded.aws_id));
+        }
+    }
+    for actual in existing {
+        let owned = plans.iter().any(|p| p.name == actual.aws_id)
+            || orphans.iter().any(|p| p.name == actual.aws_id);
+        if !owned {
+            orphans.push(orphan(actual.kind, &actual.id(), &actual.aws_id));
+        }
+    }
+    orphans.sort_by_key(|p| std::cmp::Reverse(kind_rank(p.kind)));
+    plans.extend(orphans);
+    (plans, ids)
+}
+
+/// The plan deleting a resource no longer declared
+fn orphan(kind: &str, id: &str, aws_id: &str) -> NamedPlan {
+    let kind = NETWORK_KINDS
+        .iter()
+        .find(|k| **k == kind)
+        .copied()
+        .unwrap_or("vpc");
+    NamedPlan::new(kind, id, aws_id, PlanAction::Delete)
+}
+
+/// Compute the plans deleting every networking resource `aws_sym`
+/// deployed, the ones recorded in `state` and the ones tagged with the
+/// stack, in the reverse order of their creation
+pub(crate) fn destroy(existing: &[ExistingNetwork], state: &StackState) -> Vec<NamedPlan> {
+    let mut plans: Vec<NamedPlan> = state
+        .network
+        .values()
+        .map(|n| orphan(&n.kind, &n.id, &n.aws_id))
+        .collect();
+    for actual in existing {
+        if !plans.iter().any(|p| p.name == actual.aws_id) {
+            plans.push(orphan(actual.kind, &actual.id(), &actual.aws_id));
+        }
+    }
+    plans.sort_by_key(|p| std::cmp::Reverse(kind_rank(p.kind)));
+    plans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::fixtures::{empty_state, stack};
+    use crate::aws::RouteSym;
+    use crate::state::NetworkState;
+
+    fn network_sym(kind: &'static str, id: &str) -> NetworkSym {
+        NetworkSym {
+            kind: kind,
+            id: id.to_string(),
+            name: id.to_string(),
+            cidr: None,
+            vpc_id: None,
+            subnet_id: None,
+            az: None,
+            public: false,
+            subnet_ids: vec![],
+            routes: vec![],
+            tags: BTreeMap::new(),
+        }
+    }
+
+    fn actual(aws_sym: &AwsSym, network: &NetworkSym, aws_id: &str) -> ExistingNetwork {
+        let tags = desired_tags(aws_sym, network);
+        ExistingNetwork {
+            kind: network.kind,
+            aws_id: aws_id.to_string(),
+            key: tags.get(ID_TAG).cloned(),
+            settings: network
+                .settings()
+                .into_iter()
+                .map(|(k, v, _)| (k.to_string(), v))
+                .collect(),
+            tags: tags,
+        }
+    }
+
+    #[test]
+    fn test_network_plan() {
+        let mut aws_sym = stack();
+        let mut vpc = network_sym("vpc", "main");
+        vpc.cidr = Some("10.0.0.0/16".to_string());
+        let mut subnet = network_sym("subnet", "web");
+        subnet.vpc_id = Some(pending_id("vpc", "main"));
+        subnet.cidr = Some("10.0.1.0/24".to_string());
+        let mut table = network_sym("route_table", "public");
+        table.vpc_id = Some(pending_id("vpc", "main"));
+        table.subnet_ids = vec![pending_id("subnet", "web")];
+        table.routes = vec![RouteSym {
+            cidr: "0.0.0.0/0".to_string(),
+            target: "igw-1".to_string(),
+        }];
+        aws_sym.add_network(table.clone());
+        aws_sym.add_network(subnet.clone());
+        aws_sym.add_network(vpc.clone());
+        let state = empty_state();
+
+        // nothing exists yet, the resources are created in order
+        let (plans, ids) = diff(&aws_sym, &[], &state);
+        let kinds: Vec<&str> = plans.iter().map(|p| p.kind).collect();
+        assert_eq!(kinds, vec!["vpc", "subnet", "route_table"]);
+        assert!(plans.iter().all(|p| p.action == PlanAction::Create));
+        assert!(ids.is_empty());
+
+        // everything exists: the ids are known and nothing changes
+        let ids = BTreeMap::from([
+            (pending_id("vpc", "main"), "vpc-1".to_string()),
+            (pending_id("subnet", "web"), "subnet-1".to_string()),
+        ]);
+        let mut existing = vec![
+            actual(&aws_sym, &vpc, "vpc-1"),
+            actual(&aws_sym, &subnet.resolved(&ids), "subnet-1"),
+            actual(&aws_sym, &table.resolved(&ids), "rtb-1"),
+        ];
+        let (plans, known) = diff(&aws_sym, &existing, &state);
+        assert!(plans.iter().all(|p| p.action == PlanAction::NoOp));
+        assert_eq!(known.get("<subnet.web.id>"), Some(&"subnet-1".to_string()));
+        assert_eq!(plans[2].name, "rtb-1");
+
+        // a new route is added in place
+        existing[2]
+            .settings
+            .insert("routes".to_string(), String::new());
+        let (plans, _) = diff(&aws_sym, &existing, &state);
+        assert_eq!(plans[2].action, PlanAction::Update);
+        assert_eq!(plans[2].changes[0].attr, "routes");
+
+        // a new vpc cidr replaces the vpc and what sits in it
+        existing[0]
+            .settings
+            .insert("cidr".to_string(), "10.9.0.0/16".to_string());
+        let (plans, known) = diff(&aws_sym, &existing, &state);
+        let actions: Vec<PlanAction> = plans.iter().map(|p| p.action.clone()).collect();
+        assert_eq!(
+            actions,
+            vec![
+                PlanAction::Replace,
+                PlanAction::Replace,
+                PlanAction::Replace
+            ]
+        );
+        assert!(known.is_empty());
+
+        // a recorded resource no longer in the file is deleted, last
+        let mut state = empty_state();
+        state.network.insert(
+            network_key("internet_gateway", "old"),
+            NetworkState::new(&network_sym("internet_gateway", "old"), "igw-9"),
+        );
+        let (plans, _) = diff(&aws_sym, &existing[..1], &state);
+        let last = plans.last().unwrap();
+        assert_eq!(last.action, PlanAction::Delete);
+        assert_eq!(
+            (last.kind, last.name.as_str()),
+            ("internet_gateway", "igw-9")
+        );
+
+        // destroy deletes in the reverse order of creation
+        let plans = destroy(&existing, &state);
+        let kinds: Vec<&str> = plans.iter().map(|p| p.kind).collect();
+        assert_eq!(
+            kinds,
+            vec!["route_table", "subnet", "internet_gateway", "vpc"]
+        );
+    }
+}
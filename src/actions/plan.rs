@@ -0,0 +1,802 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::aws::{AwsSym, Ec2Sym};
+use crate::state::StackState;
+
+/// Prefix of the `Name` tag set on every instance launched by awsdsl
+pub(crate) const NAME_TAG_PREFIX: &'static str = "awsdsl - ";
+
+/// Tag holding the stack (aws block) an instance belongs to
+pub(crate) const STACK_TAG: &'static str = "awsdsl:stack";
+
+/// Tag holding the position of an instance among the `count` instances
+/// of its resource, so scaling always adds and removes the same ones
+pub(crate) const INDEX_TAG: &'static str = "awsdsl:index";
+
+/// Tag holding the `<kind>.<id>` of a networking resource
+pub(crate) const ID_TAG: &'static str = "awsdsl:id";
+
+/// Tag holding the `for_each` key or `count` index of an expanded resource
+pub(crate) const KEY_TAG: &'static str = "awsdsl:key";
+
+/// Build the `Name` tag value for an ec2 resource
+pub(crate) fn name_tag(name: &str) -> String {
+    format!("{}{}", NAME_TAG_PREFIX, name)
+}
+
+/// The tags of the instance of `ec2` at `index`: the user tags and the
+/// ones awsdsl sets
+pub(crate) fn desired_tags(aws_sym: &AwsSym, ec2: &Ec2Sym, index: u32) -> BTreeMap<String, String> {
+    let mut tags: BTreeMap<String, String> = ec2.tags.clone();
+    tags.insert("Name".to_string(), name_tag(&ec2.name));
+    tags.insert(STACK_TAG.to_string(), aws_sym.name.to_string());
+    tags.insert(INDEX_TAG.to_string(), index.to_string());
+    if let Some(key) = &ec2.key {
+        tags.insert(KEY_TAG.to_string(), key.to_string());
+    }
+    tags
+}
+
+/// The `desired` tags whose value differs on a resource carrying the
+/// `actual` ones, as (key, actual, desired) with `<none>` for a missing
+/// tag. Only the tags of the file are compared, others are left alone.
+pub(crate) fn tag_diff(
+    actual: &BTreeMap<String, String>,
+    desired: &BTreeMap<String, String>,
+) -> Vec<(String, String, String)> {
+    let mut diff: Vec<(String, String, String)> = vec![];
+    for (k, v) in desired {
+        let a = actual.get(k).map(|a| a.as_str()).unwrap_or("<none>");
+        if a != v {
+            diff.push((k.to_string(), a.to_string(), v.to_string()));
+        }
+    }
+    diff
+}
+
+/// The in place change of the tags of a resource, `None` when the
+/// `desired` tags are all in place
+pub(crate) fn tags_change(
+    actual: &BTreeMap<String, String>,
+    desired: &BTreeMap<String, String>,
+) -> Option<AttrChange> {
+    let diff = tag_diff(actual, desired);
+    if diff.is_empty() {
+        return None;
+    }
+    let actual_tags: Vec<String> = diff
+        .iter()
+        .map(|(k, a, _)| format!("{}={}", k, a))
+        .collect();
+    let desired_tags: Vec<String> = diff
+        .iter()
+        .map(|(k, _, d)| format!("{}={}", k, d))
+        .collect();
+    let mut change = AttrChange::new("tags", actual_tags.join(", "), desired_tags.join(", "));
+    change.kind = ChangeKind::InPlace;
+    Some(change)
+}
+
+/// The keys of the `recorded` tags, the ones applied on the last run, that
+/// are no longer in `desired`
+pub(crate) fn dropped_tags(
+    recorded: &BTreeMap<String, String>,
+    desired: &BTreeMap<String, String>,
+) -> Vec<String> {
+    recorded
+        .keys()
+        .filter(|k| !desired.contains_key(*k))
+        .cloned()
+        .collect()
+}
+
+/// Security group ids in a stable order, for comparing two sets of them
+pub(crate) fn sorted_ids(ids: &[String]) -> String {
+    let mut ids = ids.to_vec();
+    ids.sort();
+    ids.join(",")
+}
+
+/// An instance that already exists in the account, as returned by
+/// `describe_instances`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ExistingInstance {
+    pub(crate) instance_id: String,
+    pub(crate) name: String,
+    pub(crate) stack: Option<String>,
+    /// pending, running, stopping, stopped, ...
+    pub(crate) state: String,
+    pub(crate) instance_type: String,
+    pub(crate) ami_id: String,
+    pub(crate) subnet_id: String,
+    pub(crate) sg_ids: Vec<String>,
+    pub(crate) key_name: String,
+    pub(crate) tags: BTreeMap<String, String>,
+    /// seconds since the epoch
+    pub(crate) launch_time: i64,
+}
+
+impl ExistingInstance {
+    /// The index from the `INDEX_TAG`, if the instance has a valid one
+    pub(crate) fn index(&self) -> Option<u32> {
+        self.tags.get(INDEX_TAG).and_then(|i| i.parse::<u32>().ok())
+    }
+}
+
+/// `Update` changes the existing instances in place, `Replace`
+/// terminates them and launches new ones
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum PlanAction {
+    Create,
+    Update,
+    Replace,
+    Delete,
+    NoOp,
+}
+
+impl fmt::Display for PlanAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Create => "+ create",
+            Self::Update => "~ update",
+            Self::Replace => "-/+ replace",
+            Self::Delete => "- delete",
+            Self::NoOp => "= no-op",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// How a change of an `Ec2Sym` attribute is carried out
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ChangeKind {
+    /// the running instance is modified
+    InPlace,
+    /// the instance has to be terminated and launched again
+    Replace,
+}
+
+/// Classify the `Ec2Sym` attributes: the instance type (through a
+/// stop/start), the security groups and the tags can be changed on a
+/// running instance and the count by launching or terminating the
+/// difference; anything else needs a new instance.
+pub(crate) fn change_kind(attr: &str) -> ChangeKind {
+    match attr {
+        "instance_type" | "sg_ids" | "tags" | "count" => ChangeKind::InPlace,
+        _ => ChangeKind::Replace,
+    }
+}
+
+/// A single attribute whose desired value differs from the actual one on
+/// `instance_ids`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct AttrChange {
+    pub(crate) attr: String,
+    pub(crate) actual: String,
+    pub(crate) desired: String,
+    pub(crate) kind: ChangeKind,
+    pub(crate) instance_ids: Vec<String>,
+}
+
+impl AttrChange {
+    pub(crate) fn new(
+        attr: impl std::convert::Into<String>,
+        actual: impl std::convert::Into<String>,
+        desired: impl std::convert::Into<String>,
+    ) -> Self {
+        let attr = attr.into();
+        AttrChange {
+            kind: change_kind(&attr),
+            attr: attr,
+            actual: actual.into(),
+            desired: desired.into(),
+            instance_ids: vec![],
+        }
+    }
+}
+
+impl fmt::Display for AttrChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            ChangeKind::InPlace => "",
+            ChangeKind::Replace => " (forces replacement)",
+        };
+        write!(
+            f,
+            "{}: {} -> {}{}",
+            self.attr, self.actual, self.desired, kind
+        )
+    }
+}
+
+/// The action planned for one ec2 resource. `ec2_id` is the id of the
+/// `Ec2Sym` (or the name found in the tag for deletes) and
+/// `instance_ids` are the existing instances affected by the action.
+/// `indexes` maps the instances that are kept to their index and
+/// `launch_indexes` are the indexes of the instances to launch.
+#[derive(Debug, Clone)]
+pub(crate) struct ResourcePlan {
+    pub(crate) ec2_id: String,
+    pub(crate) action: PlanAction,
+    pub(crate) instance_ids: Vec<String>,
+    pub(crate) changes: Vec<AttrChange>,
+    pub(crate) indexes: BTreeMap<String, u32>,
+    pub(crate) launch_indexes: Vec<u32>,
+}
+
+impl ResourcePlan {
+    pub(crate) fn new(ec2_id: impl std::convert::Into<String>, action: PlanAction) -> Self {
+        ResourcePlan {
+            ec2_id: ec2_id.into(),
+            action: action,
+            instance_ids: vec![],
+            changes: vec![],
+            indexes: BTreeMap::new(),
+            launch_indexes: vec![],
+        }
+    }
+}
+
+impl fmt::Display for ResourcePlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = format!("{} ec2 [{}]", self.action, self.ec2_id);
+        if !self.instance_ids.is_empty() {
+            s = format!("{} ({})", s, self.instance_ids.join(", "));
+        }
+        for change in &self.changes {
+            s = format!("{}\n    {}", s, change);
+        }
+        write!(f, "{}", s)
+    }
+}
+
+/// The action planned for a resource that is a single named AWS object,
+/// e.g. a bucket. `id` is the id of its symbol (or the one recorded in
+/// the state for deletes) and `name` the name of the object in AWS.
+#[derive(Debug, Clone)]
+pub(crate) struct NamedPlan {
+    pub(crate) kind: &'static str,
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) action: PlanAction,
+    pub(crate) changes: Vec<AttrChange>,
+}
+
+impl NamedPlan {
+    pub(crate) fn new(
+        kind: &'static str,
+        id: impl std::convert::Into<String>,
+        name: impl std::convert::Into<String>,
+        action: PlanAction,
+    ) -> Self {
+        NamedPlan {
+            kind: kind,
+            id: id.into(),
+            name: name.into(),
+            action: action,
+            changes: vec![],
+        }
+    }
+}
+
+impl fmt::Display for NamedPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = format!(
+            "{} {} [{}] ({})",
+            self.action, self.kind, self.id, self.name
+        );
+        for change in &self.changes {
+            s = format!("{}\n    {}", s, change);
+        }
+        write!(f, "{}", s)
+    }
+}
+
+/// Plan for a whole aws block. `network` holds the plans of the
+/// networking resources in the order they are created, the deletes last,
+/// `groups` the ones of the security groups and `keys` the ones of the
+/// key pairs; `ids` maps the pending ids of the ones that exist and are
+/// kept to their AWS ids.
+pub(crate) struct Plan {
+    pub(crate) resources: Vec<ResourcePlan>,
+    pub(crate) named: Vec<NamedPlan>,
+    pub(crate) network: Vec<NamedPlan>,
+    pub(crate) groups: Vec<NamedPlan>,
+    pub(crate) keys: Vec<NamedPlan>,
+    pub(crate) ids: BTreeMap<String, String>,
+}
+
+impl Plan {
+    /// true if applying the plan would not change anything
+    pub(crate) fn is_empty(&self) -> bool {
+        self.resources.iter().all(|r| r.action == PlanAction::NoOp)
+            && self.named.iter().all(|n| n.action == PlanAction::NoOp)
+            && self.network.iter().all(|n| n.action == PlanAction::NoOp)
+            && self.groups.iter().all(|g| g.action == PlanAction::NoOp)
+            && self.keys.iter().all(|k| k.action == PlanAction::NoOp)
+    }
+
+    pub(crate) fn count(&self, action: PlanAction) -> usize {
+        self.resources.iter().filter(|r| r.action == action).count()
+            + self.named.iter().filter(|n| n.action == action).count()
+            + self.network.iter().filter(|n| n.action == action).count()
+            + self.groups.iter().filter(|g| g.action == action).count()
+            + self.keys.iter().filter(|k| k.action == action).count()
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = String::from("plan:");
+        for network in &self.network {
+            s = format!("{}\n  {}", s, network);
+        }
+        for group in &self.groups {
+            s = format!("{}\n  {}", s, group);
+        }
+        for key in &self.keys {
+            s = format!("{}\n  {}", s, key);
+        }
+        for rsrc in &self.resources {
+            s = format!("{}\n  {}", s, rsrc);
+        }
+        for named in &self.named {
+            s = format!("{}\n  {}", s, named);
+        }
+        s = format!(
+            "{}\n{} to create, {} to update, {} to replace, {} to delete, {} unchanged",
+            s,
+            self.count(PlanAction::Create),
+            self.count(PlanAction::Update),
+            self.count(PlanAction::Replace),
+            self.count(PlanAction::Delete),
+            self.count(PlanAction::NoOp)
+        );
+        write!(f, "{}", s)
+    }
+}
+
+/// Compare the desired attributes of an `Ec2Sym` against one existing
+/// instance and return the attributes that differ
+fn diff_instance(
+    aws_sym: &AwsSym,
+    ec2: &Ec2Sym,
+    inst: &ExistingInstance,
+    index: u32,
+    recorded_tags: &BTreeMap<String, String>,
+) -> Vec<AttrChange> {
+    let mut changes: Vec<AttrChange> = vec![];
+    let mut check = |attr: &str, actual: &str, desired: &str| {
+        if actual != desired {
+            let mut change = AttrChange::new(attr, actual, desired);
+            change.instance_ids.push(inst.instance_id.clone());
+            changes.push(change);
+        }
+    };
+    check("instance_type", &inst.instance_type, &ec2.instance_type);
+    check("ami", &inst.ami_id, &ec2.ami_id);
+    check("subnet_id", &inst.subnet_id, &ec2.subnet_id);
+    check(
+        "sg_ids",
+        &sorted_ids(&inst.sg_ids),
+        &sorted_ids(&ec2.sg_ids),
+    );
+    check("key_name", &inst.key_name, &ec2.key_name);
+
+    // a tag applied from the file on an earlier run and dropped from it
+    // since is wanted absent
+    let mut desired = desired_tags(aws_sym, ec2, index);
+    for k in dropped_tags(recorded_tags, &desired) {
+        desired.insert(k, "<none>".to_string());
+    }
+    if let Some(mut change) = tags_change(&inst.tags, &desired) {
+        change.instance_ids.push(inst.instance_id.clone());
+        changes.push(change);
+    }
+
+    changes
+}
+
+/// true if `inst` belongs to the stack of `aws_sym`. Instances launched
+/// before the stack tag was introduced only carry a `Name` tag, they are
+/// claimed by name when `untagged` is set.
+fn in_stack(aws_sym: &AwsSym, inst: &ExistingInstance, untagged: bool) -> bool {
+    match &inst.stack {
+        Some(stack) => stack == &aws_sym.name,
+        None => untagged,
+    }
+}
+
+/// true if `inst` is deployed for `ec2`. The state file is authoritative,
+/// instances it doesn't know about are matched by their `Name` tag.
+fn owned_by(aws_sym: &AwsSym, state: &StackState, ec2: &Ec2Sym, inst: &ExistingInstance) -> bool {
+    match state.owner_of(&inst.instance_id) {
+        Some(owner) => owner == ec2.id,
+        None => inst.name == ec2.name && in_stack(aws_sym, inst, true),
+    }
+}
+
+/// Give every instance of a resource an index, sorted by it. A valid,
+/// unique index tag is kept; the other instances get the lowest free
+/// indexes, the oldest first.
+fn assign_indexes<'a>(matched: &[&'a ExistingInstance]) -> Vec<(&'a ExistingInstance, u32)> {
+    let mut insts: Vec<&ExistingInstance> = matched.to_vec();
+    insts.sort_by_key(|i| (i.index().unwrap_or(u32::MAX), i.launch_time));
+    let mut indexed: Vec<(&ExistingInstance, u32)> = vec![];
+    let mut unindexed: Vec<&ExistingInstance> = vec![];
+    for inst in insts {
+        match inst.index() {
+            Some(i) if !indexed.iter().any(|(_, x)| *x == i) => indexed.push((inst, i)),
+            _ => unindexed.push(inst),
+        }
+    }
+    let mut free = 0..;
+    for inst in unindexed {
+        let index = free
+            .find(|i| !indexed.iter().any(|(_, x)| x == i))
+            .unwrap_or_default();
+        indexed.push((inst, index));
+    }
+    indexed.sort_by_key(|(_, i)| *i);
+    indexed
+}
+
+/// Compute the plan for `aws_sym` given the instances that already
+/// exist and the recorded `state`. Instances are matched to an `Ec2Sym`
+/// through the state, or their `Name` tag; instances of the same stack
+/// that match no `Ec2Sym` are deleted.
+pub(crate) fn diff(aws_sym: &AwsSym, existing: &[ExistingInstance], state: &StackState) -> Plan {
+    let mut resources: Vec<ResourcePlan> = vec![];
+    for ec2 in &aws_sym.ec2s {
+        let matched: Vec<&ExistingInstance> = existing
+            .iter()
+            .filter(|i| owned_by(aws_sym, state, ec2, i))
+            .collect();
+        if matched.is_empty() {
+            // a resource scaled to zero has nothing to create
+            let action = match ec2.count {
+                0 => PlanAction::NoOp,
+                _ => PlanAction::Create,
+            };
+            let mut rsrc = ResourcePlan::new(ec2.id.as_str(), action);
+            rsrc.launch_indexes = (0..ec2.count as u32).collect();
+            resources.push(rsrc);
+            continue;
+        }
+
+        let mut rsrc = ResourcePlan::new(ec2.id.as_str(), PlanAction::NoOp);
+        rsrc.instance_ids = matched.iter().map(|i| i.instance_id.clone()).collect();
+        let indexed = assign_indexes(&matched);
+        let count = ec2.count as usize;
+        let mut changes: Vec<AttrChange> = vec![];
+        if matched.len() != count {
+            let mut change = AttrChange::new("count", matched.len().to_string(), count.to_string());
+            // the surplus goes from the highest index down
+            for (inst, _) in indexed.iter().skip(count) {
+                change.instance_ids.push(inst.instance_id.clone());
+            }
+            changes.push(change);
+        }
+        let recorded_tags = state
+            .ec2s
+            .get(&ec2.id)
+            .map(|s| s.tags.clone())
+            .unwrap_or_default();
+        for (inst, index) in indexed.iter().take(count) {
+            rsrc.indexes.insert(inst.instance_id.clone(), *index);
+            for change in diff_instance(aws_sym, ec2, inst, *index, &recorded_tags) {
+                match changes.iter_mut().find(|c| {
+                    c.attr == change.attr
+                        && c.actual == change.actual
+                        && c.desired == change.desired
+                }) {
+                    Some(c) => c.instance_ids.extend(change.instance_ids),
+                    None => changes.push(change),
+                }
+            }
+        }
+        rsrc.action = if changes.is_empty() {
+            PlanAction::NoOp
+        } else if changes.iter().all(|c| c.kind == ChangeKind::InPlace) {
+            PlanAction::Update
+        } else {
+            PlanAction::Replace
+        };
+        rsrc.launch_indexes = match rsrc.action {
+            PlanAction::Replace => (0..count as u32).collect(),
+            _ => (0..)
+                .filter(|i| !rsrc.indexes.values().any(|x| x == i))
+                .take(count.saturating_sub(rsrc.indexes.len()))
+                .collect(),
+        };
+        rsrc.changes = changes;
+        resources.push(rsrc);
+    }
+
+    // anything left in this stack, or recorded in the state, is no longer
+    // declared in the file
+    let mut orphans: Vec<(String, &ExistingInstance)> = existing
+        .iter()
+        .filter(|i| state.owner_of(&i.instance_id).is_some() || in_stack(aws_sym, i, false))
+        .filter(|i| !aws_sym.ec2s.iter().any(|e| owned_by(aws_sym, state, e, i)))
+        .map(|i| {
+            let owner = state.owner_of(&i.instance_id).unwrap_or(i.name.as_str());
+            (owner.to_string(), i)
+        })
+        .collect();
+    orphans.sort_by(|a, b| a.0.cmp(&b.0));
+    for (owner, inst) in orphans {
+        match resources
+            .iter_mut()
+            .find(|r| r.action == PlanAction::Delete && r.ec2_id == owner)
+        {
+            Some(rsrc) => rsrc.instance_ids.push(inst.instance_id.clone()),
+            None => {
+                let mut rsrc = ResourcePlan::new(owner, PlanAction::Delete);
+                rsrc.instance_ids.push(inst.instance_id.clone());
+                resources.push(rsrc);
+            }
+        }
+    }
+
+    Plan {
+        resources: resources,
+        named: vec![],
+        network: vec![],
+        groups: vec![],
+        keys: vec![],
+        ids: BTreeMap::new(),
+    }
+}
+
+/// Compute the plan tearing down everything `aws_sym` deployed: the
+/// instances recorded in `state`, the instances tagged with the stack and
+/// the untagged ones named after an `Ec2Sym` of the file. Resources of
+/// the state whose instances are already gone are deleted from it.
+pub(crate) fn destroy(aws_sym: &AwsSym, existing: &[ExistingInstance], state: &StackState) -> Plan {
+    let mut resources: Vec<ResourcePlan> = state
+        .ec2s
+        .values()
+        .map(|e| ResourcePlan::new(e.id.as_str(), PlanAction::Delete))
+        .collect();
+    for inst in existing {
+        let owner = match state.owner_of(&inst.instance_id) {
+            Some(owner) => Some(owner.to_string()),
+            None => aws_sym
+                .ec2s
+                .iter()
+                .find(|e| e.name == inst.name && in_stack(aws_sym, inst, true))
+                .map(|e| e.id.clone())
+                .or(if in_stack(aws_sym, inst, false) {
+                    Some(inst.name.clone())
+                } else {
+                    None
+                }),
+        };
+        let owner = match owner {
+            Some(o) => o,
+            None => continue,
+        };
+        match resources.iter_mut().find(|r| r.ec2_id == owner) {
+            Some(rsrc) => rsrc.instance_ids.push(inst.instance_id.clone()),
+            None => {
+                let mut rsrc = ResourcePlan::new(owner, PlanAction::Delete);
+                rsrc.instance_ids.push(inst.instance_id.clone());
+                resources.push(rsrc);
+            }
+        }
+    }
+    resources.sort_by(|a, b| a.ec2_id.cmp(&b.ec2_id));
+
+    Plan {
+        resources: resources,
+        named: vec![],
+        network: vec![],
+        groups: vec![],
+        keys: vec![],
+        ids: BTreeMap::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::fixtures::{ec2_stack, ec2_sym, empty_state, instance};
+    use crate::state::Ec2State;
+
+    fn indexes(inst_ids: &[&str]) -> BTreeMap<String, u32> {
+        inst_ids
+            .iter()
+            .enumerate()
+            .map(|(n, i)| (i.to_string(), n as u32))
+            .collect()
+    }
+
+    #[test]
+    fn test_plan_create_and_noop() {
+        let aws_sym = ec2_stack(vec![ec2_sym("web01", 1), ec2_sym("web02", 1)]);
+        let plan = diff(
+            &aws_sym,
+            &[instance("i-1", "web01", Some("stack"))],
+            &empty_state(),
+        );
+        assert_eq!(plan.resources[0].action, PlanAction::NoOp);
+        assert_eq!(plan.resources[1].action, PlanAction::Create);
+        assert!(!plan.is_empty());
+    }
+
+    #[test]
+    fn test_plan_update() {
+        let aws_sym = ec2_stack(vec![ec2_sym("web01", 1)]);
+        let mut inst = instance("i-1", "web01", None);
+        inst.instance_type = "t3.small".to_string();
+        let plan = diff(&aws_sym, &[inst], &empty_state());
+        assert_eq!(plan.resources[0].action, PlanAction::Update);
+        assert_eq!(plan.resources[0].changes[0].attr, "instance_type");
+        assert_eq!(plan.resources[0].changes[0].instance_ids, vec!["i-1"]);
+        // the untagged instance gets the stack tag
+        assert_eq!(plan.resources[0].changes[1].attr, "tags");
+    }
+
+    #[test]
+    fn test_plan_replace() {
+        let aws_sym = ec2_stack(vec![ec2_sym("web01", 1)]);
+        let mut inst = instance("i-1", "web01", Some("stack"));
+        inst.instance_type = "t3.small".to_string();
+        inst.ami_id = "ami-0".to_string();
+        let plan = diff(&aws_sym, &[inst], &empty_state());
+        assert_eq!(plan.resources[0].action, PlanAction::Replace);
+        assert_eq!(plan.resources[0].changes[1].kind, ChangeKind::Replace);
+    }
+
+    #[test]
+    fn test_plan_delete_only_own_stack() {
+        let aws_sym = ec2_stack(vec![]);
+        let plan = diff(
+            &aws_sym,
+            &[
+                instance("i-1", "old", Some("stack")),
+                instance("i-2", "other", Some("other-stack")),
+            ],
+            &empty_state(),
+        );
+        assert_eq!(plan.resources.len(), 1);
+        assert_eq!(plan.resources[0].action, PlanAction::Delete);
+        assert_eq!(plan.resources[0].instance_ids, vec!["i-1".to_string()]);
+    }
+
+    #[test]
+    fn test_plan_matches_through_state() {
+        // the Name tag was changed by hand, the state still knows i-1
+        let aws_sym = ec2_stack(vec![ec2_sym("web01", 1)]);
+        let mut state = empty_state();
+        let ec2 = ec2_sym("web01", 1);
+        state
+            .ec2s
+            .insert(ec2.id.clone(), Ec2State::new(&ec2, indexes(&["i-1"])));
+        let plan = diff(&aws_sym, &[instance("i-1", "renamed", None)], &state);
+        assert_eq!(plan.resources.len(), 1);
+        // the tags are put back in place
+        assert_eq!(plan.resources[0].action, PlanAction::Update);
+        assert_eq!(plan.resources[0].changes[0].attr, "tags");
+
+        // web01 removed from the file, the instance is deleted
+        let plan = diff(
+            &ec2_stack(vec![]),
+            &[instance("i-1", "renamed", None)],
+            &state,
+        );
+        assert_eq!(plan.resources[0].action, PlanAction::Delete);
+        assert_eq!(plan.resources[0].ec2_id, "web01");
+    }
+
+    #[test]
+    fn test_plan_dropped_tags() {
+        let mut ec2 = ec2_sym("web01", 1);
+        ec2.tags.insert("team".to_string(), "web".to_string());
+        let mut state = empty_state();
+        state
+            .ec2s
+            .insert(ec2.id.clone(), Ec2State::new(&ec2, indexes(&["i-1"])));
+        let mut inst = instance("i-1", "web01", Some("stack"));
+        inst.tags.insert("team".to_string(), "web".to_string());
+        inst.tags.insert("owner".to_string(), "ops".to_string());
+
+        // the tag applied from the file is removed, the others are kept
+        let plan = diff(
+            &ec2_stack(vec![ec2_sym("web01", 1)]),
+            &[inst.clone()],
+            &state,
+        );
+        assert_eq!(plan.resources[0].action, PlanAction::Update);
+        assert_eq!(plan.resources[0].changes[0].attr, "tags");
+        assert_eq!(plan.resources[0].changes[0].actual, "team=web");
+        assert_eq!(plan.resources[0].changes[0].desired, "team=<none>");
+
+        // once gone from the instance there is nothing left to do
+        inst.tags.remove("team");
+        let plan = diff(&ec2_stack(vec![ec2_sym("web01", 1)]), &[inst], &state);
+        assert_eq!(plan.resources[0].action, PlanAction::NoOp);
+    }
+
+    #[test]
+    fn test_destroy_plan() {
+        let aws_sym = ec2_stack(vec![ec2_sym("web01", 1), ec2_sym("web02", 1)]);
+        let mut state = empty_state();
+        let ec2 = ec2_sym("db01", 1);
+        state
+            .ec2s
+            .insert(ec2.id.clone(), Ec2State::new(&ec2, indexes(&["i-9"])));
+        let plan = destroy(
+            &aws_sym,
+            &[
+                instance("i-1", "web01", None),
+                instance("i-2", "old", Some("stack")),
+                instance("i-3", "web02", Some("other-stack")),
+            ],
+            &state,
+        );
+        let ids: Vec<&str> = plan.resources.iter().map(|r| r.ec2_id.as_str()).collect();
+        assert_eq!(ids, vec!["db01", "old", "web01"]);
+        assert!(
+            plan.resources
+                .iter()
+                .all(|r| r.action == PlanAction::Delete)
+        );
+        // i-9 is recorded but already gone
+        assert!(plan.resources[0].instance_ids.is_empty());
+        assert_eq!(plan.resources[2].instance_ids, vec!["i-1".to_string()]);
+    }
+
+    #[test]
+    fn test_plan_scale_up() {
+        let aws_sym = ec2_stack(vec![ec2_sym("web01", 3)]);
+        let mut inst = instance("i-1", "web01", Some("stack"));
+        inst.tags.insert(INDEX_TAG.to_string(), "1".to_string());
+        let plan = diff(&aws_sym, &[inst], &empty_state());
+        let rsrc = &plan.resources[0];
+        assert_eq!(rsrc.action, PlanAction::Update);
+        assert_eq!(rsrc.changes[0].attr, "count");
+        assert!(rsrc.changes[0].instance_ids.is_empty());
+        assert_eq!(rsrc.launch_indexes, vec![0, 2]);
+    }
+
+    #[test]
+    fn test_plan_scale_down() {
+        // i-2 has no index tag and is the newest, it takes index 1
+        let aws_sym = ec2_stack(vec![ec2_sym("web01", 1)]);
+        let mut old = instance("i-1", "web01", Some("stack"));
+        old.launch_time = 10;
+        old.tags.remove(INDEX_TAG);
+        let mut new = instance("i-2", "web01", Some("stack"));
+        new.launch_time = 20;
+        new.tags.remove(INDEX_TAG);
+        let plan = diff(&aws_sym, &[new, old], &empty_state());
+        let rsrc = &plan.resources[0];
+        assert_eq!(rsrc.action, PlanAction::Update);
+        assert_eq!(rsrc.changes[0].attr, "count");
+        assert_eq!(rsrc.changes[0].instance_ids, vec!["i-2"]);
+        assert_eq!(rsrc.indexes.get("i-1"), Some(&0));
+        assert!(rsrc.launch_indexes.is_empty());
+        // the kept instance gets its index tag
+        assert_eq!(rsrc.changes[1].attr, "tags");
+        assert_eq!(rsrc.changes[1].instance_ids, vec!["i-1"]);
+    }
+
+    #[test]
+    fn test_plan_scale_to_zero() {
+        let aws_sym = ec2_stack(vec![ec2_sym("web01", 0)]);
+        let plan = diff(
+            &aws_sym,
+            &[instance("i-1", "web01", Some("stack"))],
+            &empty_state(),
+        );
+        assert_eq!(plan.resources[0].action, PlanAction::Update);
+        assert_eq!(plan.resources[0].changes[0].instance_ids, vec!["i-1"]);
+        // once the instances are gone there is nothing to create
+        let plan = diff(&aws_sym, &[], &empty_state());
+        assert_eq!(plan.resources[0].action, PlanAction::NoOp);
+        assert!(plan.resources[0].launch_indexes.is_empty());
+    }
+}
@@ -0,0 +1,532 @@
+use aws_sdk_ec2::error::ProvideErrorMetadata;
+use aws_sdk_ec2::types::{
+    Filter, IpPermission, IpRange, ResourceType, SecurityGroup, Tag, TagSpecification,
+    UserIdGroupPair,
+};
+use aws_sdk_ec2::{Client, Error};
+use std::collections::BTreeMap;
+
+use crate::actions::plan::{
+    AttrChange, ChangeKind, ID_TAG, NamedPlan, PlanAction, STACK_TAG, tags_change,
+};
+use crate::aws::{AwsSym, GROUP_KIND, RuleSym, SecurityGroupSym, pending_id};
+use crate::state::{StackState, network_key};
+
+/// Kind of the security group plans
+pub(crate) const KIND: &'static str = GROUP_KIND;
+
+/// A security group of the stack, or recorded in the state, as returned
+/// by `describe_groups`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ExistingGroup {
+    pub(crate) aws_id: String,
+    pub(crate) name: String,
+    pub(crate) description: String,
+    pub(crate) vpc_id: String,
+    /// `security_group.<id>` from the id tag
+    pub(crate) key: Option<String>,
+    pub(crate) ingress: Vec<RuleSym>,
+    pub(crate) egress: Vec<RuleSym>,
+    pub(crate) tags: BTreeMap<String, String>,
+}
+
+impl ExistingGroup {
+    /// The id of the group in the file it was created for, its name when
+    /// it has no id tag
+    pub(crate) fn id(&self) -> String {
+        self.key
+            .as_ref()
+            .and_then(|k| k.split_once('.'))
+            .map(|(_, id)| id.to_string())
+            .unwrap_or(self.name.to_string())
+    }
+}
+
+/// The tags of the group `group`: the user tags, its name and the stack
+/// and id tags it is found by
+pub(crate) fn desired_tags(aws_sym: &AwsSym, group: &SecurityGroupSym) -> BTreeMap<String, String> {
+    let mut tags: BTreeMap<String, String> = group.tags.clone();
+    tags.insert("Name".to_string(), group.name.to_string());
+    tags.insert(STACK_TAG.to_string(), aws_sym.name.to_string());
+    tags.insert(ID_TAG.to_string(), network_key(KIND, &group.id));
+    tags
+}
+
+/// The rules of `permissions`, one per CIDR block or group. IPv6 ranges
+/// and prefix lists are not managed and left out.
+fn to_rules(permissions: &[IpPermission]) -> Vec<RuleSym> {
+    let mut rules: Vec<RuleSym> = vec![];
+    for perm in permissions {
+        let protocol = match perm.ip_protocol().unwrap_or_default() {
+            "-1" => "all",
+            p => p,
+        };
+        let rule = |source: &str| RuleSym {
+            protocol: protocol.to_string(),
+            from: match protocol {
+                "all" => 0,
+                _ => perm.from_port().unwrap_or_default(),
+            },
+            to: match protocol {
+                "all" => 0,
+                _ => perm.to_port().unwrap_or_default(),
+            },
+            source: source.to_string(),
+        };
+        for range in perm.ip_ranges() {
+            rules.extend(range.cidr_ip().map(rule));
+        }
+        for pair in perm.user_id_group_pairs() {
+            rules.extend(pair.group_id().map(rule));
+        }
+    }
+    rules
+}
+
+/// The permission granting `rule`
+fn to_permission(rule: &RuleSym) -> IpPermission {
+    let mut perm = match rule.protocol.as_str() {
+        "all" => IpPermission::builder().ip_protocol("-1"),
+        protocol => IpPermission::builder()
+            .ip_protocol(protocol)
+            .from_port(rule.from)
+            .to_port(rule.to),
+    };
+    perm = match rule.is_group() {
+        true => perm.user_id_group_pairs(UserIdGroupPair::builder().group_id(&rule.source).build()),
+        false => perm.ip_ranges(IpRange::builder().cidr_ip(&rule.source).build()),
+    };
+    perm.build()
+}
+
+fn to_existing(group: &SecurityGroup) -> ExistingGroup {
+    let tags: BTreeMap<String, String> = group
+        .tags()
+        .iter()
+        .map(|t| {
+            (
+                t.key().unwrap_or_default().to_string(),
+                t.value().unwrap_or_default().to_string(),
+            )
+        })
+        .collect();
+    ExistingGroup {
+        aws_id: group.group_id().unwrap_or_default().to_string(),
+        name: group.group_name().unwrap_or_default().to_string(),
+        description: group.description().unwrap_or_default().to_string(),
+        vpc_id: group.vpc_id().unwrap_or_default().to_string(),
+        key: tags.get(ID_TAG).cloned(),
+        ingress: to_rules(group.ip_permissions()),
+        egress: to_rules(group.ip_permissions_egress()),
+        tags: tags,
+    }
+}
+
+/// Describe the groups tagged with the stack `stack` and the groups
+/// `aws_ids` recorded in the state, the missing ones are left out
+pub(crate) async fn describe_groups(
+    client: &Client,
+    stack: &str,
+    aws_ids: &[String],
+) -> Result<Vec<ExistingGroup>, Error> {
+    let mut filters = vec![
+        Filter::builder()
+            .name(format!("tag:{}", STACK_TAG))
+            .values(stack)
+            .build(),
+    ];
+    if !aws_ids.is_empty() {
+        filters.push(
+            Filter::builder()
+                .name("group-id")
+                .set_values(Some(aws_ids.to_vec()))
+                .build(),
+        );
+    }
+    let mut existing: Vec<ExistingGroup> = vec![];
+    // filters are and-ed, each one is a call of its own
+    for filter in filters {
+        let mut pages = client
+            .describe_security_groups()
+            .filters(filter)
+            .into_paginator()
+            .send();
+        while let Some(page) = pages.next().await {
+            for group in page?.security_groups() {
+                let group = to_existing(group);
+                if !existing.iter().any(|g| g.aws_id == group.aws_id) {
+                    existing.push(group);
+                }
+            }
+        }
+    }
+    Ok(existing)
+}
+
+/// Create the group `group`, whose vpc must be resolved, without its
+/// rules: they may refer to groups that don't exist yet. Returns its id.
+pub(crate) async fn create_group(
+    client: &Client,
+    aws_sym: &AwsSym,
+    group: &SecurityGroupSym,
+) -> Result<String, Error> {
+    let tag_spec = TagSpecification::builder()
+        .resource_type(ResourceType::SecurityGroup)
+        .set_tags(Some(
+            desired_tags(aws_sym, group)
+                .iter()
+                .map(|(k, v)| Tag::builder().key(k).value(v).build())
+                .collect(),
+        ))
+        .build();
+    let created = client
+        .create_security_group()
+        .group_name(&group.name)
+        .description(&group.description)
+        .set_vpc_id(group.vpc_id.clone())
+        .tag_specifications(tag_spec)
+        .send()
+        .await?;
+    let aws_id = created.group_id().unwrap_or_default().to_string();
+    println!("  {}", aws_id);
+    Ok(aws_id)
+}
+
+/// Make the rules of the group `aws_id` the ones of `group`: the rules
+/// that are not wanted are revoked, the missing ones authorized. `None`
+/// revokes every rule.
+pub(crate) async fn sync_rules(
+    client: &Client,
+    aws_id: &str,
+    group: Option<&SecurityGroupSym>,
+) -> Result<(), Error> {
+    let described = client
+        .describe_security_groups()
+        .filters(Filter::builder().name("group-id").values(aws_id).build())
+        .send()
+        .await?;
+    let actual = match described.security_groups().first() {
+        Some(actual) => to_existing(actual),
+        None => return Ok(()),
+    };
+    let (ingress, egress) = match group {
+        Some(group) => (group.ingress.clone(), group.egress.clone()),
+        None => (vec![], vec![]),
+    };
+    let delta = |actual: &[RuleSym], desired: &[RuleSym]| -> Vec<IpPermission> {
+        actual
+            .iter()
+            .filter(|r| !desired.contains(r))
+            .inspect(|r| println!("  {}: - {}", aws_id, r))
+            .map(to_permission)
+            .collect()
+    };
+
+    let revoked = delta(&actual.ingress, &ingress);
+    if !revoked.is_empty() {
+        client
+            .revoke_security_group_ingress()
+            .group_id(aws_id)
+            .set_ip_permissions(Some(revoked))
+            .send()
+            .await?;
+    }
+    let revoked = delta(&actual.egress, &egress);
+    if !revoked.is_empty() {
+        client
+            .revoke_security_group_egress()
+            .group_id(aws_id)
+            .set_ip_permissions(Some(revoked))
+            .send()
+            .await?;
+    }
+    let authorized = delta(&ingress, &actual.ingress);
+    if !authorized.is_empty() {
+        client
+            .authorize_security_group_ingress()
+            .group_id(aws_id)
+            .set_ip_permissions(Some(authorized))
+            .send()
+            .await?;
+    }
+    let authorized = delta(&egress, &actual.egress);
+    if !authorized.is_empty() {
+        client
+            .authorize_security_group_egress()
+            .group_id(aws_id)
+            .set_ip_permissions(Some(authorized))
+            .send()
+            .await?;
+    }
+    Ok(())
+}
+
+/// Create or overwrite the tags of the group `aws_id`, the ones not set
+/// by the file are kept
+pub(crate) async fn set_tags(
+    client: &Client,
+    aws_sym: &AwsSym,
+    group: &SecurityGroupSym,
+    aws_id: &str,
+) -> Result<(), Error> {
+    client
+        .create_tags()
+        .resources(aws_id)
+        .set_tags(Some(
+            desired_tags(aws_sym, group)
+                .iter()
+                .map(|(k, v)| Tag::builder().key(k).value(v).build())
+                .collect(),
+        ))
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Delete the group `aws_id`, a group that is already gone is fine. Its
+/// rules must be revoked first when other groups being deleted refer to
+/// it.
+pub(crate) async fn delete_group(client: &Client, aws_id: &str) -> Result<(), Error> {
+    let deleted = client.delete_security_group().group_id(aws_id).send().await;
+    match deleted {
+        Err(e) if e.code().is_some_and(|c| c.ends_with("NotFound")) => Ok(()),
+        Err(e) => Err(e.into()),
+        Ok(_) => Ok(()),
+    }
+}
+
+/// The existing group deployed for `group`: the one recorded in the
+/// state, or the one carrying its id tag
+fn find_existing<'a>(
+    group: &SecurityGroupSym,
+    existing: &'a [ExistingGroup],
+    state: &StackState,
+) -> Option<&'a ExistingGroup> {
+    let key = network_key(KIND, &group.id);
+    match state.groups.get(&group.id) {
+        Some(recorded) => existing.iter().find(|e| e.aws_id == recorded.aws_id),
+        None => existing.iter().find(|e| e.key.as_ref() == Some(&key)),
+    }
+}
+
+/// Compare the desired settings of `group` against the existing group
+fn diff_group(
+    aws_sym: &AwsSym,
+    group: &SecurityGroupSym,
+    actual: &ExistingGroup,
+) -> Vec<AttrChange> {
+    let mut changes: Vec<AttrChange> = vec![];
+    for (attr, desired, replace) in group.settings() {
+        let actual = match attr {
+            "name" => actual.name.to_string(),
+            "description" => actual.description.to_string(),
+            "vpc" => actual.vpc_id.to_string(),
+            "ingress" => SecurityGroupSym::sorted_rules(&actual.ingress),
+            _ => SecurityGroupSym::sorted_rules(&actual.egress),
+        };
+        if actual != desired {
+            let mut change = AttrChange::new(attr, actual, desired);
+            change.kind = match replace {
+                true => ChangeKind::Replace,
+                false => ChangeKind::InPlace,
+            };
+            changes.push(change);
+        }
+    }
+
+    changes.extend(tags_change(&actual.tags, &desired_tags(aws_sym, group)));
+    changes
+}
+
+/// Compute the plans of the security groups of `aws_sym`, whose
+/// networking ids are resolved, given the groups that exist and the
+/// recorded `state`. A group whose name, description or vpc changed is
+/// replaced, its rules are updated in place; the groups of the state or
+/// the stack no longer in the file are deleted. The plans name the groups
+/// by their AWS id when they exist. Returns the plans and the AWS ids of
+/// the groups that are kept, by pending id.
+pub(crate) fn diff(
+    aws_sym: &AwsSym,
+    existing: &[ExistingGroup],
+    state: &StackState,
+) -> (Vec<NamedPlan>, BTreeMap<String, String>) {
+    // the rules may refer to any group, the ids of the kept ones are
+    // known before the rules are compared
+    let mut ids: BTreeMap<String, String> = BTreeMap::new();
+    for group in &aws_sym.groups {
+        if let Some(actual) = find_existing(group, existing, state) {
+            let replaced = diff_group(aws_sym, group, actual)
+                .iter()
+                .any(|c| c.kind == ChangeKind::Replace);
+            if !replaced {
+                ids.insert(pending_id(KIND, &group.id), actual.aws_id.to_string());
+            }
+        }
+    }
+
+    let mut plans: Vec<NamedPlan> = vec![];
+    for group in &aws_sym.groups {
+        let group = &group.resolved(&ids);
+        let mut plan = NamedPlan::new(
+            KIND,
+            group.id.as_str(),
+            group.name.as_str(),
+            PlanAction::Create,
+        );
+        if let Some(actual) = find_existing(group, existing, state) {
+            plan.name = actual.aws_id.to_string();
+            plan.changes = diff_group(aws_sym, group, actual);
+            plan.action = if plan.changes.is_empty() {
+                PlanAction::NoOp
+            } else if plan.changes.iter().all(|c| c.kind == ChangeKind::InPlace) {
+                PlanAction::Update
+            } else {
+                PlanAction::Replace
+            };
+        }
+        plans.push(plan);
+    }
+
+    for recorded in state.groups.values() {
+        if !aws_sym.groups.iter().any(|g| g.id == recorded.id) {
+            plans.push(NamedPlan::new(
+                KIND,
+                recorded.id.as_str(),
+                recorded.aws_id.as_str(),
+                PlanAction::Delete,
+            ));
+        }
+    }
+    for actual in existing {
+        if !plans.iter().any(|p| p.name == actual.aws_id) {
+            plans.push(NamedPlan::new(
+                KIND,
+                actual.id(),
+                actual.aws_id.as_str(),
+                PlanAction::Delete,
+            ));
+        }
+    }
+    (plans, ids)
+}
+
+/// Compute the plans deleting every security group `aws_sym` deployed:
+/// the ones recorded in `state` and the ones tagged with the stack
+pub(crate) fn destroy(existing: &[ExistingGroup], state: &StackState) -> Vec<NamedPlan> {
+    let mut plans: Vec<NamedPlan> = state
+        .groups
+        .values()
+        .map(|g| NamedPlan::new(KIND, g.id.as_str(), g.aws_id.as_str(), PlanAction::Delete))
+        .collect();
+    for actual in existing {
+        if !plans.iter().any(|p| p.name == actual.aws_id) {
+            plans.push(NamedPlan::new(
+                KIND,
+                actual.id(),
+                actual.aws_id.as_str(),
+                PlanAction::Delete,
+            ));
+        }
+    }
+    plans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::fixtures::{empty_state, stack};
+    use crate::state::GroupState;
+
+    fn rule(protocol: &str, port: i32, source: &str) -> RuleSym {
+        RuleSym {
+            protocol: protocol.to_string(),
+            from: port,
+            to: port,
+            source: source.to_string(),
+        }
+    }
+
+    fn group_sym(id: &str, ingress: Vec<RuleSym>) -> SecurityGroupSym {
+        SecurityGroupSym {
+            id: id.to_string(),
+            name: id.to_string(),
+            description: "managed by awsdsl".to_string(),
+            vpc_id: Some("vpc-1".to_string()),
+            ingress: ingress,
+            egress: vec![rule("all", 0, "0.0.0.0/0")],
+            tags: BTreeMap::new(),
+        }
+    }
+
+    fn actual(aws_sym: &AwsSym, group: &SecurityGroupSym, aws_id: &str) -> ExistingGroup {
+        let tags = desired_tags(aws_sym, group);
+        ExistingGroup {
+            aws_id: aws_id.to_string(),
+            name: group.name.to_string(),
+            description: group.description.to_string(),
+            vpc_id: group.vpc_id.clone().unwrap_or_default(),
+            key: tags.get(ID_TAG).cloned(),
+            ingress: group.ingress.clone(),
+            egress: group.egress.clone(),
+            tags: tags,
+        }
+    }
+
+    #[test]
+    fn test_group_plan() {
+        let mut aws_sym = stack();
+        let web = group_sym(
+            "web",
+            vec![
+                rule("tcp", 443, "0.0.0.0/0"),
+                rule("tcp", 80, &pending_id(KIND, "lb")),
+            ],
+        );
+        let lb = group_sym("lb", vec![rule("tcp", 443, "0.0.0.0/0")]);
+        aws_sym.add_group(web.clone());
+        aws_sym.add_group(lb.clone());
+        let state = empty_state();
+
+        let (plans, ids) = diff(&aws_sym, &[], &state);
+        assert!(plans.iter().all(|p| p.action == PlanAction::Create));
+        assert!(ids.is_empty());
+
+        // the rule referring to lb is compared with its id, whatever the
+        // order of the groups
+        let ids = BTreeMap::from([(pending_id(KIND, "lb"), "sg-2".to_string())]);
+        let mut existing = vec![
+            actual(&aws_sym, &web.resolved(&ids), "sg-1"),
+            actual(&aws_sym, &lb, "sg-2"),
+        ];
+        let (plans, known) = diff(&aws_sym, &existing, &state);
+        assert!(plans.iter().all(|p| p.action == PlanAction::NoOp));
+        assert_eq!(
+            known.get("<security_group.web.id>"),
+            Some(&"sg-1".to_string())
+        );
+
+        // a rule opened by hand is revoked in place
+        existing[0].ingress.push(rule("tcp", 22, "0.0.0.0/0"));
+        let (plans, _) = diff(&aws_sym, &existing, &state);
+        assert_eq!(plans[0].action, PlanAction::Update);
+        assert_eq!(plans[0].changes[0].attr, "ingress");
+
+        // a new description replaces the group
+        existing[1].description = "old".to_string();
+        let (plans, known) = diff(&aws_sym, &existing, &state);
+        assert_eq!(plans[1].action, PlanAction::Replace);
+        assert!(!known.contains_key("<security_group.lb.id>"));
+
+        // a recorded group no longer in the file is deleted
+        let mut state = empty_state();
+        state.groups.insert(
+            "db".to_string(),
+            GroupState::new(&group_sym("db", vec![]), "sg-9"),
+        );
+        let (plans, _) = diff(&aws_sym, &existing, &state);
+        let last = plans.last().unwrap();
+        assert_eq!(last.action, PlanAction::Delete);
+        assert_eq!(last.name, "sg-9");
+        assert_eq!(destroy(&existing, &state).len(), 3);
+    }
+}
@@ -0,0 +1,792 @@
+use aws_config::SdkConfig;
+use aws_sdk_dynamodb::client::Waiters;
+use aws_sdk_dynamodb::error::BuildError;
+use aws_sdk_dynamodb::types::{
+    AttributeDefinition, BillingMode, CreateGlobalSecondaryIndexAction,
+    DeleteGlobalSecondaryIndexAction, GlobalSecondaryIndex, GlobalSecondaryIndexDescription,
+    GlobalSecondaryIndexUpdate, IndexStatus, KeySchemaElement, KeyType, Projection, ProjectionType,
+    ProvisionedThroughput, ScalarAttributeType, TableDescription, TableStatus, Tag,
+    TimeToLiveSpecification, TimeToLiveStatus, UpdateGlobalSecondaryIndexAction,
+};
+use aws_sdk_dynamodb::{Client, Error};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::actions::plan::{AttrChange, ChangeKind, NamedPlan, PlanAction, STACK_TAG, tags_change};
+use crate::aws::{AwsSym, DynamoSym, GsiSym};
+use crate::state::StackState;
+
+/// Kind of the table plans
+pub(crate) const KIND: &'static str = "dynamodb";
+
+/// The billing mode with read and write capacities
+const PROVISIONED: &'static str = "PROVISIONED";
+
+/// How long a table or an index may take to become active, or go away
+const WAIT: Duration = Duration::from_secs(600);
+
+/// The changes of a table that can't be made on the existing table
+pub(crate) fn change_kind(attr: &str) -> ChangeKind {
+    match attr {
+        "name" | "hash_key" | "range_key" | "attributes" => ChangeKind::Replace,
+        _ => ChangeKind::InPlace,
+    }
+}
+
+/// A table that already exists, as returned by `describe_table`. The
+/// capacities are the ones of a `PROVISIONED` table.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ExistingTable {
+    pub(crate) name: String,
+    pub(crate) arn: String,
+    /// CREATING, ACTIVE, UPDATING, ...
+    pub(crate) status: String,
+    pub(crate) hash_key: String,
+    pub(crate) range_key: Option<String>,
+    pub(crate) attributes: BTreeMap<String, String>,
+    pub(crate) billing_mode: String,
+    pub(crate) read_capacity: Option<i64>,
+    pub(crate) write_capacity: Option<i64>,
+    /// the attribute of the enabled time to live, if any
+    pub(crate) ttl_attribute: Option<String>,
+    pub(crate) gsis: Vec<GsiSym>,
+    pub(crate) tags: BTreeMap<String, String>,
+}
+
+/// The tags of the table `table`: the user tags and the stack tag
+pub(crate) fn desired_tags(aws_sym: &AwsSym, table: &DynamoSym) -> BTreeMap<String, String> {
+    let mut tags = table.tags.clone();
+    tags.insert(STACK_TAG.to_string(), aws_sym.name.to_string());
+    tags
+}
+
+/// The hash and range key of a key schema
+fn keys(schema: &[KeySchemaElement]) -> (String, Option<String>) {
+    let key = |key_type: KeyType| {
+        schema
+            .iter()
+            .find(|k| *k.key_type() == key_type)
+            .map(|k| k.attribute_name().to_string())
+    };
+    (key(KeyType::Hash).unwrap_or_default(), key(KeyType::Range))
+}
+
+fn key_schema(
+    hash_key: &str,
+    range_key: &Option<String>,
+) -> Result<Vec<KeySchemaElement>, BuildError> {
+    let mut schema = vec![
+        KeySchemaElement::builder()
+            .attribute_name(hash_key)
+            .key_type(KeyType::Hash)
+            .build()?,
+    ];
+    if let Some(range_key) = range_key {
+        schema.push(
+            KeySchemaElement::builder()
+                .attribute_name(range_key)
+                .key_type(KeyType::Range)
+                .build()?,
+        );
+    }
+    Ok(schema)
+}
+
+fn attribute_definitions(table: &DynamoSym) -> Result<Vec<AttributeDefinition>, BuildError> {
+    let mut definitions = vec![];
+    for (name, attr_type) in &table.attributes {
+        definitions.push(
+            AttributeDefinition::builder()
+                .attribute_name(name)
+                .attribute_type(ScalarAttributeType::from(attr_type.as_str()))
+                .build()?,
+        );
+    }
+    Ok(definitions)
+}
+
+fn throughput(
+    read: Option<i64>,
+    write: Option<i64>,
+) -> Result<Option<ProvisionedThroughput>, BuildError> {
+    match (read, write) {
+        (Some(read), Some(write)) => Ok(Some(
+            ProvisionedThroughput::builder()
+                .read_capacity_units(read)
+                .write_capacity_units(write)
+                .build()?,
+        )),
+        _ => Ok(None),
+    }
+}
+
+fn existing_gsi(gsi: &GlobalSecondaryIndexDescription) -> GsiSym {
+    let (hash_key, range_key) = keys(gsi.key_schema());
+    let throughput = gsi.provisioned_throughput();
+    GsiSym {
+        name: gsi.index_name().unwrap_or_default().to_string(),
+        hash_key: hash_key,
+        range_key: range_key,
+        projection: gsi
+            .projection()
+            .and_then(|p| p.projection_type())
+            .map(|p| p.as_str().to_string())
+            .unwrap_or_default(),
+        // on demand indexes report 0
+        read_capacity: throughput
+            .and_then(|t| t.read_capacity_units())
+            .filter(|r| *r > 0),
+        write_capacity: throughput
+            .and_then(|t| t.write_capacity_units())
+            .filter(|w| *w > 0),
+    }
+}
+
+fn existing_table(desc: &TableDescription) -> ExistingTable {
+    let (hash_key, range_key) = keys(desc.key_schema());
+    // tables created as provisioned have no billing mode summary
+    let billing_mode = desc
+        .billing_mode_summary()
+        .and_then(|b| b.billing_mode())
+        .map(|b| b.as_str().to_string())
+        .unwrap_or(PROVISIONED.to_string());
+    let throughput = desc
+        .provisioned_throughput()
+        .filter(|_| billing_mode == PROVISIONED);
+    ExistingTable {
+        name: desc.table_name().unwrap_or_default().to_string(),
+        arn: desc.table_arn().unwrap_or_default().to_string(),
+        status: desc
+            .table_status()
+            .map(|s| s.as_str().to_string())
+            .unwrap_or_default(),
+        hash_key: hash_key,
+        range_key: range_key,
+        attributes: desc
+            .attribute_definitions()
+            .iter()
+            .map(|a| {
+                (
+                    a.attribute_name().to_string(),
+                    a.attribute_type().as_str().to_string(),
+                )
+            })
+            .collect(),
+        read_capacity: throughput.and_then(|t| t.read_capacity_units()),
+        write_capacity: throughput.and_then(|t| t.write_capacity_units()),
+        billing_mode: billing_mode,
+        ttl_attribute: None,
+        gsis: desc
+            .global_secondary_indexes()
+            .iter()
+            .map(existing_gsi)
+            .collect(),
+        tags: BTreeMap::new(),
+    }
+}
+
+/// Read the table `name`, its time to live and its tags, `None` if there
+/// is no such table
+pub(crate) async fn describe_table(
+    client: &Client,
+    name: &str,
+) -> Result<Option<ExistingTable>, Error> {
+    let output = match client.describe_table().table_name(name).send().await {
+        Ok(output) => output,
+        Err(e)
+            if e.as_service_error()
+                .map(|se| se.is_resource_not_found_exception())
+                == Some(true) =>
+        {
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut table = match output.table() {
+        Some(desc) => existing_table(desc),
+        None => return Ok(None),
+    };
+
+    let ttl = client
+        .describe_time_to_live()
+        .table_name(name)
+        .send()
+        .await?;
+    table.ttl_attribute = ttl
+        .time_to_live_description()
+        .filter(|t| {
+            matches!(
+                t.time_to_live_status(),
+                Some(TimeToLiveStatus::Enabled | TimeToLiveStatus::Enabling)
+            )
+        })
+        .and_then(|t| t.attribute_name())
+        .map(|a| a.to_string());
+    let mut next_token: Option<String> = None;
+    loop {
+        let page = client
+            .list_tags_of_resource()
+            .resource_arn(&table.arn)
+            .set_next_token(next_token)
+            .send()
+            .await?;
+        for tag in page.tags() {
+            table
+                .tags
+                .insert(tag.key().to_string(), tag.value().to_string());
+        }
+        next_token = page.next_token().map(|t| t.to_string());
+        if next_token.is_none() {
+            break;
+        }
+    }
+
+    Ok(Some(table))
+}
+
+/// Describe the tables named in `names`, the missing ones are left out
+pub(crate) async fn describe_tables(
+    client: &Client,
+    names: &[String],
+) -> Result<BTreeMap<String, ExistingTable>, Error> {
+    let mut existing: BTreeMap<String, ExistingTable> = BTreeMap::new();
+    for name in names {
+        if let Some(table) = describe_table(client, name).await? {
+            existing.insert(name.to_string(), table);
+        }
+    }
+    Ok(existing)
+}
+
+/// Wait until the table `name` and all its indexes are active
+async fn wait_active(client: &Client, name: &str) -> Result<(), Error> {
+    let started = Instant::now();
+    loop {
+        let output = client.describe_table().table_name(name).send().await?;
+        let active = output.table().is_some_and(|t| {
+            t.table_status() == Some(&TableStatus::Active)
+                && t.global_secondary_indexes()
+                    .iter()
+                    .all(|g| g.index_status() == Some(&IndexStatus::Active))
+        });
+        if active || started.elapsed() > WAIT {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+fn gsi_projection(gsi: &GsiSym) -> Projection {
+    Projection::builder()
+        .projection_type(ProjectionType::from(gsi.projection.as_str()))
+        .build()
+}
+
+/// Create the table `table`, wait until it is active and enable its time
+/// to live. A table of that name that already exists is taken over as is.
+pub(crate) async fn create_table(
+    client: &Client,
+    aws_sym: &AwsSym,
+    table: &DynamoSym,
+) -> Result<(), Error> {
+    let mut req = client
+        .create_table()
+        .table_name(&table.name)
+        .set_attribute_definitions(Some(attribute_definitions(table)?))
+        .set_key_schema(Some(key_schema(&table.hash_key, &table.range_key)?))
+        .billing_mode(BillingMode::from(table.billing_mode.as_str()))
+        .set_provisioned_throughput(throughput(table.read_capacity, table.write_capacity)?);
+    for gsi in &table.gsis {
+        req = req.global_secondary_indexes(
+            GlobalSecondaryIndex::builder()
+                .index_name(&gsi.name)
+                .set_key_schema(Some(key_schema(&gsi.hash_key, &gsi.range_key)?))
+                .projection(gsi_projection(gsi))
+                .set_provisioned_throughput(throughput(gsi.read_capacity, gsi.write_capacity)?)
+                .build()?,
+        );
+    }
+    for (k, v) in desired_tags(aws_sym, table) {
+        req = req.tags(Tag::builder().key(k).value(v).build()?);
+    }
+    match req.send().await {
+        Ok(_) => {}
+        Err(e)
+            if e.as_service_error()
+                .map(|se| se.is_resource_in_use_exception())
+                == Some(true) => {}
+        Err(e) => return Err(e.into()),
+    }
+    wait_active(client, &table.name).await?;
+    if table.ttl_attribute.is_some() {
+        set_ttl(client, table, None).await?;
+    }
+    Ok(())
+}
+
+/// Switch the time to live from the attribute `current` to the one of
+/// `table`. Only one of them can be enabled at a time.
+async fn set_ttl(client: &Client, table: &DynamoSym, current: Option<&str>) -> Result<(), Error> {
+    let mut specs = vec![];
+    if let Some(current) = current {
+        specs.push((current, false));
+    }
+    if let Some(desired) = &table.ttl_attribute {
+        specs.push((desired.as_str(), true));
+    }
+    for (attr, enabled) in specs {
+        client
+            .update_time_to_live()
+            .table_name(&table.name)
+            .time_to_live_specification(
+                TimeToLiveSpecification::builder()
+                    .attribute_name(attr)
+                    .enabled(enabled)
+                    .build()?,
+            )
+            .send()
+            .await?;
+    }
+    Ok(())
+}
+
+/// Make the `settings` of the existing table `table` what the file says:
+/// the billing mode and capacities, the indexes, the time to live and the
+/// tags. DynamoDB takes one index creation or deletion per update, the
+/// table is waited for after each.
+pub(crate) async fn update_table(
+    client: &Client,
+    aws_sym: &AwsSym,
+    table: &DynamoSym,
+    settings: &[&str],
+) -> Result<(), Error> {
+    let existing = match describe_table(client, &table.name).await? {
+        Some(existing) => existing,
+        None => return Ok(()),
+    };
+    wait_active(client, &table.name).await?;
+    let provisioned = table.billing_mode == PROVISIONED;
+
+    if settings.contains(&"billing_mode") || settings.contains(&"throughput") {
+        let mut req = client
+            .update_table()
+            .table_name(&table.name)
+            .billing_mode(BillingMode::from(table.billing_mode.as_str()))
+            .set_provisioned_throughput(throughput(table.read_capacity, table.write_capacity)?);
+        // switching to provisioned needs the capacities of the kept indexes
+        for gsi in &table.gsis {
+            let kept = existing.gsis.iter().any(|g| g.schema() == gsi.schema());
+            if let (true, true, Some(t)) = (
+                provisioned,
+                kept,
+                throughput(gsi.read_capacity, gsi.write_capacity)?,
+            ) {
+                req = req.global_secondary_index_updates(
+                    GlobalSecondaryIndexUpdate::builder()
+                        .update(
+                            UpdateGlobalSecondaryIndexAction::builder()
+                                .index_name(&gsi.name)
+                                .provisioned_throughput(t)
+                                .build()?,
+                        )
+                        .build(),
+                );
+            }
+        }
+        req.send().await?;
+        wait_active(client, &table.name).await?;
+    }
+
+    if settings.contains(&"gsis") {
+        // an index whose keys or projection changed is rebuilt
+        for gsi in &existing.gsis {
+            if table.gsis.iter().any(|g| g.schema() == gsi.schema()) {
+                continue;
+            }
+            client
+                .update_table()
+                .table_name(&table.name)
+                .global_secondary_index_updates(
+                    GlobalSecondaryIndexUpdate::builder()
+                        .delete(
+                            DeleteGlobalSecondaryIndexAction::builder()
+                                .index_name(&gsi.name)
+                                .build()?,
+                        )
+                        .build(),
+                )
+                .send()
+                .await?;
+            wait_active(client, &table.name).await?;
+        }
+        for gsi in &table.gsis {
+            let update = match existing.gsis.iter().find(|g| g.schema() == gsi.schema()) {
+                Some(g) if g == gsi || !provisioned => continue,
+                Some(_) => GlobalSecondaryIndexUpdate::builder()
+                    .update(
+                        UpdateGlobalSecondaryIndexAction::builder()
+                            .index_name(&gsi.name)
+                            .set_provisioned_throughput(throughput(
+                                gsi.read_capacity,
+                                gsi.write_capacity,
+                            )?)
+                            .build()?,
+                    )
+                    .build(),
+                None => GlobalSecondaryIndexUpdate::builder()
+                    .create(
+                        CreateGlobalSecondaryIndexAction::builder()
+                            .index_name(&gsi.name)
+                            .set_key_schema(Some(key_schema(&gsi.hash_key, &gsi.range_key)?))
+                            .projection(gsi_projection(gsi))
+                            .set_provisioned_throughput(throughput(
+                                gsi.read_capacity,
+                                gsi.write_capacity,
+                            )?)
+                            .build()?,
+                    )
+                    .build(),
+            };
+            client
+                .update_table()
+                .table_name(&table.name)
+                .set_attribute_definitions(Some(attribute_definitions(table)?))
+                .global_secondary_index_updates(update)
+                .send()
+                .await?;
+            wait_active(client, &table.name).await?;
+        }
+    }
+
+    if settings.contains(&"ttl_attribute") {
+        set_ttl(client, table, existing.ttl_attribute.as_deref()).await?;
+    }
+
+    if settings.contains(&"tags") {
+        let mut tags = vec![];
+        for (k, v) in desired_tags(aws_sym, table) {
+            tags.push(Tag::builder().key(k).value(v).build()?);
+        }
+        client
+            .tag_resource()
+            .resource_arn(&existing.arn)
+            .set_tags(Some(tags))
+            .send()
+            .await?;
+    }
+    Ok(())
+}
+
+/// Delete the table `name` and wait until it is gone, a table that is
+/// already gone is fine
+pub(crate) async fn delete_table(client: &Client, name: &str) -> Result<(), Error> {
+    match client.delete_table().table_name(name).send().await {
+        Ok(_) => {}
+        Err(e)
+            if e.as_service_error()
+                .map(|se| se.is_resource_not_found_exception())
+                == Some(true) =>
+        {
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    }
+    let _ = client
+        .wait_until_table_not_exists()
+        .table_name(name)
+        .wait(WAIT)
+        .await;
+    Ok(())
+}
+
+fn check(changes: &mut Vec<AttrChange>, attr: &str, actual: String, desired: String) {
+    if actual != desired {
+        let mut change = AttrChange::new(attr, actual, desired);
+        change.kind = change_kind(attr);
+        changes.push(change);
+    }
+}
+
+/// Compare the desired settings of `table` against the existing table
+fn diff_table(aws_sym: &AwsSym, table: &DynamoSym, existing: &ExistingTable) -> Vec<AttrChange> {
+    let none = || String::from("<none>");
+    let mut changes: Vec<AttrChange> = vec![];
+    check(
+        &mut changes,
+        "hash_key",
+        existing.hash_key.to_string(),
+        table.hash_key.to_string(),
+    );
+    check(
+        &mut changes,
+        "range_key",
+        existing.range_key.clone().unwrap_or_else(none),
+        table.range_key.clone().unwrap_or_else(none),
+    );
+    // the types of the table keys, the ones of the indexes go with them
+    let key_types = |attributes: &BTreeMap<String, String>| {
+        let mut types: Vec<String> = vec![];
+        for key in [Some(&table.hash_key), table.range_key.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            let t = attributes.get(key).map(|t| t.as_str()).unwrap_or("?");
+            types.push(format!("{}:{}", key, t));
+        }
+        types.join(", ")
+    };
+    check(
+        &mut changes,
+        "attributes",
+        key_types(&existing.attributes),
+        key_types(&table.attributes),
+    );
+    check(
+        &mut changes,
+        "billing_mode",
+        existing.billing_mode.to_string(),
+        table.billing_mode.to_string(),
+    );
+    let units = |r: Option<i64>, w: Option<i64>| match (r, w) {
+        (Some(r), Some(w)) => format!("{}r/{}w", r, w),
+        _ => none(),
+    };
+    check(
+        &mut changes,
+        "throughput",
+        units(existing.read_capacity, existing.write_capacity),
+        units(table.read_capacity, table.write_capacity),
+    );
+    let gsis = |gsis: &[GsiSym]| {
+        let mut gsis: Vec<String> = gsis.iter().map(|g| g.to_string()).collect();
+        gsis.sort();
+        format!("[{}]", gsis.join(", "))
+    };
+    check(
+        &mut changes,
+        "gsis",
+        gsis(&existing.gsis),
+        gsis(&table.gsis),
+    );
+    check(
+        &mut changes,
+        "ttl_attribute",
+        existing.ttl_attribute.clone().unwrap_or_else(none),
+        table.ttl_attribute.clone().unwrap_or_else(none),
+    );
+
+    changes.extend(tags_change(&existing.tags, &desired_tags(aws_sym, table)));
+
+    changes
+}
+
+/// Compute the plans of the tables of `aws_sym` given the tables that
+/// exist, by name, and the recorded `state`. A table whose name or keys
+/// changed is replaced; tables of the state no longer in the file are
+/// deleted.
+pub(crate) fn diff(
+    aws_sym: &AwsSym,
+    existing: &BTreeMap<String, ExistingTable>,
+    state: &StackState,
+) -> Vec<NamedPlan> {
+    let mut plans: Vec<NamedPlan> = vec![];
+    for table in &aws_sym.tables {
+        let mut plan = NamedPlan::new(
+            KIND,
+            table.id.as_str(),
+            table.name.as_str(),
+            PlanAction::NoOp,
+        );
+        let renamed = state
+            .tables
+            .get(&table.id)
+            .filter(|t| t.name != table.name && existing.contains_key(&t.name));
+        match (renamed, existing.get(&table.name)) {
+            (Some(old), _) => {
+                plan.action = PlanAction::Replace;
+                check(
+                    &mut plan.changes,
+                    "name",
+                    old.name.to_string(),
+                    table.name.to_string(),
+                );
+            }
+            (None, None) => plan.action = PlanAction::Create,
+            (None, Some(existing)) => {
+                plan.changes = diff_table(aws_sym, table, existing);
+                plan.action = if plan.changes.is_empty() {
+                    PlanAction::NoOp
+                } else if plan.changes.iter().all(|c| c.kind == ChangeKind::InPlace) {
+                    PlanAction::Update
+                } else {
+                    PlanAction::Replace
+                };
+            }
+        }
+        plans.push(plan);
+    }
+    for table in state.tables.values() {
+        if !aws_sym.tables.iter().any(|t| t.id == table.id) {
+            plans.push(NamedPlan::new(
+                KIND,
+                table.id.as_str(),
+                table.name.as_str(),
+                PlanAction::Delete,
+            ));
+        }
+    }
+    plans
+}
+
+/// Compute the plans deleting every table `aws_sym` deployed: the ones
+/// recorded in `state` and the existing tables of the file tagged with
+/// the stack
+pub(crate) fn destroy(
+    aws_sym: &AwsSym,
+    existing: &BTreeMap<String, ExistingTable>,
+    state: &StackState,
+) -> Vec<NamedPlan> {
+    let mut plans: Vec<NamedPlan> = state
+        .tables
+        .values()
+        .map(|t| NamedPlan::new(KIND, t.id.as_str(), t.name.as_str(), PlanAction::Delete))
+        .collect();
+    for table in &aws_sym.tables {
+        let tagged = existing
+            .get(&table.name)
+            .is_some_and(|t| t.tags.get(STACK_TAG) == Some(&aws_sym.name));
+        if tagged && !plans.iter().any(|p| p.name == table.name) {
+            plans.push(NamedPlan::new(
+                KIND,
+                table.id.as_str(),
+                table.name.as_str(),
+                PlanAction::Delete,
+            ));
+        }
+    }
+    plans.sort_by(|a, b| a.id.cmp(&b.id));
+    plans
+}
+
+/// The table names to describe for a plan: the ones of the file and the
+/// ones recorded in the state
+pub(crate) fn known_names(aws_sym: &AwsSym, state: &StackState) -> Vec<String> {
+    let mut names: Vec<String> = aws_sym.tables.iter().map(|t| t.name.clone()).collect();
+    for table in state.tables.values() {
+        if !names.contains(&table.name) {
+            names.push(table.name.clone());
+        }
+    }
+    names
+}
+
+/// DynamoDB client for a stack
+pub(crate) fn client(config: &SdkConfig) -> Client {
+    Client::new(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::fixtures::{empty_state, stack};
+    use crate::state::TableState;
+
+    fn table_sym(id: &str, name: &str) -> DynamoSym {
+        DynamoSym {
+            id: id.to_string(),
+            name: name.to_string(),
+            hash_key: "user_id".to_string(),
+            range_key: None,
+            attributes: BTreeMap::from([
+                ("user_id".to_string(), "S".to_string()),
+                ("email".to_string(), "S".to_string()),
+            ]),
+            billing_mode: PROVISIONED.to_string(),
+            read_capacity: Some(5),
+            write_capacity: Some(5),
+            ttl_attribute: Some("expires".to_string()),
+            gsis: vec![GsiSym {
+                name: "by_email".to_string(),
+                hash_key: "email".to_string(),
+                range_key: None,
+                projection: "ALL".to_string(),
+                read_capacity: Some(1),
+                write_capacity: Some(1),
+            }],
+            tags: BTreeMap::new(),
+        }
+    }
+
+    fn table_stack(tables: Vec<DynamoSym>) -> AwsSym {
+        let mut aws_sym = stack();
+        for table in tables {
+            aws_sym.add_table(table);
+        }
+        aws_sym
+    }
+
+    fn existing(aws_sym: &AwsSym, table: &DynamoSym) -> ExistingTable {
+        ExistingTable {
+            name: table.name.to_string(),
+            arn: format!("arn:aws:dynamodb:eu-west-1:1:table/{}", table.name),
+            status: "ACTIVE".to_string(),
+            hash_key: table.hash_key.to_string(),
+            range_key: table.range_key.clone(),
+            attributes: table.attributes.clone(),
+            billing_mode: table.billing_mode.to_string(),
+            read_capacity: table.read_capacity,
+            write_capacity: table.write_capacity,
+            ttl_attribute: table.ttl_attribute.clone(),
+            gsis: table.gsis.clone(),
+            tags: desired_tags(aws_sym, table),
+        }
+    }
+
+    #[test]
+    fn test_table_plan() {
+        let aws_sym = table_stack(vec![
+            table_sym("users", "users"),
+            table_sym("orders", "orders"),
+        ]);
+        let mut table = existing(&aws_sym, &aws_sym.tables[0]);
+        let mut state = empty_state();
+        state.tables.insert(
+            "old".to_string(),
+            TableState::new(&table_sym("old", "old-table")),
+        );
+        let mut tables = BTreeMap::from([(table.name.clone(), table.clone())]);
+        let plans = diff(&aws_sym, &tables, &state);
+        let actions: Vec<&PlanAction> = plans.iter().map(|p| &p.action).collect();
+        assert_eq!(
+            actions,
+            vec![&PlanAction::NoOp, &PlanAction::Create, &PlanAction::Delete]
+        );
+
+        // capacities and indexes change in place
+        table.read_capacity = Some(10);
+        table.gsis[0].write_capacity = Some(2);
+        table.ttl_attribute = None;
+        tables.insert(table.name.clone(), table.clone());
+        let plans = diff(&aws_sym, &tables, &state);
+        assert_eq!(plans[0].action, PlanAction::Update);
+        let changes: Vec<String> = plans[0].changes.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            changes,
+            vec![
+                "throughput: 10r/5w -> 5r/5w",
+                "gsis: [by_email(email) ALL 1r/2w] -> [by_email(email) ALL 1r/1w]",
+                "ttl_attribute: <none> -> expires",
+            ]
+        );
+
+        // a new key means a new table
+        table.hash_key = "id".to_string();
+        tables.insert(table.name.clone(), table);
+        let plans = diff(&aws_sym, &tables, &state);
+        assert_eq!(plans[0].action, PlanAction::Replace);
+        assert_eq!(
+            plans[0].changes[0].to_string(),
+            "hash_key: id -> user_id (forces replacement)"
+        );
+    }
+}
@@ -1,30 +1,188 @@
+use std::collections::BTreeMap;
 use std::fmt;
 
+use crate::symbols::scope::Value;
+
 pub(crate) mod nodes;
 pub(crate) mod parser;
+pub(crate) mod source;
 
 pub(crate) trait ParseTree {
     fn print_ast(&self, n_spaces: u8) -> String;
 }
 
+#[derive(Clone)]
 pub(crate) struct AwsSym {
     id: String,
+    pub(crate) name: String,
     pub(crate) region: String,
+    /// named profile the credentials are read from
+    pub(crate) profile: Option<String>,
+    /// role assumed with those credentials to deploy the stack
+    pub(crate) assume_role: Option<AssumeRoleSym>,
+    pub(crate) state: Option<StateSym>,
+    /// default tags, already merged into the tags of every resource
+    pub(crate) tags: BTreeMap<String, String>,
+    /// endpoint of every service client, e.g. a local stand-in
+    pub(crate) endpoint: Option<String>,
     pub(crate) ec2s: Vec<Ec2Sym>,
+    pub(crate) s3s: Vec<S3Sym>,
+    pub(crate) tables: Vec<DynamoSym>,
+    /// in the order of `NETWORK_KINDS`, then of declaration
+    pub(crate) networks: Vec<NetworkSym>,
+    pub(crate) groups: Vec<SecurityGroupSym>,
+    pub(crate) keys: Vec<KeyPairSym>,
 }
 
 impl AwsSym {
-    pub(crate) fn new(id: String, region: String) -> Self {
+    pub(crate) fn new(id: String, name: String, region: String) -> Self {
         AwsSym {
             id: id,
+            name: name,
             region: region,
+            profile: None,
+            assume_role: None,
+            state: None,
+            tags: BTreeMap::new(),
+            endpoint: None,
             ec2s: vec![],
+            s3s: vec![],
+            tables: vec![],
+            networks: vec![],
+            groups: vec![],
+            keys: vec![],
         }
     }
 
+    pub(crate) fn set_state(&mut self, state: StateSym) {
+        self.state = Some(state);
+    }
+
+    pub(crate) fn set_tags(&mut self, tags: BTreeMap<String, String>) {
+        self.tags = tags;
+    }
+
     pub(crate) fn add_ec2(&mut self, ec2: Ec2Sym) {
         self.ec2s.push(ec2);
     }
+
+    pub(crate) fn add_s3(&mut self, s3: S3Sym) {
+        self.s3s.push(s3);
+    }
+
+    pub(crate) fn add_table(&mut self, table: DynamoSym) {
+        self.tables.push(table);
+    }
+
+    /// Add a networking resource after the ones of its kind, so module
+    /// resources still come in the order they are created
+    pub(crate) fn add_network(&mut self, network: NetworkSym) {
+        let rank = kind_rank(network.kind);
+        let at = self
+            .networks
+            .iter()
+            .position(|n| kind_rank(n.kind) > rank)
+            .unwrap_or(self.networks.len());
+        self.networks.insert(at, network);
+    }
+
+    pub(crate) fn add_group(&mut self, group: SecurityGroupSym) {
+        self.groups.push(group);
+    }
+
+    pub(crate) fn add_key(&mut self, key: KeyPairSym) {
+        self.keys.push(key);
+    }
+
+    /// Replace the key pairs of the file the ec2s refer to by their
+    /// pending ids, so the instances wait for them. Other key names are
+    /// key pairs made outside of the file and left alone.
+    pub(crate) fn link_keys(&mut self) {
+        for ec2 in &mut self.ec2s {
+            if let Some(key) = self.keys.iter().find(|k| k.name == ec2.key_name) {
+                ec2.key_name = pending_id(KEY_KIND, &key.id);
+            }
+        }
+    }
+
+    /// Replace the security groups referred to by name, in the ec2s and
+    /// the rules, by their pending ids. Ids (`sg-...`) are left alone.
+    pub(crate) fn link_groups(&mut self) {
+        let ids: BTreeMap<String, String> = self
+            .groups
+            .iter()
+            .map(|g| (g.name.to_string(), pending_id(GROUP_KIND, &g.id)))
+            .collect();
+        let link = |s: &String| ids.get(s).cloned().unwrap_or(s.to_string());
+        for ec2 in &mut self.ec2s {
+            ec2.sg_ids = ec2.sg_ids.iter().map(link).collect();
+        }
+        for group in &mut self.groups {
+            for rule in group.ingress.iter_mut().chain(group.egress.iter_mut()) {
+                rule.source = link(&rule.source);
+            }
+        }
+    }
+
+    /// A copy of the stack with the pending ids of `ids` replaced by the
+    /// ids AWS gave the resources
+    pub(crate) fn resolved(&self, ids: &BTreeMap<String, String>) -> AwsSym {
+        let mut aws_sym = self.clone();
+        for ec2 in &mut aws_sym.ec2s {
+            ec2.subnet_id = resolve(&ec2.subnet_id, ids);
+            ec2.sg_ids = ec2.sg_ids.iter().map(|s| resolve(s, ids)).collect();
+            ec2.key_name = resolve(&ec2.key_name, ids);
+        }
+        aws_sym.networks = self.networks.iter().map(|n| n.resolved(ids)).collect();
+        aws_sym.groups = self.groups.iter().map(|g| g.resolved(ids)).collect();
+        aws_sym
+    }
+}
+
+/// The kinds of networking resources, in the order they are created
+pub(crate) const NETWORK_KINDS: [&str; 5] = [
+    "vpc",
+    "internet_gateway",
+    "subnet",
+    "nat_gateway",
+    "route_table",
+];
+
+/// Kind of the security groups, in pending ids and plans
+pub(crate) const GROUP_KIND: &'static str = "security_group";
+
+/// Kind of the key pairs, in pending ids and plans
+pub(crate) const KEY_KIND: &'static str = "key_pair";
+
+/// Position of a networking `kind` in `NETWORK_KINDS`
+pub(crate) fn kind_rank(kind: &str) -> usize {
+    NETWORK_KINDS
+        .iter()
+        .position(|k| *k == kind)
+        .unwrap_or(NETWORK_KINDS.len())
+}
+
+/// The id a resource `<kind>.<id>` refers to before AWS gave it one. It
+/// is replaced by the AWS id once the resource exists.
+pub(crate) fn pending_id(kind: &str, id: &str) -> String {
+    format!("<{}.{}.id>", kind, id)
+}
+
+/// true if `s` still refers to a resource that doesn't exist yet
+pub(crate) fn is_pending(s: &str) -> bool {
+    s.contains('<') && s.contains(".id>")
+}
+
+/// Replace the pending ids in `s` by the AWS ids of `ids`
+pub(crate) fn resolve(s: &str, ids: &BTreeMap<String, String>) -> String {
+    if !is_pending(s) {
+        return s.to_string();
+    }
+    let mut s = s.to_string();
+    for (pending, id) in ids {
+        s = s.replace(pending.as_str(), id);
+    }
+    s
 }
 
 impl fmt::Display for AwsSym {
@@ -33,9 +191,50 @@ impl fmt::Display for AwsSym {
         for ec2 in &self.ec2s {
             ec2_s = format!("{}\n {}", ec2_s, ec2)
         }
-        write!(f, "[{}], region: {}{}", self.id, self.region, ec2_s)
+        for s3 in &self.s3s {
+            ec2_s = format!("{}\n {}", ec2_s, s3)
+        }
+        for table in &self.tables {
+            ec2_s = format!("{}\n {}", ec2_s, table)
+        }
+        for network in &self.networks {
+            ec2_s = format!("{}\n {}", ec2_s, network)
+        }
+        for group in &self.groups {
+            ec2_s = format!("{}\n {}", ec2_s, group)
+        }
+        for key in &self.keys {
+            ec2_s = format!("{}\n {}", ec2_s, key)
+        }
+        write!(
+            f,
+            "[{}], name: {}, region: {}{}",
+            self.id, self.name, self.region, ec2_s
+        )
     }
 }
+/// The role a stack is deployed with, assumed from the credentials of
+/// the profile or the default chain. `external_id` is passed when the
+/// trust policy of the role requires one.
+#[derive(Clone)]
+pub(crate) struct AssumeRoleSym {
+    pub(crate) role_arn: String,
+    pub(crate) session_name: String,
+    pub(crate) external_id: Option<String>,
+}
+
+/// Where the state of the stack is kept remotely: `s3://<bucket>/<prefix>`,
+/// locked through the DynamoDB `lock_table` when one is given. `endpoint`
+/// overrides the AWS endpoints, e.g. for a local S3/DynamoDB stand-in.
+#[derive(Clone)]
+pub(crate) struct StateSym {
+    pub(crate) bucket: String,
+    pub(crate) prefix: String,
+    pub(crate) lock_table: Option<String>,
+    pub(crate) endpoint: Option<String>,
+}
+
+#[derive(Clone)]
 pub(crate) struct Ec2Sym {
     pub(crate) id: String,
     pub(crate) name: String,
@@ -43,10 +242,14 @@ pub(crate) struct Ec2Sym {
     pub(crate) instance_type: String,
     pub(crate) ami_id: String,
     pub(crate) subnet_id: String,
-    pub(crate) sg_id: String,
+    pub(crate) sg_ids: Vec<String>,
     pub(crate) app_version: f32,
     pub(crate) count: u8,
     pub(crate) key_name: String,
+    /// user tags, on top of the ones awsdsl sets
+    pub(crate) tags: BTreeMap<String, String>,
+    /// the `for_each` key or `count` index of an expanded resource
+    pub(crate) key: Option<String>,
 }
 
 impl fmt::Display for Ec2Sym {
@@ -63,34 +266,469 @@ impl fmt::Debug for Ec2Sym {
         s = format!("{}\n - {}", s, self.instance_type);
         s = format!("{}\n - {}", s, self.ami_id);
         s = format!("{}\n - {}", s, self.subnet_id);
-        s = format!("{}\n - {}", s, self.sg_id);
+        s = format!("{}\n - {}", s, self.sg_ids.join(", "));
+        s = format!("{}\n - {}", s, self.app_version);
         write!(f, "[{}], name: {}", self.id, s)
     }
 }
 
 impl Ec2Sym {
+    /// The pending ids the instances still wait for
+    pub(crate) fn pending(&self) -> Vec<String> {
+        let mut refs: Vec<&String> = vec![&self.subnet_id, &self.key_name];
+        refs.extend(&self.sg_ids);
+        refs.into_iter()
+            .filter(|r| is_pending(r))
+            .cloned()
+            .collect()
+    }
+
+    /// The attributes other resources can refer to, as `ec2.<name>.<attr>`
+    pub(crate) fn attrs(&self) -> Vec<(&'static str, Value)> {
+        vec![
+            ("name", Value::Str(self.name.to_string())),
+            ("description", Value::Str(self.desc.to_string())),
+            ("instance_type", Value::Str(self.instance_type.to_string())),
+            ("ami", Value::Str(self.ami_id.to_string())),
+            ("subnet_id", Value::Str(self.subnet_id.to_string())),
+            (
+                "sg_ids",
+                Value::List(
+                    self.sg_ids
+                        .iter()
+                        .map(|s| Value::Str(s.to_string()))
+                        .collect(),
+                ),
+            ),
+            ("key_name", Value::Str(self.key_name.to_string())),
+            ("count", Value::Number(self.count as f64)),
+            ("app_version", Value::Number(self.app_version as f64)),
+            (
+                "tags",
+                Value::Map(
+                    self.tags
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), Value::Str(v.to_string())))
+                        .collect(),
+                ),
+            ),
+        ]
+    }
+
     pub(crate) fn new(
         name: String,
         desc: String,
         instance_type: String,
         ami_id: String,
         subnet_id: String,
-        sg_id: String,
+        sg_ids: Vec<String>,
         app_version: f32,
         count: u8,
         key_name: String,
     ) -> Self {
         Ec2Sym {
-            id: (&name).to_string(),
+            id: name.to_string(),
             name: name,
             desc: desc,
             instance_type: instance_type,
             ami_id: ami_id,
             subnet_id: subnet_id,
-            sg_id: sg_id,
+            sg_ids: sg_ids,
             app_version: app_version,
             count: count,
             key_name: key_name,
+            tags: BTreeMap::new(),
+            key: None,
+        }
+    }
+
+    pub(crate) fn set_tags(&mut self, tags: BTreeMap<String, String>) {
+        self.tags = tags;
+    }
+}
+
+/// An S3 bucket. `name` is the bucket name, global to all accounts;
+/// `id` is the label of the block, or the name.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct S3Sym {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) versioning: bool,
+    /// the default server side encryption, `AES256` or `aws:kms`
+    pub(crate) encryption: String,
+    /// block every kind of public access
+    pub(crate) public_access_block: bool,
+    pub(crate) lifecycle: Vec<LifecycleRule>,
+    pub(crate) tags: BTreeMap<String, String>,
+}
+
+impl fmt::Display for S3Sym {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[s3.{}], bucket: {}", self.id, self.name)
+    }
+}
+
+impl S3Sym {
+    /// The attributes other resources can refer to, as `s3.<id>.<attr>`
+    pub(crate) fn attrs(&self) -> Vec<(&'static str, Value)> {
+        vec![
+            ("name", Value::Str(self.name.to_string())),
+            ("arn", Value::Str(format!("arn:aws:s3:::{}", self.name))),
+        ]
+    }
+}
+
+/// A lifecycle rule: the objects under `prefix` expire after
+/// `expiration_days`, their noncurrent versions after
+/// `noncurrent_expiration_days`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LifecycleRule {
+    pub(crate) prefix: String,
+    pub(crate) expiration_days: Option<i32>,
+    pub(crate) noncurrent_expiration_days: Option<i32>,
+}
+
+impl fmt::Display for LifecycleRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let days = |d: Option<i32>| match d {
+            Some(d) => format!("{}d", d),
+            None => "-".to_string(),
+        };
+        write!(
+            f,
+            "{}* expires {}, noncurrent {}",
+            self.prefix,
+            days(self.expiration_days),
+            days(self.noncurrent_expiration_days)
+        )
+    }
+}
+
+/// A DynamoDB table. `attributes` maps the key attributes, of the table
+/// and of its indexes, to their type: S, N or B. The capacities are only
+/// set when `billing_mode` is `PROVISIONED`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DynamoSym {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) hash_key: String,
+    pub(crate) range_key: Option<String>,
+    pub(crate) attributes: BTreeMap<String, String>,
+    /// `PAY_PER_REQUEST` or `PROVISIONED`
+    pub(crate) billing_mode: String,
+    pub(crate) read_capacity: Option<i64>,
+    pub(crate) write_capacity: Option<i64>,
+    /// the attribute holding the expiry time of an item
+    pub(crate) ttl_attribute: Option<String>,
+    pub(crate) gsis: Vec<GsiSym>,
+    pub(crate) tags: BTreeMap<String, String>,
+}
+
+impl fmt::Display for DynamoSym {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[dynamodb.{}], table: {}, billing: {}",
+            self.id, self.name, self.billing_mode
+        )
+    }
+}
+
+impl DynamoSym {
+    /// The attributes other resources can refer to, as
+    /// `dynamodb.<id>.<attr>`
+    pub(crate) fn attrs(&self) -> Vec<(&'static str, Value)> {
+        vec![
+            ("name", Value::Str(self.name.to_string())),
+            ("hash_key", Value::Str(self.hash_key.to_string())),
+        ]
+    }
+}
+
+/// A global secondary index, `projection` is `ALL` or `KEYS_ONLY`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct GsiSym {
+    pub(crate) name: String,
+    pub(crate) hash_key: String,
+    pub(crate) range_key: Option<String>,
+    pub(crate) projection: String,
+    pub(crate) read_capacity: Option<i64>,
+    pub(crate) write_capacity: Option<i64>,
+}
+
+impl GsiSym {
+    /// The keys and projection, an index has to be rebuilt when they change
+    pub(crate) fn schema(&self) -> String {
+        match &self.range_key {
+            Some(range_key) => format!(
+                "{}({}, {}) {}",
+                self.name, self.hash_key, range_key, self.projection
+            ),
+            None => format!("{}({}) {}", self.name, self.hash_key, self.projection),
+        }
+    }
+}
+
+impl fmt::Display for GsiSym {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.read_capacity, self.write_capacity) {
+            (Some(r), Some(w)) => write!(f, "{} {}r/{}w", self.schema(), r, w),
+            _ => write!(f, "{}", self.schema()),
+        }
+    }
+}
+
+/// A networking resource, `kind` is one of `NETWORK_KINDS`. The ids of
+/// the resources it sits in or routes to are pending ids until those
+/// exist.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct NetworkSym {
+    pub(crate) kind: &'static str,
+    pub(crate) id: String,
+    /// the `Name` tag
+    pub(crate) name: String,
+    /// vpc and subnet
+    pub(crate) cidr: Option<String>,
+    /// subnet, internet gateway and route table
+    pub(crate) vpc_id: Option<String>,
+    /// nat gateway
+    pub(crate) subnet_id: Option<String>,
+    /// subnet, AWS picks one when unset
+    pub(crate) az: Option<String>,
+    /// subnet: instances get a public ip
+    pub(crate) public: bool,
+    /// route table: the subnets it is associated with
+    pub(crate) subnet_ids: Vec<String>,
+    pub(crate) routes: Vec<RouteSym>,
+    pub(crate) tags: BTreeMap<String, String>,
+}
+
+impl fmt::Display for NetworkSym {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}.{}], name: {}", self.kind, self.id, self.name)
+    }
+}
+
+impl NetworkSym {
+    /// The attributes other resources can refer to, as
+    /// `<kind>.<id>.<attr>`
+    pub(crate) fn attrs(&self) -> Vec<(&'static str, Value)> {
+        let mut attrs = vec![
+            ("id", Value::Str(pending_id(self.kind, &self.id))),
+            ("name", Value::Str(self.name.to_string())),
+        ];
+        if let Some(cidr) = &self.cidr {
+            attrs.push(("cidr", Value::Str(cidr.to_string())));
+        }
+        attrs
+    }
+
+    /// A copy with the pending ids of `ids` replaced by the AWS ids
+    pub(crate) fn resolved(&self, ids: &BTreeMap<String, String>) -> NetworkSym {
+        let mut network = self.clone();
+        network.vpc_id = self.vpc_id.as_ref().map(|v| resolve(v, ids));
+        network.subnet_id = self.subnet_id.as_ref().map(|s| resolve(s, ids));
+        network.subnet_ids = self.subnet_ids.iter().map(|s| resolve(s, ids)).collect();
+        for route in &mut network.routes {
+            route.target = resolve(&route.target, ids);
+        }
+        network
+    }
+
+    /// The pending ids the resource still waits for
+    pub(crate) fn pending(&self) -> Vec<String> {
+        let mut refs: Vec<&String> = vec![];
+        refs.extend(&self.vpc_id);
+        refs.extend(&self.subnet_id);
+        refs.extend(&self.subnet_ids);
+        refs.extend(self.routes.iter().map(|r| &r.target));
+        refs.into_iter()
+            .filter(|r| is_pending(r))
+            .cloned()
+            .collect()
+    }
+
+    /// The settings compared against the existing resource, with whether
+    /// a change needs a new resource. Unset optional settings are left
+    /// to AWS.
+    pub(crate) fn settings(&self) -> Vec<(&'static str, String, bool)> {
+        let mut settings: Vec<(&'static str, String, bool)> = vec![];
+        let replace = matches!(self.kind, "vpc" | "subnet" | "nat_gateway" | "route_table");
+        if let Some(cidr) = &self.cidr {
+            settings.push(("cidr", cidr.to_string(), true));
         }
+        if let Some(vpc_id) = &self.vpc_id {
+            // a gateway is detached and attached to the new vpc
+            settings.push(("vpc", vpc_id.to_string(), replace));
+        }
+        if let Some(subnet_id) = &self.subnet_id {
+            settings.push(("subnet", subnet_id.to_string(), true));
+        }
+        if let Some(az) = &self.az {
+            settings.push(("az", az.to_string(), true));
+        }
+        match self.kind {
+            "subnet" => settings.push(("public", self.public.to_string(), false)),
+            "route_table" => {
+                let mut subnet_ids = self.subnet_ids.clone();
+                subnet_ids.sort();
+                settings.push(("subnets", subnet_ids.join(","), false));
+                let mut routes: Vec<String> = self.routes.iter().map(|r| r.to_string()).collect();
+                routes.sort();
+                settings.push(("routes", routes.join(", "), false));
+            }
+            _ => {}
+        }
+        settings
+    }
+}
+
+/// A route of a route table: the traffic to `cidr` goes to `target`, an
+/// internet gateway (`igw-`) or a nat gateway (`nat-`)
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RouteSym {
+    pub(crate) cidr: String,
+    pub(crate) target: String,
+}
+
+impl fmt::Display for RouteSym {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {}", self.cidr, self.target)
+    }
+}
+
+/// A security group. `vpc_id`, the sources of its rules and the groups
+/// of the ec2s are pending ids until the resources they refer to exist.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SecurityGroupSym {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) description: String,
+    /// the default vpc of the region when unset
+    pub(crate) vpc_id: Option<String>,
+    pub(crate) ingress: Vec<RuleSym>,
+    pub(crate) egress: Vec<RuleSym>,
+    pub(crate) tags: BTreeMap<String, String>,
+}
+
+impl fmt::Display for SecurityGroupSym {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}], name: {}, {} ingress, {} egress",
+            self.id,
+            self.name,
+            self.ingress.len(),
+            self.egress.len()
+        )
+    }
+}
+
+impl SecurityGroupSym {
+    /// The attributes other resources can refer to, as
+    /// `security_group.<id>.<attr>`
+    pub(crate) fn attrs(&self) -> Vec<(&'static str, Value)> {
+        vec![
+            ("id", Value::Str(pending_id(GROUP_KIND, &self.id))),
+            ("name", Value::Str(self.name.to_string())),
+        ]
+    }
+
+    /// A copy with the pending ids of `ids` replaced by the AWS ids
+    pub(crate) fn resolved(&self, ids: &BTreeMap<String, String>) -> SecurityGroupSym {
+        let mut group = self.clone();
+        group.vpc_id = self.vpc_id.as_ref().map(|v| resolve(v, ids));
+        for rule in group.ingress.iter_mut().chain(group.egress.iter_mut()) {
+            rule.source = resolve(&rule.source, ids);
+        }
+        group
+    }
+
+    /// The rules of one direction in a stable order, for comparing them
+    pub(crate) fn sorted_rules(rules: &[RuleSym]) -> String {
+        let mut rules: Vec<String> = rules.iter().map(|r| r.to_string()).collect();
+        rules.sort();
+        rules.join(", ")
+    }
+
+    /// The settings compared against the existing group, with whether a
+    /// change needs a new group: only the rules change in place
+    pub(crate) fn settings(&self) -> Vec<(&'static str, String, bool)> {
+        let mut settings: Vec<(&'static str, String, bool)> = vec![
+            ("name", self.name.to_string(), true),
+            ("description", self.description.to_string(), true),
+        ];
+        if let Some(vpc_id) = &self.vpc_id {
+            settings.push(("vpc", vpc_id.to_string(), true));
+        }
+        settings.push(("ingress", Self::sorted_rules(&self.ingress), false));
+        settings.push(("egress", Self::sorted_rules(&self.egress), false));
+        settings
+    }
+}
+
+/// A rule of a security group: `protocol` (tcp, udp, icmp or all) on the
+/// ports `from` to `to`, from (ingress) or to (egress) `source`, a CIDR
+/// block or a security group id. The ports of `all` are ignored.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct RuleSym {
+    pub(crate) protocol: String,
+    pub(crate) from: i32,
+    pub(crate) to: i32,
+    pub(crate) source: String,
+}
+
+impl RuleSym {
+    /// true if `source` is a security group rather than a CIDR block
+    pub(crate) fn is_group(&self) -> bool {
+        !self.source.contains('/')
+    }
+}
+
+impl fmt::Display for RuleSym {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.protocol.as_str() {
+            "all" => write!(f, "all {}", self.source),
+            _ => write!(
+                f,
+                "{} {}-{} {}",
+                self.protocol, self.from, self.to, self.source
+            ),
+        }
+    }
+}
+
+/// A key pair, `name` in EC2. The public key is imported from
+/// `public_key_file`; without one a key pair is generated and its private
+/// key written to `private_key_file`. The pending id of a key pair stands
+/// for its name, which is what instances refer to.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct KeyPairSym {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) public_key_file: Option<String>,
+    /// only set for generated keys
+    pub(crate) private_key_file: Option<String>,
+    pub(crate) tags: BTreeMap<String, String>,
+}
+
+impl fmt::Display for KeyPairSym {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.public_key_file {
+            Some(file) => write!(
+                f,
+                "[key_pair.{}], name: {}, from {}",
+                self.id, self.name, file
+            ),
+            None => write!(f, "[key_pair.{}], name: {}, generated", self.id, self.name),
+        }
+    }
+}
+
+impl KeyPairSym {
+    /// The attributes other resources can refer to, as
+    /// `key_pair.<id>.<attr>`
+    pub(crate) fn attrs(&self) -> Vec<(&'static str, Value)> {
+        vec![("name", Value::Str(self.name.to_string()))]
     }
 }
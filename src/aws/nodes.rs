@@ -2,215 +2,1359 @@ use std::fmt;
 
 use crate::aws::ParseTree;
 
+/// Operators of the expressions, `Neg` and `Not` are unary
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    Neg,
+    Not,
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Op::Add => "+",
+            Op::Sub => "-",
+            Op::Mul => "*",
+            Op::Div => "/",
+            Op::Rem => "%",
+            Op::Eq => "==",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+            Op::And => "&&",
+            Op::Or => "||",
+            Op::Neg => "-",
+            Op::Not => "!",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// An attribute value: a literal, a reference to a variable declared
+/// with `var`/`let` or a built-in, a dotted reference to an attribute of
+/// a resource (`ec2.web.subnet_id`), an interpolated string, a `[...]`
+/// list, a `{ key = value }` map, or an operation on other expressions. References and operators keep their
+/// location for error messages.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Expr {
+    Str(String),
+    Number(f64),
+    Bool(bool),
+    Ident {
+        name: String,
+        line_no: usize,
+        column_no: usize,
+    },
+    Path {
+        segments: Vec<String>,
+        line_no: usize,
+        column_no: usize,
+    },
+    Interp(Vec<InterpPart>),
+    List(Vec<Expr>),
+    /// entries in the order they are written, keys are unique
+    Map(Vec<(String, Expr)>),
+    /// `expr[index]`, an item of a list or the value of a map key
+    Index {
+        expr: Box<Expr>,
+        index: Box<Expr>,
+        line_no: usize,
+        column_no: usize,
+    },
+    Unary {
+        op: Op,
+        expr: Box<Expr>,
+        line_no: usize,
+        column_no: usize,
+    },
+    Binary {
+        op: Op,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+        line_no: usize,
+        column_no: usize,
+    },
+}
+
+impl Expr {
+    /// true if the expression refers to the variable or built-in `name`
+    pub(crate) fn refers_to(&self, name: &str) -> bool {
+        match self {
+            Expr::Ident { name: n, .. } => n == name,
+            Expr::Interp(parts) => parts.iter().any(|p| match p {
+                InterpPart::Expr { expr, .. } => expr.refers_to(name),
+                InterpPart::Text(_) => false,
+            }),
+            Expr::List(items) => items.iter().any(|e| e.refers_to(name)),
+            Expr::Map(entries) => entries.iter().any(|(_, e)| e.refers_to(name)),
+            Expr::Index { expr, index, .. } => expr.refers_to(name) || index.refers_to(name),
+            Expr::Unary { expr, .. } => expr.refers_to(name),
+            Expr::Binary { lhs, rhs, .. } => lhs.refers_to(name) || rhs.refers_to(name),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Str(s) => write!(f, "\"{}\"", s),
+            Expr::Number(n) => write!(f, "{}", n),
+            Expr::Bool(b) => write!(f, "{}", b),
+            Expr::Ident { name, .. } => write!(f, "{}", name),
+            Expr::Path { segments, .. } => write!(f, "{}", segments.join(".")),
+            Expr::Interp(parts) => {
+                let mut s = String::from("");
+                for part in parts {
+                    s = match part {
+                        InterpPart::Text(t) => format!("{}{}", s, t),
+                        InterpPart::Expr { source, .. } => format!("{}${{{}}}", s, source),
+                    };
+                }
+                write!(f, "\"{}\"", s)
+            }
+            Expr::List(items) => {
+                let items: Vec<String> = items.iter().map(|e| e.to_string()).collect();
+                write!(f, "[{}]", items.join(", "))
+            }
+            Expr::Map(entries) => {
+                let entries: Vec<String> = entries
+                    .iter()
+                    .map(|(k, e)| format!("{} = {}", k, e))
+                    .collect();
+                write!(f, "{{ {} }}", entries.join(", "))
+            }
+            Expr::Index { expr, index, .. } => write!(f, "{}[{}]", expr, index),
+            Expr::Unary { op, expr, .. } => write!(f, "{}{}", op, expr),
+            Expr::Binary { op, lhs, rhs, .. } => write!(f, "({} {} {})", lhs, op, rhs),
+        }
+    }
+}
+
+/// A piece of an interpolated string: text, or the expression of a
+/// `${...}` with its source and the location of the `$`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum InterpPart {
+    Text(String),
+    Expr {
+        expr: Expr,
+        source: String,
+        line_no: usize,
+        column_no: usize,
+    },
+}
+
+/// Print the attributes that are set, one per line
+fn print_attrs(attrs: &[(&str, &Option<Expr>)], n_spaces: usize) -> String {
+    let empty_spaces = " ".repeat(n_spaces);
+    let mut s = String::from("");
+    for (attr, val) in attrs {
+        if let Some(v) = val {
+            s = format!("{}\n{}- [{}]: {}", s, empty_spaces, attr, v);
+        }
+    }
+    s
+}
+
+/// A top-level `var <name> = <value>` (or `let`) declaration
+pub(crate) struct VarNode {
+    pub(crate) kind: String,
+    pub(crate) name: String,
+    pub(crate) value: Expr,
+    pub(crate) line_no: usize,
+    pub(crate) column_no: usize,
+    /// the file it is declared in
+    pub(crate) file: String,
+}
+
+impl ParseTree for VarNode {
+    fn print_ast(&self, n_spaces: u8) -> String {
+        let empty_spaces = " ".repeat(n_spaces as usize);
+        format!(
+            "{}- [{}] {}: {}",
+            empty_spaces, self.kind, self.name, self.value
+        )
+    }
+}
+
+/// A whole dsl file: the variable declarations and the aws blocks, one
+/// per stack, with the ones of the files it imports
+pub(crate) struct ProgramNode {
+    pub(crate) vars: Vec<VarNode>,
+    pub(crate) aws_nodes: Vec<AwsNode>,
+    /// the contents of the imported and module files, for the source hash
+    pub(crate) sources: Vec<String>,
+}
+
+impl ParseTree for ProgramNode {
+    fn print_ast(&self, n_spaces: u8) -> String {
+        let mut s = String::from("[program]");
+        for var in &self.vars {
+            s = format!("{}\n{}", s, var.print_ast(n_spaces));
+        }
+        for aws_node in &self.aws_nodes {
+            s = format!("{}\n{}", s, aws_node.print_ast(n_spaces));
+        }
+        s
+    }
+}
+
 pub(crate) struct AwsNode {
     pub(crate) id: String,
-    pub(crate) name: Option<String>,
-    pub(crate) description: Option<String>,
-    pub(crate) region: Option<String>,
-    pub(crate) ec2_nodes: Vec<Ec2Node>,
+    pub(crate) name: Option<Expr>,
+    pub(crate) description: Option<Expr>,
+    pub(crate) region: Option<Expr>,
+    /// named profile of the shared config/credentials files
+    pub(crate) profile: Option<Expr>,
+    /// role assumed to deploy the stack, e.g. in another account; short
+    /// for an `assume_role` block with only a `role_arn`
+    pub(crate) role_arn: Option<Expr>,
+    pub(crate) assume_role: Option<AssumeRoleNode>,
+    pub(crate) state: Option<StateNode>,
+    /// default tags, merged into the tags of every resource
+    pub(crate) tags: Option<Expr>,
+    /// endpoint of every service client, e.g. a local stand-in
+    pub(crate) endpoint: Option<Expr>,
+    pub(crate) resources: ResourceNodes,
+    /// the file the block is written in
+    pub(crate) file: String,
 }
 
 impl AwsNode {
     pub(crate) fn new(id: String) -> Self {
         AwsNode {
             id: id,
-            ec2_nodes: vec![],
+            resources: ResourceNodes::new(),
+            file: String::from(""),
+            endpoint: None,
             region: None,
+            profile: None,
+            role_arn: None,
+            assume_role: None,
+            state: None,
             name: None,
             description: None,
+            tags: None,
         }
     }
 
-    pub(crate) fn set_name(&mut self, name: impl std::convert::Into<String>) {
-        self.name = Some(name.into());
+    pub(crate) fn set_name(&mut self, name: Expr) {
+        self.name = Some(name);
+    }
+
+    pub(crate) fn set_description(&mut self, description: Expr) {
+        self.description = Some(description);
+    }
+
+    pub(crate) fn set_region(&mut self, region: Expr) {
+        self.region = Some(region);
     }
 
-    pub(crate) fn set_description(&mut self, description: impl std::convert::Into<String>) {
-        self.description = Some(description.into());
+    pub(crate) fn set_profile(&mut self, profile: Expr) {
+        self.profile = Some(profile);
     }
 
-    pub(crate) fn set_region(&mut self, region: impl std::convert::Into<String>) {
-        self.region = Some(region.into());
+    pub(crate) fn set_role_arn(&mut self, role_arn: Expr) {
+        self.role_arn = Some(role_arn);
+    }
+
+    pub(crate) fn set_assume_role(&mut self, assume_role: AssumeRoleNode) {
+        self.assume_role = Some(assume_role);
+    }
+
+    pub(crate) fn set_state(&mut self, state: StateNode) {
+        self.state = Some(state);
+    }
+
+    pub(crate) fn set_tags(&mut self, tags: Expr) {
+        self.tags = Some(tags);
+    }
+
+    pub(crate) fn set_endpoint(&mut self, endpoint: Expr) {
+        self.endpoint = Some(endpoint);
+    }
+}
+
+/// The resources declared in an aws block, a module or a branch of an
+/// if, in the order of declaration within each kind
+pub(crate) struct ResourceNodes {
+    pub(crate) ec2_nodes: Vec<Ec2Node>,
+    pub(crate) s3_nodes: Vec<S3Node>,
+    pub(crate) dynamodb_nodes: Vec<DynamoNode>,
+    pub(crate) network_nodes: Vec<NetworkNode>,
+    pub(crate) security_group_nodes: Vec<SecurityGroupNode>,
+    pub(crate) key_pair_nodes: Vec<KeyPairNode>,
+    pub(crate) modules: Vec<ModuleNode>,
+}
+
+/// How many resources of each kind were declared at some point, see
+/// `ResourceNodes::whens_since`
+pub(crate) struct ResourceMark {
+    ec2s: usize,
+    s3s: usize,
+    tables: usize,
+    networks: usize,
+    groups: usize,
+    keys: usize,
+}
+
+impl ResourceNodes {
+    pub(crate) fn new() -> Self {
+        ResourceNodes {
+            ec2_nodes: vec![],
+            s3_nodes: vec![],
+            dynamodb_nodes: vec![],
+            network_nodes: vec![],
+            security_group_nodes: vec![],
+            key_pair_nodes: vec![],
+            modules: vec![],
+        }
     }
 
     pub(crate) fn add_ec2(&mut self, ec2: Ec2Node) {
         self.ec2_nodes.push(ec2);
     }
+
+    pub(crate) fn add_s3(&mut self, s3: S3Node) {
+        self.s3_nodes.push(s3);
+    }
+
+    pub(crate) fn add_dynamodb(&mut self, table: DynamoNode) {
+        self.dynamodb_nodes.push(table);
+    }
+
+    pub(crate) fn add_network(&mut self, network: NetworkNode) {
+        self.network_nodes.push(network);
+    }
+
+    pub(crate) fn add_security_group(&mut self, group: SecurityGroupNode) {
+        self.security_group_nodes.push(group);
+    }
+
+    pub(crate) fn add_key_pair(&mut self, key: KeyPairNode) {
+        self.key_pair_nodes.push(key);
+    }
+
+    pub(crate) fn add_module(&mut self, module: ModuleNode) {
+        self.modules.push(module);
+    }
+
+    pub(crate) fn mark(&self) -> ResourceMark {
+        ResourceMark {
+            ec2s: self.ec2_nodes.len(),
+            s3s: self.s3_nodes.len(),
+            tables: self.dynamodb_nodes.len(),
+            networks: self.network_nodes.len(),
+            groups: self.security_group_nodes.len(),
+            keys: self.key_pair_nodes.len(),
+        }
+    }
+
+    /// The `when` of the resources declared since `mark`
+    pub(crate) fn whens_since(&mut self, mark: &ResourceMark) -> Vec<&mut Option<Expr>> {
+        let mut whens: Vec<&mut Option<Expr>> = vec![];
+        whens.extend(self.ec2_nodes[mark.ec2s..].iter_mut().map(|e| &mut e.when));
+        whens.extend(self.s3_nodes[mark.s3s..].iter_mut().map(|s| &mut s.when));
+        whens.extend(
+            self.dynamodb_nodes[mark.tables..]
+                .iter_mut()
+                .map(|t| &mut t.when),
+        );
+        whens.extend(
+            self.network_nodes[mark.networks..]
+                .iter_mut()
+                .map(|n| &mut n.when),
+        );
+        whens.extend(
+            self.security_group_nodes[mark.groups..]
+                .iter_mut()
+                .map(|g| &mut g.when),
+        );
+        whens.extend(
+            self.key_pair_nodes[mark.keys..]
+                .iter_mut()
+                .map(|k| &mut k.when),
+        );
+        whens
+    }
+
+    fn print_ast(&self, n_spaces: u8) -> String {
+        let mut s = String::from("");
+        for ec2 in &self.ec2_nodes {
+            s = format!("{}\n{}", s, ec2.print_ast(n_spaces));
+        }
+        for s3 in &self.s3_nodes {
+            s = format!("{}\n{}", s, s3.print_ast(n_spaces));
+        }
+        for table in &self.dynamodb_nodes {
+            s = format!("{}\n{}", s, table.print_ast(n_spaces));
+        }
+        for network in &self.network_nodes {
+            s = format!("{}\n{}", s, network.print_ast(n_spaces));
+        }
+        for group in &self.security_group_nodes {
+            s = format!("{}\n{}", s, group.print_ast(n_spaces));
+        }
+        for key in &self.key_pair_nodes {
+            s = format!("{}\n{}", s, key.print_ast(n_spaces));
+        }
+        for module in &self.modules {
+            s = format!("{}\n{}", s, module.print_ast(n_spaces));
+        }
+        s
+    }
 }
 
 impl fmt::Display for AwsNode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut ec2_s = "".to_string();
-        for ec2 in &self.ec2_nodes {
+        for ec2 in &self.resources.ec2_nodes {
             ec2_s = format!("{}\n -- {}", ec2_s, ec2)
         }
-        write!(
-            f,
-            "---\n{}[{}]{}",
-            self.id,
-            self.region.as_ref().unwrap(),
-            ec2_s
-        )
+        let region = match &self.region {
+            Some(r) => r.to_string(),
+            None => "-".to_string(),
+        };
+        write!(f, "---\n{}[{}]{}", self.id, region, ec2_s)
     }
 }
 
 impl ParseTree for AwsNode {
     fn print_ast(&self, n_spaces: u8) -> String {
         let mut s = String::from("[aws]");
-        let empty_spaces = " ".repeat(n_spaces as usize);
         s = format!(
-            "{}\n{}- [name]: {}",
+            "{}{}",
             s,
-            empty_spaces,
-            self.name.as_ref().unwrap()
+            print_attrs(
+                &[
+                    ("name", &self.name),
+                    ("description", &self.description),
+                    ("region", &self.region),
+                    ("profile", &self.profile),
+                    ("role_arn", &self.role_arn),
+                    ("endpoint", &self.endpoint),
+                    ("tags", &self.tags),
+                ],
+                n_spaces as usize
+            )
         );
-        s = format!(
-            "{}\n{}- [description]: {}",
-            s,
-            empty_spaces,
-            self.description.as_ref().unwrap()
+        if let Some(assume_role) = &self.assume_role {
+            s = format!("{}\n{}", s, assume_role.print_ast(n_spaces));
+        }
+        if let Some(state) = &self.state {
+            s = format!("{}\n{}", s, state.print_ast(n_spaces));
+        }
+        s = format!("{}{}", s, self.resources.print_ast(n_spaces));
+
+        s
+    }
+}
+
+/// `module "<name>" { source = "<path>" <input> = <value> ... }`, with
+/// the declarations and resources of the file `source` points at
+pub(crate) struct ModuleNode {
+    pub(crate) name: String,
+    /// the module file, resolved from the including file
+    pub(crate) file: String,
+    pub(crate) inputs: Vec<(String, Expr)>,
+    pub(crate) vars: Vec<VarNode>,
+    pub(crate) resources: ResourceNodes,
+    pub(crate) line_no: usize,
+    pub(crate) column_no: usize,
+}
+
+impl ParseTree for ModuleNode {
+    fn print_ast(&self, n_spaces: u8) -> String {
+        let empty_spaces = " ".repeat(n_spaces as usize);
+        let mut s = format!(
+            "{}- [module] \"{}\" ({})",
+            empty_spaces, self.name, self.file
         );
-        s = format!(
-            "{}\n{}- [region]: {}",
-            s,
-            empty_spaces,
-            self.region.as_ref().unwrap()
+        let inner_spaces = " ".repeat((n_spaces * 2) as usize);
+        for (name, value) in &self.inputs {
+            s = format!("{}\n{}- [{}]: {}", s, inner_spaces, name, value);
+        }
+        for var in &self.vars {
+            s = format!("{}\n{}", s, var.print_ast(n_spaces * 2));
+        }
+        format!("{}{}", s, self.resources.print_ast(n_spaces * 2))
+    }
+}
+
+/// The role assumed to deploy a stack: its ARN, the name of the session
+/// and the external id the role's trust policy may require
+pub(crate) struct AssumeRoleNode {
+    pub(crate) role_arn: Option<Expr>,
+    pub(crate) session_name: Option<Expr>,
+    pub(crate) external_id: Option<Expr>,
+}
+
+impl AssumeRoleNode {
+    pub(crate) fn new() -> Self {
+        AssumeRoleNode {
+            role_arn: None,
+            session_name: None,
+            external_id: None,
+        }
+    }
+
+    pub(crate) fn set_role_arn(&mut self, role_arn: Expr) {
+        self.role_arn = Some(role_arn);
+    }
+
+    pub(crate) fn set_session_name(&mut self, session_name: Expr) {
+        self.session_name = Some(session_name);
+    }
+
+    pub(crate) fn set_external_id(&mut self, external_id: Expr) {
+        self.external_id = Some(external_id);
+    }
+}
+
+impl ParseTree for AssumeRoleNode {
+    fn print_ast(&self, n_spaces: u8) -> String {
+        let empty_spaces = " ".repeat(n_spaces as usize);
+        let s = format!("{}- [assume_role]", empty_spaces);
+        let attrs = print_attrs(
+            &[
+                ("role_arn", &self.role_arn),
+                ("session_name", &self.session_name),
+                ("external_id", &self.external_id),
+            ],
+            (n_spaces * 2) as usize,
         );
-        for ec2 in &self.ec2_nodes {
-            s = format!("{}\n{}", s, ec2.print_ast(n_spaces));
+        format!("{}{}", s, attrs)
+    }
+}
+
+/// Remote state settings: the S3 bucket (and key prefix) holding the
+/// state and the optional DynamoDB table used for locking
+pub(crate) struct StateNode {
+    pub(crate) bucket: Option<String>,
+    pub(crate) prefix: Option<String>,
+    pub(crate) lock_table: Option<String>,
+    pub(crate) endpoint: Option<String>,
+}
+
+impl StateNode {
+    pub(crate) fn new() -> Self {
+        StateNode {
+            bucket: None,
+            prefix: None,
+            lock_table: None,
+            endpoint: None,
+        }
+    }
+
+    pub(crate) fn set_bucket(&mut self, bucket: impl std::convert::Into<String>) {
+        self.bucket = Some(bucket.into());
+    }
+
+    pub(crate) fn set_prefix(&mut self, prefix: impl std::convert::Into<String>) {
+        self.prefix = Some(prefix.into());
+    }
+
+    pub(crate) fn set_lock_table(&mut self, lock_table: impl std::convert::Into<String>) {
+        self.lock_table = Some(lock_table.into());
+    }
+
+    pub(crate) fn set_endpoint(&mut self, endpoint: impl std::convert::Into<String>) {
+        self.endpoint = Some(endpoint.into());
+    }
+}
+
+impl ParseTree for StateNode {
+    fn print_ast(&self, n_spaces: u8) -> String {
+        let mut empty_spaces = " ".repeat(n_spaces as usize);
+        let mut s = format!("{}- [state]", empty_spaces);
+        empty_spaces = " ".repeat((n_spaces * 2) as usize);
+        let attrs = [
+            ("bucket", &self.bucket),
+            ("prefix", &self.prefix),
+            ("lock_table", &self.lock_table),
+            ("endpoint", &self.endpoint),
+        ];
+        for (attr, val) in attrs {
+            if let Some(v) = val {
+                s = format!("{}\n{}- [{}]: {}", s, empty_spaces, attr, v);
+            }
         }
 
         s
     }
 }
 
+/// What a labelled resource is repeated over: one resource per item of a
+/// list or key of a map, or `count` resources
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum MetaArg {
+    ForEach(Expr),
+    Count(Expr),
+}
+
+impl fmt::Display for MetaArg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetaArg::ForEach(e) => write!(f, "for_each = {}", e),
+            MetaArg::Count(e) => write!(f, "count = {}", e),
+        }
+    }
+}
+
 pub(crate) struct Ec2Node {
-    pub(crate) id: Option<String>,
-    pub(crate) name: Option<String>,
-    pub(crate) description: Option<String>,
-    pub(crate) instance_type: Option<String>,
-    pub(crate) count: u8,
-    pub(crate) app_version: f32,
-    pub(crate) ami: Option<String>,
-    pub(crate) subnet_id: Option<String>,
-    pub(crate) sg_id: Option<String>,
-    pub(crate) key_name: Option<String>,
+    /// `ec2 "<label>"`, the id of the resource
+    pub(crate) label: Option<String>,
+    pub(crate) meta_arg: Option<MetaArg>,
+    /// the resource is only declared when this is true
+    pub(crate) when: Option<Expr>,
+    pub(crate) name: Option<Expr>,
+    pub(crate) description: Option<Expr>,
+    pub(crate) instance_type: Option<Expr>,
+    pub(crate) count: Option<Expr>,
+    pub(crate) app_version: Option<Expr>,
+    pub(crate) ami: Option<Expr>,
+    pub(crate) subnet_id: Option<Expr>,
+    pub(crate) sg_id: Option<Expr>,
+    pub(crate) sg_ids: Option<Expr>,
+    pub(crate) key_name: Option<Expr>,
+    pub(crate) tags: Option<Expr>,
 }
 
 impl Ec2Node {
     pub(crate) fn new() -> Self {
         Ec2Node {
-            id: None,
+            label: None,
+            meta_arg: None,
+            when: None,
             name: None,
             description: None,
             instance_type: None,
-            count: 0,
+            count: None,
             ami: None,
-            app_version: 0.0,
+            app_version: None,
             subnet_id: None,
             sg_id: None,
+            sg_ids: None,
             key_name: None,
+            tags: None,
         }
     }
 
-    pub(crate) fn set_name(&mut self, name: impl std::convert::Into<String>) {
-        let s = name.into();
-        self.id = Some(s.clone());
-        self.name = Some(s);
+    pub(crate) fn set_name(&mut self, name: Expr) {
+        self.name = Some(name);
+    }
+
+    pub(crate) fn set_when(&mut self, when: Expr) {
+        self.when = Some(when);
+    }
+
+    pub(crate) fn set_description(&mut self, description: Expr) {
+        self.description = Some(description);
     }
 
-    pub(crate) fn set_description(&mut self, description: impl std::convert::Into<String>) {
-        self.description = Some(description.into());
+    pub(crate) fn set_instance_type(&mut self, instance_type: Expr) {
+        self.instance_type = Some(instance_type);
     }
 
-    pub(crate) fn set_instance_type(&mut self, instance_type: impl std::convert::Into<String>) {
-        self.instance_type = Some(instance_type.into());
+    pub(crate) fn set_ami(&mut self, ami: Expr) {
+        self.ami = Some(ami);
     }
 
-    pub(crate) fn set_ami(&mut self, ami: impl std::convert::Into<String>) {
-        self.ami = Some(ami.into());
+    pub(crate) fn set_subnet_id(&mut self, subnet_id: Expr) {
+        self.subnet_id = Some(subnet_id);
     }
 
-    pub(crate) fn set_subnet_id(&mut self, subnet_id: impl std::convert::Into<String>) {
-        self.subnet_id = Some(subnet_id.into());
+    pub(crate) fn set_sg_id(&mut self, sg_id: Expr) {
+        self.sg_id = Some(sg_id);
     }
 
-    pub(crate) fn set_sg_id(&mut self, sg_id: impl std::convert::Into<String>) {
-        self.sg_id = Some(sg_id.into());
+    pub(crate) fn set_sg_ids(&mut self, sg_ids: Expr) {
+        self.sg_ids = Some(sg_ids);
     }
 
-    pub(crate) fn set_count(&mut self, count: u8) {
-        self.count = count;
+    pub(crate) fn set_tags(&mut self, tags: Expr) {
+        self.tags = Some(tags);
     }
 
-    pub(crate) fn set_app_version(&mut self, app_version: f32) {
-        self.app_version = app_version;
+    pub(crate) fn set_count(&mut self, count: Expr) {
+        self.count = Some(count);
     }
 
-    pub(crate) fn set_key_name(&mut self, key_name: impl std::convert::Into<String>) {
-        self.key_name = Some(key_name.into());
+    pub(crate) fn set_app_version(&mut self, app_version: Expr) {
+        self.app_version = Some(app_version);
+    }
+
+    pub(crate) fn set_key_name(&mut self, key_name: Expr) {
+        self.key_name = Some(key_name);
+    }
+}
+
+impl Ec2Node {
+    /// The attributes that are set, by name
+    pub(crate) fn attrs(&self) -> Vec<(&'static str, &Expr)> {
+        let attrs = [
+            ("when", &self.when),
+            ("name", &self.name),
+            ("description", &self.description),
+            ("instance_type", &self.instance_type),
+            ("count", &self.count),
+            ("app_version", &self.app_version),
+            ("ami", &self.ami),
+            ("subnet_id", &self.subnet_id),
+            ("sg_id", &self.sg_id),
+            ("sg_ids", &self.sg_ids),
+            ("key_name", &self.key_name),
+            ("tags", &self.tags),
+        ];
+        attrs
+            .into_iter()
+            .filter_map(|(attr, e)| e.as_ref().map(|e| (attr, e)))
+            .collect()
     }
 }
 
 impl fmt::Display for Ec2Node {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |e: &Option<Expr>| match e {
+            Some(e) => e.to_string(),
+            None => "-".to_string(),
+        };
         write!(
             f,
             "{}[{}, x {}]",
-            self.name.as_ref().unwrap(),
-            self.instance_type.as_ref().unwrap(),
-            self.count
+            show(&self.name),
+            show(&self.instance_type),
+            show(&self.count)
         )
     }
 }
 
 impl ParseTree for Ec2Node {
     fn print_ast(&self, n_spaces: u8) -> String {
-        let mut empty_spaces = " ".repeat(n_spaces as usize);
+        let empty_spaces = " ".repeat(n_spaces as usize);
         let mut s = format!("{}- [ec2]", empty_spaces);
-        empty_spaces = " ".repeat((n_spaces * 2) as usize);
-        s = format!(
-            "{}\n{}- [name]: {}",
-            s,
-            empty_spaces,
-            self.name.as_ref().unwrap()
+        if let Some(label) = &self.label {
+            s = format!("{} \"{}\"", s, label);
+        }
+        if let Some(meta_arg) = &self.meta_arg {
+            s = format!("{} {}", s, meta_arg);
+        }
+        let attrs = print_attrs(
+            &[
+                ("when", &self.when),
+                ("name", &self.name),
+                ("description", &self.description),
+                ("instance_type", &self.instance_type),
+                ("count", &self.count),
+                ("app_version", &self.app_version),
+                ("ami", &self.ami),
+                ("subnet_id", &self.subnet_id),
+                ("sg_id", &self.sg_id),
+                ("sg_ids", &self.sg_ids),
+                ("key_name", &self.key_name),
+                ("tags", &self.tags),
+            ],
+            (n_spaces * 2) as usize,
         );
+
+        format!("{}{}", s, attrs)
+    }
+}
+
+/// An S3 bucket: `s3 ["<label>"] { name = ... }`. Each `lifecycle`
+/// block is one rule.
+pub(crate) struct S3Node {
+    /// `s3 "<label>"`, the id of the resource
+    pub(crate) label: Option<String>,
+    /// the bucket is only declared when this is true
+    pub(crate) when: Option<Expr>,
+    pub(crate) name: Option<Expr>,
+    pub(crate) versioning: Option<Expr>,
+    pub(crate) encryption: Option<Expr>,
+    pub(crate) public_access_block: Option<Expr>,
+    pub(crate) lifecycle: Vec<LifecycleNode>,
+    pub(crate) tags: Option<Expr>,
+}
+
+impl S3Node {
+    pub(crate) fn new() -> Self {
+        S3Node {
+            label: None,
+            when: None,
+            name: None,
+            versioning: None,
+            encryption: None,
+            public_access_block: None,
+            lifecycle: vec![],
+            tags: None,
+        }
+    }
+
+    pub(crate) fn set_when(&mut self, when: Expr) {
+        self.when = Some(when);
+    }
+
+    pub(crate) fn set_name(&mut self, name: Expr) {
+        self.name = Some(name);
+    }
+
+    pub(crate) fn set_versioning(&mut self, versioning: Expr) {
+        self.versioning = Some(versioning);
+    }
+
+    pub(crate) fn set_encryption(&mut self, encryption: Expr) {
+        self.encryption = Some(encryption);
+    }
+
+    pub(crate) fn set_public_access_block(&mut self, public_access_block: Expr) {
+        self.public_access_block = Some(public_access_block);
+    }
+
+    pub(crate) fn add_lifecycle(&mut self, lifecycle: LifecycleNode) {
+        self.lifecycle.push(lifecycle);
+    }
+
+    pub(crate) fn set_tags(&mut self, tags: Expr) {
+        self.tags = Some(tags);
+    }
+}
+
+impl ParseTree for S3Node {
+    fn print_ast(&self, n_spaces: u8) -> String {
+        let empty_spaces = " ".repeat(n_spaces as usize);
+        let mut s = format!("{}- [s3]", empty_spaces);
+        if let Some(label) = &self.label {
+            s = format!("{} \"{}\"", s, label);
+        }
         s = format!(
-            "{}\n{}- [description]: {}",
+            "{}{}",
             s,
-            empty_spaces,
-            self.description.as_ref().unwrap()
+            print_attrs(
+                &[
+                    ("when", &self.when),
+                    ("name", &self.name),
+                    ("versioning", &self.versioning),
+                    ("encryption", &self.encryption),
+                    ("public_access_block", &self.public_access_block),
+                    ("tags", &self.tags),
+                ],
+                (n_spaces * 2) as usize,
+            )
         );
+        for rule in &self.lifecycle {
+            s = format!("{}\n{}", s, rule.print_ast(n_spaces * 2));
+        }
+        s
+    }
+}
+
+/// A lifecycle rule of a bucket: the objects under `prefix` expire after
+/// `expiration_days`, their noncurrent versions after
+/// `noncurrent_expiration_days`
+pub(crate) struct LifecycleNode {
+    pub(crate) prefix: Option<Expr>,
+    pub(crate) expiration_days: Option<Expr>,
+    pub(crate) noncurrent_expiration_days: Option<Expr>,
+}
+
+impl ParseTree for LifecycleNode {
+    fn print_ast(&self, n_spaces: u8) -> String {
+        let empty_spaces = " ".repeat(n_spaces as usize);
+        let s = format!("{}- [lifecycle]", empty_spaces);
+        let attrs = print_attrs(
+            &[
+                ("prefix", &self.prefix),
+                ("expiration_days", &self.expiration_days),
+                (
+                    "noncurrent_expiration_days",
+                    &self.noncurrent_expiration_days,
+                ),
+            ],
+            (n_spaces * 2) as usize,
+        );
+        format!("{}{}", s, attrs)
+    }
+}
+
+/// A DynamoDB table: `dynamodb ["<label>"] { name = ... }`. `attributes`
+/// maps the key attributes to their type, each `gsi` block is one global
+/// secondary index.
+pub(crate) struct DynamoNode {
+    /// `dynamodb "<label>"`, the id of the resource
+    pub(crate) label: Option<String>,
+    /// the table is only declared when this is true
+    pub(crate) when: Option<Expr>,
+    pub(crate) name: Option<Expr>,
+    pub(crate) hash_key: Option<Expr>,
+    pub(crate) range_key: Option<Expr>,
+    pub(crate) attributes: Option<Expr>,
+    pub(crate) billing_mode: Option<Expr>,
+    pub(crate) read_capacity: Option<Expr>,
+    pub(crate) write_capacity: Option<Expr>,
+    pub(crate) ttl_attribute: Option<Expr>,
+    pub(crate) gsis: Vec<GsiNode>,
+    pub(crate) tags: Option<Expr>,
+}
+
+impl DynamoNode {
+    pub(crate) fn new() -> Self {
+        DynamoNode {
+            label: None,
+            when: None,
+            name: None,
+            hash_key: None,
+            range_key: None,
+            attributes: None,
+            billing_mode: None,
+            read_capacity: None,
+            write_capacity: None,
+            ttl_attribute: None,
+            gsis: vec![],
+            tags: None,
+        }
+    }
+
+    pub(crate) fn set_when(&mut self, when: Expr) {
+        self.when = Some(when);
+    }
+
+    pub(crate) fn set_name(&mut self, name: Expr) {
+        self.name = Some(name);
+    }
+
+    pub(crate) fn set_hash_key(&mut self, hash_key: Expr) {
+        self.hash_key = Some(hash_key);
+    }
+
+    pub(crate) fn set_range_key(&mut self, range_key: Expr) {
+        self.range_key = Some(range_key);
+    }
+
+    pub(crate) fn set_attributes(&mut self, attributes: Expr) {
+        self.attributes = Some(attributes);
+    }
+
+    pub(crate) fn set_billing_mode(&mut self, billing_mode: Expr) {
+        self.billing_mode = Some(billing_mode);
+    }
+
+    pub(crate) fn set_read_capacity(&mut self, read_capacity: Expr) {
+        self.read_capacity = Some(read_capacity);
+    }
+
+    pub(crate) fn set_write_capacity(&mut self, write_capacity: Expr) {
+        self.write_capacity = Some(write_capacity);
+    }
+
+    pub(crate) fn set_ttl_attribute(&mut self, ttl_attribute: Expr) {
+        self.ttl_attribute = Some(ttl_attribute);
+    }
+
+    pub(crate) fn add_gsi(&mut self, gsi: GsiNode) {
+        self.gsis.push(gsi);
+    }
+
+    pub(crate) fn set_tags(&mut self, tags: Expr) {
+        self.tags = Some(tags);
+    }
+}
+
+impl ParseTree for DynamoNode {
+    fn print_ast(&self, n_spaces: u8) -> String {
+        let empty_spaces = " ".repeat(n_spaces as usize);
+        let mut s = format!("{}- [dynamodb]", empty_spaces);
+        if let Some(label) = &self.label {
+            s = format!("{} \"{}\"", s, label);
+        }
         s = format!(
-            "{}\n{}- [instance_type]: {}",
+            "{}{}",
             s,
-            empty_spaces,
-            self.instance_type.as_ref().unwrap()
+            print_attrs(
+                &[
+                    ("when", &self.when),
+                    ("name", &self.name),
+                    ("hash_key", &self.hash_key),
+                    ("range_key", &self.range_key),
+                    ("attributes", &self.attributes),
+                    ("billing_mode", &self.billing_mode),
+                    ("read_capacity", &self.read_capacity),
+                    ("write_capacity", &self.write_capacity),
+                    ("ttl_attribute", &self.ttl_attribute),
+                    ("tags", &self.tags),
+                ],
+                (n_spaces * 2) as usize,
+            )
         );
-        s = format!("{}\n{}- [count]: {}", s, empty_spaces, self.count);
-        s = format!(
-            "{}\n{}- [app_version]: {}",
-            s, empty_spaces, self.app_version
+        for gsi in &self.gsis {
+            s = format!("{}\n{}", s, gsi.print_ast(n_spaces * 2));
+        }
+        s
+    }
+}
+
+/// A global secondary index of a table
+pub(crate) struct GsiNode {
+    pub(crate) name: Option<Expr>,
+    pub(crate) hash_key: Option<Expr>,
+    pub(crate) range_key: Option<Expr>,
+    pub(crate) projection: Option<Expr>,
+    pub(crate) read_capacity: Option<Expr>,
+    pub(crate) write_capacity: Option<Expr>,
+}
+
+impl ParseTree for GsiNode {
+    fn print_ast(&self, n_spaces: u8) -> String {
+        let empty_spaces = " ".repeat(n_spaces as usize);
+        let s = format!("{}- [gsi]", empty_spaces);
+        let attrs = print_attrs(
+            &[
+                ("name", &self.name),
+                ("hash_key", &self.hash_key),
+                ("range_key", &self.range_key),
+                ("projection", &self.projection),
+                ("read_capacity", &self.read_capacity),
+                ("write_capacity", &self.write_capacity),
+            ],
+            (n_spaces * 2) as usize,
         );
+        format!("{}{}", s, attrs)
+    }
+}
+
+/// A networking resource: `<kind> ["<label>"] { ... }` where `kind` is
+/// one of `NETWORK_KINDS`. The kinds share one node, the attributes a
+/// kind doesn't take are never set by the parser.
+pub(crate) struct NetworkNode {
+    pub(crate) kind: &'static str,
+    /// `<kind> "<label>"`, the id of the resource
+    pub(crate) label: Option<String>,
+    /// the resource is only declared when this is true
+    pub(crate) when: Option<Expr>,
+    pub(crate) name: Option<Expr>,
+    pub(crate) cidr: Option<Expr>,
+    pub(crate) vpc: Option<Expr>,
+    pub(crate) subnet: Option<Expr>,
+    pub(crate) az: Option<Expr>,
+    pub(crate) public: Option<Expr>,
+    pub(crate) subnets: Option<Expr>,
+    pub(crate) routes: Vec<RouteNode>,
+    pub(crate) tags: Option<Expr>,
+}
+
+impl NetworkNode {
+    pub(crate) fn new(kind: &'static str) -> Self {
+        NetworkNode {
+            kind: kind,
+            label: None,
+            when: None,
+            name: None,
+            cidr: None,
+            vpc: None,
+            subnet: None,
+            az: None,
+            public: None,
+            subnets: None,
+            routes: vec![],
+            tags: None,
+        }
+    }
+
+    /// The attributes a block of `kind` takes, besides `when`
+    pub(crate) fn attrs(kind: &str) -> &'static [&'static str] {
+        match kind {
+            "vpc" => &["name", "cidr", "tags"],
+            "subnet" => &["name", "vpc", "cidr", "az", "public", "tags"],
+            "internet_gateway" => &["name", "vpc", "tags"],
+            "nat_gateway" => &["name", "subnet", "tags"],
+            "route_table" => &["name", "vpc", "subnets", "route", "tags"],
+            _ => &[],
+        }
+    }
+
+    /// Set the attribute `attr`, one of `attrs`
+    pub(crate) fn set(&mut self, attr: &str, value: Expr) {
+        let field = match attr {
+            "when" => &mut self.when,
+            "name" => &mut self.name,
+            "cidr" => &mut self.cidr,
+            "vpc" => &mut self.vpc,
+            "subnet" => &mut self.subnet,
+            "az" => &mut self.az,
+            "public" => &mut self.public,
+            "subnets" => &mut self.subnets,
+            "tags" => &mut self.tags,
+            _ => return,
+        };
+        *field = Some(value);
+    }
+
+    pub(crate) fn add_route(&mut self, route: RouteNode) {
+        self.routes.push(route);
+    }
+}
+
+impl ParseTree for NetworkNode {
+    fn print_ast(&self, n_spaces: u8) -> String {
+        let empty_spaces = " ".repeat(n_spaces as usize);
+        let mut s = format!("{}- [{}]", empty_spaces, self.kind);
+        if let Some(label) = &self.label {
+            s = format!("{} \"{}\"", s, label);
+        }
         s = format!(
-            "{}\n{}- [ami]: {}",
+            "{}{}",
             s,
-            empty_spaces,
-            self.ami.as_ref().unwrap()
+            print_attrs(
+                &[
+                    ("when", &self.when),
+                    ("name", &self.name),
+                    ("cidr", &self.cidr),
+                    ("vpc", &self.vpc),
+                    ("subnet", &self.subnet),
+                    ("az", &self.az),
+                    ("public", &self.public),
+                    ("subnets", &self.subnets),
+                    ("tags", &self.tags),
+                ],
+                (n_spaces * 2) as usize,
+            )
         );
-        s = format!(
-            "{}\n{}- [subnet_id]: {}",
-            s,
-            empty_spaces,
-            self.subnet_id.as_ref().unwrap()
+        for route in &self.routes {
+            s = format!("{}\n{}", s, route.print_ast(n_spaces * 2));
+        }
+        s
+    }
+}
+
+/// A route of a route table: the traffic to `cidr` goes through an
+/// internet `gateway` or a `nat_gateway`
+pub(crate) struct RouteNode {
+    pub(crate) cidr: Option<Expr>,
+    pub(crate) gateway: Option<Expr>,
+    pub(crate) nat_gateway: Option<Expr>,
+}
+
+impl ParseTree for RouteNode {
+    fn print_ast(&self, n_spaces: u8) -> String {
+        let empty_spaces = " ".repeat(n_spaces as usize);
+        let s = format!("{}- [route]", empty_spaces);
+        let attrs = print_attrs(
+            &[
+                ("cidr", &self.cidr),
+                ("gateway", &self.gateway),
+                ("nat_gateway", &self.nat_gateway),
+            ],
+            (n_spaces * 2) as usize,
         );
+        format!("{}{}", s, attrs)
+    }
+}
+
+/// A security group: `security_group ["<label>"] { name = ... }`, each
+/// `ingress` and `egress` block is one rule
+pub(crate) struct SecurityGroupNode {
+    /// `security_group "<label>"`, the id of the resource
+    pub(crate) label: Option<String>,
+    /// the group is only declared when this is true
+    pub(crate) when: Option<Expr>,
+    pub(crate) name: Option<Expr>,
+    pub(crate) description: Option<Expr>,
+    pub(crate) vpc_id: Option<Expr>,
+    pub(crate) ingress: Vec<RuleNode>,
+    pub(crate) egress: Vec<RuleNode>,
+    pub(crate) tags: Option<Expr>,
+}
+
+impl SecurityGroupNode {
+    pub(crate) fn new() -> Self {
+        SecurityGroupNode {
+            label: None,
+            when: None,
+            name: None,
+            description: None,
+            vpc_id: None,
+            ingress: vec![],
+            egress: vec![],
+            tags: None,
+        }
+    }
+
+    pub(crate) fn set_when(&mut self, when: Expr) {
+        self.when = Some(when);
+    }
+
+    pub(crate) fn set_name(&mut self, name: Expr) {
+        self.name = Some(name);
+    }
+
+    pub(crate) fn set_description(&mut self, description: Expr) {
+        self.description = Some(description);
+    }
+
+    pub(crate) fn set_vpc_id(&mut self, vpc_id: Expr) {
+        self.vpc_id = Some(vpc_id);
+    }
+
+    pub(crate) fn add_ingress(&mut self, rule: RuleNode) {
+        self.ingress.push(rule);
+    }
+
+    pub(crate) fn add_egress(&mut self, rule: RuleNode) {
+        self.egress.push(rule);
+    }
+
+    pub(crate) fn set_tags(&mut self, tags: Expr) {
+        self.tags = Some(tags);
+    }
+}
+
+impl ParseTree for SecurityGroupNode {
+    fn print_ast(&self, n_spaces: u8) -> String {
+        let empty_spaces = " ".repeat(n_spaces as usize);
+        let mut s = format!("{}- [security_group]", empty_spaces);
+        if let Some(label) = &self.label {
+            s = format!("{} \"{}\"", s, label);
+        }
         s = format!(
-            "{}\n{}- [sg_id]: {}",
+            "{}{}",
             s,
-            empty_spaces,
-            self.sg_id.as_ref().unwrap()
+            print_attrs(
+                &[
+                    ("when", &self.when),
+                    ("name", &self.name),
+                    ("description", &self.description),
+                    ("vpc_id", &self.vpc_id),
+                    ("tags", &self.tags),
+                ],
+                (n_spaces * 2) as usize,
+            )
         );
-
+        for rule in &self.ingress {
+            s = format!("{}\n{}", s, rule.print_ast("ingress", n_spaces * 2));
+        }
+        for rule in &self.egress {
+            s = format!("{}\n{}", s, rule.print_ast("egress", n_spaces * 2));
+        }
         s
     }
 }
+
+/// An ingress or egress rule of a security group: the `protocol` on the
+/// ports `from` to `to`, from or to a `cidr` or the members of the group
+/// `source_sg`
+pub(crate) struct RuleNode {
+    pub(crate) from: Option<Expr>,
+    pub(crate) to: Option<Expr>,
+    pub(crate) protocol: Option<Expr>,
+    pub(crate) cidr: Option<Expr>,
+    pub(crate) source_sg: Option<Expr>,
+}
+
+impl RuleNode {
+    fn print_ast(&self, direction: &str, n_spaces: u8) -> String {
+        let empty_spaces = " ".repeat(n_spaces as usize);
+        let s = format!("{}- [{}]", empty_spaces, direction);
+        let attrs = print_attrs(
+            &[
+                ("from", &self.from),
+                ("to", &self.to),
+                ("protocol", &self.protocol),
+                ("cidr", &self.cidr),
+                ("source_sg", &self.source_sg),
+            ],
+            (n_spaces * 2) as usize,
+        );
+        format!("{}{}", s, attrs)
+    }
+}
+
+/// A key pair: `key_pair ["<label>"] { name = ... }`, imported from
+/// `public_key_file` or generated
+pub(crate) struct KeyPairNode {
+    /// `key_pair "<label>"`, the id of the resource
+    pub(crate) label: Option<String>,
+    /// the key pair is only declared when this is true
+    pub(crate) when: Option<Expr>,
+    pub(crate) name: Option<Expr>,
+    pub(crate) public_key_file: Option<Expr>,
+    pub(crate) generate: Option<Expr>,
+    pub(crate) private_key_file: Option<Expr>,
+    pub(crate) tags: Option<Expr>,
+    /// the file the block is written in, its key files are relative to it
+    pub(crate) file: String,
+}
+
+impl KeyPairNode {
+    pub(crate) fn new() -> Self {
+        KeyPairNode {
+            label: None,
+            when: None,
+            name: None,
+            public_key_file: None,
+            generate: None,
+            private_key_file: None,
+            tags: None,
+            file: String::new(),
+        }
+    }
+
+    pub(crate) fn set_when(&mut self, when: Expr) {
+        self.when = Some(when);
+    }
+
+    pub(crate) fn set_name(&mut self, name: Expr) {
+        self.name = Some(name);
+    }
+
+    pub(crate) fn set_public_key_file(&mut self, public_key_file: Expr) {
+        self.public_key_file = Some(public_key_file);
+    }
+
+    pub(crate) fn set_generate(&mut self, generate: Expr) {
+        self.generate = Some(generate);
+    }
+
+    pub(crate) fn set_private_key_file(&mut self, private_key_file: Expr) {
+        self.private_key_file = Some(private_key_file);
+    }
+
+    pub(crate) fn set_tags(&mut self, tags: Expr) {
+        self.tags = Some(tags);
+    }
+}
+
+impl ParseTree for KeyPairNode {
+    fn print_ast(&self, n_spaces: u8) -> String {
+        let empty_spaces = " ".repeat(n_spaces as usize);
+        let mut s = format!("{}- [key_pair]", empty_spaces);
+        if let Some(label) = &self.label {
+            s = format!("{} \"{}\"", s, label);
+        }
+        let attrs = print_attrs(
+            &[
+                ("when", &self.when),
+                ("name", &self.name),
+                ("public_key_file", &self.public_key_file),
+                ("generate", &self.generate),
+                ("private_key_file", &self.private_key_file),
+                ("tags", &self.tags),
+            ],
+            (n_spaces * 2) as usize,
+        );
+        format!("{}{}", s, attrs)
+    }
+}
@@ -1,13 +1,25 @@
+use std::collections::BTreeSet;
 use std::error::Error;
-use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::{fmt, fs, mem};
 
-use crate::aws::nodes::{AwsNode, Ec2Node};
-use crate::lex::{Scanner, Token, TokenType};
+use crate::aws::NETWORK_KINDS;
+use crate::aws::nodes::{
+    AssumeRoleNode, AwsNode, DynamoNode, Ec2Node, Expr, GsiNode, InterpPart, KeyPairNode,
+    LifecycleNode, MetaArg, ModuleNode, NetworkNode, Op, ProgramNode, ResourceNodes, RouteNode,
+    RuleNode, S3Node, SecurityGroupNode, StateNode, VarNode,
+};
+use crate::lex::{Scanner, StrPart, Token, TokenType, read_lex_file};
+
+/// The file a module `source` directory stands for
+const MODULE_MAIN: &'static str = "main.aws";
 
 #[derive(Debug)]
 pub(crate) enum ParseErrorType {
     TokenMismatch,
     UnknownToken,
+    /// an imported or module file can't be read, or includes itself
+    Include,
 }
 
 impl fmt::Display for ParseErrorType {
@@ -15,6 +27,7 @@ impl fmt::Display for ParseErrorType {
         let s = match self {
             Self::TokenMismatch => "Token mismatch",
             Self::UnknownToken => "Unknown token",
+            Self::Include => "Include error",
         };
         write!(f, "{}", s)
     }
@@ -24,6 +37,8 @@ impl fmt::Display for ParseErrorType {
 pub(crate) struct ParseError {
     err_type: ParseErrorType,
     msg: String,
+    /// the file the error is in, when known
+    file: Option<String>,
 }
 
 impl Error for ParseError {}
@@ -33,26 +48,57 @@ impl ParseError {
         ParseError {
             err_type: err_type,
             msg: msg.into(),
+            file: None,
+        }
+    }
+
+    /// Name `file` as the one the error is in, unless an error from an
+    /// included file already names its own
+    fn in_file(mut self, file: &str) -> Self {
+        if self.file.is_none() && !file.is_empty() {
+            self.file = Some(file.to_string());
         }
+        self
     }
 }
 
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "error type: {}, details: {}", self.err_type, self.msg)
+        match &self.file {
+            Some(file) => write!(
+                f,
+                "error type: {}, details: in {}: {}",
+                self.err_type, file, self.msg
+            ),
+            None => write!(f, "error type: {}, details: {}", self.err_type, self.msg),
+        }
     }
 }
 
 pub(crate) struct Parser {
     scanner: Scanner,
+    /// a token read ahead by `peek_token`, returned by the next read
+    peeked: Option<Token>,
+    /// the files including this one, outermost first
+    including: Vec<String>,
+    /// the files imported so far, canonical
+    imported: BTreeSet<PathBuf>,
+    /// the contents of the imported and module files read so far
+    sources: Vec<String>,
 }
 
 impl Parser {
     pub(crate) fn new(scanner: Scanner) -> Self {
-        Parser { scanner: scanner }
+        Parser {
+            scanner: scanner,
+            peeked: None,
+            including: vec![],
+            imported: BTreeSet::new(),
+            sources: vec![],
+        }
     }
 
-    /// Peeks into the current token but doesn't move the pointer
+    // Peeks into the current token but doesn't move the pointer
     // The scanner.start has not moved (as it will be reset only
     // at the next_token call). So we reset current to the start
     // [OR] another possibility: don't go all the way back but just
@@ -78,45 +124,268 @@ impl Parser {
 
     /// Retrieves the next lexeme from the scanner
     fn next(&mut self) -> Option<Token> {
+        if let Some(tok) = self.peeked.take() {
+            return Some(tok);
+        }
         let next_tok = self.scanner.next_token();
         Some(next_tok)
     }
 
-    /// parse starts from aws block
-    pub(crate) fn parse(&mut self) -> Result<AwsNode, ParseError> {
+    /// parse the whole program: `var`/`let` declarations, imports and
+    /// the aws blocks, in any order
+    pub(crate) fn parse(&mut self) -> Result<ProgramNode, ParseError> {
         println!("==> parsing ...");
-        if let Some(n) = self.next() {
-            if n.token_type == TokenType::EoF {
-                let s = format!("Expecting program to begin with aws block!");
+        let file = self.scanner.source.to_string();
+        let program = self.program().map_err(|e| e.in_file(&file))?;
+        if program.aws_nodes.is_empty() {
+            let s = "Expecting program to have an aws block!".to_string();
+            return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
+        }
+        Ok(program)
+    }
+
+    /// parse the statements of a file and of the files it imports
+    fn program(&mut self) -> Result<ProgramNode, ParseError> {
+        let mut vars: Vec<VarNode> = vec![];
+        let mut aws_nodes: Vec<AwsNode> = vec![];
+        while let Some(tok) = self.next() {
+            match tok.token_type {
+                TokenType::EoF => break,
+                TokenType::Comment => {}
+                TokenType::Keyword if tok.lexeme == "var" || tok.lexeme == "let" => {
+                    vars.push(self.var(&tok)?);
+                }
+                TokenType::Keyword if tok.lexeme == "aws" => {
+                    aws_nodes.push(self.aws()?);
+                }
+                TokenType::Keyword if tok.lexeme == "import" => {
+                    self.import(&tok, &mut vars, &mut aws_nodes)?;
+                }
+                _ => {
+                    let s = format!(
+                        "Invalid token {} at location ({},{}), expecting var, let, import or aws",
+                        tok.lexeme, tok.line_no, tok.column_no
+                    );
+                    return Err(ParseError::new(ParseErrorType::UnknownToken, s));
+                }
+            }
+        }
+
+        Ok(ProgramNode {
+            vars: vars,
+            aws_nodes: aws_nodes,
+            sources: mem::take(&mut self.sources),
+        })
+    }
+
+    /// Parse `import "<path>"` after `kw`. The declarations and aws
+    /// blocks of the file are added to the program; a file imported
+    /// twice is only read once.
+    fn import(
+        &mut self,
+        kw: &Token,
+        vars: &mut Vec<VarNode>,
+        aws_nodes: &mut Vec<AwsNode>,
+    ) -> Result<(), ParseError> {
+        let tok = self.next_token();
+        let (path, canonical) = self.resolve(kw, &tok)?;
+        self.check_cycle(kw, &path, &canonical)?;
+        if !self.imported.insert(canonical) {
+            return Ok(());
+        }
+        let mut parser = self.open(kw, &path)?;
+        let program = parser
+            .program()
+            .map_err(|e| e.in_file(&parser.scanner.source));
+        self.close(parser);
+        let mut program = program?;
+        vars.append(&mut program.vars);
+        aws_nodes.append(&mut program.aws_nodes);
+        self.sources.append(&mut program.sources);
+        Ok(())
+    }
+
+    /// The file named by the string `tok` after `kw`, relative to the
+    /// directory of this one, and its canonical path. A directory stands
+    /// for the `main.aws` in it.
+    fn resolve(&self, kw: &Token, tok: &Token) -> Result<(PathBuf, PathBuf), ParseError> {
+        let name = match (&tok.token_type, plain_text(tok)) {
+            (TokenType::StringLiteral, Some(name)) if !name.is_empty() => name,
+            _ => {
+                let s = format!(
+                    "Invalid token at location ({},{}), expecting the path of the {} file as a plain string, found: {}",
+                    tok.line_no, tok.column_no, kw.lexeme, tok.lexeme
+                );
                 return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
             }
-            if n.lexeme == "aws" {
-                return self.aws();
+        };
+        let dir = Path::new(&self.scanner.source)
+            .parent()
+            .unwrap_or(Path::new(""));
+        let mut path = dir.to_path_buf();
+        for part in Path::new(name).components() {
+            if part != Component::CurDir {
+                path.push(part);
+            }
+        }
+        if path.is_dir() {
+            path = path.join(MODULE_MAIN);
+        }
+        match fs::canonicalize(&path) {
+            Ok(canonical) => Ok((path, canonical)),
+            Err(e) => {
+                let s = format!(
+                    "can't read {} at ({},{}): {}",
+                    path.display(),
+                    tok.line_no,
+                    tok.column_no,
+                    e
+                );
+                Err(ParseError::new(ParseErrorType::Include, s))
             }
         }
+    }
 
-        let s = format!("Unknown literal, should start from aws block!");
-        Err(ParseError::new(ParseErrorType::TokenMismatch, s))
+    /// Including at `kw` this file, or one of the files that include it,
+    /// is a cycle
+    fn check_cycle(&self, kw: &Token, path: &Path, canonical: &Path) -> Result<(), ParseError> {
+        let mut chain = self.including.clone();
+        chain.push(self.scanner.source.to_string());
+        if chain
+            .iter()
+            .any(|f| fs::canonicalize(f).is_ok_and(|c| c == canonical))
+        {
+            chain.push(path.display().to_string());
+            let s = format!(
+                "{} cycle at ({},{}): {}",
+                kw.lexeme,
+                kw.line_no,
+                kw.column_no,
+                chain.join(" -> ")
+            );
+            return Err(ParseError::new(ParseErrorType::Include, s));
+        }
+        Ok(())
+    }
+
+    /// A parser for the file at `path`, included at `kw`, sharing what
+    /// this one collected until `close` takes it back
+    fn open(&mut self, kw: &Token, path: &Path) -> Result<Parser, ParseError> {
+        let mut chain = self.including.clone();
+        chain.push(self.scanner.source.to_string());
+        let scanner = match read_lex_file(&path.display().to_string()) {
+            Ok(scanner) => scanner,
+            Err(e) => {
+                let s = format!(
+                    "can't read {} at ({},{}): {}",
+                    path.display(),
+                    kw.line_no,
+                    kw.column_no,
+                    e
+                );
+                return Err(ParseError::new(ParseErrorType::Include, s));
+            }
+        };
+        self.sources.push(scanner.contents.to_string());
+        let mut parser = Parser::new(scanner);
+        parser.including = chain;
+        parser.imported = mem::take(&mut self.imported);
+        Ok(parser)
+    }
+
+    /// Take back what `parser`, from `open`, collected
+    fn close(&mut self, mut parser: Parser) {
+        self.imported = mem::take(&mut parser.imported);
+        self.sources.append(&mut parser.sources);
+    }
+
+    /// Parse `<name> = <value>` after the `var` or `let` keyword
+    fn var(&mut self, kw: &Token) -> Result<VarNode, ParseError> {
+        let tok = self.next_token();
+        // a keyword is a valid name, e.g. `var subnet`, paths may start
+        // with one
+        if !matches!(tok.token_type, TokenType::Identifier | TokenType::Keyword) {
+            let s = format!(
+                "Invalid token at location ({},{}), expecting a variable name, found: {}",
+                tok.line_no, tok.column_no, tok.lexeme
+            );
+            return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
+        }
+        if tok.lexeme == "true" || tok.lexeme == "false" {
+            let s = format!(
+                "`{}` at location ({},{}) is a literal, it can't name a variable",
+                tok.lexeme, tok.line_no, tok.column_no
+            );
+            return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
+        }
+        Ok(VarNode {
+            kind: kw.lexeme.to_string(),
+            name: tok.lexeme.to_string(),
+            value: self.attr_value()?,
+            line_no: tok.line_no,
+            column_no: tok.column_no,
+            file: self.scanner.source.to_string(),
+        })
     }
 
     /// Parse aws block, expects one of name, description, region or ec2 block
     pub(crate) fn aws(&mut self) -> Result<AwsNode, ParseError> {
         println!("== inside aws()");
         let mut aws_node = AwsNode::new("aws".to_string());
+        aws_node.file = self.scanner.source.to_string();
         while let Some(aws_attr) = self.next() {
             match aws_attr.token_type {
                 TokenType::Keyword => match aws_attr.lexeme.as_str() {
                     "region" => {
-                        self.aws_region(&mut aws_node)?;
+                        aws_node.set_region(self.attr_value()?);
+                    }
+                    "profile" => {
+                        aws_node.set_profile(self.attr_value()?);
+                    }
+                    "role_arn" => {
+                        aws_node.set_role_arn(self.attr_value()?);
+                    }
+                    "assume_role" => {
+                        aws_node.set_assume_role(self.assume_role()?);
                     }
                     "name" => {
-                        self.aws_name(&mut aws_node)?;
+                        aws_node.set_name(self.attr_value()?);
                     }
                     "description" => {
-                        self.aws_description(&mut aws_node)?;
+                        aws_node.set_description(self.attr_value()?);
+                    }
+                    "endpoint" => {
+                        aws_node.set_endpoint(self.attr_value()?);
                     }
                     "ec2" => {
-                        self.ec2(&mut aws_node)?;
+                        self.ec2(&mut aws_node.resources)?;
+                    }
+                    "s3" => {
+                        self.s3(&mut aws_node.resources)?;
+                    }
+                    "dynamodb" => {
+                        self.dynamodb(&mut aws_node.resources)?;
+                    }
+                    "security_group" => {
+                        self.security_group(&mut aws_node.resources)?;
+                    }
+                    "key_pair" => {
+                        self.key_pair(&mut aws_node.resources)?;
+                    }
+                    "vpc" | "subnet" | "internet_gateway" | "nat_gateway" | "route_table" => {
+                        self.network(&mut aws_node.resources, &aws_attr)?;
+                    }
+                    "if" => {
+                        self.if_block(&mut aws_node.resources, &aws_attr)?;
+                    }
+                    "module" => {
+                        self.module(&mut aws_node.resources, &aws_attr)?;
+                    }
+                    "state" => {
+                        self.state(&mut aws_node)?;
+                    }
+                    "tags" => {
+                        aws_node.set_tags(self.tags_value()?);
                     }
                     _ => {
                         let s = format!(
@@ -126,6 +395,7 @@ impl Parser {
                         return Err(ParseError::new(ParseErrorType::UnknownToken, s));
                     }
                 },
+                TokenType::Comment => {}
                 TokenType::LeftBrace => {}
                 TokenType::RightBrace => {
                     return Ok(aws_node);
@@ -147,102 +417,461 @@ impl Parser {
         Err(ParseError::new(ParseErrorType::TokenMismatch, s))
     }
 
-    /// parse the name statement after `name` keyword
-    fn aws_name(&mut self, aws_node: &mut AwsNode) -> Result<(), ParseError> {
-        while let Some(tok) = self.next() {
+    /// The next token that isn't a comment
+    fn next_token(&mut self) -> Token {
+        if let Some(tok) = self.peeked.take() {
+            return tok;
+        }
+        loop {
+            let tok = self.scanner.next_token();
+            if tok.token_type != TokenType::Comment {
+                return tok;
+            }
+        }
+    }
+
+    /// The next token that isn't a comment, left to be read again
+    fn peek_token(&mut self) -> &Token {
+        if self.peeked.is_none() {
+            let tok = self.next_token();
+            self.peeked = Some(tok);
+        }
+        self.peeked.as_ref().unwrap()
+    }
+
+    /// parse `= <expr>`
+    fn attr_value(&mut self) -> Result<Expr, ParseError> {
+        let tok = self.next_token();
+        if tok.token_type != TokenType::Equal {
+            let s = format!(
+                "Invalid token at location ({},{}), expecting =, found: {}",
+                tok.line_no, tok.column_no, tok.lexeme
+            );
+            return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
+        }
+        self.expr(0)
+    }
+
+    /// parse a `tags { key = value ... }` block, or `tags = <expr>`
+    fn tags_value(&mut self) -> Result<Expr, ParseError> {
+        if self.peek_token().token_type == TokenType::LeftBrace {
+            let open = self.next_token();
+            return self.map(&open);
+        }
+        self.attr_value()
+    }
+
+    /// Parse an expression by precedence climbing: operands bind to the
+    /// operators whose left binding power is above `min_bp`
+    fn expr(&mut self, min_bp: u8) -> Result<Expr, ParseError> {
+        let mut lhs = self.unary()?;
+        loop {
+            let tok = self.peek_token();
+            let op = match binary_op(&tok.token_type) {
+                Some(op) => op,
+                None => return Ok(lhs),
+            };
+            let (l_bp, r_bp) = binding_power(op);
+            if l_bp < min_bp {
+                return Ok(lhs);
+            }
+            let tok = self.next_token();
+            let rhs = self.expr(r_bp)?;
+            lhs = Expr::Binary {
+                op: op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+                line_no: tok.line_no,
+                column_no: tok.column_no,
+            };
+        }
+    }
+
+    /// `-` or `!` applied to an operand, or an operand
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        let op = match self.peek_token().token_type {
+            TokenType::Minus => Op::Neg,
+            TokenType::Bang => Op::Not,
+            _ => return self.postfix(),
+        };
+        let tok = self.next_token();
+        Ok(Expr::Unary {
+            op: op,
+            expr: Box::new(self.unary()?),
+            line_no: tok.line_no,
+            column_no: tok.column_no,
+        })
+    }
+
+    /// An operand followed by any number of `[index]`
+    fn postfix(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.primary()?;
+        while self.peek_token().token_type == TokenType::LeftBracket {
+            let open = self.next_token();
+            let index = self.expr(0)?;
+            let close = self.next_token();
+            if close.token_type != TokenType::RightBracket {
+                let s = format!(
+                    "Invalid token at location ({},{}), expecting ] to close [ at ({},{}), found: {}",
+                    close.line_no, close.column_no, open.line_no, open.column_no, close.lexeme
+                );
+                return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
+            }
+            expr = Expr::Index {
+                expr: Box::new(expr),
+                index: Box::new(index),
+                line_no: open.line_no,
+                column_no: open.column_no,
+            };
+        }
+        Ok(expr)
+    }
+
+    /// A literal, a variable, a path like `ec2.web.name` or an expression
+    /// in parentheses
+    fn primary(&mut self) -> Result<Expr, ParseError> {
+        let tok = self.next_token();
+        match tok.token_type {
+            TokenType::StringLiteral => self.string(&tok),
+            TokenType::Number => match tok.lexeme.parse::<f64>() {
+                Ok(n) => Ok(Expr::Number(n)),
+                Err(_) => {
+                    let s = format!(
+                        "Invalid number at location ({},{}): {}",
+                        tok.line_no, tok.column_no, tok.lexeme
+                    );
+                    Err(ParseError::new(ParseErrorType::TokenMismatch, s))
+                }
+            },
+            TokenType::Keyword if tok.lexeme == "true" => Ok(Expr::Bool(true)),
+            TokenType::Keyword if tok.lexeme == "false" => Ok(Expr::Bool(false)),
+            // built-ins like `region` and paths like `ec2.web.name` start
+            // with a keyword
+            TokenType::Keyword | TokenType::Identifier => self.path(&tok),
+            TokenType::LeftBracket => self.list(&tok),
+            TokenType::LeftBrace => self.map(&tok),
+            TokenType::LeftParen => {
+                let expr = self.expr(0)?;
+                let close = self.next_token();
+                if close.token_type != TokenType::RightParen {
+                    let s = format!(
+                        "Invalid token at location ({},{}), expecting ) to close ( at ({},{}), found: {}",
+                        close.line_no, close.column_no, tok.line_no, tok.column_no, close.lexeme
+                    );
+                    return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
+                }
+                Ok(expr)
+            }
+            _ => {
+                let s = format!(
+                    "Invalid token at location ({},{}), expecting a value, found: {}",
+                    tok.line_no, tok.column_no, tok.lexeme
+                );
+                Err(ParseError::new(ParseErrorType::TokenMismatch, s))
+            }
+        }
+    }
+
+    /// The items of a list after its `[`, separated by commas; a trailing
+    /// comma is allowed
+    fn list(&mut self, open: &Token) -> Result<Expr, ParseError> {
+        let mut items: Vec<Expr> = vec![];
+        loop {
+            if self.peek_token().token_type == TokenType::RightBracket {
+                self.next_token();
+                return Ok(Expr::List(items));
+            }
+            items.push(self.expr(0)?);
+            let tok = self.next_token();
             match tok.token_type {
-                TokenType::Equal => {}
-                TokenType::StringLiteral => {
-                    aws_node.set_name(tok.lexeme.trim_matches('"'));
-                    return Ok(());
+                TokenType::Comma => {}
+                TokenType::RightBracket => return Ok(Expr::List(items)),
+                _ => {
+                    let s = format!(
+                        "Invalid token at location ({},{}), expecting , or ] to close [ at ({},{}), found: {}",
+                        tok.line_no, tok.column_no, open.line_no, open.column_no, tok.lexeme
+                    );
+                    return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
+                }
+            }
+        }
+    }
+
+    /// The `key = value` entries of a map after its `{`, optionally
+    /// separated by commas. A key is a name or a string and can't be
+    /// repeated.
+    fn map(&mut self, open: &Token) -> Result<Expr, ParseError> {
+        let mut entries: Vec<(String, Expr)> = vec![];
+        loop {
+            let tok = self.next_token();
+            let key = match tok.token_type {
+                TokenType::RightBrace => return Ok(Expr::Map(entries)),
+                TokenType::Comma if !entries.is_empty() => continue,
+                TokenType::Identifier | TokenType::Keyword => tok.lexeme.to_string(),
+                TokenType::StringLiteral if plain_text(&tok).is_some() => {
+                    plain_text(&tok).unwrap_or_default().to_string()
                 }
                 _ => {
                     let s = format!(
-                        "Invalid token at location ({},{}), found: {}",
+                        "Invalid token at location ({},{}), expecting a key or }} to close {{ at ({},{}), found: {}",
+                        tok.line_no, tok.column_no, open.line_no, open.column_no, tok.lexeme
+                    );
+                    return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
+                }
+            };
+            if entries.iter().any(|(k, _)| k == &key) {
+                let s = format!(
+                    "Duplicate key `{}` at location ({},{})",
+                    key, tok.line_no, tok.column_no
+                );
+                return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
+            }
+            entries.push((key, self.attr_value()?));
+        }
+    }
+
+    /// A name, or a dotted path when the name is followed by `.`
+    fn path(&mut self, first: &Token) -> Result<Expr, ParseError> {
+        let mut segments = vec![first.lexeme.to_string()];
+        while self.peek_token().token_type == TokenType::Dot {
+            self.next_token();
+            let tok = self.next_token();
+            match tok.token_type {
+                TokenType::Identifier | TokenType::Keyword => {
+                    segments.push(tok.lexeme.to_string());
+                }
+                _ => {
+                    let s = format!(
+                        "Invalid token at location ({},{}), expecting a name after ., found: {}",
                         tok.line_no, tok.column_no, tok.lexeme
                     );
-                    return error(&&self.scanner, ParseErrorType::TokenMismatch, Some(s));
+                    return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
                 }
             }
         }
+        if segments.len() == 1 {
+            return Ok(Expr::Ident {
+                name: first.lexeme.to_string(),
+                line_no: first.line_no,
+                column_no: first.column_no,
+            });
+        }
+        Ok(Expr::Path {
+            segments: segments,
+            line_no: first.line_no,
+            column_no: first.column_no,
+        })
+    }
 
-        error(&self.scanner, ParseErrorType::TokenMismatch, None)
+    /// A string literal, interpolated when it has `${...}` parts
+    fn string(&mut self, tok: &Token) -> Result<Expr, ParseError> {
+        // errors found by the scanner in the literal, e.g. an open `${`
+        if let Some(e) = self.scanner.errors.pop() {
+            return Err(ParseError::new(ParseErrorType::TokenMismatch, e));
+        }
+        if let Some(t) = plain_text(tok) {
+            return Ok(Expr::Str(t.to_string()));
+        }
+        let mut parts: Vec<InterpPart> = vec![];
+        for part in &tok.parts {
+            match part {
+                StrPart::Text(t) => parts.push(InterpPart::Text(t.to_string())),
+                StrPart::Interp {
+                    source,
+                    line_no,
+                    column_no,
+                } => parts.push(InterpPart::Expr {
+                    expr: interpolation(source, *line_no, *column_no)?,
+                    source: source.to_string(),
+                    line_no: *line_no,
+                    column_no: *column_no,
+                }),
+            }
+        }
+        Ok(Expr::Interp(parts))
     }
 
-    fn aws_description(&mut self, aws_node: &mut AwsNode) -> Result<(), ParseError> {
-        while let Some(tok) = self.next() {
-            match tok.token_type {
-                TokenType::Equal => {}
-                TokenType::StringLiteral => {
-                    aws_node.set_description(tok.lexeme.trim_matches('"'));
+    /// Parse the assume_role block, expects role_arn, session_name or
+    /// external_id
+    fn assume_role(&mut self) -> Result<AssumeRoleNode, ParseError> {
+        let mut assume_role = AssumeRoleNode::new();
+        let open = self.next_token();
+        if open.token_type != TokenType::LeftBrace {
+            let s = format!(
+                "Invalid token at location ({},{}), expecting {{, found: {}",
+                open.line_no, open.column_no, open.lexeme
+            );
+            return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
+        }
+        loop {
+            let tok = self.next_token();
+            match (&tok.token_type, tok.lexeme.as_str()) {
+                (TokenType::Keyword, "role_arn") => {
+                    assume_role.set_role_arn(self.attr_value()?);
+                }
+                (TokenType::Keyword, "session_name") => {
+                    assume_role.set_session_name(self.attr_value()?);
+                }
+                (TokenType::Keyword, "external_id") => {
+                    assume_role.set_external_id(self.attr_value()?);
+                }
+                (TokenType::RightBrace, _) => return Ok(assume_role),
+                (TokenType::EoF, _) => {
+                    let s = format!(
+                        "Unterminated assume_role block opened at location ({},{})",
+                        open.line_no, open.column_no
+                    );
+                    return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
+                }
+                _ => {
+                    let s = format!(
+                        "Invalid token {} at location ({},{}), expecting role_arn, session_name or external_id",
+                        tok.lexeme, tok.line_no, tok.column_no
+                    );
+                    return Err(ParseError::new(ParseErrorType::UnknownToken, s));
+                }
+            }
+        }
+    }
+
+    /// Parse the state block, expects bucket, prefix, lock_table or endpoint
+    fn state(&mut self, aws_node: &mut AwsNode) -> Result<(), ParseError> {
+        let mut state = StateNode::new();
+
+        while let Some(state_attr) = self.next() {
+            match state_attr.token_type {
+                TokenType::Keyword => match state_attr.lexeme.as_str() {
+                    "bucket" => {
+                        state.set_bucket(self.string_value()?);
+                    }
+                    "prefix" => {
+                        state.set_prefix(self.string_value()?);
+                    }
+                    "lock_table" => {
+                        state.set_lock_table(self.string_value()?);
+                    }
+                    "endpoint" => {
+                        state.set_endpoint(self.string_value()?);
+                    }
+                    _ => {
+                        let s = format!(
+                            "Invalid token {} at location ({},{})",
+                            state_attr.lexeme, state_attr.line_no, state_attr.column_no
+                        );
+                        return Err(ParseError::new(ParseErrorType::UnknownToken, s));
+                    }
+                },
+                TokenType::Comment => {}
+                TokenType::LeftBrace => {}
+                TokenType::RightBrace => {
+                    aws_node.set_state(state);
                     return Ok(());
                 }
                 _ => {
                     let s = format!(
-                        "Invalid token at location ({},{}), found: {}",
-                        tok.line_no, tok.column_no, tok.lexeme
+                        "Invalid token {} at location ({},{})",
+                        state_attr.lexeme, state_attr.line_no, state_attr.column_no
                     );
-                    return error(&&self.scanner, ParseErrorType::TokenMismatch, Some(s));
+                    return Err(ParseError::new(ParseErrorType::UnknownToken, s));
                 }
             }
         }
 
-        error(&self.scanner, ParseErrorType::TokenMismatch, None)
+        let s = format!(
+            "Invalid token at (line,column): {},{}",
+            self.scanner.line, self.scanner.column_no
+        );
+        Err(ParseError::new(ParseErrorType::TokenMismatch, s))
     }
 
-    fn aws_region(&mut self, aws_node: &mut AwsNode) -> Result<(), ParseError> {
+    /// parse `= "value"` and return the unquoted value
+    fn string_value(&mut self) -> Result<String, ParseError> {
         while let Some(tok) = self.next() {
             match tok.token_type {
                 TokenType::Equal => {}
                 TokenType::StringLiteral => {
-                    aws_node.set_region(tok.lexeme.trim_matches('"'));
-                    return Ok(());
+                    return Ok(tok.lexeme.trim_matches('"').to_string());
                 }
                 _ => {
                     let s = format!(
                         "Invalid token at location ({},{}), found: {}",
                         tok.line_no, tok.column_no, tok.lexeme
                     );
-                    return error(&&self.scanner, ParseErrorType::TokenMismatch, Some(s));
+                    return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
                 }
             }
         }
 
-        error(&self.scanner, ParseErrorType::TokenMismatch, None)
+        let s = format!(
+            "Invalid token at (line,column): {},{}",
+            self.scanner.line, self.scanner.column_no
+        );
+        Err(ParseError::new(ParseErrorType::TokenMismatch, s))
     }
 
-    fn ec2(&mut self, aws_node: &mut AwsNode) -> Result<(), ParseError> {
+    /// Parse an ec2 block: `ec2 ["<label>"] [for_each = <expr> | count =
+    /// <expr>] { ... }`
+    fn ec2(&mut self, resources: &mut ResourceNodes) -> Result<(), ParseError> {
         let mut ec2 = Ec2Node::new();
+        ec2.label = self.label("ec2")?;
+        let tok = self.next_token();
+        let open = match (&tok.token_type, tok.lexeme.as_str()) {
+            (TokenType::Keyword, "for_each" | "count") if ec2.label.is_some() => {
+                let value = self.attr_value()?;
+                ec2.meta_arg = Some(match tok.lexeme.as_str() {
+                    "for_each" => MetaArg::ForEach(value),
+                    _ => MetaArg::Count(value),
+                });
+                self.next_token()
+            }
+            _ => tok,
+        };
+        if open.token_type != TokenType::LeftBrace {
+            let s = format!(
+                "Invalid token at location ({},{}), expecting {{, found: {} (for_each and count before the block need a label, e.g. ec2 \"web\" count = 2 {{ ... }})",
+                open.line_no, open.column_no, open.lexeme
+            );
+            return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
+        }
 
         while let Some(ec2_attr) = self.next() {
             match ec2_attr.token_type {
                 TokenType::Keyword => match ec2_attr.lexeme.as_str() {
                     "name" => {
-                        self.ec2_name(&mut ec2)?;
+                        ec2.set_name(self.attr_value()?);
+                    }
+                    "when" => {
+                        ec2.set_when(self.attr_value()?);
                     }
                     "description" => {
-                        self.ec2_description(&mut ec2)?;
+                        ec2.set_description(self.attr_value()?);
                     }
                     "instance_type" => {
-                        self.ec2_instance_type(&mut ec2)?;
+                        ec2.set_instance_type(self.attr_value()?);
                     }
                     "count" => {
-                        self.ec2_count(&mut ec2)?;
+                        ec2.set_count(self.attr_value()?);
                     }
                     "ami" => {
-                        self.ec2_ami(&mut ec2)?;
+                        ec2.set_ami(self.attr_value()?);
                     }
                     "subnet_id" => {
-                        self.ec2_subnet_id(&mut ec2)?;
+                        ec2.set_subnet_id(self.attr_value()?);
                     }
                     "sg_id" => {
-                        self.ec2_sg_id(&mut ec2)?;
+                        ec2.set_sg_id(self.attr_value()?);
+                    }
+                    "sg_ids" => {
+                        ec2.set_sg_ids(self.attr_value()?);
+                    }
+                    "tags" => {
+                        ec2.set_tags(self.tags_value()?);
                     }
                     "app_version" => {
-                        self.ec2_app_version(&mut ec2)?;
+                        ec2.set_app_version(self.attr_value()?);
                     }
                     "key_name" => {
-                        self.ec2_key_name(&mut ec2)?;
+                        ec2.set_key_name(self.attr_value()?);
                     }
                     _ => {
                         let s = format!(
@@ -253,9 +882,8 @@ impl Parser {
                     }
                 },
                 TokenType::Comment => {}
-                TokenType::LeftBrace => {}
                 TokenType::RightBrace => {
-                    aws_node.add_ec2(ec2);
+                    resources.add_ec2(ec2);
                     return Ok(());
                 }
                 _ => {
@@ -271,196 +899,587 @@ impl Parser {
         error(&self.scanner, ParseErrorType::TokenMismatch, None)
     }
 
-    fn ec2_name(&mut self, ec2_node: &mut Ec2Node) -> Result<(), ParseError> {
-        while let Some(tok) = self.next() {
-            match tok.token_type {
-                TokenType::Equal => {}
-                TokenType::StringLiteral => {
-                    ec2_node.set_name(tok.lexeme.trim_matches('"'));
-                    return Ok(());
+    /// Parse `if <expr> { ... } [else if <expr> { ... }] [else { ... }]`
+    /// after `if_tok`. The resources of a branch get its condition added
+    /// to their `when`.
+    fn if_block(
+        &mut self,
+        resources: &mut ResourceNodes,
+        if_tok: &Token,
+    ) -> Result<(), ParseError> {
+        let cond = self.expr(0)?;
+        let mark = resources.mark();
+        self.branch(resources)?;
+        guard(resources.whens_since(&mark), &cond, if_tok);
+
+        let tok = self.peek_token();
+        if !(tok.token_type == TokenType::Keyword && tok.lexeme == "else") {
+            return Ok(());
+        }
+        let else_tok = self.next_token();
+        let mark = resources.mark();
+        let tok = self.peek_token();
+        if tok.token_type == TokenType::Keyword && tok.lexeme == "if" {
+            let if_tok = self.next_token();
+            self.if_block(resources, &if_tok)?;
+        } else {
+            self.branch(resources)?;
+        }
+        let not_cond = Expr::Unary {
+            op: Op::Not,
+            expr: Box::new(cond),
+            line_no: else_tok.line_no,
+            column_no: else_tok.column_no,
+        };
+        guard(resources.whens_since(&mark), &not_cond, &else_tok);
+        Ok(())
+    }
+
+    /// Parse the `{ ... }` of an if or else branch, which holds resources
+    /// and nested ifs
+    fn branch(&mut self, resources: &mut ResourceNodes) -> Result<(), ParseError> {
+        let open = self.next_token();
+        if open.token_type != TokenType::LeftBrace {
+            let s = format!(
+                "Invalid token at location ({},{}), expecting {{, found: {}",
+                open.line_no, open.column_no, open.lexeme
+            );
+            return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
+        }
+        loop {
+            let tok = self.next_token();
+            match (&tok.token_type, tok.lexeme.as_str()) {
+                (TokenType::Keyword, "ec2") => self.ec2(resources)?,
+                (TokenType::Keyword, "s3") => self.s3(resources)?,
+                (TokenType::Keyword, "dynamodb") => self.dynamodb(resources)?,
+                (TokenType::Keyword, "security_group") => self.security_group(resources)?,
+                (TokenType::Keyword, "key_pair") => self.key_pair(resources)?,
+                (
+                    TokenType::Keyword,
+                    "vpc" | "subnet" | "internet_gateway" | "nat_gateway" | "route_table",
+                ) => self.network(resources, &tok)?,
+                (TokenType::Keyword, "if") => self.if_block(resources, &tok)?,
+                (TokenType::RightBrace, _) => return Ok(()),
+                (TokenType::EoF, _) => {
+                    let s = format!(
+                        "Invalid token at location ({},{}), expecting }} to close {{ at ({},{})",
+                        tok.line_no, tok.column_no, open.line_no, open.column_no
+                    );
+                    return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
                 }
                 _ => {
                     let s = format!(
-                        "Invalid token at location ({},{}), found: {}",
-                        tok.line_no, tok.column_no, tok.lexeme
+                        "Invalid token {} at location ({},{}), only resources can be declared in an if block",
+                        tok.lexeme, tok.line_no, tok.column_no
                     );
-                    return error(&&self.scanner, ParseErrorType::TokenMismatch, Some(s));
+                    return Err(ParseError::new(ParseErrorType::UnknownToken, s));
                 }
             }
         }
-
-        error(&self.scanner, ParseErrorType::TokenMismatch, None)
     }
 
-    fn ec2_description(&mut self, ec2_node: &mut Ec2Node) -> Result<(), ParseError> {
-        while let Some(tok) = self.next() {
+    /// Parse `module "<name>" { source = "<path>" <input> = <value> ... }`
+    /// after `kw`, and the module file `source` points at
+    fn module(&mut self, resources: &mut ResourceNodes, kw: &Token) -> Result<(), ParseError> {
+        let tok = self.next_token();
+        let name = match (&tok.token_type, plain_text(&tok)) {
+            (TokenType::StringLiteral, Some(name)) if !name.is_empty() => name.to_string(),
+            _ => {
+                let s = format!(
+                    "Invalid module name at location ({},{}), expecting a plain string",
+                    tok.line_no, tok.column_no
+                );
+                return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
+            }
+        };
+        let open = self.next_token();
+        if open.token_type != TokenType::LeftBrace {
+            let s = format!(
+                "Invalid token at location ({},{}), expecting {{, found: {}",
+                open.line_no, open.column_no, open.lexeme
+            );
+            return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
+        }
+
+        let mut source: Option<Token> = None;
+        let mut inputs: Vec<(String, Expr)> = vec![];
+        loop {
+            let tok = self.next_token();
             match tok.token_type {
-                TokenType::Equal => {}
-                TokenType::StringLiteral => {
-                    ec2_node.set_description(tok.lexeme.trim_matches('"'));
-                    return Ok(());
+                TokenType::Keyword if tok.lexeme == "source" => {
+                    let eq = self.next_token();
+                    if eq.token_type != TokenType::Equal {
+                        let s = format!(
+                            "Invalid token at location ({},{}), expecting =, found: {}",
+                            eq.line_no, eq.column_no, eq.lexeme
+                        );
+                        return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
+                    }
+                    source = Some(self.next_token());
                 }
+                TokenType::Identifier => {
+                    if inputs.iter().any(|(n, _)| *n == tok.lexeme) {
+                        let s = format!(
+                            "module input `{}` at ({},{}) is given twice",
+                            tok.lexeme, tok.line_no, tok.column_no
+                        );
+                        return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
+                    }
+                    inputs.push((tok.lexeme.to_string(), self.attr_value()?));
+                }
+                TokenType::RightBrace => break,
                 _ => {
                     let s = format!(
-                        "Invalid token at location ({},{}), found: {}",
-                        tok.line_no, tok.column_no, tok.lexeme
+                        "Invalid token {} at location ({},{}), expecting source or a module input",
+                        tok.lexeme, tok.line_no, tok.column_no
                     );
-                    return error(&&self.scanner, ParseErrorType::TokenMismatch, Some(s));
+                    return Err(ParseError::new(ParseErrorType::UnknownToken, s));
                 }
             }
         }
+        let source = match source {
+            Some(source) => source,
+            None => {
+                let s = format!(
+                    "module `{}` at ({},{}) has no source",
+                    name, kw.line_no, kw.column_no
+                );
+                return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
+            }
+        };
 
-        error(&self.scanner, ParseErrorType::TokenMismatch, None)
+        let (path, canonical) = self.resolve(kw, &source)?;
+        self.check_cycle(kw, &path, &canonical)?;
+        let mut parser = self.open(kw, &path)?;
+        let mut module = ModuleNode {
+            name: name,
+            file: parser.scanner.source.to_string(),
+            inputs: inputs,
+            vars: vec![],
+            resources: ResourceNodes::new(),
+            line_no: kw.line_no,
+            column_no: kw.column_no,
+        };
+        let body = parser
+            .module_body(&mut module)
+            .map_err(|e| e.in_file(&module.file));
+        self.close(parser);
+        body?;
+        resources.add_module(module);
+        Ok(())
     }
 
-    fn ec2_instance_type(&mut self, ec2_node: &mut Ec2Node) -> Result<(), ParseError> {
-        while let Some(tok) = self.next() {
-            match tok.token_type {
-                TokenType::Equal => {}
-                TokenType::StringLiteral => {
-                    ec2_node.set_instance_type(tok.lexeme.trim_matches('"'));
-                    return Ok(());
-                }
+    /// Parse a module file: declarations and resources, outside of any
+    /// aws block
+    fn module_body(&mut self, module: &mut ModuleNode) -> Result<(), ParseError> {
+        let resources = &mut module.resources;
+        loop {
+            let tok = self.next_token();
+            match (&tok.token_type, tok.lexeme.as_str()) {
+                (TokenType::EoF, _) => return Ok(()),
+                (TokenType::Keyword, "var" | "let") => module.vars.push(self.var(&tok)?),
+                (TokenType::Keyword, "ec2") => self.ec2(resources)?,
+                (TokenType::Keyword, "s3") => self.s3(resources)?,
+                (TokenType::Keyword, "dynamodb") => self.dynamodb(resources)?,
+                (TokenType::Keyword, "security_group") => self.security_group(resources)?,
+                (TokenType::Keyword, "key_pair") => self.key_pair(resources)?,
+                (
+                    TokenType::Keyword,
+                    "vpc" | "subnet" | "internet_gateway" | "nat_gateway" | "route_table",
+                ) => self.network(resources, &tok)?,
+                (TokenType::Keyword, "if") => self.if_block(resources, &tok)?,
+                (TokenType::Keyword, "module") => self.module(resources, &tok)?,
                 _ => {
                     let s = format!(
-                        "Invalid token at location ({},{}), found: {}",
-                        tok.line_no, tok.column_no, tok.lexeme
+                        "Invalid token {} at location ({},{}), expecting var, let, a resource, if or module",
+                        tok.lexeme, tok.line_no, tok.column_no
                     );
-                    return error(&&self.scanner, ParseErrorType::TokenMismatch, Some(s));
+                    return Err(ParseError::new(ParseErrorType::UnknownToken, s));
                 }
             }
         }
+    }
 
-        error(&self.scanner, ParseErrorType::TokenMismatch, None)
+    /// Parse the optional `"<label>"` after the `kind` of a resource
+    fn label(&mut self, kind: &str) -> Result<Option<String>, ParseError> {
+        if self.peek_token().token_type != TokenType::StringLiteral {
+            return Ok(None);
+        }
+        let tok = self.next_token();
+        match plain_text(&tok) {
+            Some(label) if !label.is_empty() => Ok(Some(label.to_string())),
+            _ => {
+                let s = format!(
+                    "Invalid {} label at location ({},{}), expecting a plain string",
+                    kind, tok.line_no, tok.column_no
+                );
+                Err(ParseError::new(ParseErrorType::TokenMismatch, s))
+            }
+        }
     }
 
-    fn ec2_ami(&mut self, ec2_node: &mut Ec2Node) -> Result<(), ParseError> {
-        while let Some(tok) = self.next() {
-            match tok.token_type {
-                TokenType::Equal => {}
-                TokenType::StringLiteral => {
-                    ec2_node.set_ami(tok.lexeme.trim_matches('"'));
-                    return Ok(());
+    /// Expect the `{` opening the block of a `kind` resource
+    fn block_start(&mut self, kind: &str) -> Result<(), ParseError> {
+        let open = self.next_token();
+        if open.token_type != TokenType::LeftBrace {
+            let s = format!(
+                "Invalid token at location ({},{}), expecting {{ to start the {} block, found: {}",
+                open.line_no, open.column_no, kind, open.lexeme
+            );
+            return Err(ParseError::new(ParseErrorType::TokenMismatch, s));
+        }
+        Ok(())
+    }
+
+    /// Parse an s3 block: `s3 ["<label>"] { ... }`
+    fn s3(&mut self, resources: &mut ResourceNodes) -> Result<(), ParseError> {
+        let mut s3 = S3Node::new();
+        s3.label = self.label("s3")?;
+        self.block_start("s3")?;
+        loop {
+            let tok = self.next_token();
+            match (&tok.token_type, tok.lexeme.as_str()) {
+                (TokenType::Keyword, "when") => s3.set_when(self.attr_value()?),
+                (TokenType::Keyword, "name") => s3.set_name(self.attr_value()?),
+                (TokenType::Keyword, "versioning") => s3.set_versioning(self.attr_value()?),
+                (TokenType::Keyword, "encryption") => s3.set_encryption(self.attr_value()?),
+                (TokenType::Keyword, "public_access_block") => {
+                    s3.set_public_access_block(self.attr_value()?)
                 }
-                _ => {
-                    let s = format!(
-                        "Invalid token at location ({},{}), found: {}",
-                        tok.line_no, tok.column_no, tok.lexeme
-                    );
-                    return error(&&self.scanner, ParseErrorType::TokenMismatch, Some(s));
+                (TokenType::Keyword, "lifecycle") => s3.add_lifecycle(self.lifecycle()?),
+                (TokenType::Keyword, "tags") => s3.set_tags(self.tags_value()?),
+                (TokenType::RightBrace, _) => {
+                    resources.add_s3(s3);
+                    return Ok(());
                 }
+                _ => return Err(unexpected(&tok, "an s3 attribute")),
             }
         }
-
-        error(&self.scanner, ParseErrorType::TokenMismatch, None)
     }
 
-    fn ec2_subnet_id(&mut self, ec2_node: &mut Ec2Node) -> Result<(), ParseError> {
-        while let Some(tok) = self.next() {
-            match tok.token_type {
-                TokenType::Equal => {}
-                TokenType::StringLiteral => {
-                    ec2_node.set_subnet_id(tok.lexeme.trim_matches('"'));
-                    return Ok(());
+    /// Parse a dynamodb block: `dynamodb ["<label>"] { ... }`
+    fn dynamodb(&mut self, resources: &mut ResourceNodes) -> Result<(), ParseError> {
+        let mut table = DynamoNode::new();
+        table.label = self.label("dynamodb")?;
+        self.block_start("dynamodb")?;
+        loop {
+            let tok = self.next_token();
+            match (&tok.token_type, tok.lexeme.as_str()) {
+                (TokenType::Keyword, "when") => table.set_when(self.attr_value()?),
+                (TokenType::Keyword, "name") => table.set_name(self.attr_value()?),
+                (TokenType::Keyword, "hash_key") => table.set_hash_key(self.attr_value()?),
+                (TokenType::Keyword, "range_key") => table.set_range_key(self.attr_value()?),
+                (TokenType::Keyword, "attributes") => table.set_attributes(self.tags_value()?),
+                (TokenType::Keyword, "billing_mode") => table.set_billing_mode(self.attr_value()?),
+                (TokenType::Keyword, "read_capacity") => {
+                    table.set_read_capacity(self.attr_value()?)
                 }
-                _ => {
-                    let s = format!(
-                        "Invalid token at location ({},{}), found: {}",
-                        tok.line_no, tok.column_no, tok.lexeme
-                    );
-                    return error(&&self.scanner, ParseErrorType::TokenMismatch, Some(s));
+                (TokenType::Keyword, "write_capacity") => {
+                    table.set_write_capacity(self.attr_value()?)
+                }
+                (TokenType::Keyword, "ttl_attribute") => {
+                    table.set_ttl_attribute(self.attr_value()?)
+                }
+                (TokenType::Keyword, "gsi") => table.add_gsi(self.gsi()?),
+                (TokenType::Keyword, "tags") => table.set_tags(self.tags_value()?),
+                (TokenType::RightBrace, _) => {
+                    resources.add_dynamodb(table);
+                    return Ok(());
                 }
+                _ => return Err(unexpected(&tok, "a dynamodb attribute")),
             }
         }
-
-        error(&self.scanner, ParseErrorType::TokenMismatch, None)
     }
 
-    fn ec2_sg_id(&mut self, ec2_node: &mut Ec2Node) -> Result<(), ParseError> {
-        while let Some(tok) = self.next() {
-            match tok.token_type {
-                TokenType::Equal => {}
-                TokenType::StringLiteral => {
-                    ec2_node.set_sg_id(tok.lexeme.trim_matches('"'));
-                    return Ok(());
+    /// Parse a `gsi { ... }` global secondary index of a dynamodb block
+    fn gsi(&mut self) -> Result<GsiNode, ParseError> {
+        let mut gsi = GsiNode {
+            name: None,
+            hash_key: None,
+            range_key: None,
+            projection: None,
+            read_capacity: None,
+            write_capacity: None,
+        };
+        self.block_start("gsi")?;
+        loop {
+            let tok = self.next_token();
+            match (&tok.token_type, tok.lexeme.as_str()) {
+                (TokenType::Keyword, "name") => gsi.name = Some(self.attr_value()?),
+                (TokenType::Keyword, "hash_key") => gsi.hash_key = Some(self.attr_value()?),
+                (TokenType::Keyword, "range_key") => gsi.range_key = Some(self.attr_value()?),
+                (TokenType::Keyword, "projection") => gsi.projection = Some(self.attr_value()?),
+                (TokenType::Keyword, "read_capacity") => {
+                    gsi.read_capacity = Some(self.attr_value()?)
                 }
-                _ => {
-                    let s = format!(
-                        "Invalid token at location ({},{}), found: {}",
-                        tok.line_no, tok.column_no, tok.lexeme
-                    );
-                    return error(&&self.scanner, ParseErrorType::TokenMismatch, Some(s));
+                (TokenType::Keyword, "write_capacity") => {
+                    gsi.write_capacity = Some(self.attr_value()?)
                 }
+                (TokenType::RightBrace, _) => return Ok(gsi),
+                _ => return Err(unexpected(&tok, "a gsi attribute")),
             }
         }
-
-        error(&self.scanner, ParseErrorType::TokenMismatch, None)
     }
 
-    fn ec2_count(&mut self, ec2_node: &mut Ec2Node) -> Result<(), ParseError> {
-        while let Some(tok) = self.next() {
-            match tok.token_type {
-                TokenType::Equal => {}
-                TokenType::Number => {
-                    ec2_node.set_count(tok.lexeme.parse::<u8>().unwrap_or_default());
-                    return Ok(());
+    /// Parse a networking block: `<kind> ["<label>"] { ... }`, `kind_tok`
+    /// is the kind, e.g. `vpc`
+    fn network(
+        &mut self,
+        resources: &mut ResourceNodes,
+        kind_tok: &Token,
+    ) -> Result<(), ParseError> {
+        let kind = match NETWORK_KINDS.iter().find(|k| **k == kind_tok.lexeme) {
+            Some(kind) => *kind,
+            None => return Err(unexpected(kind_tok, "a networking resource")),
+        };
+        let mut network = NetworkNode::new(kind);
+        network.label = self.label(kind)?;
+        self.block_start(kind)?;
+        let attrs = NetworkNode::attrs(kind);
+        loop {
+            let tok = self.next_token();
+            match (&tok.token_type, tok.lexeme.as_str()) {
+                (TokenType::Keyword, "route") if attrs.contains(&"route") => {
+                    network.add_route(self.route()?)
                 }
-                _ => {
-                    let s = format!(
-                        "Invalid token at location ({},{}), found: {}",
-                        tok.line_no, tok.column_no, tok.lexeme
-                    );
-                    return error(&&self.scanner, ParseErrorType::TokenMismatch, Some(s));
+                (TokenType::Keyword, "tags") if attrs.contains(&"tags") => {
+                    network.set("tags", self.tags_value()?)
+                }
+                (TokenType::Keyword, attr) if attr == "when" || attrs.contains(&attr) => {
+                    network.set(attr, self.attr_value()?)
+                }
+                (TokenType::RightBrace, _) => {
+                    resources.add_network(network);
+                    return Ok(());
                 }
+                _ => return Err(unexpected(&tok, &format!("a {} attribute", kind))),
             }
         }
+    }
 
-        error(&self.scanner, ParseErrorType::TokenMismatch, None)
+    /// Parse a `route { ... }` of a route table
+    fn route(&mut self) -> Result<RouteNode, ParseError> {
+        let mut route = RouteNode {
+            cidr: None,
+            gateway: None,
+            nat_gateway: None,
+        };
+        self.block_start("route")?;
+        loop {
+            let tok = self.next_token();
+            match (&tok.token_type, tok.lexeme.as_str()) {
+                (TokenType::Keyword, "cidr") => route.cidr = Some(self.attr_value()?),
+                (TokenType::Keyword, "gateway") => route.gateway = Some(self.attr_value()?),
+                (TokenType::Keyword, "nat_gateway") => route.nat_gateway = Some(self.attr_value()?),
+                (TokenType::RightBrace, _) => return Ok(route),
+                _ => return Err(unexpected(&tok, "a route attribute")),
+            }
+        }
     }
 
-    fn ec2_app_version(&mut self, ec2_node: &mut Ec2Node) -> Result<(), ParseError> {
-        while let Some(tok) = self.next() {
-            match tok.token_type {
-                TokenType::Equal => {}
-                TokenType::Number => {
-                    ec2_node.set_app_version(tok.lexeme.parse::<f32>().unwrap_or_default());
+    /// Parse a `security_group` block
+    fn security_group(&mut self, resources: &mut ResourceNodes) -> Result<(), ParseError> {
+        let mut group = SecurityGroupNode::new();
+        group.label = self.label("security_group")?;
+        self.block_start("security_group")?;
+        loop {
+            let tok = self.next_token();
+            match (&tok.token_type, tok.lexeme.as_str()) {
+                (TokenType::Keyword, "when") => group.set_when(self.attr_value()?),
+                (TokenType::Keyword, "name") => group.set_name(self.attr_value()?),
+                (TokenType::Keyword, "description") => group.set_description(self.attr_value()?),
+                (TokenType::Keyword, "vpc_id") => group.set_vpc_id(self.attr_value()?),
+                (TokenType::Keyword, "ingress") => group.add_ingress(self.rule("ingress")?),
+                (TokenType::Keyword, "egress") => group.add_egress(self.rule("egress")?),
+                (TokenType::Keyword, "tags") => group.set_tags(self.tags_value()?),
+                (TokenType::RightBrace, _) => {
+                    resources.add_security_group(group);
                     return Ok(());
                 }
-                _ => {
-                    let s = format!(
-                        "Invalid token at location ({},{}), found: {}",
-                        tok.line_no, tok.column_no, tok.lexeme
-                    );
-                    return error(&&self.scanner, ParseErrorType::TokenMismatch, Some(s));
+                _ => return Err(unexpected(&tok, "a security_group attribute")),
+            }
+        }
+    }
+
+    /// Parse a `key_pair` block
+    fn key_pair(&mut self, resources: &mut ResourceNodes) -> Result<(), ParseError> {
+        let mut key = KeyPairNode::new();
+        key.file = self.scanner.source.to_string();
+        key.label = self.label("key_pair")?;
+        self.block_start("key_pair")?;
+        loop {
+            let tok = self.next_token();
+            match (&tok.token_type, tok.lexeme.as_str()) {
+                (TokenType::Keyword, "when") => key.set_when(self.attr_value()?),
+                (TokenType::Keyword, "name") => key.set_name(self.attr_value()?),
+                (TokenType::Keyword, "public_key_file") => {
+                    key.set_public_key_file(self.attr_value()?)
+                }
+                (TokenType::Keyword, "generate") => key.set_generate(self.attr_value()?),
+                (TokenType::Keyword, "private_key_file") => {
+                    key.set_private_key_file(self.attr_value()?)
                 }
+                (TokenType::Keyword, "tags") => key.set_tags(self.tags_value()?),
+                (TokenType::RightBrace, _) => {
+                    resources.add_key_pair(key);
+                    return Ok(());
+                }
+                _ => return Err(unexpected(&tok, "a key_pair attribute")),
             }
         }
+    }
 
-        error(&self.scanner, ParseErrorType::TokenMismatch, None)
+    /// Parse an `ingress { ... }` or `egress { ... }` rule of a security
+    /// group
+    fn rule(&mut self, direction: &str) -> Result<RuleNode, ParseError> {
+        let mut rule = RuleNode {
+            from: None,
+            to: None,
+            protocol: None,
+            cidr: None,
+            source_sg: None,
+        };
+        self.block_start(direction)?;
+        loop {
+            let tok = self.next_token();
+            match (&tok.token_type, tok.lexeme.as_str()) {
+                (TokenType::Keyword, "from") => rule.from = Some(self.attr_value()?),
+                (TokenType::Keyword, "to") => rule.to = Some(self.attr_value()?),
+                (TokenType::Keyword, "protocol") => rule.protocol = Some(self.attr_value()?),
+                (TokenType::Keyword, "cidr") => rule.cidr = Some(self.attr_value()?),
+                (TokenType::Keyword, "source_sg") => rule.source_sg = Some(self.attr_value()?),
+                (TokenType::RightBrace, _) => return Ok(rule),
+                _ => return Err(unexpected(&tok, &format!("an {} attribute", direction))),
+            }
+        }
     }
 
-    fn ec2_key_name(&mut self, ec2_node: &mut Ec2Node) -> Result<(), ParseError> {
-        while let Some(tok) = self.next() {
-            match tok.token_type {
-                TokenType::Equal => {}
-                TokenType::StringLiteral => {
-                    ec2_node.set_key_name(tok.lexeme.trim_matches('"'));
-                    return Ok(());
+    /// Parse a `lifecycle { ... }` rule of an s3 block
+    fn lifecycle(&mut self) -> Result<LifecycleNode, ParseError> {
+        let mut rule = LifecycleNode {
+            prefix: None,
+            expiration_days: None,
+            noncurrent_expiration_days: None,
+        };
+        self.block_start("lifecycle")?;
+        loop {
+            let tok = self.next_token();
+            match (&tok.token_type, tok.lexeme.as_str()) {
+                (TokenType::Keyword, "prefix") => rule.prefix = Some(self.attr_value()?),
+                (TokenType::Keyword, "expiration_days") => {
+                    rule.expiration_days = Some(self.attr_value()?)
                 }
-                _ => {
-                    let s = format!(
-                        "Invalid token at location ({},{}), found: {}",
-                        tok.line_no, tok.column_no, tok.lexeme
-                    );
-                    return error(&&self.scanner, ParseErrorType::TokenMismatch, Some(s));
+                (TokenType::Keyword, "noncurrent_expiration_days") => {
+                    rule.noncurrent_expiration_days = Some(self.attr_value()?)
                 }
+                (TokenType::RightBrace, _) => return Ok(rule),
+                _ => return Err(unexpected(&tok, "a lifecycle attribute")),
             }
         }
+    }
+}
 
-        error(&self.scanner, ParseErrorType::TokenMismatch, None)
+/// The error for `tok` found where `expected` should be; the end of the
+/// file means a block was left open
+fn unexpected(tok: &Token, expected: &str) -> ParseError {
+    let s = match tok.token_type {
+        TokenType::EoF => format!(
+            "Invalid token at location ({},{}), expecting {} or }}, found the end of the file",
+            tok.line_no, tok.column_no, expected
+        ),
+        _ => format!(
+            "Invalid token {} at location ({},{}), expecting {} or }}",
+            tok.lexeme, tok.line_no, tok.column_no, expected
+        ),
+    };
+    ParseError::new(ParseErrorType::UnknownToken, s)
+}
+
+/// Add `cond` to the `when` of the resources of a branch of the if at `tok`
+fn guard(whens: Vec<&mut Option<Expr>>, cond: &Expr, tok: &Token) {
+    for when in whens {
+        *when = Some(match when.take() {
+            Some(when) => Expr::Binary {
+                op: Op::And,
+                lhs: Box::new(cond.clone()),
+                rhs: Box::new(when),
+                line_no: tok.line_no,
+                column_no: tok.column_no,
+            },
+            None => cond.clone(),
+        });
     }
 }
 
+/// The contents of a string literal without interpolations
+fn plain_text(tok: &Token) -> Option<&str> {
+    match tok.parts.as_slice() {
+        [StrPart::Text(t)] => Some(t.as_str()),
+        _ => None,
+    }
+}
+
+/// The operator of a binary expression
+fn binary_op(token_type: &TokenType) -> Option<Op> {
+    let op = match token_type {
+        TokenType::Plus => Op::Add,
+        TokenType::Minus => Op::Sub,
+        TokenType::Star => Op::Mul,
+        TokenType::Div => Op::Div,
+        TokenType::Percent => Op::Rem,
+        TokenType::EqualEqual => Op::Eq,
+        TokenType::BangEqual => Op::Ne,
+        TokenType::Less => Op::Lt,
+        TokenType::LessEqual => Op::Le,
+        TokenType::Greater => Op::Gt,
+        TokenType::GreaterEqual => Op::Ge,
+        TokenType::And => Op::And,
+        TokenType::Or => Op::Or,
+        _ => return None,
+    };
+    Some(op)
+}
+
+/// Left and right binding powers of a binary operator, from `||`, the
+/// loosest, to `*`, `/` and `%`. The right one being higher makes the
+/// operators left associative.
+fn binding_power(op: Op) -> (u8, u8) {
+    match op {
+        Op::Or => (1, 2),
+        Op::And => (3, 4),
+        Op::Eq | Op::Ne => (5, 6),
+        Op::Lt | Op::Le | Op::Gt | Op::Ge => (7, 8),
+        Op::Add | Op::Sub => (9, 10),
+        _ => (11, 12),
+    }
+}
+
+/// Parse the inside of `${...}` found at (`line_no`,`column_no`) as an
+/// expression, keeping the locations of its tokens in the file
+fn interpolation(source: &str, line_no: usize, column_no: usize) -> Result<Expr, ParseError> {
+    let mut scanner = Scanner::new("".to_string(), source.to_string());
+    scanner.line = line_no;
+    // the source starts after `${`
+    scanner.column_no = column_no + 1;
+    let mut parser = Parser::new(scanner);
+    let invalid = |found: &str| {
+        let s = format!(
+            "Invalid interpolation `${{{}}}` at location ({},{}): {}",
+            source, line_no, column_no, found
+        );
+        ParseError::new(ParseErrorType::TokenMismatch, s)
+    };
+    let expr = parser.expr(0).map_err(|e| invalid(&e.msg))?;
+    let tok = parser.next_token();
+    if tok.token_type != TokenType::EoF {
+        return Err(invalid(&format!(
+            "unexpected {} at ({},{})",
+            tok.lexeme, tok.line_no, tok.column_no
+        )));
+    }
+    Ok(expr)
+}
+
 pub(crate) fn error(
     scanr: &Scanner,
     err_type: ParseErrorType,
@@ -477,3 +1496,166 @@ pub(crate) fn error(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    fn parse(s: &str) -> Result<ProgramNode, ParseError> {
+        let mut parser = Parser::new(Scanner::new("".to_string(), s.to_string()));
+        parser.parse()
+    }
+
+    /// Write `files` under `dir` and parse the first one
+    fn parse_files(dir: &Path, files: &[(&str, &str)]) -> Result<ProgramNode, String> {
+        for (name, contents) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        let root = dir.join(files[0].0);
+        let mut parser = Parser::new(read_lex_file(root.to_str().unwrap()).unwrap());
+        parser.parse().map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_var_names() {
+        // keywords are reserved in blocks only, a variable may take one
+        let program = parse("var subnet_id = \"subnet-1\"\nlet name = 1\naws { }").unwrap();
+        let names: Vec<&str> = program.vars.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, vec!["subnet_id", "name"]);
+
+        let err = parse("var true = 1\naws { }").err().unwrap();
+        assert_eq!(
+            err.msg,
+            "`true` at location (1,5) is a literal, it can't name a variable"
+        );
+    }
+
+    #[test]
+    fn test_imports() {
+        let dir = env::temp_dir().join(format!("awsdsl-parse-imports-{}", process::id()));
+        let d = dir.display();
+        let program = parse_files(
+            &dir,
+            &[
+                (
+                    "main.aws",
+                    "import \"vars.aws\"\nimport \"./vars.aws\"\naws { region = region_name }",
+                ),
+                ("vars.aws", "var region_name = \"eu-west-1\"\n"),
+            ],
+        )
+        .unwrap();
+        // a file imported twice is read once
+        let names: Vec<&str> = program.vars.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, vec!["region_name"]);
+        assert_eq!(program.vars[0].file, format!("{d}/vars.aws"));
+        assert_eq!(program.aws_nodes.len(), 1);
+        assert_eq!(program.sources.len(), 1);
+
+        let err = parse_files(&dir, &[("a.aws", "import vars\naws { }")])
+            .err()
+            .unwrap();
+        assert_eq!(
+            err,
+            format!(
+                "error type: Token mismatch, details: in {d}/a.aws: Invalid token at location (1,8), expecting the path of the import file as a plain string, found: vars"
+            )
+        );
+        let err = parse_files(&dir, &[("b.aws", "import \"vars-${x}.aws\"\naws { }")])
+            .err()
+            .unwrap();
+        assert!(
+            err.contains("expecting the path of the import file as a plain string"),
+            "{}",
+            err
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_modules() {
+        let dir = env::temp_dir().join(format!("awsdsl-parse-modules-{}", process::id()));
+        let d = dir.display();
+        let web = "var size = \"t2.micro\"\nec2 \"web\" { instance_type = size }\ns3 \"logs\" { name = \"logs\" }\n";
+        let program = parse_files(
+            &dir,
+            &[
+                (
+                    "main.aws",
+                    "aws { region = \"eu-west-1\"\n  module \"web\" { source = \"./web\" size = \"t3.large\" } }",
+                ),
+                ("web/main.aws", web),
+            ],
+        )
+        .unwrap();
+        let modules = &program.aws_nodes[0].resources.modules;
+        assert_eq!(modules.len(), 1);
+        let module = &modules[0];
+        assert_eq!(module.name, "web");
+        assert_eq!(module.file, format!("{d}/web/main.aws"));
+        assert_eq!((module.line_no, module.column_no), (2, 3));
+        let inputs: Vec<&str> = module.inputs.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(inputs, vec!["size"]);
+        assert_eq!(module.vars[0].name, "size");
+        assert_eq!(module.resources.ec2_nodes.len(), 1);
+        assert_eq!(module.resources.s3_nodes.len(), 1);
+        assert_eq!(program.sources, vec![web.to_string()]);
+
+        let module_err = |body: &str| {
+            let src = format!("aws {{ region = \"eu-west-1\" module {} }}", body);
+            parse_files(&dir, &[("m.aws", &src)]).err().unwrap()
+        };
+        let in_main =
+            |kind: &str, msg: &str| format!("error type: {}, details: in {d}/m.aws: {}", kind, msg);
+        assert_eq!(
+            module_err("web { source = \"./web\" }"),
+            in_main(
+                "Token mismatch",
+                "Invalid module name at location (1,35), expecting a plain string"
+            )
+        );
+        assert_eq!(
+            module_err("\"web\" { size = 1 }"),
+            in_main("Token mismatch", "module `web` at (1,28) has no source")
+        );
+        assert_eq!(
+            module_err("\"web\" { source = \"./web\" size = 1 size = 2 }"),
+            in_main(
+                "Token mismatch",
+                "module input `size` at (1,69) is given twice"
+            )
+        );
+        assert_eq!(
+            module_err("\"web\" { source = \"./web\" ; }"),
+            in_main(
+                "Unknown token",
+                "Invalid token ; at location (1,60), expecting source or a module input"
+            )
+        );
+
+        // a module holds resources, not aws blocks; the error names the
+        // module file
+        let err = parse_files(
+            &dir,
+            &[
+                (
+                    "n.aws",
+                    "aws { region = \"eu-west-1\" module \"x\" { source = \"x.aws\" } }",
+                ),
+                ("x.aws", "aws { }"),
+            ],
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err,
+            format!(
+                "error type: Unknown token, details: in {d}/x.aws: Invalid token aws at location (1,1), expecting var, let, a resource, if or module"
+            )
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
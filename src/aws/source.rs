@@ -0,0 +1,108 @@
+use std::collections::BTreeMap;
+
+use crate::aws::{AwsSym, Ec2Sym};
+
+/// The lexer has no escapes, a double quote can't appear inside a string;
+/// `${` is written as `$${` so it isn't read back as an interpolation
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('"', "'").replace("${", "$${"))
+}
+
+/// `items` as a list of strings
+fn list(items: &[String]) -> String {
+    let items: Vec<String> = items.iter().map(|s| quote(s)).collect();
+    format!("[{}]", items.join(", "))
+}
+
+/// `tags` as a `tags { ... }` block, keys are quoted as they may not be
+/// names
+fn tags_block(tags: &BTreeMap<String, String>) -> String {
+    let entries: Vec<String> = tags
+        .iter()
+        .map(|(k, v)| format!("{} = {}", quote(k), quote(v)))
+        .collect();
+    format!("tags {{ {} }}", entries.join(" "))
+}
+
+/// Write `ec2` back as an `ec2 { ... }` block, indented by `n_spaces`
+pub(crate) fn ec2_block(ec2: &Ec2Sym, n_spaces: u8) -> String {
+    let outer = " ".repeat(n_spaces as usize);
+    let inner = " ".repeat(n_spaces as usize + 2);
+    let mut s = format!("{}ec2 {{", outer);
+    s = format!("{}\n{}name = {}", s, inner, quote(&ec2.name));
+    s = format!("{}\n{}description = {}", s, inner, quote(&ec2.desc));
+    s = format!(
+        "{}\n{}instance_type = {}",
+        s,
+        inner,
+        quote(&ec2.instance_type)
+    );
+    s = format!("{}\n{}count = {}", s, inner, ec2.count);
+    s = format!("{}\n{}app_version = {:?}", s, inner, ec2.app_version);
+    s = format!("{}\n{}ami = {}", s, inner, quote(&ec2.ami_id));
+    s = format!("{}\n{}subnet_id = {}", s, inner, quote(&ec2.subnet_id));
+    s = format!("{}\n{}sg_ids = {}", s, inner, list(&ec2.sg_ids));
+    s = format!("{}\n{}key_name = {}", s, inner, quote(&ec2.key_name));
+    if !ec2.tags.is_empty() {
+        s = format!("{}\n{}{}", s, inner, tags_block(&ec2.tags));
+    }
+    format!("{}\n{}}}", s, outer)
+}
+
+/// Write `aws_sym` back as an `aws { ... }` document, `description`
+/// being the description of the aws block
+pub(crate) fn aws_block(aws_sym: &AwsSym, description: &str) -> String {
+    let mut s = String::from("aws {");
+    s = format!("{}\n  region = {}", s, quote(&aws_sym.region));
+    s = format!("{}\n  name = {}", s, quote(&aws_sym.name));
+    s = format!("{}\n  description = {}", s, quote(description));
+    if !aws_sym.tags.is_empty() {
+        s = format!("{}\n  {}", s, tags_block(&aws_sym.tags));
+    }
+    for ec2 in &aws_sym.ec2s {
+        s = format!("{}\n{}", s, ec2_block(ec2, 2));
+    }
+    format!("{}\n}}\n", s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aws::parser::Parser;
+    use crate::lex::Scanner;
+    use crate::symbols::walk_ast;
+
+    #[test]
+    fn test_ec2_block_parses_back() {
+        let mut ec2 = Ec2Sym::new(
+            "web01".to_string(),
+            "imported from \"i-1\" ${x}".to_string(),
+            "t3.small".to_string(),
+            "ami-1".to_string(),
+            "subnet-1".to_string(),
+            vec!["sg-1".to_string(), "sg-2".to_string()],
+            0.0,
+            1,
+            "key".to_string(),
+        );
+        ec2.set_tags(BTreeMap::from([(
+            "cost center".to_string(),
+            "42".to_string(),
+        )]));
+        let s = format!(
+            "aws {{\n  region = \"eu-west-1\"\n  name = \"stack\"\n  description = \"d\"\n{}\n}}\n",
+            ec2_block(&ec2, 2)
+        );
+        let mut parser = Parser::new(Scanner::new("".to_string(), s));
+        let aws_sym = walk_ast(&parser.parse().unwrap()).unwrap().remove(0);
+        let parsed = &aws_sym.ec2s[0];
+        assert_eq!(parsed.name, "web01");
+        assert_eq!(parsed.desc, "imported from 'i-1' ${x}");
+        assert_eq!(parsed.instance_type, "t3.small");
+        assert_eq!(parsed.count, 1);
+        assert_eq!(parsed.app_version, 0.0);
+        assert_eq!(parsed.sg_ids, vec!["sg-1", "sg-2"]);
+        assert_eq!(parsed.tags["cost center"], "42");
+        assert_eq!(parsed.key_name, "key");
+    }
+}
@@ -2,12 +2,26 @@ use std::error;
 use std::fmt;
 use std::fs;
 
+/// A piece of a string literal: plain text, or the source of a `${...}`
+/// interpolation with the location of its `$`
+#[derive(PartialEq, Clone, Debug)]
+pub(crate) enum StrPart {
+    Text(String),
+    Interp {
+        source: String,
+        line_no: usize,
+        column_no: usize,
+    },
+}
+
 pub(crate) struct Token {
     pub(crate) token_type: TokenType,
     pub(crate) lexeme: String,
     pub(crate) literal: Option<String>,
     pub(crate) line_no: usize,
     pub(crate) column_no: usize,
+    /// the pieces of a string literal, empty for other tokens
+    pub(crate) parts: Vec<StrPart>,
 }
 
 impl Token {
@@ -24,6 +38,7 @@ impl Token {
             literal: literal,
             line_no: line_no,
             column_no: column_no,
+            parts: vec![],
         }
     }
 }
@@ -38,9 +53,7 @@ impl fmt::Display for Token {
             Some(x) => {
                 format!("{}, literal: {}", s, x)
             }
-            _ => {
-                format!("{}", s)
-            }
+            _ => s.to_string(),
         };
         write!(f, "{}", lit)
     }
@@ -48,16 +61,14 @@ impl fmt::Display for Token {
 
 impl Clone for Token {
     fn clone(&self) -> Self {
-        let lit = match &self.literal {
-            Some(x) => Some(x.to_string()),
-            _ => None,
-        };
+        let lit = self.literal.as_ref().map(|x| x.to_string());
         Self {
             token_type: self.token_type.clone(),
             lexeme: self.lexeme.to_string(),
             literal: lit,
             line_no: self.line_no,
             column_no: self.column_no,
+            parts: self.parts.clone(),
         }
     }
 }
@@ -70,6 +81,8 @@ pub(crate) enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     // markers
     SemiColon,
     Colon,
@@ -86,7 +99,11 @@ pub(crate) enum TokenType {
     LessEqual,
     GreaterEqual,
     Minus,
+    Plus,
     Div,
+    Percent,
+    And,
+    Or,
     // others
     Comment,
     StringLiteral,
@@ -99,52 +116,124 @@ pub(crate) enum TokenType {
 
 impl fmt::Display for TokenType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let tok_s = match self {
-            &TokenType::LeftParen => "LEFT_PAREN",
-            &TokenType::RightParen => "RIGHT_PAREN",
-            &TokenType::LeftBrace => "LEFT_BRACE",
-            &TokenType::RightBrace => "RIGHT_BRACE",
-            &TokenType::SemiColon => "SEMICOLON",
-            &TokenType::Colon => "COLON",
-            &TokenType::Dot => "DOT",
-            &TokenType::Star => "STAR",
-            &TokenType::Comma => "COMMA",
-            &TokenType::Div => "DIV",
-            &TokenType::Minus => "MINUS",
-            &TokenType::Equal => "EQUAL",
-            &TokenType::Bang => "BANG",
-            &TokenType::Less => "LESS",
-            &TokenType::Greater => "GREATER",
-            &TokenType::BangEqual => "BANG_EQUAL",
-            &TokenType::EqualEqual => "EQUAL_EQUAL",
-            &TokenType::LessEqual => "LESS_EQUAL",
-            &TokenType::GreaterEqual => "GREATER_EQUAL",
-            &TokenType::Comment => "COMMENT",
-            &TokenType::StringLiteral => "STRING_LITERAL",
-            &TokenType::Number => "NUMBER",
-            &TokenType::Identifier => "IDENTIFIER",
-            &TokenType::Keyword => "KEYWORD",
-            &TokenType::EoF => "EOF",
+        let tok_s = match *self {
+            TokenType::LeftParen => "LEFT_PAREN",
+            TokenType::RightParen => "RIGHT_PAREN",
+            TokenType::LeftBrace => "LEFT_BRACE",
+            TokenType::RightBrace => "RIGHT_BRACE",
+            TokenType::LeftBracket => "LEFT_BRACKET",
+            TokenType::RightBracket => "RIGHT_BRACKET",
+            TokenType::SemiColon => "SEMICOLON",
+            TokenType::Colon => "COLON",
+            TokenType::Dot => "DOT",
+            TokenType::Star => "STAR",
+            TokenType::Comma => "COMMA",
+            TokenType::Div => "DIV",
+            TokenType::Minus => "MINUS",
+            TokenType::Plus => "PLUS",
+            TokenType::Percent => "PERCENT",
+            TokenType::And => "AND",
+            TokenType::Or => "OR",
+            TokenType::Equal => "EQUAL",
+            TokenType::Bang => "BANG",
+            TokenType::Less => "LESS",
+            TokenType::Greater => "GREATER",
+            TokenType::BangEqual => "BANG_EQUAL",
+            TokenType::EqualEqual => "EQUAL_EQUAL",
+            TokenType::LessEqual => "LESS_EQUAL",
+            TokenType::GreaterEqual => "GREATER_EQUAL",
+            TokenType::Comment => "COMMENT",
+            TokenType::StringLiteral => "STRING_LITERAL",
+            TokenType::Number => "NUMBER",
+            TokenType::Identifier => "IDENTIFIER",
+            TokenType::Keyword => "KEYWORD",
+            TokenType::EoF => "EOF",
         };
         write!(f, "tok: {}", tok_s)
     }
 }
 
-static KEYWORDS: [&str; 14] = [
+/// Words scanned as keywords. They are only reserved where the parser
+/// expects a block or an attribute: a variable can take any of them as its
+/// name, except the literals `true` and `false`.
+static KEYWORDS: [&str; 77] = [
     "aws",
     "ec2",
     "ec2_id",
     "name",
     "description",
     "count",
+    "for_each",
+    "when",
+    "if",
+    "else",
+    "import",
+    "module",
+    "source",
     "app_version",
     "image",
     "ami",
     "subnet_id",
     "instance_type",
     "sg_id",
+    "sg_ids",
+    "tags",
     "region",
+    "profile",
+    "role_arn",
+    "assume_role",
+    "session_name",
+    "external_id",
     "key_name",
+    "s3",
+    "versioning",
+    "encryption",
+    "public_access_block",
+    "lifecycle",
+    "expiration_days",
+    "noncurrent_expiration_days",
+    "dynamodb",
+    "hash_key",
+    "range_key",
+    "attributes",
+    "billing_mode",
+    "read_capacity",
+    "write_capacity",
+    "ttl_attribute",
+    "gsi",
+    "projection",
+    "vpc",
+    "cidr",
+    "subnet",
+    "az",
+    "public",
+    "internet_gateway",
+    "nat_gateway",
+    "route_table",
+    "route",
+    "gateway",
+    "subnets",
+    "security_group",
+    "vpc_id",
+    "ingress",
+    "egress",
+    "from",
+    "to",
+    "protocol",
+    "source_sg",
+    "key_pair",
+    "public_key_file",
+    "generate",
+    "private_key_file",
+    "state",
+    "bucket",
+    "prefix",
+    "lock_table",
+    "endpoint",
+    "var",
+    "let",
+    "true",
+    "false",
 ];
 
 /// Scanner
@@ -196,24 +285,20 @@ impl Scanner {
     pub(crate) fn read_until_eol(&mut self) {
         while self.current < self.contents.len() {
             let c = self.advance();
-            match c {
-                Some('\n') => {
-                    self.line += 1;
-                    self.column_no = 0;
-                    return;
-                }
-                Some('\r') => {
-                    self.line += 1;
-                    self.column_no = 0;
-                    return;
-                }
-                _ => {}
+            if let Some('\n') = c {
+                self.line += 1;
+                self.column_no = 0;
+                return;
             }
         }
     }
 
-    /// read until end of a quote, handles multi lines
-    pub(crate) fn read_until_eo_quote(&mut self) {
+    /// read until end of a quote, handles multi lines. The contents are
+    /// split into text and `${...}` interpolations; `$${` is a literal
+    /// `${`. An interpolation left open is recorded in `errors`.
+    pub(crate) fn read_until_eo_quote(&mut self) -> Vec<StrPart> {
+        let mut parts: Vec<StrPart> = vec![];
+        let mut text = String::from("");
         while self.current < self.contents.len() {
             let c = self.advance();
             match c {
@@ -221,47 +306,73 @@ impl Scanner {
                     self.line += 1;
                     // println!("n, {}", self.column_no);
                     self.column_no = 0;
-                }
-                Some('\r') => {
-                    self.line += 1;
-                    // println!("r, {}", self.column_no);
-                    self.column_no = 0;
+                    text.push('\n');
                 }
                 Some('"') => {
                     // println!("\nend of quote, {}", self.column_no);
-                    return;
+                    break;
+                }
+                Some('$') if self.contents[self.current..].starts_with("${") => {
+                    self.advance();
+                    text.push('$');
                 }
-                _ => {}
+                Some('$') if self.peek() == Some('{') => {
+                    let (line_no, column_no) = (self.line, self.column_no);
+                    self.advance();
+                    let start = self.current;
+                    let end = match self.contents[start..].find(['}', '"', '\n']) {
+                        Some(n) if self.contents[start + n..].starts_with('}') => start + n,
+                        _ => {
+                            self.errors.push(format!(
+                                "unterminated interpolation at ({},{}), expecting }}",
+                                line_no, column_no
+                            ));
+                            text.push_str("${");
+                            continue;
+                        }
+                    };
+                    if !text.is_empty() {
+                        parts.push(StrPart::Text(text));
+                        text = String::from("");
+                    }
+                    parts.push(StrPart::Interp {
+                        source: self.contents[start..end].to_string(),
+                        line_no: line_no,
+                        column_no: column_no,
+                    });
+                    while self.current <= end {
+                        self.advance();
+                    }
+                }
+                Some(x) => text.push(x),
+                None => {}
             }
         }
+        if !text.is_empty() || parts.is_empty() {
+            parts.push(StrPart::Text(text));
+        }
+        parts
     }
 
-    /// scan for an integer or a decimal
+    /// scan for an integer or a decimal, stops before the first character
+    /// that isn't part of the number
     pub(crate) fn scan_number(&mut self) {
-        while self.current < self.contents.len() {
-            let c = self.advance();
-            match c {
-                Some('.') => {} // decimal number
-                _ => {
-                    if let Some(n) = c {
-                        if !n.is_numeric() {
-                            return;
-                        }
-                    }
-                }
+        while let Some(c) = self.peek() {
+            if !c.is_numeric() && c != '.' {
+                return;
             }
+            self.advance();
         }
     }
 
-    /// identifier can contain alphanumeric and '_'
+    /// identifier can contain alphanumeric and '_', stops before the first
+    /// character that isn't part of it
     pub(crate) fn scan_lexeme_with_underscore(&mut self) {
-        while self.current < self.contents.len() {
-            let c = self.advance();
-            if let Some(x) = c {
-                if !x.is_alphanumeric() && x != '_' {
-                    return;
-                }
+        while let Some(c) = self.peek() {
+            if !c.is_alphanumeric() && c != '_' {
+                return;
             }
+            self.advance();
         }
     }
 
@@ -269,9 +380,10 @@ impl Scanner {
     pub(crate) fn emit_token(&mut self, tok_type: TokenType, literal: Option<String>) -> Token {
         let curr_str = &self.contents[self.start..self.current];
         let len_tok = self.current - self.start;
-        let mut start_col_no = 0;
+        // columns count from 1, `column_no` is the last character read
+        let mut start_col_no = 1;
         if self.column_no > len_tok {
-            start_col_no = self.column_no - len_tok;
+            start_col_no = self.column_no - len_tok + 1;
         }
         return Token::new(
             tok_type,
@@ -282,7 +394,7 @@ impl Scanner {
         );
     }
 
-    /// Start scanning the tokens from start
+    // Start scanning the tokens from start
     // pub(crate) fn scan_tokens(&mut self) {
     //     println!("=> scanning tokens from {}", self.source);
     //     while self.current < self.contents.len() {
@@ -299,18 +411,11 @@ impl Scanner {
     //     self.tokens.push(eof_tok);
     // }
 
-    pub(crate) fn backup(&mut self) {
-        self.current = self.start;
-    }
-
     pub(crate) fn next_token(&mut self) -> Token {
         while self.current < self.contents.len() {
             self.start = self.current;
-            match self.scan_token() {
-                Some(tok) => {
-                    return tok;
-                }
-                _ => {}
+            if let Some(tok) = self.scan_token() {
+                return tok;
             }
         }
         let eof_tok = Token::new(
@@ -343,6 +448,12 @@ impl Scanner {
             Some('}') => {
                 return Some(self.emit_token(TokenType::RightBrace, None));
             }
+            Some('[') => {
+                return Some(self.emit_token(TokenType::LeftBracket, None));
+            }
+            Some(']') => {
+                return Some(self.emit_token(TokenType::RightBracket, None));
+            }
             Some(';') => {
                 return Some(self.emit_token(TokenType::SemiColon, None));
             }
@@ -361,15 +472,29 @@ impl Scanner {
             Some('-') => {
                 return Some(self.emit_token(TokenType::Minus, None));
             }
+            Some('+') => {
+                return Some(self.emit_token(TokenType::Plus, None));
+            }
+            Some('%') => {
+                return Some(self.emit_token(TokenType::Percent, None));
+            }
+            // `&&` and `||`, a single `&` or `|` is not an operator
+            Some('&') if self.peek() == Some('&') => {
+                self.advance();
+                return Some(self.emit_token(TokenType::And, None));
+            }
+            Some('|') if self.peek() == Some('|') => {
+                self.advance();
+                return Some(self.emit_token(TokenType::Or, None));
+            }
             // handle two character operators
             Some('<') => {
                 // peek, if '=', then LessEqual
                 // otherwise emit Equal
                 match self.peek() {
                     Some('=') => {
-                        let tok = self.emit_token(TokenType::LessEqual, None);
-                        self.current += 1;
-                        return Some(tok);
+                        self.advance();
+                        return Some(self.emit_token(TokenType::LessEqual, None));
                     }
                     _ => {
                         return Some(self.emit_token(TokenType::Less, None));
@@ -381,9 +506,8 @@ impl Scanner {
                 // otherwise emit Greater
                 match self.peek() {
                     Some('=') => {
-                        let tok = self.emit_token(TokenType::GreaterEqual, None);
-                        self.current += 1;
-                        return Some(tok);
+                        self.advance();
+                        return Some(self.emit_token(TokenType::GreaterEqual, None));
                     }
                     _ => {
                         return Some(self.emit_token(TokenType::Greater, None));
@@ -395,9 +519,8 @@ impl Scanner {
                 // otherwise emit bang
                 match self.peek() {
                     Some('=') => {
-                        let tok = self.emit_token(TokenType::BangEqual, None);
-                        self.current += 1;
-                        return Some(tok);
+                        self.advance();
+                        return Some(self.emit_token(TokenType::BangEqual, None));
                     }
                     _ => {
                         return Some(self.emit_token(TokenType::Bang, None));
@@ -409,9 +532,8 @@ impl Scanner {
                 // otherwise emit Equal
                 match self.peek() {
                     Some('=') => {
-                        let tok = self.emit_token(TokenType::EqualEqual, None);
-                        self.current += 1;
-                        return Some(tok);
+                        self.advance();
+                        return Some(self.emit_token(TokenType::EqualEqual, None));
                     }
                     _ => {
                         return Some(self.emit_token(TokenType::Equal, None));
@@ -423,9 +545,9 @@ impl Scanner {
                 // otherwise emit Div
                 match self.peek() {
                     Some('/') => {
+                        // the end of line is consumed with the comment
                         self.read_until_eol();
                         let tok = self.emit_token(TokenType::Comment, None);
-                        self.current += 1;
                         return Some(tok);
                     }
                     _ => {
@@ -436,9 +558,10 @@ impl Scanner {
             // handle string literal
             Some('"') => {
                 //TODO: lose the double quotes at both ends?
-                self.read_until_eo_quote();
+                let parts = self.read_until_eo_quote();
                 let literal = &self.contents[self.start + 1..self.current - 1];
-                let tok = self.emit_token(TokenType::StringLiteral, Some(literal.to_string()));
+                let mut tok = self.emit_token(TokenType::StringLiteral, Some(literal.to_string()));
+                tok.parts = parts;
                 return Some(tok);
                 // println!("\nquote, final: {}", self.colu
                 // println!(
@@ -454,15 +577,13 @@ impl Scanner {
             Some('\t') => {
                 return None;
             }
+            // a CRLF counts as one line, on the '\n'
             Some('\r') => {
-                self.line += 1;
-                self.column_no = 1;
-                // println!("main r, final: {}", self.column_no);
                 return None;
             }
             Some('\n') => {
                 self.line += 1;
-                self.column_no = 1;
+                self.column_no = 0;
                 return None;
                 // println!("main n, final: {}", self.column_no);
             }
@@ -476,7 +597,6 @@ impl Scanner {
                     // println!("other inside: {}", self.column_no);
                     if x.is_numeric() {
                         self.scan_number();
-                        self.current -= 1;
                         let n = &self.contents[self.start..self.current];
                         let tok = self.emit_token(TokenType::Number, Some(n.to_string()));
                         // self.current += 1;
@@ -488,19 +608,15 @@ impl Scanner {
                         // identifiers can contain digits as long as they don't
                         // start with a digit.
                         self.scan_lexeme_with_underscore();
-                        let s = &self.contents[self.start..self.current - 1];
+                        let s = &self.contents[self.start..self.current];
                         // println!("s: {}", s);
                         if KEYWORDS.contains(&s) {
-                            self.current -= 1;
                             let tok = self.emit_token(TokenType::Keyword, Some(s.to_string()));
                             println!("==> adding keyword, {}", tok);
-                            self.current += 1;
                             return Some(tok);
                         } else {
-                            self.current -= 1;
                             println!("==> adding identifier!");
                             let tok = self.emit_token(TokenType::Identifier, Some(s.to_string()));
-                            self.current += 1;
                             return Some(tok);
                         }
                     }
@@ -515,7 +631,7 @@ impl Scanner {
 /// Report an error using the line number and a short message
 pub(crate) fn error(line: u32, msg: &str) {
     // also add to errors
-    report(line, "".to_string().as_str(), &msg);
+    report(line, "".to_string().as_str(), msg);
 }
 
 /// Print the error message
@@ -580,6 +696,101 @@ mod tests {
         assert_eq!(tok.lexeme, s);
     }
 
+    #[test]
+    fn test_identifier_keeps_next_char() {
+        let s = "ec2 { sg_id = web_sg}\nlet x = 10";
+        let mut scanr = Scanner::new("".to_string(), s.to_string());
+        for _ in 1..=4 {
+            scanr.next_token();
+        }
+        let tok = scanr.next_token();
+        assert_eq!(tok.token_type, TokenType::Identifier);
+        assert_eq!(tok.lexeme, "web_sg");
+        assert_eq!(tok.column_no, 15);
+        assert_eq!(scanr.next_token().token_type, TokenType::RightBrace);
+        let tok = scanr.next_token();
+        assert_eq!(tok.lexeme, "let");
+        assert_eq!((tok.line_no, tok.column_no), (2, 1));
+        scanr.next_token();
+        scanr.next_token();
+        // a number at the end of the file is kept whole
+        assert_eq!(scanr.next_token().lexeme, "10");
+        assert_eq!(scanr.next_token().token_type, TokenType::EoF);
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let s = "name = \"web-${env}-$${x}\nb${ index }\"";
+        let mut scanr = Scanner::new("".to_string(), s.to_string());
+        scanr.next_token();
+        scanr.next_token();
+        let tok = scanr.next_token();
+        assert_eq!(
+            tok.parts,
+            vec![
+                StrPart::Text("web-".to_string()),
+                StrPart::Interp {
+                    source: "env".to_string(),
+                    line_no: 1,
+                    column_no: 13,
+                },
+                StrPart::Text("-${x}\nb".to_string()),
+                StrPart::Interp {
+                    source: " index ".to_string(),
+                    line_no: 2,
+                    column_no: 2,
+                },
+            ]
+        );
+        assert_eq!(scanr.next_token().token_type, TokenType::EoF);
+
+        let mut scanr = Scanner::new("".to_string(), "\"web-${env\"".to_string());
+        let tok = scanr.next_token();
+        assert_eq!(tok.parts, vec![StrPart::Text("web-${env".to_string())]);
+        assert_eq!(
+            scanr.errors,
+            vec!["unterminated interpolation at (1,6), expecting }"]
+        );
+    }
+
+    #[test]
+    fn test_operators() {
+        let s = "a <= 2 && b != c || !d + 1 % 2 [x]";
+        let mut scanr = Scanner::new("".to_string(), s.to_string());
+        let mut toks: Vec<(TokenType, String)> = vec![];
+        loop {
+            let tok = scanr.next_token();
+            if tok.token_type == TokenType::EoF {
+                break;
+            }
+            toks.push((tok.token_type, tok.lexeme));
+        }
+        let types: Vec<&TokenType> = toks.iter().map(|(t, _)| t).collect();
+        assert_eq!(
+            types,
+            vec![
+                &TokenType::Identifier,
+                &TokenType::LessEqual,
+                &TokenType::Number,
+                &TokenType::And,
+                &TokenType::Identifier,
+                &TokenType::BangEqual,
+                &TokenType::Identifier,
+                &TokenType::Or,
+                &TokenType::Bang,
+                &TokenType::Identifier,
+                &TokenType::Plus,
+                &TokenType::Number,
+                &TokenType::Percent,
+                &TokenType::Number,
+                &TokenType::LeftBracket,
+                &TokenType::Identifier,
+                &TokenType::RightBracket,
+            ]
+        );
+        assert_eq!(toks[1].1, "<=");
+    }
+
     #[test]
     fn test_comment() {
         let s = "aws 
@@ -1,51 +1,277 @@
-use crate::aws::{AwsSym, ParseTree};
+use crate::actions::instances::InstanceFilter;
+use crate::aws::{AwsSym, ParseTree, source};
 use crate::lex::{error, read_lex_file};
+use crate::state::Backend;
 
 use std::env;
+use std::process;
 
 mod actions;
 mod aws;
 mod lex;
+mod state;
 mod symbols;
 
+/// Run mode, given as the first argument. Defaults to `apply` when only
+/// a file is given.
+enum Mode {
+    Plan,
+    Apply,
+    Destroy,
+    Drift,
+    /// `import <file> <ec2-name> <instance-id> [--emit]`, `emit` prints
+    /// the equivalent ec2 block
+    Import {
+        ec2_name: String,
+        instance_id: String,
+        emit: bool,
+    },
+}
+
+/// Exit code of `drift` when something changed out of band
+const DRIFT_EXIT_CODE: i32 = 2;
+
+/// Parse the args into the run mode and the dsl file
+fn parse_args(args: &[String]) -> Option<(Mode, &String)> {
+    match args.get(1).map(|s| s.as_str()) {
+        Some("plan") => args.get(2).map(|f| (Mode::Plan, f)),
+        Some("apply") => args.get(2).map(|f| (Mode::Apply, f)),
+        Some("destroy") => args.get(2).map(|f| (Mode::Destroy, f)),
+        Some("drift") => args.get(2).map(|f| (Mode::Drift, f)),
+        Some("import") => match (args.get(2), args.get(3), args.get(4)) {
+            (Some(f), Some(ec2_name), Some(instance_id)) => Some((
+                Mode::Import {
+                    ec2_name: ec2_name.to_string(),
+                    instance_id: instance_id.to_string(),
+                    emit: args.get(5).map(|a| a == "--emit") == Some(true),
+                },
+                f,
+            )),
+            _ => None,
+        },
+        Some(_) => args.get(1).map(|f| (Mode::Apply, f)),
+        None => None,
+    }
+}
+
+/// Parse the args of `generate <region> [--name <stack>] [--tag <key>[=<value>]]
+/// [--vpc <vpc-id>]` into the region, the stack name and the filter
+fn parse_generate_args(args: &[String]) -> Option<(String, String, InstanceFilter)> {
+    let region = args.first()?.to_string();
+    let mut stack = region.to_string();
+    let mut filter = InstanceFilter::default();
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        let value = rest.next()?;
+        match arg.as_str() {
+            "--name" => stack = value.to_string(),
+            "--vpc" => filter.vpc_id = Some(value.to_string()),
+            "--tag" => match value.split_once('=') {
+                Some((k, v)) => {
+                    filter.tag_key = Some(k.to_string());
+                    filter.tag_value = Some(v.to_string());
+                }
+                None => filter.tag_key = Some(value.to_string()),
+            },
+            _ => return None,
+        }
+    }
+    Some((region, stack, filter))
+}
+
+/// Print the dsl document of the existing instances, so it can be
+/// redirected to a file
+async fn generate(args: &[String]) {
+    let (region, stack, filter) = match parse_generate_args(args) {
+        Some(parsed) => parsed,
+        None => {
+            eprintln!(
+                "usage: generate <region> [--name <stack>] [--tag <key>[=<value>]] [--vpc <vpc-id>]"
+            );
+            process::exit(1);
+        }
+    };
+    match actions::generate_aws(&stack, &region, &filter).await {
+        Ok(doc) => {
+            print!("{}", doc);
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e.show());
+            process::exit(1);
+        }
+    }
+}
+
+/// `--dry-run` checks the calls of `apply` and `destroy` without making
+/// any change
+const DRY_RUN_FLAG: &'static str = "--dry-run";
+
+/// Run `mode` on one stack, returns the exit code: 0, 1 on error or
+/// `DRIFT_EXIT_CODE`
+async fn run(mode: &Mode, aws_sym: &AwsSym, backend: &Backend, dry_run: bool) -> i32 {
+    println!("=> stack: {} ({})", aws_sym.name, aws_sym.region);
+    let result = match mode {
+        Mode::Plan => actions::plan_aws(aws_sym, backend).await.map(|plan| {
+            println!("{}", plan);
+            0
+        }),
+        Mode::Apply => actions::apply_aws(aws_sym, backend, dry_run)
+            .await
+            .map(|_| {
+                if dry_run {
+                    println!("=> dry run passed!");
+                }
+                0
+            }),
+        Mode::Destroy => actions::destroy_aws(aws_sym, backend, dry_run)
+            .await
+            .map(|_| {
+                if dry_run {
+                    println!("=> dry run passed!");
+                } else {
+                    println!("=> destroyed!");
+                }
+                0
+            }),
+        Mode::Drift => actions::drift_aws(aws_sym, backend).await.map(|drifts| {
+            if drifts.is_empty() {
+                println!("=> no drift!");
+                return 0;
+            }
+            for drift in &drifts {
+                println!("{}", drift);
+            }
+            println!("=> {} attribute(s) drifted!", drifts.len());
+            DRIFT_EXIT_CODE
+        }),
+        Mode::Import {
+            ec2_name,
+            instance_id,
+            emit,
+        } => actions::import_aws(aws_sym, backend, ec2_name, instance_id)
+            .await
+            .map(|ec2| {
+                if *emit {
+                    println!("{}", source::ec2_block(&ec2, 2));
+                }
+                0
+            }),
+    };
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {}", e.show());
+            1
+        }
+    }
+}
+
+/// The stacks `mode` runs on: all of them, except for an import which
+/// goes to the stack declaring the ec2 resource, or the only stack
+fn stacks_for<'a>(mode: &Mode, aws_syms: &'a [AwsSym]) -> Result<Vec<&'a AwsSym>, String> {
+    let ec2_name = match mode {
+        Mode::Import { ec2_name, .. } => ec2_name,
+        _ => return Ok(aws_syms.iter().collect()),
+    };
+    let declaring: Vec<&AwsSym> = aws_syms
+        .iter()
+        .filter(|a| a.ec2s.iter().any(|e| &e.name == ec2_name))
+        .collect();
+    match (declaring.len(), aws_syms.len()) {
+        (1, _) => Ok(declaring),
+        (0, 1) => Ok(aws_syms.iter().collect()),
+        (0, _) => Err(format!(
+            "ec2 `{}` is not declared in any aws block, declare it before importing",
+            ec2_name
+        )),
+        _ => Err(format!(
+            "ec2 `{}` is declared in several aws blocks",
+            ec2_name
+        )),
+    }
+}
+
+/// Run `mode` on every stack in the order of the file. The exit code is
+/// 1 if a stack failed, else the highest code.
+async fn run_stacks(
+    mode: Mode,
+    dslf: &str,
+    aws_syms: &[AwsSym],
+    source_hash: String,
+    dry_run: bool,
+) {
+    let stacks = match stacks_for(&mode, aws_syms) {
+        Ok(stacks) => stacks,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+    let mut codes: Vec<i32> = vec![];
+    for aws_sym in stacks {
+        let backend = Backend::open(dslf, aws_sym, source_hash.to_string()).await;
+        codes.push(run(&mode, aws_sym, &backend, dry_run).await);
+    }
+    let code = match codes.contains(&1) {
+        true => 1,
+        false => codes.into_iter().max().unwrap_or_default(),
+    };
+    if code != 0 {
+        process::exit(code);
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() > 1 {
-        let dslf = &args[1];
+    let dry_run = args.iter().any(|a| a == DRY_RUN_FLAG);
+    let args: Vec<String> = args.into_iter().filter(|a| a != DRY_RUN_FLAG).collect();
+    if args.get(1).map(|s| s.as_str()) == Some("generate") {
+        generate(&args[2..]).await;
+        return;
+    }
+    if let Some((mode, dslf)) = parse_args(&args) {
         println!("=> running file: {}", dslf);
         match read_lex_file(dslf.as_str()) {
             Ok(my_scanner) => {
                 // my_scanner.scan_tokens();
-                if my_scanner.errors.len() > 0 {
-                    error(0, "Lexer had errors!");
+                if !my_scanner.errors.is_empty() {
+                    error(0, &format!("Lexer had errors in {}!", my_scanner.source));
+                    process::exit(1);
                 }
+                let contents = my_scanner.contents.to_string();
                 let mut aws_parser = aws::parser::Parser::new(my_scanner);
                 match aws_parser.parse() {
-                    Ok(aws_node) => {
-                        println!("\nparse tree:\n{}", aws_node.print_ast(2));
-                        match symbols::walk_ast(&aws_node) {
-                            Ok(aws_sym) => {
-                                println!("Aws resources:{}", aws_sym);
-                                match actions::apply_aws(&aws_sym).await {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        eprintln!("Error: {}", e.show());
-                                    }
+                    Ok(program) => {
+                        // the imported and module files are part of the source
+                        let source_hash = state::hash_source(&format!(
+                            "{}{}",
+                            contents,
+                            program.sources.concat()
+                        ));
+                        println!("\nparse tree:\n{}", program.print_ast(2));
+                        match symbols::walk_ast(&program) {
+                            Ok(aws_syms) => {
+                                for aws_sym in &aws_syms {
+                                    println!("Aws resources:{}", aws_sym);
                                 }
+                                run_stacks(mode, dslf, &aws_syms, source_hash, dry_run).await;
                             }
                             Err(e) => {
                                 println!("AST -> Symbol Error: {}", e);
+                                process::exit(1);
                             }
                         }
                     }
                     Err(e) => {
                         println!("Error: {}", e);
+                        process::exit(1);
                     }
                 }
             }
             Err(e) => {
-                println!("err: {}", e.to_string())
+                println!("err: {}", e);
+                process::exit(1);
             }
         }
     } else {
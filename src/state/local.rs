@@ -0,0 +1,119 @@
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::state::{StackState, StateError, lock_holder, stack_file_name};
+
+/// Directory, next to the dsl file, holding the state files
+const STATE_DIR: &'static str = ".awsdsl";
+
+/// State kept in a local json file, locked through a `.lock` file next
+/// to it
+pub(crate) struct LocalBackend {
+    pub(crate) path: PathBuf,
+    pub(crate) lock_path: PathBuf,
+    pub(crate) source_hash: String,
+}
+
+impl LocalBackend {
+    /// State of `stack` lives in `.awsdsl/<stack>.json` next to `dsl_file`
+    pub(crate) fn new(dsl_file: &str, stack: &str, source_hash: String) -> Self {
+        let dir = Path::new(dsl_file)
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(STATE_DIR);
+        let stack_file = stack_file_name(stack);
+        LocalBackend {
+            path: dir.join(format!("{}.json", stack_file)),
+            lock_path: dir.join(format!("{}.lock", stack_file)),
+            source_hash: source_hash,
+        }
+    }
+
+    pub(crate) fn read(&self) -> Result<Option<StackState>, StateError> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let s = fs::read_to_string(&self.path)
+            .map_err(|e| StateError::new(format!("reading {}: {}", self.path.display(), e)))?;
+        StackState::from_json(&s).map(Some)
+    }
+
+    /// The file is replaced through a rename so an interrupted write never
+    /// leaves half a state behind.
+    pub(crate) fn write(&self, state: &StackState) -> Result<(), StateError> {
+        let s = state.to_json()?;
+        self.create_dir()?;
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, s).map_err(|e| StateError::new(e.to_string()))?;
+        fs::rename(&tmp, &self.path)
+            .map_err(|e| StateError::new(format!("writing {}: {}", self.path.display(), e)))?;
+        println!("=> state written to {}", self.path.display());
+        Ok(())
+    }
+
+    /// The lock file is created exclusively, so only one run gets it
+    pub(crate) fn lock(&self) -> Result<(), StateError> {
+        self.create_dir()?;
+        let lock_file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&self.lock_path);
+        match lock_file {
+            Ok(mut f) => f
+                .write_all(lock_holder().as_bytes())
+                .map_err(|e| StateError::new(e.to_string())),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                let holder = fs::read_to_string(&self.lock_path).unwrap_or_default();
+                let s = format!(
+                    "state is locked by {}, remove {} if that run is gone",
+                    holder,
+                    self.lock_path.display()
+                );
+                Err(StateError::new(s))
+            }
+            Err(e) => Err(StateError::new(e.to_string())),
+        }
+    }
+
+    pub(crate) fn unlock(&self) -> Result<(), StateError> {
+        fs::remove_file(&self.lock_path)
+            .map_err(|e| StateError::new(format!("unlocking {}: {}", self.lock_path.display(), e)))
+    }
+
+    fn create_dir(&self) -> Result<(), StateError> {
+        match self.path.parent() {
+            Some(dir) => fs::create_dir_all(dir).map_err(|e| StateError::new(e.to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::hash_source;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_state_path() {
+        let local = LocalBackend::new("envs/demo.aws", "eu west kv", hash_source(""));
+        assert_eq!(local.path, Path::new("envs/.awsdsl/eu_west_kv.json"));
+        assert_eq!(local.lock_path, Path::new("envs/.awsdsl/eu_west_kv.lock"));
+        assert_eq!(local.source_hash.len(), 64);
+    }
+
+    #[test]
+    fn test_lock_is_exclusive() {
+        let dir = env::temp_dir().join(format!("awsdsl-lock-{}", process::id()));
+        let dsl_file = dir.join("demo.aws");
+        let local = LocalBackend::new(dsl_file.to_str().unwrap(), "stack", hash_source(""));
+        local.lock().unwrap();
+        assert!(local.lock().is_err());
+        local.unlock().unwrap();
+        local.lock().unwrap();
+        local.unlock().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,430 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::process;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::aws::{AwsSym, DynamoSym, Ec2Sym, KeyPairSym, NetworkSym, S3Sym, SecurityGroupSym};
+use crate::state::local::LocalBackend;
+use crate::state::s3::S3Backend;
+
+pub(crate) mod local;
+pub(crate) mod s3;
+
+/// Version of the state file layout, bumped on incompatible changes
+pub(crate) const STATE_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub(crate) struct StateError {
+    msg: String,
+}
+
+impl StateError {
+    pub(crate) fn new(msg: impl std::convert::Into<String>) -> Self {
+        Self { msg: msg.into() }
+    }
+}
+
+impl Error for StateError {}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "State Error: {}", self.msg)
+    }
+}
+
+/// What awsdsl deployed for one `Ec2Sym`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Ec2State {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) instance_ids: Vec<String>,
+    pub(crate) instance_type: String,
+    pub(crate) ami_id: String,
+    pub(crate) subnet_id: String,
+    pub(crate) sg_ids: Vec<String>,
+    pub(crate) key_name: String,
+    pub(crate) count: u8,
+    pub(crate) app_version: f32,
+    /// instance id -> index among the `count` instances
+    #[serde(default)]
+    pub(crate) indexes: BTreeMap<String, u32>,
+    /// user tags, on top of the ones awsdsl sets
+    #[serde(default)]
+    pub(crate) tags: BTreeMap<String, String>,
+    /// the `for_each` key or `count` index of an expanded resource
+    #[serde(default)]
+    pub(crate) key: Option<String>,
+}
+
+impl Ec2State {
+    /// Record the attributes of `ec2` deployed as the instances in
+    /// `indexes`
+    pub(crate) fn new(ec2: &Ec2Sym, indexes: BTreeMap<String, u32>) -> Self {
+        let mut instance_ids: Vec<String> = indexes.keys().cloned().collect();
+        instance_ids.sort_by_key(|i| indexes.get(i).copied());
+        Ec2State {
+            id: ec2.id.to_string(),
+            name: ec2.name.to_string(),
+            instance_ids: instance_ids,
+            indexes: indexes,
+            instance_type: ec2.instance_type.to_string(),
+            ami_id: ec2.ami_id.to_string(),
+            subnet_id: ec2.subnet_id.to_string(),
+            sg_ids: ec2.sg_ids.clone(),
+            key_name: ec2.key_name.to_string(),
+            count: ec2.count,
+            app_version: ec2.app_version,
+            tags: ec2.tags.clone(),
+            key: ec2.key.clone(),
+        }
+    }
+}
+
+/// The bucket awsdsl created, or took over, for one `S3Sym`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct BucketState {
+    pub(crate) id: String,
+    pub(crate) name: String,
+}
+
+impl BucketState {
+    pub(crate) fn new(s3: &S3Sym) -> Self {
+        BucketState {
+            id: s3.id.to_string(),
+            name: s3.name.to_string(),
+        }
+    }
+}
+
+/// The table awsdsl created, or took over, for one `DynamoSym`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct TableState {
+    pub(crate) id: String,
+    pub(crate) name: String,
+}
+
+impl TableState {
+    pub(crate) fn new(table: &DynamoSym) -> Self {
+        TableState {
+            id: table.id.to_string(),
+            name: table.name.to_string(),
+        }
+    }
+}
+
+/// The networking resource AWS created for one `NetworkSym`, `aws_id`
+/// is e.g. `vpc-0123`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct NetworkState {
+    pub(crate) kind: String,
+    pub(crate) id: String,
+    pub(crate) aws_id: String,
+}
+
+impl NetworkState {
+    pub(crate) fn new(network: &NetworkSym, aws_id: impl std::convert::Into<String>) -> Self {
+        NetworkState {
+            kind: network.kind.to_string(),
+            id: network.id.to_string(),
+            aws_id: aws_id.into(),
+        }
+    }
+}
+
+/// The security group AWS created for one `SecurityGroupSym`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct GroupState {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    pub(crate) aws_id: String,
+}
+
+impl GroupState {
+    pub(crate) fn new(group: &SecurityGroupSym, aws_id: impl std::convert::Into<String>) -> Self {
+        GroupState {
+            id: group.id.to_string(),
+            name: group.name.to_string(),
+            aws_id: aws_id.into(),
+        }
+    }
+}
+
+/// The key pair awsdsl imported for one `KeyPairSym`, with the private
+/// key it wrote when the key was generated
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct KeyState {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) private_key_file: Option<String>,
+}
+
+impl KeyState {
+    pub(crate) fn new(key: &KeyPairSym) -> Self {
+        KeyState {
+            id: key.id.to_string(),
+            name: key.name.to_string(),
+            private_key_file: key.private_key_file.clone(),
+        }
+    }
+}
+
+/// Key of a networking resource in the state and value of its id tag
+pub(crate) fn network_key(kind: &str, id: &str) -> String {
+    format!("{}.{}", kind, id)
+}
+
+/// Everything deployed for one aws block (stack). `serial` is bumped on
+/// every write so two copies of the state can be ordered.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct StackState {
+    pub(crate) version: u32,
+    pub(crate) serial: u64,
+    pub(crate) stack: String,
+    pub(crate) region: String,
+    pub(crate) source_hash: String,
+    pub(crate) ec2s: BTreeMap<String, Ec2State>,
+    /// s3 id -> bucket
+    #[serde(default)]
+    pub(crate) buckets: BTreeMap<String, BucketState>,
+    /// dynamodb id -> table
+    #[serde(default)]
+    pub(crate) tables: BTreeMap<String, TableState>,
+    /// `<kind>.<id>` -> networking resource
+    #[serde(default)]
+    pub(crate) network: BTreeMap<String, NetworkState>,
+    /// security_group id -> group
+    #[serde(default)]
+    pub(crate) groups: BTreeMap<String, GroupState>,
+    /// key_pair id -> key pair
+    #[serde(default)]
+    pub(crate) keys: BTreeMap<String, KeyState>,
+}
+
+impl StackState {
+    pub(crate) fn new(
+        stack: impl std::convert::Into<String>,
+        region: impl std::convert::Into<String>,
+    ) -> Self {
+        StackState {
+            version: STATE_VERSION,
+            serial: 0,
+            stack: stack.into(),
+            region: region.into(),
+            source_hash: String::from(""),
+            ec2s: BTreeMap::new(),
+            buckets: BTreeMap::new(),
+            tables: BTreeMap::new(),
+            network: BTreeMap::new(),
+            groups: BTreeMap::new(),
+            keys: BTreeMap::new(),
+        }
+    }
+
+    /// The id of the `Ec2Sym` owning `instance_id`, if any
+    pub(crate) fn owner_of(&self, instance_id: &str) -> Option<&str> {
+        self.ec2s
+            .values()
+            .find(|e| e.instance_ids.iter().any(|i| i == instance_id))
+            .map(|e| e.id.as_str())
+    }
+
+    pub(crate) fn to_json(&self) -> Result<String, StateError> {
+        serde_json::to_string_pretty(self).map_err(|e| StateError::new(e.to_string()))
+    }
+
+    /// Parse a state document, refusing layouts newer than `STATE_VERSION`
+    pub(crate) fn from_json(s: &str) -> Result<Self, StateError> {
+        let state: StackState =
+            serde_json::from_str(s).map_err(|e| StateError::new(e.to_string()))?;
+        if state.version > STATE_VERSION {
+            let s = format!(
+                "state version {} is newer than the supported version {}",
+                state.version, STATE_VERSION
+            );
+            return Err(StateError::new(s));
+        }
+        Ok(state)
+    }
+}
+
+impl fmt::Display for StackState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = format!(
+            "[{}] serial: {}, region: {}, source: {}",
+            self.stack, self.serial, self.region, self.source_hash
+        );
+        for ec2 in self.ec2s.values() {
+            s = format!("{}\n - {}: {}", s, ec2.id, ec2.instance_ids.join(", "));
+        }
+        for bucket in self.buckets.values() {
+            s = format!("{}\n - s3.{}: {}", s, bucket.id, bucket.name);
+        }
+        for table in self.tables.values() {
+            s = format!("{}\n - dynamodb.{}: {}", s, table.id, table.name);
+        }
+        for (key, network) in &self.network {
+            s = format!("{}\n - {}: {}", s, key, network.aws_id);
+        }
+        for group in self.groups.values() {
+            s = format!(
+                "{}\n - security_group.{}: {} ({})",
+                s, group.id, group.aws_id, group.name
+            );
+        }
+        for key in self.keys.values() {
+            s = format!("{}\n - key_pair.{}: {}", s, key.id, key.name);
+        }
+        write!(f, "{}", s)
+    }
+}
+
+/// Hash of the dsl source, recorded so the state can be traced back to
+/// the file that produced it
+pub(crate) fn hash_source(contents: &str) -> String {
+    let digest = Sha256::digest(contents.as_bytes());
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// File name safe version of the stack name
+pub(crate) fn stack_file_name(stack: &str) -> String {
+    stack
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Identifies who holds a lock: user, host and process id
+pub(crate) fn lock_holder() -> String {
+    let user = env::var("USER").unwrap_or(String::from("unknown"));
+    let host = env::var("HOSTNAME").unwrap_or(String::from("localhost"));
+    format!("{}@{}:{}", user, host, process::id())
+}
+
+/// Where the state of one stack is kept: a local json file, or an S3
+/// object when the aws block has a `state` block
+pub(crate) enum Backend {
+    Local(LocalBackend),
+    S3(S3Backend),
+}
+
+impl Backend {
+    /// Pick the backend for `aws_sym`, `dsl_file` is the file it was read
+    /// from and `source_hash` the hash of its contents
+    pub(crate) async fn open(dsl_file: &str, aws_sym: &AwsSym, source_hash: String) -> Self {
+        match &aws_sym.state {
+            Some(state_sym) => Backend::S3(S3Backend::new(state_sym, aws_sym, source_hash).await),
+            None => Backend::Local(LocalBackend::new(dsl_file, &aws_sym.name, source_hash)),
+        }
+    }
+
+    /// Read the state, a missing state is an empty state for `aws_sym`
+    pub(crate) async fn read(&self, aws_sym: &AwsSym) -> Result<StackState, StateError> {
+        let state = match self {
+            Backend::Local(local) => local.read()?,
+            Backend::S3(s3) => s3.read().await?,
+        };
+        Ok(state.unwrap_or(StackState::new(
+            aws_sym.name.as_str(),
+            aws_sym.region.as_str(),
+        )))
+    }
+
+    /// Write the state, bumping its serial
+    pub(crate) async fn write(&self, state: &mut StackState) -> Result<(), StateError> {
+        state.serial += 1;
+        match self {
+            Backend::Local(local) => {
+                state.source_hash = local.source_hash.to_string();
+                local.write(state)
+            }
+            Backend::S3(s3) => {
+                state.source_hash = s3.source_hash.to_string();
+                s3.write(state).await
+            }
+        }
+    }
+
+    /// Take the lock on the stack, failing if someone else holds it
+    pub(crate) async fn lock(&self) -> Result<(), StateError> {
+        match self {
+            Backend::Local(local) => local.lock(),
+            Backend::S3(s3) => s3.lock().await,
+        }
+    }
+
+    pub(crate) async fn unlock(&self) -> Result<(), StateError> {
+        match self {
+            Backend::Local(local) => local.unlock(),
+            Backend::S3(s3) => s3.unlock().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ec2_state(id: &str, inst_ids: Vec<&str>) -> Ec2State {
+        Ec2State {
+            id: id.to_string(),
+            name: id.to_string(),
+            instance_ids: inst_ids.iter().map(|i| i.to_string()).collect(),
+            instance_type: "t2.micro".to_string(),
+            ami_id: "ami-1".to_string(),
+            subnet_id: "subnet-1".to_string(),
+            sg_ids: vec!["sg-1".to_string()],
+            key_name: "key".to_string(),
+            count: 1,
+            app_version: 0.0,
+            indexes: inst_ids
+                .iter()
+                .enumerate()
+                .map(|(n, i)| (i.to_string(), n as u32))
+                .collect(),
+            tags: BTreeMap::new(),
+            key: None,
+        }
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut state = StackState::new("stack", "eu-west-1");
+        state
+            .ec2s
+            .insert("web01".to_string(), ec2_state("web01", vec!["i-1"]));
+        let s = state.to_json().unwrap();
+        let read = StackState::from_json(&s).unwrap();
+        assert_eq!(read, state);
+        assert_eq!(read.owner_of("i-1"), Some("web01"));
+        assert_eq!(read.owner_of("i-2"), None);
+    }
+
+    #[test]
+    fn test_state_without_named_resources() {
+        // states written before buckets and tables were managed lack them
+        let s = r#"{"version": 1, "serial": 3, "stack": "stack", "region": "eu-west-1",
+            "source_hash": "", "ec2s": {}}"#;
+        let state = StackState::from_json(s).unwrap();
+        assert_eq!(state.serial, 3);
+        assert!(state.buckets.is_empty() && state.tables.is_empty());
+    }
+
+    #[test]
+    fn test_state_newer_version() {
+        let mut state = StackState::new("stack", "eu-west-1");
+        state.version = STATE_VERSION + 1;
+        let s = state.to_json().unwrap();
+        assert!(StackState::from_json(&s).is_err());
+    }
+}
@@ -0,0 +1,286 @@
+use aws_config::{BehaviorVersion, Region, SdkConfig};
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_s3::primitives::ByteStream;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::aws::{AwsSym, StateSym};
+use crate::state::{StackState, StateError, lock_holder, stack_file_name};
+
+/// Hash key of the DynamoDB lock table
+const LOCK_KEY: &'static str = "LockID";
+
+/// State kept as an object in an S3 bucket. Bucket versioning keeps every
+/// previous state around. When a `lock_table` is given, a DynamoDB item
+/// keyed on the object path guards against concurrent runs.
+pub(crate) struct S3Backend {
+    s3: aws_sdk_s3::Client,
+    dynamodb: aws_sdk_dynamodb::Client,
+    pub(crate) bucket: String,
+    pub(crate) key: String,
+    pub(crate) lock_table: Option<String>,
+    pub(crate) source_hash: String,
+    holder: String,
+}
+
+/// Sdk config for the state backend, in the region of the stack and with
+/// the endpoint overridden when one is given. The credentials are the
+/// ones of the profile of the stack: the state stays with the identity
+/// running awsdsl, not in the account of an assumed role.
+async fn load_config(state_sym: &StateSym, region: &str, profile: Option<&str>) -> SdkConfig {
+    let mut loader =
+        aws_config::defaults(BehaviorVersion::latest()).region(Region::new(region.to_string()));
+    if let Some(profile) = profile {
+        loader = loader.profile_name(profile);
+    }
+    if let Some(endpoint) = &state_sym.endpoint {
+        loader = loader.endpoint_url(endpoint);
+    }
+    loader.load().await
+}
+
+/// Object key of the state of `stack` under `prefix`
+pub(crate) fn state_key(prefix: &str, stack: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    if prefix.is_empty() {
+        return format!("{}.json", stack_file_name(stack));
+    }
+    format!("{}/{}.json", prefix, stack_file_name(stack))
+}
+
+impl S3Backend {
+    pub(crate) async fn new(state_sym: &StateSym, aws_sym: &AwsSym, source_hash: String) -> Self {
+        let config = load_config(state_sym, &aws_sym.region, aws_sym.profile.as_deref()).await;
+        // local stand-ins don't serve virtual hosted buckets
+        let s3_config = aws_sdk_s3::config::Builder::from(&config)
+            .force_path_style(state_sym.endpoint.is_some())
+            .build();
+        S3Backend {
+            s3: aws_sdk_s3::Client::from_conf(s3_config),
+            dynamodb: aws_sdk_dynamodb::Client::new(&config),
+            bucket: state_sym.bucket.to_string(),
+            key: state_key(&state_sym.prefix, &aws_sym.name),
+            lock_table: state_sym.lock_table.clone(),
+            source_hash: source_hash,
+            holder: lock_holder(),
+        }
+    }
+
+    /// The id of the lock item: the full path of the state object
+    fn lock_id(&self) -> String {
+        format!("{}/{}", self.bucket, self.key)
+    }
+
+    fn location(&self) -> String {
+        format!("s3://{}/{}", self.bucket, self.key)
+    }
+
+    pub(crate) async fn read(&self) -> Result<Option<StackState>, StateError> {
+        let object = self
+            .s3
+            .get_object()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .send()
+            .await;
+        let object = match object {
+            Ok(o) => o,
+            Err(e) => {
+                if e.as_service_error().map(|se| se.is_no_such_key()) == Some(true) {
+                    return Ok(None);
+                }
+                let s = format!("reading {}: {}", self.location(), e);
+                return Err(StateError::new(s));
+            }
+        };
+        let body = object
+            .body
+            .collect()
+            .await
+            .map_err(|e| StateError::new(format!("reading {}: {}", self.location(), e)))?;
+        let s = String::from_utf8(body.to_vec()).map_err(|e| StateError::new(e.to_string()))?;
+        StackState::from_json(&s).map(Some)
+    }
+
+    /// Write the state, refusing to overwrite a state written by someone
+    /// else since it was read. The serial check and the put are two calls,
+    /// not one atomic write: only the DynamoDB lock, held by the caller for
+    /// the whole run, keeps a concurrent writer out between them. Without
+    /// a `lock_table` the check narrows the window but can't close it.
+    pub(crate) async fn write(&self, state: &StackState) -> Result<(), StateError> {
+        // the caller holds the lock, see above: nobody else puts the object
+        // between this read and the put below
+        if let Some(remote) = self.read().await?
+            && remote.serial >= state.serial
+        {
+            let s = format!(
+                "{} has serial {}, newer than ours ({}), re-run to pick it up",
+                self.location(),
+                remote.serial,
+                state.serial - 1
+            );
+            return Err(StateError::new(s));
+        }
+        let put = self
+            .s3
+            .put_object()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .content_type("application/json")
+            .body(ByteStream::from(state.to_json()?.into_bytes()))
+            .send()
+            .await
+            .map_err(|e| StateError::new(format!("writing {}: {}", self.location(), e)))?;
+        match put.version_id() {
+            Some(v) => println!("=> state written to {}, version: {}", self.location(), v),
+            None => println!(
+                "=> state written to {}, bucket versioning is off, previous states are lost!",
+                self.location()
+            ),
+        }
+        Ok(())
+    }
+
+    /// Put the lock item, conditional on nobody else having put it
+    pub(crate) async fn lock(&self) -> Result<(), StateError> {
+        let table = match &self.lock_table {
+            Some(t) => t,
+            None => {
+                println!("=> no lock_table given, {} is not locked!", self.location());
+                return Ok(());
+            }
+        };
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let put = self
+            .dynamodb
+            .put_item()
+            .table_name(table)
+            .item(LOCK_KEY, AttributeValue::S(self.lock_id()))
+            .item("Holder", AttributeValue::S(self.holder.to_string()))
+            .item("Created", AttributeValue::N(created.to_string()))
+            .condition_expression("attribute_not_exists(#id)")
+            .expression_attribute_names("#id", LOCK_KEY)
+            .send()
+            .await;
+        match put {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                if e.as_service_error()
+                    .map(|se| se.is_conditional_check_failed_exception())
+                    == Some(true)
+                {
+                    let s = format!(
+                        "{} is locked by {}",
+                        self.location(),
+                        self.current_holder(table).await
+                    );
+                    return Err(StateError::new(s));
+                }
+                Err(StateError::new(format!(
+                    "locking {}: {}",
+                    self.location(),
+                    e
+                )))
+            }
+        }
+    }
+
+    /// Delete the lock item, only if we still hold it
+    pub(crate) async fn unlock(&self) -> Result<(), StateError> {
+        let table = match &self.lock_table {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        self.dynamodb
+            .delete_item()
+            .table_name(table)
+            .key(LOCK_KEY, AttributeValue::S(self.lock_id()))
+            .condition_expression("Holder = :holder")
+            .expression_attribute_values(":holder", AttributeValue::S(self.holder.to_string()))
+            .send()
+            .await
+            .map_err(|e| StateError::new(format!("unlocking {}: {}", self.location(), e)))?;
+        Ok(())
+    }
+
+    /// Who holds the lock, for error messages
+    async fn current_holder(&self, table: &str) -> String {
+        let item = self
+            .dynamodb
+            .get_item()
+            .table_name(table)
+            .key(LOCK_KEY, AttributeValue::S(self.lock_id()))
+            .consistent_read(true)
+            .send()
+            .await;
+        match item {
+            Ok(out) => out
+                .item()
+                .and_then(|i| i.get("Holder"))
+                .and_then(|h| h.as_s().ok())
+                .map(|h| h.to_string())
+                .unwrap_or(String::from("unknown")),
+            Err(_) => String::from("unknown"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::hash_source;
+    use std::env;
+
+    #[test]
+    fn test_state_key() {
+        assert_eq!(state_key("awsdsl", "eu west kv"), "awsdsl/eu_west_kv.json");
+        assert_eq!(state_key("awsdsl/", "web"), "awsdsl/web.json");
+        assert_eq!(state_key("", "web"), "web.json");
+    }
+
+    /// Runs against a local S3/DynamoDB stand-in, e.g. localstack:
+    /// AWSDSL_TEST_ENDPOINT=http://localhost:4566 cargo test -- --ignored
+    /// The bucket `awsdsl-state` and the table `awsdsl-lock` (hash key
+    /// `LockID`) must exist.
+    #[tokio::test]
+    #[ignore]
+    async fn test_s3_backend_round_trip() {
+        let endpoint = env::var("AWSDSL_TEST_ENDPOINT").unwrap();
+        let state_sym = StateSym {
+            bucket: "awsdsl-state".to_string(),
+            prefix: "test".to_string(),
+            lock_table: Some("awsdsl-lock".to_string()),
+            endpoint: Some(endpoint),
+        };
+        let aws_sym = AwsSym::new(
+            "aws".to_string(),
+            "stack".to_string(),
+            "eu-west-1".to_string(),
+        );
+        let backend = S3Backend::new(&state_sym, &aws_sym, hash_source("")).await;
+        let other = S3Backend::new(&state_sym, &aws_sym, hash_source("")).await;
+
+        backend.lock().await.unwrap();
+        let mut state = backend
+            .read()
+            .await
+            .unwrap()
+            .unwrap_or(StackState::new("stack", "eu-west-1"));
+        state.serial += 1;
+        backend.write(&state).await.unwrap();
+        assert_eq!(backend.read().await.unwrap(), Some(state.clone()));
+        // a stale state is refused
+        assert!(backend.write(&state).await.is_err());
+        backend.unlock().await.unwrap();
+
+        // a second run holds the lock now, unlocking the first is refused
+        let mut other_holder = other;
+        other_holder.holder = format!("{}-other", other_holder.holder);
+        other_holder.lock().await.unwrap();
+        assert!(backend.lock().await.is_err());
+        assert!(backend.unlock().await.is_err());
+        other_holder.unlock().await.unwrap();
+    }
+}
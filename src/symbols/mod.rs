@@ -1,16 +1,39 @@
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt;
+use std::path::{Component, Path};
 
-use crate::aws::{AwsSym, Ec2Sym, nodes};
+use crate::aws::{
+    AssumeRoleSym, AwsSym, DynamoSym, Ec2Sym, GROUP_KIND, GsiSym, KEY_KIND, KeyPairSym,
+    LifecycleRule, NETWORK_KINDS, NetworkSym, RouteSym, RuleSym, S3Sym, SecurityGroupSym, StateSym,
+    nodes,
+};
+use crate::symbols::scope::{Scope, Value};
+
+pub(crate) mod scope;
 
 #[derive(Debug)]
 pub(crate) struct AstError {
     msg: String,
+    /// the file the error is in, when known
+    file: Option<String>,
 }
 
 impl AstError {
     pub(crate) fn new(msg: impl std::convert::Into<String>) -> Self {
-        Self { msg: msg.into() }
+        Self {
+            msg: msg.into(),
+            file: None,
+        }
+    }
+
+    /// Name `file` as the one the error is in, unless an error from a
+    /// module already names its own file
+    pub(crate) fn in_file(mut self, file: &str) -> Self {
+        if self.file.is_none() && !file.is_empty() {
+            self.file = Some(file.to_string());
+        }
+        self
     }
 }
 
@@ -18,59 +41,2166 @@ impl Error for AstError {}
 
 impl fmt::Display for AstError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Ast Error: {}", self.msg)
+        match &self.file {
+            Some(file) => write!(f, "Ast Error: in {}: {}", file, self.msg),
+            None => write!(f, "Ast Error: {}", self.msg),
+        }
+    }
+}
+
+/// Evaluate a required string attribute, `missing` is the error when it
+/// isn't set
+fn required_string(
+    scope: &Scope,
+    attr: &str,
+    expr: &Option<nodes::Expr>,
+    missing: &str,
+) -> Result<String, AstError> {
+    match expr {
+        Some(e) => scope.string(attr, e),
+        None => Err(AstError::new(missing)),
+    }
+}
+
+/// `count` must be a whole number an instance count fits in, one instance
+/// when it is absent; scaling to zero takes an explicit `count = 0`
+fn count(scope: &Scope, expr: &Option<nodes::Expr>) -> Result<u8, AstError> {
+    match expr {
+        Some(e) => whole_count(scope.number("count", e)?),
+        None => Ok(1),
+    }
+}
+
+fn whole_count(n: f64) -> Result<u8, AstError> {
+    if n < 0.0 || n > u8::MAX as f64 || n.fract() != 0.0 {
+        let s = format!(
+            "count must be a whole number up to {}, found {}",
+            u8::MAX,
+            n
+        );
+        return Err(AstError::new(s));
+    }
+    Ok(n as u8)
+}
+
+/// The security groups, from `sg_ids` or the single `sg_id`
+fn sg_ids(scope: &Scope, ec2: &nodes::Ec2Node) -> Result<Vec<String>, AstError> {
+    let sg_ids = match (&ec2.sg_id, &ec2.sg_ids) {
+        (Some(_), Some(_)) => {
+            return Err(AstError::new("ec2 sets both sg_id and sg_ids, use sg_ids"));
+        }
+        (Some(e), None) => vec![scope.string("sg_id", e)?],
+        (None, Some(e)) => scope.string_list("sg_ids", e)?,
+        (None, None) => vec![],
+    };
+    if sg_ids.is_empty() {
+        return Err(AstError::new("No ec2 sg ids provided!"));
+    }
+    Ok(sg_ids)
+}
+
+/// The user tags; `Name` and the `awsdsl:` tags are set by awsdsl
+fn tags(scope: &Scope, expr: &Option<nodes::Expr>) -> Result<BTreeMap<String, String>, AstError> {
+    let tags = match expr {
+        Some(e) => scope.string_map("tags", e)?,
+        None => return Ok(BTreeMap::new()),
+    };
+    if let Some(key) = tags
+        .keys()
+        .find(|k| k.as_str() == "Name" || k.starts_with("awsdsl:"))
+    {
+        let s = format!("tag `{}` is set by awsdsl and can't be overridden", key);
+        return Err(AstError::new(s));
+    }
+    Ok(tags)
+}
+
+/// Evaluate the attributes of an ec2 block into its symbol, its tags
+/// overriding the `default_tags` of the aws block
+fn ec2_sym(
+    scope: &Scope,
+    ec2: &nodes::Ec2Node,
+    default_tags: &BTreeMap<String, String>,
+    key: Option<&str>,
+) -> Result<Ec2Sym, AstError> {
+    let app_version = match &ec2.app_version {
+        Some(e) => scope.number("app_version", e)? as f32,
+        None => 0.0,
+    };
+    // a labelled resource is named after its label unless it says otherwise
+    let name = match (&ec2.name, &ec2.label, key) {
+        (Some(e), _, _) => scope.string("name", e)?,
+        (None, Some(label), Some(key)) => format!("{}-{}", label, key),
+        (None, Some(label), None) => label.to_string(),
+        (None, None, _) => return Err(AstError::new("No ec2 name provided!")),
+    };
+    let mut ec2_sym = Ec2Sym::new(
+        name,
+        required_string(
+            scope,
+            "description",
+            &ec2.description,
+            "No ec2 description provided",
+        )?,
+        required_string(
+            scope,
+            "instance_type",
+            &ec2.instance_type,
+            "No ec2 instance type provided",
+        )?,
+        required_string(scope, "ami", &ec2.ami, "No ec2 ami id provided!")?,
+        required_string(
+            scope,
+            "subnet_id",
+            &ec2.subnet_id,
+            "No ec2 subnet_id provided!",
+        )?,
+        sg_ids(scope, ec2)?,
+        app_version,
+        count(scope, &ec2.count)?,
+        required_string(scope, "key_name", &ec2.key_name, "No key name provided!")?,
+    );
+    let mut merged = default_tags.clone();
+    merged.extend(tags(scope, &ec2.tags)?);
+    ec2_sym.set_tags(merged);
+    if let Some(label) = &ec2.label {
+        ec2_sym.id = match key {
+            Some(key) => format!("{}[{}]", label, key),
+            None => label.to_string(),
+        };
+    }
+    ec2_sym.key = key.map(|k| k.to_string());
+    Ok(ec2_sym)
+}
+
+/// Whether the `when` of a resource, if it has one, holds
+fn included(scope: &Scope, when: &Option<nodes::Expr>) -> Result<bool, AstError> {
+    match when {
+        Some(e) => scope.bool("when", e),
+        None => Ok(true),
+    }
+}
+
+/// One copy of an expanded resource, unless its `when` skips it
+fn ec2_copy(
+    scope: &Scope,
+    ec2: &nodes::Ec2Node,
+    default_tags: &BTreeMap<String, String>,
+    key: Option<&str>,
+) -> Result<Option<Ec2Sym>, AstError> {
+    if !included(scope, &ec2.when)? {
+        return Ok(None);
+    }
+    ec2_sym(scope, ec2, default_tags, key).map(Some)
+}
+
+/// The keys a `for_each` or `count` expands a resource over, with the
+/// builtin each copy binds and its value
+fn meta_keys(
+    scope: &Scope,
+    meta_arg: &nodes::MetaArg,
+) -> Result<Vec<(String, &'static str, Value)>, AstError> {
+    let mut keys: Vec<(String, &'static str, Value)> = vec![];
+    match meta_arg {
+        nodes::MetaArg::ForEach(e) => match scope.eval(e)? {
+            Value::List(items) => {
+                for item in items {
+                    let key = match item {
+                        Value::Str(s) => s,
+                        other => {
+                            let s = format!("for_each items must be strings, found {}", other);
+                            return Err(AstError::new(s));
+                        }
+                    };
+                    if keys.iter().any(|(k, _, _)| *k == key) {
+                        let s = format!("for_each has `{}` twice, keys must be unique", key);
+                        return Err(AstError::new(s));
+                    }
+                    keys.push((key.to_string(), "each", Value::Str(key)));
+                }
+            }
+            Value::Map(map) => {
+                for key in map.into_keys() {
+                    keys.push((key.to_string(), "each", Value::Str(key)));
+                }
+            }
+            other => {
+                let s = format!(
+                    "for_each expects a list of strings or a map, found {}",
+                    other
+                );
+                return Err(AstError::new(s));
+            }
+        },
+        nodes::MetaArg::Count(e) => {
+            let n = whole_count(scope.number("count", e)?)?;
+            for index in 0..n {
+                keys.push((index.to_string(), "index", Value::Number(index as f64)));
+            }
+        }
+    }
+    Ok(keys)
+}
+
+/// An optional string attribute
+fn optional_string(
+    scope: &Scope,
+    attr: &str,
+    expr: &Option<nodes::Expr>,
+) -> Result<Option<String>, AstError> {
+    match expr {
+        Some(e) => Ok(Some(scope.string(attr, e)?)),
+        None => Ok(None),
+    }
+}
+
+/// The region of a stack, which is required: the environment is not
+/// looked at, so the file alone says where a stack is deployed
+fn region(scope: &Scope, expr: &Option<nodes::Expr>) -> Result<String, AstError> {
+    let region = required_string(scope, "region", expr, "No region provided!")?;
+    let valid = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-';
+    if region.split('-').count() < 3 || !region.chars().all(valid) {
+        let s = format!(
+            "region must be an AWS region like eu-west-1, found {}",
+            region
+        );
+        return Err(AstError::new(s));
+    }
+    Ok(region)
+}
+
+/// Session name when none is given
+const DEFAULT_SESSION_NAME: &'static str = "awsdsl";
+
+/// The role to assume, from the `assume_role` block or the `role_arn`
+/// shorthand. The ARN and the session name are checked here so a typo
+/// doesn't surface as an STS error halfway through a run.
+fn assume_role(
+    scope: &Scope,
+    aws_node: &nodes::AwsNode,
+) -> Result<Option<AssumeRoleSym>, AstError> {
+    let (role_arn, session_name, external_id) = match (&aws_node.role_arn, &aws_node.assume_role) {
+        (None, None) => return Ok(None),
+        (Some(_), Some(_)) => {
+            return Err(AstError::new(
+                "aws sets both role_arn and assume_role, use assume_role",
+            ));
+        }
+        (Some(e), None) => (scope.string("role_arn", e)?, None, None),
+        (None, Some(node)) => (
+            required_string(
+                scope,
+                "role_arn",
+                &node.role_arn,
+                "No assume_role role_arn provided!",
+            )?,
+            optional_string(scope, "session_name", &node.session_name)?,
+            optional_string(scope, "external_id", &node.external_id)?,
+        ),
+    };
+    if !role_arn.starts_with("arn:") || !role_arn.contains(":role/") {
+        let s = format!(
+            "role_arn must be an IAM role ARN like arn:aws:iam::<account>:role/<name>, found {}",
+            role_arn
+        );
+        return Err(AstError::new(s));
+    }
+    let session_name = session_name.unwrap_or(DEFAULT_SESSION_NAME.to_string());
+    // the characters and length STS accepts
+    let valid = |c: char| c.is_ascii_alphanumeric() || "_+=,.@-".contains(c);
+    if session_name.len() < 2 || session_name.len() > 64 || !session_name.chars().all(valid) {
+        let s = format!(
+            "session_name must be 2 to 64 letters, digits or _+=,.@- characters, found {}",
+            session_name
+        );
+        return Err(AstError::new(s));
     }
+    Ok(Some(AssumeRoleSym {
+        role_arn: role_arn,
+        session_name: session_name,
+        external_id: external_id,
+    }))
 }
 
-pub(crate) fn walk_ast(aws_node: &nodes::AwsNode) -> Result<AwsSym, AstError> {
+/// Evaluate the variables, then every aws block into the symbol of its
+/// stack. Stack names must be unique as each stack has its own state.
+pub(crate) fn walk_ast(program: &nodes::ProgramNode) -> Result<Vec<AwsSym>, AstError> {
     println!("==> walking the aws ast ...");
-    let aws_region = aws_node
-        .region
-        .as_ref()
-        .ok_or(AstError::new("No region provided!"))?;
-    let mut aws_sym = AwsSym::new(aws_node.id.to_string(), String::from(aws_region));
-    for ec2 in &aws_node.ec2_nodes {
-        let ec2_sym = Ec2Sym::new(
-            String::from(
-                ec2.id
-                    .as_ref()
-                    .ok_or(AstError::new("No ec2 description provided"))?,
-            ),
-            String::from(
-                ec2.description
-                    .as_ref()
-                    .ok_or(AstError::new("No ec2 description provided"))?,
-            ),
-            String::from(
-                ec2.instance_type
-                    .as_ref()
-                    .ok_or(AstError::new("No ec2 instance type provided"))?,
-            ),
-            String::from(
-                ec2.ami
-                    .as_ref()
-                    .ok_or(AstError::new("No ec2 ami id provided!"))?,
-            ),
-            String::from(
-                ec2.subnet_id
+    let mut scope = Scope::new();
+    for var in &program.vars {
+        scope.declare(var).map_err(|e| e.in_file(&var.file))?;
+    }
+
+    let mut aws_syms: Vec<AwsSym> = vec![];
+    for aws_node in &program.aws_nodes {
+        let aws_sym = aws_sym(&mut scope, aws_node).map_err(|e| e.in_file(&aws_node.file))?;
+        if aws_syms.iter().any(|a| a.name == aws_sym.name) {
+            let s = format!(
+                "stack `{}` is declared twice, give each aws block its own name",
+                aws_sym.name
+            );
+            return Err(AstError::new(s));
+        }
+        aws_syms.push(aws_sym);
+    }
+    Ok(aws_syms)
+}
+
+/// Evaluate one aws block. Its resources can refer to the ones declared
+/// before them in the same block.
+fn aws_sym(scope: &mut Scope, aws_node: &nodes::AwsNode) -> Result<AwsSym, AstError> {
+    scope.clear_attrs();
+    let aws_region = region(scope, &aws_node.region)?;
+    // the stack name defaults to the block id when no name is given
+    let aws_name = match &aws_node.name {
+        Some(e) => scope.string("name", e)?,
+        None => aws_node.id.to_string(),
+    };
+    scope.set_builtin("region", Value::Str(aws_region.to_string()));
+    scope.set_builtin("stack", Value::Str(aws_name.to_string()));
+    let mut aws_sym = AwsSym::new(aws_node.id.to_string(), aws_name, aws_region);
+    aws_sym.profile = optional_string(scope, "profile", &aws_node.profile)?;
+    aws_sym.endpoint = optional_string(scope, "endpoint", &aws_node.endpoint)?;
+    aws_sym.assume_role = assume_role(scope, aws_node)?;
+    aws_sym.set_tags(tags(scope, &aws_node.tags)?);
+    if let Some(state) = &aws_node.state {
+        aws_sym.set_state(StateSym {
+            bucket: String::from(
+                state
+                    .bucket
                     .as_ref()
-                    .ok_or(AstError::new("No ec2 subnet_id provided!"))?,
+                    .ok_or(AstError::new("No state bucket provided!"))?,
             ),
-            String::from(
-                ec2.sg_id
-                    .as_ref()
-                    .ok_or(AstError::new("No ec2 sg id provided!"))?,
+            prefix: String::from(state.prefix.as_deref().unwrap_or("awsdsl")),
+            lock_table: state.lock_table.clone(),
+            endpoint: state.endpoint.clone(),
+        });
+    }
+    add_resources(scope, &mut aws_sym, &aws_node.resources, "")?;
+    aws_sym.link_groups();
+    aws_sym.link_keys();
+
+    Ok(aws_sym)
+}
+
+/// Evaluate the resources of an aws block or a module into `aws_sym`,
+/// their ids prefixed with `prefix`. The kinds ec2s refer to come first,
+/// within a kind resources only see the ones declared before them.
+fn add_resources(
+    scope: &mut Scope,
+    aws_sym: &mut AwsSym,
+    resources: &nodes::ResourceNodes,
+    prefix: &str,
+) -> Result<(), AstError> {
+    add_networks(scope, aws_sym, &resources.network_nodes, prefix)?;
+    add_security_groups(scope, aws_sym, &resources.security_group_nodes, prefix)?;
+    add_key_pairs(scope, aws_sym, &resources.key_pair_nodes, prefix)?;
+    add_s3s(scope, aws_sym, &resources.s3_nodes, prefix)?;
+    add_tables(scope, aws_sym, &resources.dynamodb_nodes, prefix)?;
+    add_ec2s(scope, aws_sym, &resources.ec2_nodes, prefix)?;
+    for module in &resources.modules {
+        add_module(scope, aws_sym, module, prefix)?;
+    }
+    Ok(())
+}
+
+/// Evaluate resources into `aws_sym`, their ids prefixed with `prefix`.
+/// They can refer to the ones declared before them in `scope`.
+fn add_ec2s(
+    scope: &mut Scope,
+    aws_sym: &mut AwsSym,
+    ec2_nodes: &[nodes::Ec2Node],
+    prefix: &str,
+) -> Result<(), AstError> {
+    for ec2 in ec2_nodes {
+        // a `when` that doesn't depend on the copy is checked once, so
+        // nothing else of a skipped resource is evaluated
+        let per_copy = match &ec2.when {
+            Some(e) => e.refers_to("index") || e.refers_to("each"),
+            None => false,
+        };
+        if !per_copy && !included(scope, &ec2.when)? {
+            continue;
+        }
+        // a resource using `index` is expanded into one resource per
+        // instance of its count
+        let attrs = ec2.attrs();
+        let ec2_syms = if let Some(meta_arg) = &ec2.meta_arg {
+            // a `for_each` or `count` meta-argument makes one resource per
+            // key, each of them a single instance
+            if ec2.count.is_some() {
+                let s = format!(
+                    "ec2 `{}` sets count next to a for_each or count meta-argument, each copy is one instance",
+                    ec2.label.as_deref().unwrap_or_default()
+                );
+                return Err(AstError::new(s));
+            }
+            let mut ec2_syms: Vec<Ec2Sym> = vec![];
+            for (key, builtin, value) in meta_keys(scope, meta_arg)? {
+                scope.set_builtin(builtin, value);
+                let ec2_sym = ec2_copy(scope, ec2, &aws_sym.tags, Some(&key));
+                scope.unset_builtin(builtin);
+                if let Some(mut ec2_sym) = ec2_sym? {
+                    ec2_sym.count = 1;
+                    ec2_syms.push(ec2_sym);
+                }
+            }
+            ec2_syms
+        } else if attrs.iter().any(|(_, e)| e.refers_to("index")) {
+            let n = count(scope, &ec2.count)?;
+            let mut ec2_syms: Vec<Ec2Sym> = vec![];
+            for index in 0..n {
+                scope.set_builtin("index", Value::Number(index as f64));
+                let key = ec2.label.as_ref().map(|_| index.to_string());
+                if let Some(mut ec2_sym) = ec2_copy(scope, ec2, &aws_sym.tags, key.as_deref())? {
+                    ec2_sym.count = 1;
+                    ec2_syms.push(ec2_sym);
+                }
+            }
+            scope.unset_builtin("index");
+            ec2_syms
+        } else {
+            vec![ec2_sym(scope, ec2, &aws_sym.tags, None)?]
+        };
+        for mut ec2_sym in ec2_syms {
+            ec2_sym.id = format!("{}{}", prefix, ec2_sym.id);
+            if aws_sym
+                .ec2s
+                .iter()
+                .any(|e| e.id == ec2_sym.id || e.name == ec2_sym.name)
+            {
+                let s = format!(
+                    "ec2 `{}` is declared twice, names must be unique (use ${{index}} or ${{each}} in the name of an expanded resource)",
+                    ec2_sym.name
+                );
+                return Err(AstError::new(s));
+            }
+            for (attr, value) in ec2_sym.attrs() {
+                scope.set_attr("ec2", &ec2_sym.name, attr, value);
+            }
+            aws_sym.add_ec2(ec2_sym);
+        }
+    }
+    Ok(())
+}
+
+/// Evaluate a module into `aws_sym`: its inputs in the scope of the
+/// caller, then its declarations and resources in a scope of their own,
+/// where an input takes the place of the variable of the same name.
+/// The ids of its resources are prefixed with `module.<name>.`.
+fn add_module(
+    scope: &Scope,
+    aws_sym: &mut AwsSym,
+    module: &nodes::ModuleNode,
+    prefix: &str,
+) -> Result<(), AstError> {
+    let mut inputs: BTreeMap<String, Value> = BTreeMap::new();
+    for (name, e) in &module.inputs {
+        if !module.vars.iter().any(|v| v.name == *name) {
+            let s = format!(
+                "module `{}` at ({},{}) has no input `{}`, declare it with var in {}",
+                module.name, module.line_no, module.column_no, name, module.file
+            );
+            return Err(AstError::new(s));
+        }
+        inputs.insert(name.to_string(), scope.eval(e)?);
+    }
+    let mut module_scope = Scope::new();
+    module_scope.set_builtin("region", Value::Str(aws_sym.region.to_string()));
+    module_scope.set_builtin("stack", Value::Str(aws_sym.name.to_string()));
+    let prefix = format!("{}module.{}.", prefix, module.name);
+    module_body(&mut module_scope, aws_sym, module, inputs, &prefix)
+        .map_err(|e| e.in_file(&module.file))
+}
+
+fn module_body(
+    scope: &mut Scope,
+    aws_sym: &mut AwsSym,
+    module: &nodes::ModuleNode,
+    mut inputs: BTreeMap<String, Value>,
+    prefix: &str,
+) -> Result<(), AstError> {
+    for var in &module.vars {
+        match inputs.remove(&var.name) {
+            Some(value) => scope.define(var, value)?,
+            None => scope.declare(var)?,
+        }
+    }
+    add_resources(scope, aws_sym, &module.resources, prefix)
+}
+
+/// Evaluate networking resources into `aws_sym`, kind by kind in the
+/// order they are created, their ids prefixed with `prefix`
+fn add_networks(
+    scope: &mut Scope,
+    aws_sym: &mut AwsSym,
+    network_nodes: &[nodes::NetworkNode],
+    prefix: &str,
+) -> Result<(), AstError> {
+    for kind in NETWORK_KINDS {
+        for network in network_nodes.iter().filter(|n| n.kind == kind) {
+            if !included(scope, &network.when)? {
+                continue;
+            }
+            let mut network_sym = network_sym(scope, network, &aws_sym.tags)?;
+            let id = network_sym.id.to_string();
+            network_sym.id = format!("{}{}", prefix, id);
+            if aws_sym
+                .networks
+                .iter()
+                .any(|n| n.kind == kind && n.id == network_sym.id)
+            {
+                let s = format!("{} `{}` is declared twice, ids must be unique", kind, id);
+                return Err(AstError::new(s));
+            }
+            for (attr, value) in network_sym.attrs() {
+                scope.set_attr(kind, &id, attr, value);
+            }
+            aws_sym.add_network(network_sym);
+        }
+    }
+    Ok(())
+}
+
+fn network_sym(
+    scope: &Scope,
+    network: &nodes::NetworkNode,
+    default_tags: &BTreeMap<String, String>,
+) -> Result<NetworkSym, AstError> {
+    let kind = network.kind;
+    let missing = |attr: &str| format!("No {} {} provided!", kind, attr);
+    // a labelled resource is named after its label unless it says otherwise
+    let name = match (&network.name, &network.label) {
+        (Some(e), _) => scope.string("name", e)?,
+        (None, Some(label)) => label.to_string(),
+        (None, None) => return Err(AstError::new(missing("name"))),
+    };
+    let takes = |attr: &str| nodes::NetworkNode::attrs(kind).contains(&attr);
+    let cidr = match takes("cidr") {
+        true => Some(cidr_block(
+            "cidr",
+            required_string(scope, "cidr", &network.cidr, &missing("cidr"))?,
+        )?),
+        false => None,
+    };
+    let vpc_id = match takes("vpc") {
+        true => Some(required_string(
+            scope,
+            "vpc",
+            &network.vpc,
+            &missing("vpc"),
+        )?),
+        false => None,
+    };
+    let subnet_id = match takes("subnet") {
+        true => Some(required_string(
+            scope,
+            "subnet",
+            &network.subnet,
+            &missing("subnet"),
+        )?),
+        false => None,
+    };
+    let mut routes: Vec<RouteSym> = vec![];
+    for route in &network.routes {
+        let target = match (&route.gateway, &route.nat_gateway) {
+            (Some(e), None) => scope.string("gateway", e)?,
+            (None, Some(e)) => scope.string("nat_gateway", e)?,
+            (Some(_), Some(_)) => {
+                return Err(AstError::new("a route sets both gateway and nat_gateway"));
+            }
+            (None, None) => return Err(AstError::new("a route needs a gateway or a nat_gateway")),
+        };
+        let cidr = required_string(scope, "cidr", &route.cidr, "No route cidr provided!")?;
+        routes.push(RouteSym {
+            cidr: cidr_block("cidr", cidr)?,
+            target: target,
+        });
+    }
+    let mut merged = default_tags.clone();
+    merged.extend(tags(scope, &network.tags)?);
+    Ok(NetworkSym {
+        kind: kind,
+        id: network.label.clone().unwrap_or(name.to_string()),
+        name: name,
+        cidr: cidr,
+        vpc_id: vpc_id,
+        subnet_id: subnet_id,
+        az: optional_string(scope, "az", &network.az)?,
+        public: match &network.public {
+            Some(e) => scope.bool("public", e)?,
+            None => false,
+        },
+        subnet_ids: match &network.subnets {
+            Some(e) => scope.string_list("subnets", e)?,
+            None => vec![],
+        },
+        routes: routes,
+        tags: merged,
+    })
+}
+
+/// The protocols of a security group rule, the first is the default
+const PROTOCOLS: [&str; 4] = ["tcp", "udp", "icmp", "all"];
+
+/// Description of the security groups that don't set one
+const GROUP_DESCRIPTION: &'static str = "managed by awsdsl";
+
+/// Evaluate security groups into `aws_sym`, their ids prefixed with
+/// `prefix`
+fn add_security_groups(
+    scope: &mut Scope,
+    aws_sym: &mut AwsSym,
+    group_nodes: &[nodes::SecurityGroupNode],
+    prefix: &str,
+) -> Result<(), AstError> {
+    for group in group_nodes {
+        if !included(scope, &group.when)? {
+            continue;
+        }
+        let mut group_sym = security_group_sym(scope, group, &aws_sym.tags)?;
+        let id = group_sym.id.to_string();
+        group_sym.id = format!("{}{}", prefix, id);
+        if aws_sym.groups.iter().any(|g| g.name == group_sym.name) {
+            let s = format!(
+                "security group `{}` is declared twice, names must be unique",
+                group_sym.name
+            );
+            return Err(AstError::new(s));
+        }
+        for (attr, value) in group_sym.attrs() {
+            scope.set_attr(GROUP_KIND, &id, attr, value);
+        }
+        aws_sym.add_group(group_sym);
+    }
+    Ok(())
+}
+
+fn security_group_sym(
+    scope: &Scope,
+    group: &nodes::SecurityGroupNode,
+    default_tags: &BTreeMap<String, String>,
+) -> Result<SecurityGroupSym, AstError> {
+    let name = match (&group.name, &group.label) {
+        (Some(e), _) => scope.string("name", e)?,
+        (None, Some(label)) => label.to_string(),
+        (None, None) => return Err(AstError::new("No security_group name provided!")),
+    };
+    if name.is_empty() || name.starts_with("sg-") {
+        let s = format!(
+            "security group name `{}` must not be empty or start with sg-",
+            name
+        );
+        return Err(AstError::new(s));
+    }
+    let mut ingress: Vec<RuleSym> = vec![];
+    for rule in &group.ingress {
+        ingress.push(rule_sym(scope, "ingress", rule)?);
+    }
+    // like AWS, a group without egress rules lets everything out
+    let mut egress: Vec<RuleSym> = vec![];
+    for rule in &group.egress {
+        egress.push(rule_sym(scope, "egress", rule)?);
+    }
+    if group.egress.is_empty() {
+        egress.push(RuleSym {
+            protocol: "all".to_string(),
+            from: 0,
+            to: 0,
+            source: "0.0.0.0/0".to_string(),
+        });
+    }
+    let mut merged = default_tags.clone();
+    merged.extend(tags(scope, &group.tags)?);
+    Ok(SecurityGroupSym {
+        id: group.label.clone().unwrap_or(name.to_string()),
+        name: name,
+        description: optional_string(scope, "description", &group.description)?
+            .unwrap_or(GROUP_DESCRIPTION.to_string()),
+        vpc_id: optional_string(scope, "vpc_id", &group.vpc_id)?,
+        ingress: ingress,
+        egress: egress,
+        tags: merged,
+    })
+}
+
+/// An ingress or egress rule. `to` defaults to `from`, which `all`
+/// doesn't need; the source is a CIDR block, or a group by id or name.
+fn rule_sym(scope: &Scope, direction: &str, rule: &nodes::RuleNode) -> Result<RuleSym, AstError> {
+    let protocol = one_of(scope, "protocol", &rule.protocol, &PROTOCOLS)?;
+    let source = match (&rule.cidr, &rule.source_sg) {
+        (Some(e), None) => cidr_block("cidr", scope.string("cidr", e)?)?,
+        (None, Some(e)) => scope.string("source_sg", e)?,
+        (Some(_), Some(_)) => {
+            let s = format!("an {} rule sets both cidr and source_sg", direction);
+            return Err(AstError::new(s));
+        }
+        (None, None) => {
+            let s = format!("an {} rule needs a cidr or a source_sg", direction);
+            return Err(AstError::new(s));
+        }
+    };
+    if protocol == "all" {
+        return Ok(RuleSym {
+            protocol: protocol,
+            from: 0,
+            to: 0,
+            source: source,
+        });
+    }
+    // icmp takes a type and a code, -1 for any
+    let lowest = match protocol.as_str() {
+        "icmp" => -1,
+        _ => 0,
+    };
+    let port = |attr: &str, expr: &nodes::Expr| -> Result<i32, AstError> {
+        let n = scope.number(attr, expr)?;
+        if n < lowest as f64 || n > 65535.0 || n.fract() != 0.0 {
+            let s = format!(
+                "{} must be a whole number from {} to 65535, found {}",
+                attr, lowest, n
+            );
+            return Err(AstError::new(s));
+        }
+        Ok(n as i32)
+    };
+    let from = match &rule.from {
+        Some(e) => port("from", e)?,
+        None => {
+            let s = format!("an {} rule needs a from port", direction);
+            return Err(AstError::new(s));
+        }
+    };
+    let to = match &rule.to {
+        Some(e) => port("to", e)?,
+        None => from,
+    };
+    if protocol != "icmp" && to < from {
+        let s = format!("port range {}-{} ends before it starts", from, to);
+        return Err(AstError::new(s));
+    }
+    Ok(RuleSym {
+        protocol: protocol,
+        from: from,
+        to: to,
+        source: source,
+    })
+}
+
+/// An IPv4 CIDR block like `10.0.0.0/16`
+fn cidr_block(attr: &str, cidr: String) -> Result<String, AstError> {
+    let valid = match cidr.split_once('/') {
+        Some((ip, bits)) => {
+            ip.split('.').count() == 4
+                && ip.split('.').all(|o| o.parse::<u8>().is_ok())
+                && bits.parse::<u8>().is_ok_and(|b| b <= 32)
+        }
+        None => false,
+    };
+    if !valid {
+        let s = format!(
+            "{} expects an IPv4 CIDR block like 10.0.0.0/16, found {}",
+            attr, cidr
+        );
+        return Err(AstError::new(s));
+    }
+    Ok(cidr)
+}
+
+/// Evaluate key pairs into `aws_sym`, their ids prefixed with `prefix`
+fn add_key_pairs(
+    scope: &mut Scope,
+    aws_sym: &mut AwsSym,
+    key_nodes: &[nodes::KeyPairNode],
+    prefix: &str,
+) -> Result<(), AstError> {
+    for key in key_nodes {
+        if !included(scope, &key.when)? {
+            continue;
+        }
+        let mut key_sym = key_pair_sym(scope, key, &aws_sym.tags)?;
+        if aws_sym.keys.iter().any(|k| k.name == key_sym.name) {
+            let s = format!(
+                "key pair `{}` is declared twice, names must be unique",
+                key_sym.name
+            );
+            return Err(AstError::new(s));
+        }
+        for (attr, value) in key_sym.attrs() {
+            scope.set_attr(KEY_KIND, &key_sym.id, attr, value);
+        }
+        key_sym.id = format!("{}{}", prefix, key_sym.id);
+        aws_sym.add_key(key_sym);
+    }
+    Ok(())
+}
+
+/// A path given in the file `declared_in`, relative to the directory of
+/// that file unless it is absolute or starts at the home directory
+fn relative_to(declared_in: &str, path: String) -> String {
+    if path.starts_with('~') || Path::new(&path).is_absolute() {
+        return path;
+    }
+    let mut resolved = Path::new(declared_in)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .to_path_buf();
+    for part in Path::new(&path).components() {
+        if part != Component::CurDir {
+            resolved.push(part);
+        }
+    }
+    resolved.to_string_lossy().to_string()
+}
+
+/// A key pair is imported from `public_key_file` or, with
+/// `generate = true`, generated; its private key goes to
+/// `private_key_file`, `<name>.pem` by default. Both are relative to the
+/// file the key pair is declared in.
+fn key_pair_sym(
+    scope: &Scope,
+    key: &nodes::KeyPairNode,
+    default_tags: &BTreeMap<String, String>,
+) -> Result<KeyPairSym, AstError> {
+    let name = match (&key.name, &key.label) {
+        (Some(e), _) => scope.string("name", e)?,
+        (None, Some(label)) => label.to_string(),
+        (None, None) => return Err(AstError::new("No key_pair name provided!")),
+    };
+    if name.is_empty() || name.len() > 255 || !name.is_ascii() {
+        let s = format!("key pair name `{}` must be 1 to 255 ASCII characters", name);
+        return Err(AstError::new(s));
+    }
+    let generate = match &key.generate {
+        Some(e) => scope.bool("generate", e)?,
+        None => false,
+    };
+    let public_key_file = optional_string(scope, "public_key_file", &key.public_key_file)?;
+    let private_key_file = optional_string(scope, "private_key_file", &key.private_key_file)?;
+    let private_key_file = match (&public_key_file, generate) {
+        (Some(_), true) => {
+            let s = format!("key pair `{}` sets both public_key_file and generate", name);
+            return Err(AstError::new(s));
+        }
+        (None, false) => {
+            let s = format!(
+                "key pair `{}` needs a public_key_file or generate = true",
+                name
+            );
+            return Err(AstError::new(s));
+        }
+        (Some(_), false) if private_key_file.is_some() => {
+            let s = format!(
+                "key pair `{}` sets private_key_file, only generated keys have one",
+                name
+            );
+            return Err(AstError::new(s));
+        }
+        (Some(_), false) => None,
+        (None, true) => Some(private_key_file.unwrap_or(format!("{}.pem", name))),
+    };
+    let public_key_file = public_key_file.map(|f| relative_to(&key.file, f));
+    let private_key_file = private_key_file.map(|f| relative_to(&key.file, f));
+    let mut merged = default_tags.clone();
+    merged.extend(tags(scope, &key.tags)?);
+    Ok(KeyPairSym {
+        id: key.label.clone().unwrap_or(name.to_string()),
+        name: name,
+        public_key_file: public_key_file,
+        private_key_file: private_key_file,
+        tags: merged,
+    })
+}
+
+/// The default server side encryptions of a bucket, the first is the default
+const ENCRYPTIONS: [&str; 2] = ["AES256", "aws:kms"];
+
+/// Evaluate buckets into `aws_sym`, their ids prefixed with `prefix`
+fn add_s3s(
+    scope: &mut Scope,
+    aws_sym: &mut AwsSym,
+    s3_nodes: &[nodes::S3Node],
+    prefix: &str,
+) -> Result<(), AstError> {
+    for s3 in s3_nodes {
+        if !included(scope, &s3.when)? {
+            continue;
+        }
+        let mut s3_sym = s3_sym(scope, s3, &aws_sym.tags)?;
+        if aws_sym
+            .s3s
+            .iter()
+            .any(|b| b.id == s3_sym.id || b.name == s3_sym.name)
+        {
+            let s = format!(
+                "s3 `{}` is declared twice, buckets must be unique",
+                s3_sym.name
+            );
+            return Err(AstError::new(s));
+        }
+        for (attr, value) in s3_sym.attrs() {
+            scope.set_attr("s3", &s3_sym.id, attr, value);
+        }
+        s3_sym.id = format!("{}{}", prefix, s3_sym.id);
+        aws_sym.add_s3(s3_sym);
+    }
+    Ok(())
+}
+
+fn s3_sym(
+    scope: &Scope,
+    s3: &nodes::S3Node,
+    default_tags: &BTreeMap<String, String>,
+) -> Result<S3Sym, AstError> {
+    // a labelled bucket is named after its label unless it says otherwise
+    let name = match (&s3.name, &s3.label) {
+        (Some(e), _) => scope.string("name", e)?,
+        (None, Some(label)) => label.to_string(),
+        (None, None) => return Err(AstError::new("No s3 bucket name provided!")),
+    };
+    bucket_name(&name)?;
+    let encryption = one_of(scope, "encryption", &s3.encryption, &ENCRYPTIONS)?;
+    let mut lifecycle: Vec<LifecycleRule> = vec![];
+    for rule in &s3.lifecycle {
+        let rule = LifecycleRule {
+            prefix: optional_string(scope, "prefix", &rule.prefix)?.unwrap_or_default(),
+            expiration_days: days(scope, "expiration_days", &rule.expiration_days)?,
+            noncurrent_expiration_days: days(
+                scope,
+                "noncurrent_expiration_days",
+                &rule.noncurrent_expiration_days,
+            )?,
+        };
+        if rule.expiration_days.is_none() && rule.noncurrent_expiration_days.is_none() {
+            return Err(AstError::new(
+                "a lifecycle rule needs expiration_days or noncurrent_expiration_days",
+            ));
+        }
+        lifecycle.push(rule);
+    }
+    let mut merged = default_tags.clone();
+    merged.extend(tags(scope, &s3.tags)?);
+    Ok(S3Sym {
+        id: s3.label.clone().unwrap_or(name.to_string()),
+        name: name,
+        versioning: match &s3.versioning {
+            Some(e) => scope.bool("versioning", e)?,
+            None => false,
+        },
+        encryption: encryption,
+        public_access_block: match &s3.public_access_block {
+            Some(e) => scope.bool("public_access_block", e)?,
+            None => true,
+        },
+        lifecycle: lifecycle,
+        tags: merged,
+    })
+}
+
+/// Bucket names are 3 to 63 lowercase letters, digits, dots and hyphens,
+/// starting and ending with a letter or a digit
+fn bucket_name(name: &str) -> Result<(), AstError> {
+    let alnum = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+    let valid = (3..=63).contains(&name.len())
+        && name.chars().all(|c| alnum(c) || c == '.' || c == '-')
+        && name.starts_with(alnum)
+        && name.ends_with(alnum)
+        && !name.contains("..");
+    if !valid {
+        let s = format!(
+            "s3 bucket name `{}` must be 3 to 63 lowercase letters, digits, dots or hyphens, starting and ending with a letter or digit",
+            name
+        );
+        return Err(AstError::new(s));
+    }
+    Ok(())
+}
+
+/// The billing modes of a table, the first is the default
+const BILLING_MODES: [&str; 2] = ["PAY_PER_REQUEST", "PROVISIONED"];
+
+/// The types of a key attribute: string, number and binary
+const ATTRIBUTE_TYPES: [&str; 3] = ["S", "N", "B"];
+
+/// The projections of an index, the first is the default
+const PROJECTIONS: [&str; 2] = ["ALL", "KEYS_ONLY"];
+
+/// Evaluate tables into `aws_sym`, their ids prefixed with `prefix`
+fn add_tables(
+    scope: &mut Scope,
+    aws_sym: &mut AwsSym,
+    dynamodb_nodes: &[nodes::DynamoNode],
+    prefix: &str,
+) -> Result<(), AstError> {
+    for table in dynamodb_nodes {
+        if !included(scope, &table.when)? {
+            continue;
+        }
+        let mut table_sym = dynamodb_sym(scope, table, &aws_sym.tags)?;
+        if aws_sym
+            .tables
+            .iter()
+            .any(|t| t.id == table_sym.id || t.name == table_sym.name)
+        {
+            let s = format!(
+                "dynamodb `{}` is declared twice, tables must be unique",
+                table_sym.name
+            );
+            return Err(AstError::new(s));
+        }
+        for (attr, value) in table_sym.attrs() {
+            scope.set_attr("dynamodb", &table_sym.id, attr, value);
+        }
+        table_sym.id = format!("{}{}", prefix, table_sym.id);
+        aws_sym.add_table(table_sym);
+    }
+    Ok(())
+}
+
+fn dynamodb_sym(
+    scope: &Scope,
+    table: &nodes::DynamoNode,
+    default_tags: &BTreeMap<String, String>,
+) -> Result<DynamoSym, AstError> {
+    // a labelled table is named after its label unless it says otherwise
+    let name = match (&table.name, &table.label) {
+        (Some(e), _) => scope.string("name", e)?,
+        (None, Some(label)) => label.to_string(),
+        (None, None) => return Err(AstError::new("No dynamodb table name provided!")),
+    };
+    table_name("dynamodb table", &name)?;
+    let billing_mode = one_of(scope, "billing_mode", &table.billing_mode, &BILLING_MODES)?;
+    let provisioned = billing_mode == BILLING_MODES[1];
+    let (read_capacity, write_capacity) = capacities(
+        scope,
+        provisioned,
+        &table.read_capacity,
+        &table.write_capacity,
+    )?;
+    let attributes = match &table.attributes {
+        Some(e) => scope.string_map("attributes", e)?,
+        None => BTreeMap::new(),
+    };
+    if let Some((attr, t)) = attributes
+        .iter()
+        .find(|(_, t)| !ATTRIBUTE_TYPES.contains(&t.as_str()))
+    {
+        let s = format!(
+            "attribute `{}` must be of type {}, found {}",
+            attr,
+            ATTRIBUTE_TYPES.join(", "),
+            t
+        );
+        return Err(AstError::new(s));
+    }
+    let hash_key = required_string(
+        scope,
+        "hash_key",
+        &table.hash_key,
+        "No dynamodb hash_key provided!",
+    )?;
+    let range_key = optional_string(scope, "range_key", &table.range_key)?;
+
+    let mut gsis: Vec<GsiSym> = vec![];
+    for gsi in &table.gsis {
+        let gsi_name = required_string(scope, "name", &gsi.name, "No gsi name provided!")?;
+        table_name("gsi", &gsi_name)?;
+        if gsis.iter().any(|g| g.name == gsi_name) {
+            let s = format!("gsi `{}` is declared twice in {}", gsi_name, name);
+            return Err(AstError::new(s));
+        }
+        let (read_capacity, write_capacity) =
+            capacities(scope, provisioned, &gsi.read_capacity, &gsi.write_capacity)?;
+        gsis.push(GsiSym {
+            hash_key: required_string(
+                scope,
+                "hash_key",
+                &gsi.hash_key,
+                "No gsi hash_key provided!",
+            )?,
+            range_key: optional_string(scope, "range_key", &gsi.range_key)?,
+            projection: one_of(scope, "projection", &gsi.projection, &PROJECTIONS)?,
+            name: gsi_name,
+            read_capacity: read_capacity,
+            write_capacity: write_capacity,
+        });
+    }
+
+    // DynamoDB only takes the definitions of the key attributes
+    let mut keys: Vec<&String> = vec![&hash_key];
+    keys.extend(&range_key);
+    for gsi in &gsis {
+        keys.push(&gsi.hash_key);
+        keys.extend(&gsi.range_key);
+    }
+    if let Some(key) = keys.iter().find(|k| !attributes.contains_key(k.as_str())) {
+        let s = format!(
+            "key `{}` of {} has no type, declare it in attributes",
+            key, name
+        );
+        return Err(AstError::new(s));
+    }
+    if let Some(attr) = attributes.keys().find(|a| !keys.contains(a)) {
+        let s = format!(
+            "attribute `{}` of {} is not a key, only key attributes are declared",
+            attr, name
+        );
+        return Err(AstError::new(s));
+    }
+
+    let mut merged = default_tags.clone();
+    merged.extend(tags(scope, &table.tags)?);
+    Ok(DynamoSym {
+        id: table.label.clone().unwrap_or(name.to_string()),
+        name: name,
+        hash_key: hash_key,
+        range_key: range_key,
+        attributes: attributes,
+        billing_mode: billing_mode,
+        read_capacity: read_capacity,
+        write_capacity: write_capacity,
+        ttl_attribute: optional_string(scope, "ttl_attribute", &table.ttl_attribute)?,
+        gsis: gsis,
+        tags: merged,
+    })
+}
+
+/// Table and index names are 3 to 255 letters, digits, `_`, `-` and `.`
+fn table_name(kind: &str, name: &str) -> Result<(), AstError> {
+    let valid = (3..=255).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+    if !valid {
+        let s = format!(
+            "{} name `{}` must be 3 to 255 letters, digits, underscores, hyphens or dots",
+            kind, name
+        );
+        return Err(AstError::new(s));
+    }
+    Ok(())
+}
+
+/// An optional string attribute that must be one of `values`, the first
+/// being the default
+fn one_of(
+    scope: &Scope,
+    attr: &str,
+    expr: &Option<nodes::Expr>,
+    values: &[&str],
+) -> Result<String, AstError> {
+    let value = match expr {
+        Some(e) => scope.string(attr, e)?,
+        None => return Ok(values[0].to_string()),
+    };
+    if !values.contains(&value.as_str()) {
+        let s = format!(
+            "{} must be one of {}, found {}",
+            attr,
+            values.join(", "),
+            value
+        );
+        return Err(AstError::new(s));
+    }
+    Ok(value)
+}
+
+/// The read and write capacities, required when the billing mode is
+/// `provisioned` and refused otherwise
+fn capacities(
+    scope: &Scope,
+    provisioned: bool,
+    read: &Option<nodes::Expr>,
+    write: &Option<nodes::Expr>,
+) -> Result<(Option<i64>, Option<i64>), AstError> {
+    let mut units: Vec<Option<i64>> = vec![];
+    for (attr, expr) in [("read_capacity", read), ("write_capacity", write)] {
+        let n = match (expr, provisioned) {
+            (Some(e), true) => scope.number(attr, e)?,
+            (None, false) => {
+                units.push(None);
+                continue;
+            }
+            (Some(_), false) => {
+                let s = format!("{} is only set with billing_mode PROVISIONED", attr);
+                return Err(AstError::new(s));
+            }
+            (None, true) => {
+                let s = format!("billing_mode PROVISIONED needs a {}", attr);
+                return Err(AstError::new(s));
+            }
+        };
+        if n < 1.0 || n.fract() != 0.0 {
+            let s = format!("{} must be a whole number from 1, found {}", attr, n);
+            return Err(AstError::new(s));
+        }
+        units.push(Some(n as i64));
+    }
+    Ok((units[0], units[1]))
+}
+
+/// An optional number of days, a whole number from 1
+fn days(scope: &Scope, attr: &str, expr: &Option<nodes::Expr>) -> Result<Option<i32>, AstError> {
+    let n = match expr {
+        Some(e) => scope.number(attr, e)?,
+        None => return Ok(None),
+    };
+    if n < 1.0 || n > i32::MAX as f64 || n.fract() != 0.0 {
+        let s = format!(
+            "{} must be a whole number of days from 1, found {}",
+            attr, n
+        );
+        return Err(AstError::new(s));
+    }
+    Ok(Some(n as i32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aws::parser::Parser;
+    use crate::lex::{Scanner, read_lex_file};
+    use std::{env, fs, process};
+
+    fn walk_all(s: &str) -> Result<Vec<AwsSym>, AstError> {
+        let mut parser = Parser::new(Scanner::new("".to_string(), s.to_string()));
+        walk_ast(&parser.parse().unwrap())
+    }
+
+    /// the first stack of `s`
+    fn walk(s: &str) -> Result<AwsSym, AstError> {
+        walk_all(s).map(|mut aws_syms| aws_syms.remove(0))
+    }
+
+    /// Write `files` under a fresh directory and walk the first one
+    fn walk_files(dir: &Path, files: &[(&str, &str)]) -> Result<Vec<AwsSym>, String> {
+        for (name, contents) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        let root = dir.join(files[0].0);
+        let mut parser = Parser::new(read_lex_file(root.to_str().unwrap()).unwrap());
+        let program = parser.parse().map_err(|e| e.to_string())?;
+        walk_ast(&program).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_walk_ast_with_vars() {
+        let aws_sym = walk(
+            "// shared settings
+var subnet = \"subnet-1\"
+let replicas = 2
+aws {
+  region = \"eu-west-1\"
+  ec2 { name = \"web\" description = \"d\" instance_type = \"t2.micro\"
+        count = replicas ami = \"ami-1\" subnet_id = subnet sg_id = sg key_name = \"k\" }
+}
+var sg = \"sg-1\"
+",
+        )
+        .unwrap();
+        assert_eq!(aws_sym.name, "aws");
+        let ec2 = &aws_sym.ec2s[0];
+        assert_eq!(ec2.subnet_id, "subnet-1");
+        assert_eq!(ec2.sg_ids, vec!["sg-1"]);
+        assert_eq!(ec2.count, 2);
+    }
+
+    #[test]
+    fn test_walk_ast_interpolation() {
+        let aws_sym = walk(
+            "var env = \"prod\"
+aws {
+  region = \"eu-west-1\"
+  name = \"${env}-stack\"
+  ec2 { name = \"web_${env}_${index}\" description = \"in ${region} for ${stack}\"
+        instance_type = \"t2.micro\" count = 2 ami = \"ami-1\" subnet_id = \"subnet-1\"
+        sg_id = \"sg-1\" key_name = \"k\" }
+  ec2 { name = \"db\" description = \"next to ${ec2.web_prod_1.name}\"
+        instance_type = \"t2.micro\" count = 1 ami = \"ami-1\"
+        subnet_id = \"${ec2.web_prod_0.subnet_id}\" sg_id = \"sg-1\" key_name = \"k\" }
+}
+",
+        )
+        .unwrap();
+        assert_eq!(aws_sym.name, "prod-stack");
+        let names: Vec<&str> = aws_sym.ec2s.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["web_prod_0", "web_prod_1", "db"]);
+        assert_eq!(aws_sym.ec2s[0].count, 1);
+        assert_eq!(aws_sym.ec2s[0].desc, "in eu-west-1 for prod-stack");
+        assert_eq!(aws_sym.ec2s[2].desc, "next to web_prod_1");
+        assert_eq!(aws_sym.ec2s[2].subnet_id, "subnet-1");
+    }
+
+    #[test]
+    fn test_walk_ast_interpolation_errors() {
+        let err = walk(
+            "aws { region = \"eu-west-1\"
+  ec2 { name = \"web\" description = \"${ec2.db.name}\" instance_type = \"t2.micro\"
+        ami = \"ami-1\" subnet_id = \"subnet-1\" sg_id = \"sg-1\" key_name = \"k\" }
+}",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: in `${ec2.db.name}` at (2,37): undefined reference `ec2.db.name` at (2,39), resources can only refer to the ones declared before them"
+        );
+        // without `${index}` the expanded names would clash
+        let err = walk(
+            "aws { region = \"eu-west-1\"
+  ec2 { name = \"web\" description = \"${index}\" instance_type = \"t2.micro\" count = 2
+        ami = \"ami-1\" subnet_id = \"subnet-1\" sg_id = \"sg-1\" key_name = \"k\" }
+}",
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("ec2 `web` is declared twice"));
+    }
+
+    #[test]
+    fn test_walk_ast_expressions() {
+        let aws_sym = walk(
+            "let replicas = 2
+let env = \"prod\"
+let enabled = env == \"prod\" && !(replicas < 1)
+aws {
+  region = \"eu-west-1\"
+  ec2 { name = \"web\" description = \"${replicas * 2 + 1} or ${enabled}\"
+        instance_type = \"t2.\" + \"micro\" count = replicas * 2 - 1
+        ami = \"ami-1\" subnet_id = \"subnet-1\" sg_id = \"sg-1\" key_name = \"k\" }
+}
+",
+        )
+        .unwrap();
+        let ec2 = &aws_sym.ec2s[0];
+        assert_eq!(ec2.desc, "5 or true");
+        assert_eq!(ec2.instance_type, "t2.micro");
+        assert_eq!(ec2.count, 3);
+        let err = walk("let n = 1 aws { region = \"eu-\" + n }")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: `+` at (1,32) can't apply to string eu- and number 1"
+        );
+    }
+
+    #[test]
+    fn test_walk_ast_lists_and_maps() {
+        let aws_sym = walk(
+            "let common = [\"sg-1\", \"sg-2\"]
+aws {
+  region = \"eu-west-1\"
+  ec2 { name = \"web\" description = \"d\" instance_type = \"t2.micro\" ami = \"ami-1\"
+        subnet_id = \"subnet-1\" key_name = \"k\" sg_ids = common + [\"sg-3\",]
+        tags = { team = \"web\", \"cost-center\" = \"42\" } }
+  ec2 { name = \"db\" description = \"d\" instance_type = \"t2.micro\" ami = \"ami-1\"
+        subnet_id = \"subnet-1\" key_name = \"k\" sg_ids = ec2.web.sg_ids
+        tags = ec2.web.tags + { team = \"db\" } }
+}
+",
+        )
+        .unwrap();
+        let web = &aws_sym.ec2s[0];
+        assert_eq!(web.sg_ids, vec!["sg-1", "sg-2", "sg-3"]);
+        assert_eq!(web.tags["cost-center"], "42");
+        let db = &aws_sym.ec2s[1];
+        assert_eq!(db.sg_ids, web.sg_ids);
+        assert_eq!(db.tags["team"], "db");
+        assert_eq!(db.tags["cost-center"], "42");
+
+        let err = walk(
+            "aws { region = \"eu-west-1\"
+  ec2 { name = \"web\" description = \"d\" instance_type = \"t2.micro\" ami = \"ami-1\"
+        subnet_id = \"subnet-1\" key_name = \"k\" sg_ids = [] }
+}",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.to_string(), "Ast Error: No ec2 sg ids provided!");
+        let err = walk(
+            "aws { region = \"eu-west-1\"
+  ec2 { name = \"web\" description = \"d\" instance_type = \"t2.micro\" ami = \"ami-1\"
+        subnet_id = \"subnet-1\" key_name = \"k\" sg_id = \"sg-1\" tags = { Name = \"x\" } }
+}",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: tag `Name` is set by awsdsl and can't be overridden"
+        );
+    }
+
+    #[test]
+    fn test_walk_ast_tags_blocks() {
+        let aws_sym = walk(
+            "aws {
+  region = \"eu-west-1\"
+  tags { Owner = \"team-x\" CostCenter = \"42\" }
+  ec2 { name = \"web\" description = \"d\" instance_type = \"t2.micro\" ami = \"ami-1\"
+        subnet_id = \"subnet-1\" key_name = \"k\" sg_id = \"sg-1\"
+        tags { Owner = \"team-y\" Role = \"web in ${region}\" } }
+  ec2 { name = \"db\" description = \"d\" instance_type = \"t2.micro\" ami = \"ami-1\"
+        subnet_id = \"subnet-1\" key_name = \"k\" sg_id = \"sg-1\" }
+}
+",
+        )
+        .unwrap();
+        assert_eq!(aws_sym.tags.len(), 2);
+        let web = &aws_sym.ec2s[0];
+        assert_eq!(web.tags["Owner"], "team-y");
+        assert_eq!(web.tags["CostCenter"], "42");
+        assert_eq!(web.tags["Role"], "web in eu-west-1");
+        assert_eq!(aws_sym.ec2s[1].tags, aws_sym.tags);
+
+        let err = walk("aws { region = \"eu-west-1\" tags { \"awsdsl:stack\" = \"x\" } }")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: tag `awsdsl:stack` is set by awsdsl and can't be overridden"
+        );
+    }
+
+    #[test]
+    fn test_walk_ast_meta_args() {
+        let aws_sym = walk(
+            "var subnets = { a = \"subnet-1\", b = \"subnet-2\" }
+aws {
+  region = \"eu-west-1\"
+  ec2 \"web\" for_each = [\"a\", \"b\"] {
+    description = \"web in ${each}\" instance_type = \"t2.micro\" ami = \"ami-1\"
+    subnet_id = subnets[each] key_name = \"k\" sg_id = \"sg-1\" }
+  ec2 \"db\" for_each = subnets {
+    name = \"db-${each}\" description = \"d\" instance_type = \"t2.micro\" ami = \"ami-1\"
+    subnet_id = subnets[each] key_name = \"k\" sg_id = \"sg-1\" }
+  ec2 \"worker\" count = 2 {
+    description = \"d\" instance_type = \"t2.micro\" ami = \"ami-1\"
+    subnet_id = [\"subnet-1\", \"subnet-2\"][index] key_name = \"k\" sg_id = \"sg-1\" }
+  ec2 \"bastion\" {
+    description = \"d\" instance_type = \"t2.micro\" ami = \"ami-1\"
+    subnet_id = \"subnet-3\" key_name = \"k\" sg_id = \"sg-1\" }
+}
+",
+        );
+        let aws_sym = aws_sym.unwrap();
+        let ids: Vec<&str> = aws_sym.ec2s.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            vec![
+                "web[a]",
+                "web[b]",
+                "db[a]",
+                "db[b]",
+                "worker[0]",
+                "worker[1]",
+                "bastion"
+            ]
+        );
+        let web_b = &aws_sym.ec2s[1];
+        assert_eq!(web_b.name, "web-b");
+        assert_eq!(web_b.desc, "web in b");
+        assert_eq!(web_b.subnet_id, "subnet-2");
+        assert_eq!(web_b.key, Some("b".to_string()));
+        assert_eq!(aws_sym.ec2s[3].name, "db-b");
+        assert_eq!(aws_sym.ec2s[5].subnet_id, "subnet-2");
+        assert_eq!(aws_sym.ec2s[5].key, Some("1".to_string()));
+        assert_eq!(aws_sym.ec2s[6].name, "bastion");
+        assert_eq!(aws_sym.ec2s[6].key, None);
+        assert_eq!(aws_sym.ec2s[6].subnet_id, "subnet-3");
+        // without a count a resource is one instance
+        assert_eq!(aws_sym.ec2s[6].count, 1);
+        for i in 0..6 {
+            assert_eq!(aws_sym.ec2s[i].count, 1);
+        }
+
+        let ec2 = |header: &str, subnet: &str| {
+            format!(
+                "var subnets = {{ a = \"subnet-1\" }}
+aws {{ region = \"eu-west-1\"
+  ec2 {} {{ description = \"d\" instance_type = \"t2.micro\" ami = \"ami-1\"
+    subnet_id = {} key_name = \"k\" sg_id = \"sg-1\" }}
+}}",
+                header, subnet
+            )
+        };
+        let err = walk(&ec2("\"web\" for_each = [\"a\", \"b\"]", "subnets[each]"))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: `[]` at (4,24): no b in {a = subnet-1}"
+        );
+        let err = walk(&ec2("\"web\" for_each = \"a\"", "\"subnet-1\""))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: for_each expects a list of strings or a map, found a"
+        );
+        let err = walk(&ec2("\"web\" for_each = [\"a\", \"a\"]", "\"subnet-1\""))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: for_each has `a` twice, keys must be unique"
+        );
+        let err = walk(&ec2("\"web\" count = 1.5", "\"subnet-1\""))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: count must be a whole number up to 255, found 1.5"
+        );
+        let err = walk(&ec2("\"web\" count = 2", "\"subnet-1\" count = 3"))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: ec2 `web` sets count next to a for_each or count meta-argument, each copy is one instance"
+        );
+        let err = walk(&ec2("\"web\" count = 1", "ec2.web.subnet_id"))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("ec2.web"), "{}", err);
+    }
+
+    #[test]
+    fn test_walk_ast_conditionals() {
+        let program = |env: &str| {
+            format!(
+                "var env = \"{}\"
+aws {{
+  region = \"eu-west-1\"
+  ec2 {{ name = \"web\" description = \"d\" instance_type = \"t2.micro\" ami = \"ami-1\"
+        subnet_id = \"subnet-1\" key_name = \"k\" sg_id = \"sg-1\" }}
+  // skipped, so its missing attributes are never looked at
+  ec2 {{ name = \"debug\" when = env == \"dev\" && false }}
+  if env == \"prod\" {{
+    ec2 \"db\" for_each = [\"a\", \"b\"] {{ when = each != \"b\"
+      description = \"d\" instance_type = \"m5.large\" ami = \"ami-1\"
+      subnet_id = \"subnet-1\" key_name = \"k\" sg_id = \"sg-1\" }}
+  }} else if env == \"staging\" {{
+    ec2 {{ name = \"db\" description = \"d\" instance_type = \"t2.small\" ami = \"ami-1\"
+          subnet_id = \"subnet-1\" key_name = \"k\" sg_id = \"sg-1\" }}
+  }} else {{
+    // no database
+  }}
+}}
+",
+                env
+            )
+        };
+        let names = |env: &str| -> Vec<String> {
+            walk(&program(env))
+                .unwrap()
+                .ec2s
+                .iter()
+                .map(|e| format!("{} {}", e.name, e.instance_type))
+                .collect()
+        };
+        assert_eq!(names("prod"), vec!["web t2.micro", "db-a m5.large"]);
+        assert_eq!(names("staging"), vec!["web t2.micro", "db t2.small"]);
+        assert_eq!(names("dev"), vec!["web t2.micro"]);
+
+        let err = walk("aws { region = \"eu-west-1\" ec2 { name = \"web\" when = \"yes\" } }")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: when expects a bool, found string yes"
+        );
+    }
+
+    #[test]
+    fn test_walk_ast_s3_buckets() {
+        let aws_sym = walk(
+            "var env = \"dev\"
+aws {
+  region = \"eu-west-1\"
+  endpoint = \"http://localhost:4566\"
+  tags { team = \"web\" }
+  s3 \"logs\" {
+    name = \"acme-logs-${env}\"
+    versioning = true
+    lifecycle { prefix = \"tmp/\" expiration_days = 7 }
+    lifecycle { noncurrent_expiration_days = 30 }
+  }
+  s3 \"assets\" { encryption = \"aws:kms\" public_access_block = false when = env == \"dev\" }
+  ec2 { name = \"web\" description = \"${s3.logs.arn}\" instance_type = \"t2.micro\"
+        ami = \"ami-1\" subnet_id = \"subnet-1\" key_name = \"k\" sg_id = \"sg-1\" }
+}",
+        )
+        .unwrap();
+        assert_eq!(aws_sym.endpoint, Some("http://localhost:4566".to_string()));
+        let logs = &aws_sym.s3s[0];
+        assert_eq!(logs.id, "logs");
+        assert_eq!(logs.name, "acme-logs-dev");
+        assert!(logs.versioning && logs.public_access_block);
+        assert_eq!(logs.encryption, "AES256");
+        assert_eq!(
+            logs.lifecycle,
+            vec![
+                LifecycleRule {
+                    prefix: "tmp/".to_string(),
+                    expiration_days: Some(7),
+                    noncurrent_expiration_days: None,
+                },
+                LifecycleRule {
+                    prefix: "".to_string(),
+                    expiration_days: None,
+                    noncurrent_expiration_days: Some(30),
+                },
+            ]
+        );
+        assert_eq!(logs.tags.get("team"), Some(&"web".to_string()));
+        let assets = &aws_sym.s3s[1];
+        assert_eq!(assets.name, "assets");
+        assert_eq!(assets.encryption, "aws:kms");
+        assert!(!assets.versioning && !assets.public_access_block);
+        assert_eq!(aws_sym.ec2s[0].desc, "arn:aws:s3:::acme-logs-dev");
+
+        let err = |s3: &str| {
+            walk(&format!("aws {{ region = \"eu-west-1\" {} }}", s3))
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            err("s3 \"Logs\" { }"),
+            "Ast Error: s3 bucket name `Logs` must be 3 to 63 lowercase letters, digits, dots or hyphens, starting and ending with a letter or digit"
+        );
+        assert_eq!(
+            err("s3 \"logs\" { encryption = \"des\" }"),
+            "Ast Error: encryption must be one of AES256, aws:kms, found des"
+        );
+        assert_eq!(
+            err("s3 \"logs\" { lifecycle { expiration_days = 1.5 } }"),
+            "Ast Error: expiration_days must be a whole number of days from 1, found 1.5"
+        );
+        assert_eq!(
+            err("s3 \"logs\" { lifecycle { prefix = \"a/\" } }"),
+            "Ast Error: a lifecycle rule needs expiration_days or noncurrent_expiration_days"
+        );
+        assert_eq!(
+            err("s3 \"logs\" { } s3 \"other\" { name = \"logs\" }"),
+            "Ast Error: s3 `logs` is declared twice, buckets must be unique"
+        );
+    }
+
+    #[test]
+    fn test_walk_ast_dynamodb_tables() {
+        let aws_sym = walk(
+            "aws {
+  region = \"eu-west-1\"
+  dynamodb \"sessions\" {
+    hash_key = \"user_id\"
+    range_key = \"created\"
+    attributes { user_id = \"S\" created = \"N\" email = \"S\" }
+    billing_mode = \"PROVISIONED\"
+    read_capacity = 5
+    write_capacity = 2
+    ttl_attribute = \"expires\"
+    gsi { name = \"by_email\" hash_key = \"email\" projection = \"KEYS_ONLY\"
+          read_capacity = 1 write_capacity = 1 }
+  }
+  dynamodb { name = \"events-${dynamodb.sessions.name}\" hash_key = \"id\" attributes = { id = \"S\" } }
+}",
+        )
+        .unwrap();
+        let sessions = &aws_sym.tables[0];
+        assert_eq!(sessions.id, "sessions");
+        assert_eq!(sessions.name, "sessions");
+        assert_eq!(sessions.range_key, Some("created".to_string()));
+        assert_eq!(
+            (sessions.read_capacity, sessions.write_capacity),
+            (Some(5), Some(2))
+        );
+        assert_eq!(sessions.ttl_attribute, Some("expires".to_string()));
+        assert_eq!(
+            sessions.gsis[0].to_string(),
+            "by_email(email) KEYS_ONLY 1r/1w"
+        );
+        let events = &aws_sym.tables[1];
+        assert_eq!(events.id, "events-sessions");
+        assert_eq!(events.billing_mode, "PAY_PER_REQUEST");
+        assert_eq!(events.read_capacity, None);
+
+        let err = |table: &str| {
+            walk(&format!("aws {{ region = \"eu-west-1\" {} }}", table))
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            err("dynamodb \"t1\" { hash_key = \"id\" }"),
+            "Ast Error: dynamodb table name `t1` must be 3 to 255 letters, digits, underscores, hyphens or dots"
+        );
+        assert_eq!(
+            err("dynamodb \"users\" { hash_key = \"id\" }"),
+            "Ast Error: key `id` of users has no type, declare it in attributes"
+        );
+        assert_eq!(
+            err("dynamodb \"users\" { hash_key = \"id\" attributes { id = \"S\" age = \"N\" } }"),
+            "Ast Error: attribute `age` of users is not a key, only key attributes are declared"
+        );
+        assert_eq!(
+            err("dynamodb \"users\" { hash_key = \"id\" attributes { id = \"X\" } }"),
+            "Ast Error: attribute `id` must be of type S, N, B, found X"
+        );
+        assert_eq!(
+            err(
+                "dynamodb \"users\" { hash_key = \"id\" attributes { id = \"S\" } read_capacity = 1 }"
             ),
-            ec2.app_version,
-            ec2.count,
-            String::from(
-                ec2.key_name
-                    .as_ref()
-                    .ok_or(AstError::new("No key name provided!"))?,
+            "Ast Error: read_capacity is only set with billing_mode PROVISIONED"
+        );
+        assert_eq!(
+            err(
+                "dynamodb \"users\" { hash_key = \"id\" attributes { id = \"S\" } billing_mode = \"PROVISIONED\" read_capacity = 1 }"
             ),
+            "Ast Error: billing_mode PROVISIONED needs a write_capacity"
         );
-        aws_sym.add_ec2(ec2_sym);
     }
 
-    Ok(aws_sym)
+    #[test]
+    fn test_walk_ast_network() {
+        let aws_sym = walk(
+            "aws {
+  region = \"eu-west-1\"
+  ec2 { name = \"web\" description = \"d\" instance_type = \"t2.micro\" ami = \"ami-1\"
+        subnet_id = subnet.public.id sg_id = \"sg-1\" key_name = \"k\" }
+  route_table \"public\" {
+    vpc = vpc.main.id
+    subnets = [subnet.public.id]
+    route { cidr = \"0.0.0.0/0\" gateway = internet_gateway.main.id }
+  }
+  subnet \"public\" { vpc = vpc.main.id cidr = \"10.0.1.0/24\" az = \"eu-west-1a\" public = true }
+  internet_gateway \"main\" { vpc = vpc.main.id }
+  vpc \"main\" { cidr = \"10.0.0.0/16\" tags { team = \"web\" } }
+}",
+        )
+        .unwrap();
+        let kinds: Vec<&str> = aws_sym.networks.iter().map(|n| n.kind).collect();
+        assert_eq!(
+            kinds,
+            vec!["vpc", "internet_gateway", "subnet", "route_table"]
+        );
+        let subnet = &aws_sym.networks[2];
+        assert_eq!(subnet.vpc_id, Some("<vpc.main.id>".to_string()));
+        assert!(subnet.public);
+        let table = &aws_sym.networks[3];
+        assert_eq!(table.subnet_ids, vec!["<subnet.public.id>"]);
+        assert_eq!(
+            table.routes[0].to_string(),
+            "0.0.0.0/0 -> <internet_gateway.main.id>"
+        );
+        assert_eq!(aws_sym.ec2s[0].subnet_id, "<subnet.public.id>");
+        assert_eq!(aws_sym.ec2s[0].pending(), vec!["<subnet.public.id>"]);
+
+        let ids = BTreeMap::from([("<subnet.public.id>".to_string(), "subnet-9".to_string())]);
+        assert_eq!(aws_sym.resolved(&ids).ec2s[0].subnet_id, "subnet-9");
+
+        let err = |network: &str| {
+            walk(&format!("aws {{ region = \"eu-west-1\" {} }}", network))
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            err("subnet \"a\" { cidr = \"10.0.1.0/24\" }"),
+            "Ast Error: No subnet vpc provided!"
+        );
+        assert_eq!(
+            err("vpc \"main\" { cidr = \"10.0.0/16\" }"),
+            "Ast Error: cidr expects an IPv4 CIDR block like 10.0.0.0/16, found 10.0.0/16"
+        );
+        assert_eq!(
+            err("route_table \"rt\" { vpc = \"vpc-1\" route { cidr = \"0.0.0.0/0\" } }"),
+            "Ast Error: a route needs a gateway or a nat_gateway"
+        );
+        assert_eq!(
+            err("vpc \"a\" { cidr = \"10.0.0.0/16\" } vpc { name = \"a\" cidr = \"10.1.0.0/16\" }"),
+            "Ast Error: vpc `a` is declared twice, ids must be unique"
+        );
+    }
+
+    #[test]
+    fn test_walk_ast_security_groups() {
+        let aws_sym = walk(
+            "aws {
+  region = \"eu-west-1\"
+  ec2 { name = \"web\" description = \"d\" instance_type = \"t2.micro\" ami = \"ami-1\"
+        subnet_id = \"subnet-1\" sg_ids = [\"web\", \"sg-0123\"] key_name = \"k\" }
+  vpc \"main\" { cidr = \"10.0.0.0/16\" }
+  security_group \"web\" {
+    vpc_id = vpc.main.id
+    ingress { from = 443 protocol = \"tcp\" cidr = \"0.0.0.0/0\" }
+    ingress { from = 8000 to = 8080 source_sg = \"lb\" }
+  }
+  security_group \"lb\" {
+    description = \"load balancer\"
+    ingress { protocol = \"all\" source_sg = security_group.web.id }
+    egress { protocol = \"icmp\" from = -1 to = -1 cidr = \"10.0.0.0/8\" }
+  }
+}",
+        )
+        .unwrap();
+        let web = &aws_sym.groups[0];
+        assert_eq!(web.vpc_id, Some("<vpc.main.id>".to_string()));
+        assert_eq!(web.description, "managed by awsdsl");
+        let rules: Vec<String> = web.ingress.iter().map(|r| r.to_string()).collect();
+        // groups are referred to by name, whatever their order
+        assert_eq!(
+            rules,
+            vec![
+                "tcp 443-443 0.0.0.0/0",
+                "tcp 8000-8080 <security_group.lb.id>"
+            ]
+        );
+        assert_eq!(web.egress[0].to_string(), "all 0.0.0.0/0");
+        let lb = &aws_sym.groups[1];
+        assert_eq!(lb.ingress[0].to_string(), "all <security_group.web.id>");
+        assert_eq!(lb.egress[0].to_string(), "icmp -1--1 10.0.0.0/8");
+        assert_eq!(
+            aws_sym.ec2s[0].sg_ids,
+            vec!["<security_group.web.id>", "sg-0123"]
+        );
+
+        let err = |group: &str| {
+            walk(&format!("aws {{ region = \"eu-west-1\" {} }}", group))
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            err("security_group \"a\" { ingress { from = 22 } }"),
+            "Ast Error: an ingress rule needs a cidr or a source_sg"
+        );
+        assert_eq!(
+            err("security_group \"a\" { egress { cidr = \"0.0.0.0/0\" } }"),
+            "Ast Error: an egress rule needs a from port"
+        );
+        assert_eq!(
+            err("security_group \"a\" { ingress { from = 80 to = 70 cidr = \"0.0.0.0/0\" } }"),
+            "Ast Error: port range 80-70 ends before it starts"
+        );
+        assert_eq!(
+            err("security_group \"a\" { ingress { protocol = \"gre\" cidr = \"0.0.0.0/0\" } }"),
+            "Ast Error: protocol must be one of tcp, udp, icmp, all, found gre"
+        );
+        assert_eq!(
+            err("security_group { name = \"sg-1\" }"),
+            "Ast Error: security group name `sg-1` must not be empty or start with sg-"
+        );
+    }
+
+    #[test]
+    fn test_walk_ast_key_pairs() {
+        let aws_sym = walk(
+            "aws {
+  region = \"eu-west-1\"
+  key_pair \"deploy\" { generate = true }
+  key_pair \"ops\" { name = \"ops-key\" public_key_file = \"~/.ssh/id_ed25519.pub\" }
+  ec2 { name = \"web\" description = \"d\" instance_type = \"t2.micro\" ami = \"ami-1\"
+        subnet_id = \"subnet-1\" sg_id = \"sg-1\" key_name = \"deploy\" }
+  ec2 { name = \"ops\" description = \"d\" instance_type = \"t2.micro\" ami = \"ami-1\"
+        subnet_id = \"subnet-1\" sg_id = \"sg-1\" key_name = key_pair.ops.name }
+  ec2 { name = \"old\" description = \"d\" instance_type = \"t2.micro\" ami = \"ami-1\"
+        subnet_id = \"subnet-1\" sg_id = \"sg-1\" key_name = \"made-by-hand\" }
+}",
+        )
+        .unwrap();
+        let deploy = &aws_sym.keys[0];
+        assert_eq!(deploy.public_key_file, None);
+        assert_eq!(deploy.private_key_file, Some("deploy.pem".to_string()));
+        let ops = &aws_sym.keys[1];
+        assert_eq!(ops.name, "ops-key");
+        assert_eq!(ops.private_key_file, None);
+        // the key pairs of the file are waited for, the others are not
+        let key_names: Vec<&str> = aws_sym.ec2s.iter().map(|e| e.key_name.as_str()).collect();
+        assert_eq!(
+            key_names,
+            vec!["<key_pair.deploy.id>", "<key_pair.ops.id>", "made-by-hand"]
+        );
+
+        let err = |key: &str| {
+            walk(&format!("aws {{ region = \"eu-west-1\" {} }}", key))
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            err("key_pair \"k\" { }"),
+            "Ast Error: key pair `k` needs a public_key_file or generate = true"
+        );
+        assert_eq!(
+            err("key_pair \"k\" { generate = true public_key_file = \"k.pub\" }"),
+            "Ast Error: key pair `k` sets both public_key_file and generate"
+        );
+        assert_eq!(
+            err("key_pair \"k\" { public_key_file = \"k.pub\" private_key_file = \"k\" }"),
+            "Ast Error: key pair `k` sets private_key_file, only generated keys have one"
+        );
+    }
+
+    #[test]
+    fn test_walk_ast_key_files() {
+        let dir = env::temp_dir().join(format!("awsdsl-key-files-{}", process::id()));
+        let d = dir.display();
+        let aws_syms = walk_files(
+            &dir,
+            &[
+                (
+                    "main.aws",
+                    "aws {
+  region = \"eu-west-1\"
+  key_pair \"ops\" { public_key_file = \"./keys/ops.pub\" }
+  key_pair \"home\" { public_key_file = \"~/.ssh/id_ed25519.pub\" }
+  module \"ci\" { source = \"ci\" }
+}
+",
+                ),
+                (
+                    "ci/main.aws",
+                    "key_pair \"ci\" { generate = true }
+key_pair \"deploy\" { generate = true private_key_file = \"/tmp/deploy.pem\" }
+",
+                ),
+            ],
+        )
+        .unwrap();
+        let files: Vec<String> = aws_syms[0]
+            .keys
+            .iter()
+            .map(|k| {
+                k.public_key_file
+                    .clone()
+                    .or(k.private_key_file.clone())
+                    .unwrap_or_default()
+            })
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                format!("{d}/keys/ops.pub"),
+                "~/.ssh/id_ed25519.pub".to_string(),
+                format!("{d}/ci/ci.pem"),
+                "/tmp/deploy.pem".to_string(),
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_walk_ast_imports_and_modules() {
+        let dir = env::temp_dir().join(format!("awsdsl-modules-{}", process::id()));
+        let d = dir.display();
+        let web = "// a web tier
+var env = \"dev\"
+var replicas = 1
+ec2 \"web\" count = replicas {
+  name = \"web-${env}-${index}\" description = \"web in ${stack}\" instance_type = \"t2.micro\"
+  ami = \"ami-2\" subnet_id = \"subnet-1\" key_name = ssh_key sg_id = \"sg-1\" }
+let ssh_key = \"k\"
+";
+        let aws_syms = walk_files(
+            &dir,
+            &[
+                (
+                    "main.aws",
+                    "import \"common.aws\"
+import \"more.aws\"
+aws {
+  region = region_name
+  ec2 { name = \"bastion\" description = \"d\" instance_type = \"t2.micro\" ami = base_ami
+        subnet_id = \"subnet-1\" key_name = ssh_key sg_id = \"sg-1\" }
+  module \"web\" { source = \"./web\" env = \"prod\" replicas = 2 }
+  module \"web_dev\" { source = \"web/main.aws\" }
+}
+",
+                ),
+                (
+                    "common.aws",
+                    "var region_name = \"eu-west-1\"\nvar base_ami = \"ami-1\"\n",
+                ),
+                ("more.aws", "import \"common.aws\"\nvar ssh_key = \"k\"\n"),
+                ("web/main.aws", web),
+            ],
+        );
+        let ids: Vec<String> = aws_syms.unwrap()[0]
+            .ec2s
+            .iter()
+            .map(|e| format!("{} {} {}", e.id, e.name, e.desc))
+            .collect();
+        assert_eq!(
+            ids,
+            vec![
+                "bastion bastion d",
+                "module.web.web[0] web-prod-0 web in aws",
+                "module.web.web[1] web-prod-1 web in aws",
+                "module.web_dev.web[0] web-dev-0 web in aws"
+            ]
+        );
+
+        let err = walk_files(
+            &dir,
+            &[
+                ("a.aws", "import \"b.aws\""),
+                ("b.aws", "\nimport \"a.aws\""),
+            ],
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err,
+            format!(
+                "error type: Include error, details: in {d}/b.aws: import cycle at (2,1): {d}/a.aws -> {d}/b.aws -> {d}/a.aws"
+            )
+        );
+        let err = walk_files(&dir, &[("c.aws", "import \"nope.aws\"")])
+            .err()
+            .unwrap();
+        assert!(
+            err.starts_with(&format!(
+                "error type: Include error, details: in {d}/c.aws: can't read {d}/nope.aws at (1,8)"
+            )),
+            "{}",
+            err
+        );
+        let err = walk_files(
+            &dir,
+            &[(
+                "d.aws",
+                "aws { region = \"eu-west-1\" module \"w\" { source = \"./web\" size = 3 } }",
+            )],
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err,
+            format!(
+                "Ast Error: in {d}/d.aws: module `w` at (1,28) has no input `size`, declare it with var in {d}/web/main.aws"
+            )
+        );
+        let err = walk_files(
+            &dir,
+            &[(
+                "e.aws",
+                "aws { region = \"eu-west-1\" module \"w\" { source = \"./web\" replicas = \"two\" } }",
+            )],
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err,
+            format!("Ast Error: in {d}/web/main.aws: count expects a number, found string two")
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_walk_ast_several_stacks() {
+        let aws_syms = walk_all(
+            "var base_ami = \"ami-1\"
+aws {
+  name = \"eu\" region = \"eu-west-1\" profile = \"ci\"
+  ec2 { name = \"web\" description = \"${stack}\" instance_type = \"t2.micro\" ami = base_ami
+        subnet_id = \"subnet-1\" key_name = \"k\" sg_id = \"sg-1\" }
+}
+aws {
+  name = \"us\" region = \"us-east-1\"
+  role_arn = \"arn:aws:iam::123456789012:role/deploy\"
+  ec2 { name = \"web\" description = \"${stack}\" instance_type = \"t2.micro\" ami = base_ami
+        subnet_id = \"subnet-2\" key_name = \"k\" sg_id = \"sg-2\" }
+}
+",
+        )
+        .unwrap();
+        assert_eq!(aws_syms.len(), 2);
+        assert_eq!(aws_syms[0].profile.as_deref(), Some("ci"));
+        assert!(aws_syms[0].assume_role.is_none());
+        assert_eq!(aws_syms[0].ec2s[0].desc, "eu");
+        assert_eq!(aws_syms[1].region, "us-east-1");
+        assert_eq!(aws_syms[1].profile, None);
+        let assume_role = aws_syms[1].assume_role.as_ref().unwrap();
+        assert!(assume_role.role_arn.ends_with("role/deploy"));
+        assert_eq!(assume_role.session_name, "awsdsl");
+        assert_eq!(aws_syms[1].ec2s[0].desc, "us");
+
+        // each stack has its own state, named after it
+        let err = walk_all(
+            "aws { name = \"a\" region = \"eu-west-1\" }
+aws { name = \"a\" region = \"us-east-1\" }",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: stack `a` is declared twice, give each aws block its own name"
+        );
+    }
+
+    #[test]
+    fn test_walk_ast_assume_role() {
+        let aws_sym = walk(
+            "var account = \"123456789012\"
+aws {
+  region = \"eu-west-1\"
+  profile = \"ci\"
+  assume_role {
+    role_arn = \"arn:aws:iam::${account}:role/deploy\"
+    session_name = \"ci-\" + region
+    external_id = \"xyz\"
+  }
+}",
+        )
+        .unwrap();
+        let assume_role = aws_sym.assume_role.unwrap();
+        assert_eq!(
+            assume_role.role_arn,
+            "arn:aws:iam::123456789012:role/deploy"
+        );
+        assert_eq!(assume_role.session_name, "ci-eu-west-1");
+        assert_eq!(assume_role.external_id.as_deref(), Some("xyz"));
+
+        let err = walk("aws { region = \"eu-west-1\" assume_role { session_name = \"x\" } }")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: No assume_role role_arn provided!"
+        );
+        let err = walk(
+            "aws { region = \"eu-west-1\" assume_role { role_arn = \"arn:aws:iam::1:role/r\" session_name = \"a b\" } }",
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("session_name must be"));
+        let err = walk("aws { region = \"Ireland\" }").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: region must be an AWS region like eu-west-1, found Ireland"
+        );
+        let err = walk("aws { region = \"eu-west-1\" role_arn = \"deploy\" }")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("role_arn must be an IAM role ARN"));
+    }
+
+    #[test]
+    fn test_walk_ast_errors() {
+        let err = walk("aws { region = zone }").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: undefined variable `zone` at (1,16)"
+        );
+        let err = walk("let r = 1 aws { region = r }").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: region expects a string, found number 1"
+        );
+    }
 }
@@ -0,0 +1,633 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::aws::nodes::{Expr, InterpPart, Op, VarNode};
+use crate::symbols::AstError;
+
+/// The value of an evaluated expression
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    Str(String),
+    Number(f64),
+    Bool(bool),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+}
+
+impl Value {
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "string",
+            Value::Number(_) => "number",
+            Value::Bool(_) => "bool",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Str(s) => write!(f, "{}", s),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::List(items) => {
+                let items: Vec<String> = items.iter().map(|v| v.to_string()).collect();
+                write!(f, "[{}]", items.join(", "))
+            }
+            Value::Map(entries) => {
+                let entries: Vec<String> = entries
+                    .iter()
+                    .map(|(k, v)| format!("{} = {}", k, v))
+                    .collect();
+                write!(f, "{{{}}}", entries.join(", "))
+            }
+        }
+    }
+}
+
+/// A declared variable and where it was declared
+struct Var {
+    value: Value,
+    line_no: usize,
+    column_no: usize,
+    file: String,
+}
+
+/// Names bound by the symbol pass: `region` and `stack` once the aws
+/// block is read, `index` inside a resource expanded by its count and
+/// `each` inside a resource expanded by `for_each`
+pub(crate) const BUILTINS: [&str; 4] = ["region", "stack", "index", "each"];
+
+/// The variables, built-ins and resource attributes visible to the
+/// attribute values
+pub(crate) struct Scope {
+    vars: BTreeMap<String, Var>,
+    builtins: BTreeMap<String, Value>,
+    /// `ec2.<name>.<attr>` -> value, for the resources walked so far
+    attrs: BTreeMap<String, Value>,
+}
+
+impl Scope {
+    pub(crate) fn new() -> Self {
+        Scope {
+            vars: BTreeMap::new(),
+            builtins: BTreeMap::new(),
+            attrs: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn set_builtin(&mut self, name: &str, value: Value) {
+        self.builtins.insert(name.to_string(), value);
+    }
+
+    pub(crate) fn unset_builtin(&mut self, name: &str) {
+        self.builtins.remove(name);
+    }
+
+    /// Forget the resource attributes, e.g. when moving to another stack
+    pub(crate) fn clear_attrs(&mut self) {
+        self.attrs.clear();
+    }
+
+    /// Make the attribute `attr` of the resource `kind.name` referable
+    pub(crate) fn set_attr(&mut self, kind: &str, name: &str, attr: &str, value: Value) {
+        self.attrs
+            .insert(format!("{}.{}.{}", kind, name, attr), value);
+    }
+
+    /// Evaluate and add a declaration. Its value can only refer to the
+    /// variables declared before it, and a name can't be declared twice.
+    pub(crate) fn declare(&mut self, var: &VarNode) -> Result<(), AstError> {
+        self.check_name(var)?;
+        let value = self.eval(&var.value)?;
+        self.define(var, value)
+    }
+
+    /// Add a declaration with `value` in place of its own, e.g. a module
+    /// variable given as an input
+    pub(crate) fn define(&mut self, var: &VarNode, value: Value) -> Result<(), AstError> {
+        self.check_name(var)?;
+        self.vars.insert(
+            var.name.to_string(),
+            Var {
+                value: value,
+                line_no: var.line_no,
+                column_no: var.column_no,
+                file: var.file.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    fn check_name(&self, var: &VarNode) -> Result<(), AstError> {
+        if BUILTINS.contains(&var.name.as_str()) {
+            let s = format!(
+                "{} `{}` at ({},{}) shadows the built-in `{}`",
+                var.kind, var.name, var.line_no, var.column_no, var.name
+            );
+            return Err(AstError::new(s));
+        }
+        if let Some(prev) = self.vars.get(&var.name) {
+            let mut s = format!(
+                "{} `{}` at ({},{}) shadows the declaration at ({},{})",
+                var.kind, var.name, var.line_no, var.column_no, prev.line_no, prev.column_no
+            );
+            if prev.file != var.file {
+                s = format!("{} in {}", s, prev.file);
+            }
+            return Err(AstError::new(s));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn eval(&self, expr: &Expr) -> Result<Value, AstError> {
+        match expr {
+            Expr::Str(s) => Ok(Value::Str(s.to_string())),
+            Expr::Number(n) => Ok(Value::Number(*n)),
+            Expr::Bool(b) => Ok(Value::Bool(*b)),
+            Expr::Ident {
+                name,
+                line_no,
+                column_no,
+            } => {
+                if let Some(var) = self.vars.get(name) {
+                    return Ok(var.value.clone());
+                }
+                if let Some(value) = self.builtins.get(name) {
+                    return Ok(value.clone());
+                }
+                let s = if BUILTINS.contains(&name.as_str()) {
+                    format!(
+                        "built-in `{}` at ({},{}) is not available here",
+                        name, line_no, column_no
+                    )
+                } else {
+                    format!(
+                        "undefined variable `{}` at ({},{})",
+                        name, line_no, column_no
+                    )
+                };
+                Err(AstError::new(s))
+            }
+            Expr::Path {
+                segments,
+                line_no,
+                column_no,
+            } => {
+                let path = segments.join(".");
+                match self.attrs.get(&path) {
+                    Some(value) => Ok(value.clone()),
+                    None => {
+                        let s = format!(
+                            "undefined reference `{}` at ({},{}), resources can only refer to the ones declared before them",
+                            path, line_no, column_no
+                        );
+                        Err(AstError::new(s))
+                    }
+                }
+            }
+            Expr::Interp(parts) => {
+                let mut s = String::from("");
+                for part in parts {
+                    match part {
+                        InterpPart::Text(t) => s.push_str(t),
+                        InterpPart::Expr {
+                            expr,
+                            source,
+                            line_no,
+                            column_no,
+                        } => match self.eval(expr) {
+                            Ok(value) => s.push_str(&value.to_string()),
+                            Err(e) => {
+                                let s = format!(
+                                    "in `${{{}}}` at ({},{}): {}",
+                                    source, line_no, column_no, e.msg
+                                );
+                                return Err(AstError::new(s));
+                            }
+                        },
+                    }
+                }
+                Ok(Value::Str(s))
+            }
+            Expr::List(items) => {
+                let mut values: Vec<Value> = vec![];
+                for item in items {
+                    values.push(self.eval(item)?);
+                }
+                Ok(Value::List(values))
+            }
+            Expr::Map(entries) => {
+                let mut values: BTreeMap<String, Value> = BTreeMap::new();
+                for (key, e) in entries {
+                    values.insert(key.to_string(), self.eval(e)?);
+                }
+                Ok(Value::Map(values))
+            }
+            Expr::Index {
+                expr,
+                index,
+                line_no,
+                column_no,
+            } => {
+                let value = self.eval(expr)?;
+                let index = self.eval(index)?;
+                let item = match (&value, &index) {
+                    (Value::List(items), Value::Number(n)) if n.fract() == 0.0 && *n >= 0.0 => {
+                        items.get(*n as usize)
+                    }
+                    (Value::Map(entries), Value::Str(key)) => entries.get(key),
+                    _ => {
+                        let s = format!(
+                            "`[]` at ({},{}) can't index {} {} with {} {}",
+                            line_no,
+                            column_no,
+                            value.type_name(),
+                            value,
+                            index.type_name(),
+                            index
+                        );
+                        return Err(AstError::new(s));
+                    }
+                };
+                match item {
+                    Some(item) => Ok(item.clone()),
+                    None => {
+                        let s = format!(
+                            "`[]` at ({},{}): no {} in {}",
+                            line_no, column_no, index, value
+                        );
+                        Err(AstError::new(s))
+                    }
+                }
+            }
+            Expr::Unary {
+                op,
+                expr,
+                line_no,
+                column_no,
+            } => match (op, self.eval(expr)?) {
+                (Op::Neg, Value::Number(n)) => Ok(Value::Number(-n)),
+                (Op::Not, Value::Bool(b)) => Ok(Value::Bool(!b)),
+                (_, v) => {
+                    let s = format!(
+                        "`{}` at ({},{}) can't apply to {} {}",
+                        op,
+                        line_no,
+                        column_no,
+                        v.type_name(),
+                        v
+                    );
+                    Err(AstError::new(s))
+                }
+            },
+            Expr::Binary {
+                op,
+                lhs,
+                rhs,
+                line_no,
+                column_no,
+            } => {
+                let lhs = self.eval(lhs)?;
+                // `&&` and `||` don't evaluate their right side when the
+                // left one decides
+                match (op, &lhs) {
+                    (Op::And, Value::Bool(false)) => return Ok(Value::Bool(false)),
+                    (Op::Or, Value::Bool(true)) => return Ok(Value::Bool(true)),
+                    _ => {}
+                }
+                let rhs = self.eval(rhs)?;
+                binary(*op, lhs, rhs, *line_no, *column_no)
+            }
+        }
+    }
+
+    /// Evaluate the value of `attr`, which must be a string
+    pub(crate) fn string(&self, attr: &str, expr: &Expr) -> Result<String, AstError> {
+        match self.eval(expr)? {
+            Value::Str(s) => Ok(s),
+            v => Err(type_error(attr, "string", &v)),
+        }
+    }
+
+    /// Evaluate the value of `attr`, which must be a number
+    pub(crate) fn number(&self, attr: &str, expr: &Expr) -> Result<f64, AstError> {
+        match self.eval(expr)? {
+            Value::Number(n) => Ok(n),
+            v => Err(type_error(attr, "number", &v)),
+        }
+    }
+
+    /// Evaluate the value of `attr`, which must be a list of strings
+    pub(crate) fn string_list(&self, attr: &str, expr: &Expr) -> Result<Vec<String>, AstError> {
+        let items = match self.eval(expr)? {
+            Value::List(items) => items,
+            v => return Err(type_error(attr, "list of strings", &v)),
+        };
+        let mut strings: Vec<String> = vec![];
+        for item in items {
+            match item {
+                Value::Str(s) => strings.push(s),
+                v => return Err(type_error(attr, "list of strings", &v)),
+            }
+        }
+        Ok(strings)
+    }
+
+    /// Evaluate the value of `attr`, which must be a map of strings
+    pub(crate) fn string_map(
+        &self,
+        attr: &str,
+        expr: &Expr,
+    ) -> Result<BTreeMap<String, String>, AstError> {
+        let entries = match self.eval(expr)? {
+            Value::Map(entries) => entries,
+            v => return Err(type_error(attr, "map of strings", &v)),
+        };
+        let mut strings: BTreeMap<String, String> = BTreeMap::new();
+        for (key, value) in entries {
+            match value {
+                Value::Str(s) => strings.insert(key, s),
+                v => return Err(type_error(attr, "map of strings", &v)),
+            };
+        }
+        Ok(strings)
+    }
+
+    /// Evaluate the value of `attr`, which must be a bool
+    pub(crate) fn bool(&self, attr: &str, expr: &Expr) -> Result<bool, AstError> {
+        match self.eval(expr)? {
+            Value::Bool(b) => Ok(b),
+            v => Err(type_error(attr, "bool", &v)),
+        }
+    }
+}
+
+/// Apply `op` found at (`line_no`,`column_no`), both operands having
+/// the same type
+fn binary(
+    op: Op,
+    lhs: Value,
+    rhs: Value,
+    line_no: usize,
+    column_no: usize,
+) -> Result<Value, AstError> {
+    let value = match (op, &lhs, &rhs) {
+        (Op::Eq, _, _) if lhs.type_name() == rhs.type_name() => Value::Bool(lhs == rhs),
+        (Op::Ne, _, _) if lhs.type_name() == rhs.type_name() => Value::Bool(lhs != rhs),
+        (Op::Add, Value::Str(a), Value::Str(b)) => Value::Str(format!("{}{}", a, b)),
+        // lists are concatenated, maps merged with the right side winning
+        (Op::Add, Value::List(a), Value::List(b)) => {
+            Value::List(a.iter().chain(b.iter()).cloned().collect())
+        }
+        (Op::Add, Value::Map(a), Value::Map(b)) => {
+            let mut merged = a.clone();
+            merged.extend(b.iter().map(|(k, v)| (k.to_string(), v.clone())));
+            Value::Map(merged)
+        }
+        (Op::Lt, Value::Str(a), Value::Str(b)) => Value::Bool(a < b),
+        (Op::Le, Value::Str(a), Value::Str(b)) => Value::Bool(a <= b),
+        (Op::Gt, Value::Str(a), Value::Str(b)) => Value::Bool(a > b),
+        (Op::Ge, Value::Str(a), Value::Str(b)) => Value::Bool(a >= b),
+        (Op::And, Value::Bool(a), Value::Bool(b)) => Value::Bool(*a && *b),
+        (Op::Or, Value::Bool(a), Value::Bool(b)) => Value::Bool(*a || *b),
+        (Op::Div | Op::Rem, Value::Number(_), Value::Number(b)) if *b == 0.0 => {
+            let s = format!("`{}` at ({},{}) divides by zero", op, line_no, column_no);
+            return Err(AstError::new(s));
+        }
+        (_, Value::Number(a), Value::Number(b)) => match op {
+            Op::Add => Value::Number(a + b),
+            Op::Sub => Value::Number(a - b),
+            Op::Mul => Value::Number(a * b),
+            Op::Div => Value::Number(a / b),
+            Op::Rem => Value::Number(a % b),
+            Op::Lt => Value::Bool(a < b),
+            Op::Le => Value::Bool(a <= b),
+            Op::Gt => Value::Bool(a > b),
+            Op::Ge => Value::Bool(a >= b),
+            _ => return Err(operands_error(op, &lhs, &rhs, line_no, column_no)),
+        },
+        _ => return Err(operands_error(op, &lhs, &rhs, line_no, column_no)),
+    };
+    Ok(value)
+}
+
+fn operands_error(op: Op, lhs: &Value, rhs: &Value, line_no: usize, column_no: usize) -> AstError {
+    AstError::new(format!(
+        "`{}` at ({},{}) can't apply to {} {} and {} {}",
+        op,
+        line_no,
+        column_no,
+        lhs.type_name(),
+        lhs,
+        rhs.type_name(),
+        rhs
+    ))
+}
+
+fn type_error(attr: &str, expected: &str, found: &Value) -> AstError {
+    AstError::new(format!(
+        "{} expects a {}, found {} {}",
+        attr,
+        expected,
+        found.type_name(),
+        found
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var(name: &str, value: Expr, line_no: usize) -> VarNode {
+        VarNode {
+            kind: "var".to_string(),
+            name: name.to_string(),
+            value: value,
+            line_no: line_no,
+            column_no: 5,
+            file: "".to_string(),
+        }
+    }
+
+    fn ident(name: &str) -> Expr {
+        Expr::Ident {
+            name: name.to_string(),
+            line_no: 9,
+            column_no: 3,
+        }
+    }
+
+    #[test]
+    fn test_declare_and_eval() {
+        let mut scope = Scope::new();
+        scope
+            .declare(&var("subnet", Expr::Str("subnet-1".to_string()), 1))
+            .unwrap();
+        scope
+            .declare(&var("web_subnet", ident("subnet"), 2))
+            .unwrap();
+        scope
+            .declare(&var("replicas", Expr::Number(2.0), 3))
+            .unwrap();
+        assert_eq!(
+            scope.string("subnet_id", &ident("web_subnet")).unwrap(),
+            "subnet-1"
+        );
+        assert_eq!(scope.number("count", &ident("replicas")).unwrap(), 2.0);
+        let err = scope.string("ami", &ident("replicas")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: ami expects a string, found number 2"
+        );
+    }
+
+    fn interp(source: &str, expr: Expr) -> Expr {
+        Expr::Interp(vec![
+            InterpPart::Text("web-".to_string()),
+            InterpPart::Expr {
+                expr: expr,
+                source: source.to_string(),
+                line_no: 2,
+                column_no: 12,
+            },
+        ])
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let mut scope = Scope::new();
+        scope
+            .declare(&var("env", Expr::Str("prod".to_string()), 1))
+            .unwrap();
+        let name = interp("env", ident("env"));
+        assert_eq!(scope.string("name", &name).unwrap(), "web-prod");
+
+        let index = interp("index", ident("index"));
+        let err = scope.string("name", &index).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: in `${index}` at (2,12): built-in `index` at (9,3) is not available here"
+        );
+        scope.set_builtin("index", Value::Number(2.0));
+        assert_eq!(scope.string("name", &index).unwrap(), "web-2");
+
+        let path = Expr::Path {
+            segments: vec!["ec2".to_string(), "db".to_string(), "name".to_string()],
+            line_no: 2,
+            column_no: 14,
+        };
+        assert!(
+            scope
+                .string("name", &interp("ec2.db.name", path.clone()))
+                .is_err()
+        );
+        scope.set_attr("ec2", "db", "name", Value::Str("db-1".to_string()));
+        assert_eq!(
+            scope.string("name", &interp("ec2.db.name", path)).unwrap(),
+            "web-db-1"
+        );
+    }
+
+    #[test]
+    fn test_undefined_and_shadowed() {
+        let mut scope = Scope::new();
+        let err = scope.declare(&var("a", ident("b"), 1)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: undefined variable `b` at (9,3)"
+        );
+        scope.declare(&var("a", Expr::Bool(true), 1)).unwrap();
+        let err = scope.declare(&var("a", Expr::Bool(false), 4)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: var `a` at (4,5) shadows the declaration at (1,5)"
+        );
+        assert!(scope.declare(&var("index", Expr::Bool(false), 5)).is_err());
+    }
+
+    fn binary(op: Op, lhs: Expr, rhs: Expr) -> Expr {
+        Expr::Binary {
+            op: op,
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+            line_no: 4,
+            column_no: 7,
+        }
+    }
+
+    #[test]
+    fn test_operators() {
+        let mut scope = Scope::new();
+        scope
+            .declare(&var("replicas", Expr::Number(3.0), 1))
+            .unwrap();
+        scope
+            .declare(&var("env", Expr::Str("prod".to_string()), 2))
+            .unwrap();
+        let count = binary(Op::Mul, ident("replicas"), Expr::Number(2.0));
+        assert_eq!(scope.number("count", &count).unwrap(), 6.0);
+        let enabled = binary(Op::Eq, ident("env"), Expr::Str("prod".to_string()));
+        assert_eq!(scope.eval(&enabled).unwrap(), Value::Bool(true));
+        let name = binary(Op::Add, Expr::Str("web-".to_string()), ident("env"));
+        assert_eq!(scope.string("name", &name).unwrap(), "web-prod");
+
+        let err = scope
+            .eval(&binary(Op::Add, ident("env"), Expr::Number(1.0)))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: `+` at (4,7) can't apply to string prod and number 1"
+        );
+        let err = scope
+            .eval(&binary(Op::Rem, ident("replicas"), Expr::Number(0.0)))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Ast Error: `%` at (4,7) divides by zero");
+        let not = Expr::Unary {
+            op: Op::Not,
+            expr: Box::new(Expr::Number(1.0)),
+            line_no: 4,
+            column_no: 2,
+        };
+        assert!(scope.eval(&not).is_err());
+        // the right side isn't evaluated when the left one decides
+        let or = binary(Op::Or, Expr::Bool(true), ident("undefined"));
+        assert_eq!(scope.eval(&or).unwrap(), Value::Bool(true));
+    }
+
+    #[test]
+    fn test_lists_and_maps() {
+        let scope = Scope::new();
+        let sgs = Expr::List(vec![
+            Expr::Str("sg-1".to_string()),
+            Expr::Str("sg-2".to_string()),
+        ]);
+        assert_eq!(
+            scope.string_list("sg_ids", &sgs).unwrap(),
+            vec!["sg-1", "sg-2"]
+        );
+        let more = binary(Op::Add, sgs.clone(), Expr::List(vec![Expr::Number(3.0)]));
+        let err = scope.string_list("sg_ids", &more).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: sg_ids expects a list of strings, found number 3"
+        );
+        let tags = Expr::Map(vec![
+            ("team".to_string(), Expr::Str("web".to_string())),
+            ("env".to_string(), Expr::Str("dev".to_string())),
+        ]);
+        let env = Expr::Map(vec![("env".to_string(), Expr::Str("prod".to_string()))]);
+        let merged = scope
+            .string_map("tags", &binary(Op::Add, tags, env))
+            .unwrap();
+        assert_eq!(merged["env"], "prod");
+        assert_eq!(merged["team"], "web");
+        let err = scope.string_map("tags", &sgs).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Ast Error: tags expects a map of strings, found list [sg-1, sg-2]"
+        );
+    }
+}